@@ -0,0 +1,401 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Admission control of requests, which limits how many requests are handled at once and queues
+//! the rest, up to a bound.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::oneshot;
+use utoipa::ToSchema;
+
+/// How much the latest sample weighs in the running averages of an [`AdmissionQueue`].
+const SMOOTHING: f64 = 0.2;
+
+/// Limits on the requests handled at once by an endpoint.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdmissionLimits {
+    /// The maximum number of requests handled at once. `0` means there is no limit.
+    pub max_concurrent: u32,
+
+    /// The maximum number of requests waiting for their turn, past which requests are rejected.
+    pub max_queued: u32,
+}
+
+/// The state of an [`AdmissionQueue`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct QueueStatus {
+    /// The number of requests being handled.
+    pub in_flight: u32,
+
+    /// The number of requests waiting for their turn.
+    pub queued: u32,
+
+    /// The running average of the time, in milliseconds, that requests waited for their turn.
+    pub average_wait_ms: u64,
+}
+
+/// An error that occurred while waiting for a turn in an [`AdmissionQueue`].
+#[derive(Debug, Error, PartialEq)]
+pub enum AdmissionError {
+    #[error("too many requests are queued, retry after {retry_after:?}")]
+    QueueFull {
+        /// An estimate of when there will be room in the queue.
+        retry_after: Duration,
+    },
+}
+
+/// A queue of requests waiting for their turn to be handled, under [`AdmissionLimits`].
+///
+/// Requests with a higher priority are admitted first, and requests with the same priority are
+/// admitted in the order they arrived. A request that stops waiting (e.g. because its client
+/// disconnected) gives its turn to the next one.
+#[derive(Clone, Default)]
+pub struct AdmissionQueue {
+    inner: Arc<Mutex<QueueState>>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    limits: AdmissionLimits,
+
+    /// The number of live [`AdmissionPermit`]s.
+    in_flight: u32,
+
+    /// The requests waiting for their turn.
+    waiting: BinaryHeap<Waiter>,
+
+    /// The arrival number of the next request.
+    next_seq: u64,
+
+    /// The running average of the time requests waited for their turn.
+    average_wait: Duration,
+
+    /// The running average of the time requests were handled for, if any were handled.
+    average_duration: Option<Duration>,
+}
+
+/// A request waiting for its turn.
+struct Waiter {
+    priority: i32,
+    seq: u64,
+    turn_tx: oneshot::Sender<AdmissionPermit>,
+}
+
+impl Waiter {
+    /// The key by which waiters are ordered, greatest first.
+    fn key(&self) -> (i32, Reverse<u64>) {
+        (self.priority, Reverse(self.seq))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl AdmissionQueue {
+    /// Creates a queue with the provided `limits`.
+    pub fn new(limits: AdmissionLimits) -> Self {
+        let queue = Self::default();
+        queue.set_limits(limits);
+        queue
+    }
+
+    /// Changes the limits of this queue. Requests that are already queued stay queued, even if
+    /// the queue is now over its bound, but are admitted at once if there is no longer a
+    /// concurrency limit.
+    pub fn set_limits(&self, limits: AdmissionLimits) {
+        let mut state = self.inner.lock().unwrap();
+        state.limits = limits;
+        self.admit_waiting(&mut state);
+    }
+
+    /// Returns the current state of this queue.
+    pub fn status(&self) -> QueueStatus {
+        let state = self.inner.lock().unwrap();
+        QueueStatus {
+            in_flight: state.in_flight,
+            queued: state.waiting.len() as u32,
+            average_wait_ms: state.average_wait.as_millis() as u64,
+        }
+    }
+
+    /// Waits for the turn of a request with `priority`, returning a permit that must be kept
+    /// while the request is handled.
+    ///
+    /// Fails at once if the request would have to wait but the queue is full.
+    pub async fn admit(&self, priority: i32) -> Result<AdmissionPermit, AdmissionError> {
+        let queued_at = Instant::now();
+        let turn_rx = {
+            let mut state = self.inner.lock().unwrap();
+            if state.has_room() && state.waiting.is_empty() {
+                state.in_flight += 1;
+                state.record_wait(Duration::ZERO);
+                return Ok(self.permit());
+            }
+
+            if state.waiting.len() >= state.limits.max_queued as usize {
+                return Err(AdmissionError::QueueFull {
+                    retry_after: state.retry_after(),
+                });
+            }
+
+            let (turn_tx, turn_rx) = oneshot::channel();
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.waiting.push(Waiter {
+                priority,
+                seq,
+                turn_tx,
+            });
+            turn_rx
+        };
+
+        // PANIC SAFETY: waiters are only dropped after being sent their permit, since the queue
+        // outlives every permit.
+        let mut permit = turn_rx.await.expect("the admission queue was dropped");
+        permit.started = Instant::now();
+        self.inner.lock().unwrap().record_wait(queued_at.elapsed());
+        Ok(permit)
+    }
+
+    fn permit(&self) -> AdmissionPermit {
+        AdmissionPermit {
+            queue: Some(self.clone()),
+            started: Instant::now(),
+        }
+    }
+
+    /// Gives the turn of a finished request, handled for `duration`, to the next request waiting.
+    fn release(&self, duration: Duration) {
+        let mut state = self.inner.lock().unwrap();
+        state.record_duration(duration);
+        state.in_flight -= 1;
+        self.admit_waiting(&mut state);
+    }
+
+    /// Admits the waiting requests, highest priority first, while there is room for them.
+    fn admit_waiting(&self, state: &mut QueueState) {
+        while state.has_room() {
+            let Some(waiter) = state.waiting.pop() else {
+                return;
+            };
+            state.in_flight += 1;
+            if let Err(permit) = waiter.turn_tx.send(self.permit()) {
+                // the request stopped waiting, so the turn goes to the next one
+                state.in_flight -= 1;
+                permit.disarm();
+            }
+        }
+    }
+}
+
+impl QueueState {
+    /// Returns **`true`** if another request can be handled at once.
+    fn has_room(&self) -> bool {
+        self.limits.max_concurrent == 0 || self.in_flight < self.limits.max_concurrent
+    }
+
+    fn record_wait(&mut self, wait: Duration) {
+        self.average_wait = smooth(self.average_wait, wait);
+    }
+
+    fn record_duration(&mut self, duration: Duration) {
+        self.average_duration = Some(match self.average_duration {
+            Some(average) => smooth(average, duration),
+            None => duration,
+        });
+    }
+
+    /// Returns an estimate of when a request will be able to join the queue, which is when the
+    /// request at the front of the queue is admitted.
+    fn retry_after(&self) -> Duration {
+        let concurrent = self.limits.max_concurrent.max(1);
+        let estimate = self
+            .average_duration
+            .map_or(Duration::ZERO, |average| average / concurrent);
+
+        estimate.max(Duration::from_secs(1))
+    }
+}
+
+/// Returns the running average `average` updated with `sample`.
+fn smooth(average: Duration, sample: Duration) -> Duration {
+    average.mul_f64(1.0 - SMOOTHING) + sample.mul_f64(SMOOTHING)
+}
+
+/// The turn of a request admitted by an [`AdmissionQueue`], which is given to the next request
+/// waiting when this is dropped.
+pub struct AdmissionPermit {
+    /// The queue that admitted the request, or [`None`] if this permit no longer holds a turn.
+    queue: Option<AdmissionQueue>,
+
+    /// When the request was admitted.
+    started: Instant,
+}
+
+impl AdmissionPermit {
+    /// Drops this permit without giving its turn to another request, for a permit that never
+    /// reached its request.
+    fn disarm(mut self) {
+        self.queue = None;
+    }
+}
+
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            queue.release(self.started.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll};
+
+    use futures::task::noop_waker_ref;
+
+    use super::*;
+
+    fn limits(max_concurrent: u32, max_queued: u32) -> AdmissionLimits {
+        AdmissionLimits {
+            max_concurrent,
+            max_queued,
+        }
+    }
+
+    #[tokio::test]
+    async fn unlimited() {
+        let queue = AdmissionQueue::new(AdmissionLimits::default());
+        let permits: Vec<_> = futures::future::join_all((0..8).map(|_| queue.admit(0)))
+            .await
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(queue.status().in_flight, 8);
+        drop(permits);
+        assert_eq!(queue.status().in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn queues_and_rejects() {
+        let queue = AdmissionQueue::new(limits(1, 1));
+        let first = queue.admit(0).await.unwrap();
+
+        let mut second = pin!(queue.admit(0));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(
+            queue.status(),
+            QueueStatus {
+                in_flight: 1,
+                queued: 1,
+                average_wait_ms: 0
+            }
+        );
+
+        // the queue is full
+        assert!(matches!(
+            queue.admit(0).await,
+            Err(AdmissionError::QueueFull { retry_after }) if retry_after >= Duration::from_secs(1)
+        ));
+
+        drop(first);
+        let Poll::Ready(Ok(_second)) = second.as_mut().poll(&mut cx) else {
+            panic!("the second request was not admitted");
+        };
+        assert_eq!(queue.status().in_flight, 1);
+        assert_eq!(queue.status().queued, 0);
+    }
+
+    #[tokio::test]
+    async fn higher_priority_first() {
+        let queue = AdmissionQueue::new(limits(1, 2));
+        let first = queue.admit(0).await.unwrap();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        let mut low = pin!(queue.admit(0));
+        let mut high = pin!(queue.admit(5));
+        assert!(low.as_mut().poll(&mut cx).is_pending());
+        assert!(high.as_mut().poll(&mut cx).is_pending());
+
+        drop(first);
+        assert!(low.as_mut().poll(&mut cx).is_pending());
+        let Poll::Ready(Ok(high_permit)) = high.as_mut().poll(&mut cx) else {
+            panic!("the high priority request was not admitted");
+        };
+
+        drop(high_permit);
+        assert!(matches!(low.as_mut().poll(&mut cx), Poll::Ready(Ok(_))));
+    }
+
+    #[tokio::test]
+    async fn abandoned_turns_go_to_the_next_request() {
+        let queue = AdmissionQueue::new(limits(1, 2));
+        let first = queue.admit(0).await.unwrap();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        let mut abandoned = Box::pin(queue.admit(0));
+        let mut next = pin!(queue.admit(0));
+        assert!(abandoned.as_mut().poll(&mut cx).is_pending());
+        assert!(next.as_mut().poll(&mut cx).is_pending());
+
+        drop(abandoned);
+        drop(first);
+        let Poll::Ready(Ok(_next)) = next.as_mut().poll(&mut cx) else {
+            panic!("the next request was not admitted");
+        };
+        assert_eq!(queue.status().in_flight, 1);
+    }
+
+    #[tokio::test]
+    async fn lifting_the_limit_admits_everyone() {
+        let queue = AdmissionQueue::new(limits(1, 1));
+        let _first = queue.admit(0).await.unwrap();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        let mut second = pin!(queue.admit(0));
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+
+        queue.set_limits(AdmissionLimits::default());
+        let Poll::Ready(Ok(_second)) = second.as_mut().poll(&mut cx) else {
+            panic!("the second request was not admitted");
+        };
+        assert_eq!(queue.status().in_flight, 2);
+    }
+}
@@ -0,0 +1,131 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Cooperative cancellation of work that is not stopped by dropping the future awaiting it, such
+//! as generations running on blocking threads.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+
+/// A token shared by some work and the parties that may cancel it. Cancelling any clone of the
+/// token cancels all of them.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<TokenState>,
+}
+
+#[derive(Debug, Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancellationToken {
+    /// Creates a new token that is not cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the token, waking every task waiting for [`CancellationToken::cancelled`].
+    pub fn cancel(&self) {
+        if !self.inner.cancelled.swap(true, Ordering::SeqCst) {
+            self.inner.notify.notify_waiters();
+        }
+    }
+
+    /// Returns **`true`** if the token was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the token is cancelled.
+    pub async fn cancelled(&self) {
+        // a `Notified` receives `notify_waiters` as soon as it is created, so the token cannot be
+        // cancelled unnoticed between the check and the wait
+        let notified = self.inner.notify.notified();
+        if self.is_cancelled() {
+            return;
+        }
+        notified.await
+    }
+
+    /// Returns a guard that cancels the token when dropped, which ties the work sharing the token
+    /// to the lifetime of the guard's owner (e.g. a request handler that may be dropped when its
+    /// peer disconnects).
+    pub fn drop_guard(self) -> CancelOnDrop {
+        CancelOnDrop { token: self }
+    }
+}
+
+/// A guard that cancels its [`CancellationToken`] when dropped.
+#[derive(Debug)]
+pub struct CancelOnDrop {
+    token: CancellationToken,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.token.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[test]
+    fn cancel_reaches_every_clone() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+
+        token.cancel();
+        assert!(clone.is_cancelled());
+    }
+
+    #[test]
+    fn dropping_the_guard_cancels() {
+        let token = CancellationToken::new();
+        let guard = token.clone().drop_guard();
+        assert!(!token.is_cancelled());
+
+        drop(guard);
+        assert!(token.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn cancelled_wakes_waiters() {
+        let token = CancellationToken::new();
+        let waiter = tokio::spawn({
+            let token = token.clone();
+            async move { token.cancelled().await }
+        });
+
+        tokio::task::yield_now().await;
+        token.cancel();
+        timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("the waiter was not woken")
+            .unwrap();
+
+        // waiting on a cancelled token returns at once
+        timeout(Duration::from_secs(1), token.cancelled())
+            .await
+            .expect("the cancelled token was waited on");
+    }
+}
@@ -0,0 +1,635 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Chat templates, which turn the messages of a chat into the prompt a model was trained on.
+//!
+//! Templates are [Jinja] sources, as found in the `tokenizer.chat_template` metadata of GGUF
+//! files, and are rendered the same way as [Hugging Face's `apply_chat_template`][hf]: with the
+//! `messages`, `add_generation_prompt`, `bos_token` and `eos_token` variables.
+//!
+//! [Jinja]: https://jinja.palletsprojects.com/
+//! [hf]: https://huggingface.co/docs/transformers/main/en/chat_templating
+
+use either::Either;
+use minijinja::{context, Environment, Error, ErrorKind};
+use serde::Serialize;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::gguf::GgufMetadata;
+use crate::llm::{ChatMessage, CompletionArgs, ASSISTANT_TAG, SYSTEM_TAG, TOOL_TAG, USER_TAG};
+
+/// The template reproducing the role tags that Edgen used before chat templates were supported.
+const LEGACY: &str = concat!(
+    "{% for message in messages %}{% if message.content %}",
+    "{% if message.role == 'system' %}<|SYSTEM|>",
+    "{% elif message.role == 'user' %}<|USER|>",
+    "{% elif message.role == 'assistant' %}<|ASSISTANT|>",
+    "{% else %}<|TOOL|>{% endif %}",
+    "{{ message.content }}",
+    "{% endif %}{% endfor %}",
+    "{% if add_generation_prompt %}<|ASSISTANT|>{% endif %}",
+);
+
+const CHATML: &str = concat!(
+    "{% for message in messages %}",
+    "<|im_start|>{{ message.role }}\n{{ message.content }}<|im_end|>\n",
+    "{% endfor %}",
+    "{% if add_generation_prompt %}<|im_start|>assistant\n{% endif %}",
+);
+
+const LLAMA2: &str = concat!(
+    "{% if messages[0].role == 'system' %}",
+    "{% set system = messages[0].content %}{% set messages = messages[1:] %}",
+    "{% endif %}",
+    "{% for message in messages %}",
+    "{% if (message.role == 'user') != (loop.index0 % 2 == 0) %}",
+    "{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}",
+    "{% endif %}",
+    "{% if message.role == 'user' %}{{ bos_token }}[INST] ",
+    "{% if loop.first and system is defined %}<<SYS>>\n{{ system }}\n<</SYS>>\n\n{% endif %}",
+    "{{ message.content }} [/INST]",
+    "{% else %} {{ message.content }} {{ eos_token }}{% endif %}",
+    "{% endfor %}",
+);
+
+const LLAMA3: &str = concat!(
+    "{{ bos_token }}",
+    "{% for message in messages %}",
+    "<|start_header_id|>{{ message.role }}<|end_header_id|>\n\n{{ message.content }}<|eot_id|>",
+    "{% endfor %}",
+    "{% if add_generation_prompt %}<|start_header_id|>assistant<|end_header_id|>\n\n{% endif %}",
+);
+
+const MISTRAL: &str = concat!(
+    "{{ bos_token }}",
+    "{% for message in messages %}",
+    "{% if (message.role == 'user') != (loop.index0 % 2 == 0) %}",
+    "{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}",
+    "{% endif %}",
+    "{% if message.role == 'user' %}[INST] {{ message.content }} [/INST]",
+    "{% else %}{{ message.content }}{{ eos_token }}{% endif %}",
+    "{% endfor %}",
+);
+
+const GEMMA: &str = concat!(
+    "{{ bos_token }}",
+    "{% for message in messages %}",
+    "{% if (message.role == 'user') != (loop.index0 % 2 == 0) %}",
+    "{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}",
+    "{% endif %}",
+    "<start_of_turn>{% if message.role == 'assistant' %}model{% else %}user{% endif %}\n",
+    "{{ message.content }}<end_of_turn>\n",
+    "{% endfor %}",
+    "{% if add_generation_prompt %}<start_of_turn>model\n{% endif %}",
+);
+
+const PHI3: &str = concat!(
+    "{% for message in messages %}",
+    "<|{{ message.role }}|>\n{{ message.content }}<|end|>\n",
+    "{% endfor %}",
+    "{% if add_generation_prompt %}<|assistant|>\n{% endif %}",
+);
+
+const ZEPHYR: &str = concat!(
+    "{% for message in messages %}",
+    "<|{{ message.role }}|>\n{{ message.content }}{{ eos_token }}\n",
+    "{% endfor %}",
+    "{% if add_generation_prompt %}<|assistant|>\n{% endif %}",
+);
+
+/// The names of the built-in templates, usable in the `chat_template` model setting.
+pub const TEMPLATE_NAMES: &[&str] = &[
+    "edgen", "chatml", "llama2", "llama3", "mistral", "gemma", "phi3", "zephyr",
+];
+
+/// Markers that end a message in common templates. Those found in a template are used as stop
+/// words, so that generation ends along with the assistant message.
+const END_MARKERS: &[&str] = &[
+    ASSISTANT_TAG,
+    USER_TAG,
+    TOOL_TAG,
+    SYSTEM_TAG,
+    "<|im_end|>",
+    "<|eot_id|>",
+    "<|end|>",
+    "<end_of_turn>",
+    "<|endoftext|>",
+    "<|user|>",
+    "[INST]",
+];
+
+/// Replaces the content of the last assistant message to find where it ends in the prompt.
+const SENTINEL: &str = "\u{1}EDGEN_HISTORY_END\u{1}";
+
+/// An error that occurred while rendering a [`ChatTemplate`].
+#[derive(Debug, Error)]
+pub enum TemplateError {
+    #[error("invalid chat template: {0}")]
+    Syntax(String),
+    #[error("failed to render the chat template: {0}")]
+    Render(String),
+}
+
+/// A prompt rendered from a [`ChatTemplate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prompt {
+    /// The text of the prompt.
+    pub text: String,
+
+    /// The length of the beginning of `text` that was already in the context of a previous
+    /// prompt, up to the end of the last assistant message. `0` if there is no assistant message.
+    pub history_len: usize,
+}
+
+/// A message as seen by a template.
+#[derive(Debug, Clone, Serialize)]
+struct Message {
+    role: &'static str,
+    content: String,
+}
+
+/// A template rendering chat messages into a prompt.
+#[derive(Debug, Clone)]
+pub struct ChatTemplate {
+    /// The Jinja source of the template.
+    source: String,
+
+    /// The text of the token that starts a sequence.
+    bos_token: String,
+
+    /// The text of the token that ends a sequence.
+    eos_token: String,
+
+    /// The phrases at which generation must stop.
+    stop_words: Vec<String>,
+}
+
+impl ChatTemplate {
+    /// Returns the built-in template named `name`, if any. See [`TEMPLATE_NAMES`].
+    pub fn named(name: &str) -> Option<Self> {
+        let (source, bos, eos) = match name {
+            "edgen" => (LEGACY, "", ""),
+            "chatml" => (CHATML, "", ""),
+            "llama2" => (LLAMA2, "<s>", "</s>"),
+            "llama3" => (LLAMA3, "<|begin_of_text|>", "<|eot_id|>"),
+            "mistral" => (MISTRAL, "<s>", "</s>"),
+            "gemma" => (GEMMA, "<bos>", "<eos>"),
+            "phi3" => (PHI3, "", "<|endoftext|>"),
+            "zephyr" => (ZEPHYR, "", "</s>"),
+            _ => return None,
+        };
+
+        Some(Self::from_source(source, bos, eos))
+    }
+
+    /// Creates a template from its Jinja `source`, given the texts of the tokens starting and
+    /// ending a sequence.
+    pub fn from_source(
+        source: impl Into<String>,
+        bos_token: impl Into<String>,
+        eos_token: impl Into<String>,
+    ) -> Self {
+        let source = source.into();
+        let eos_token = eos_token.into();
+
+        let mut stop_words: Vec<String> = END_MARKERS
+            .iter()
+            .filter(|marker| source.contains(*marker))
+            .map(|marker| marker.to_string())
+            .collect();
+        if !eos_token.is_empty() && !stop_words.contains(&eos_token) {
+            stop_words.push(eos_token.clone());
+        }
+
+        Self {
+            source,
+            bos_token: bos_token.into(),
+            eos_token,
+            stop_words,
+        }
+    }
+
+    /// Returns the template of a model, given its `chat_template` setting and the metadata of its
+    /// file.
+    ///
+    /// The setting can be the name of a built-in template or a Jinja source, and takes precedence
+    /// over the template in the metadata. Without either, the legacy `edgen` template is used.
+    pub fn for_model(setting: Option<&str>, metadata: Option<&GgufMetadata>) -> Self {
+        let bos = metadata
+            .and_then(|metadata| metadata.token("tokenizer.ggml.bos_token_id"))
+            .unwrap_or_default();
+        let eos = metadata
+            .and_then(|metadata| metadata.token("tokenizer.ggml.eos_token_id"))
+            .unwrap_or_default();
+
+        if let Some(setting) = setting {
+            if let Some(template) = Self::named(setting) {
+                return template;
+            }
+            if setting.contains("{%") || setting.contains("{{") {
+                return Self::from_source(setting, bos, eos);
+            }
+            warn!("Unknown chat template \"{setting}\", expected one of {TEMPLATE_NAMES:?} or a Jinja template");
+        }
+
+        match metadata.and_then(|metadata| metadata.get_str("tokenizer.chat_template")) {
+            Some(source) => Self::from_source(source, bos, eos),
+            None => Self::default(),
+        }
+    }
+
+    /// Replaces the text of the token that starts a sequence.
+    ///
+    /// Runtimes whose tokenizer adds this token by themselves should set it to an empty string.
+    pub fn with_bos_token(mut self, bos_token: impl Into<String>) -> Self {
+        self.bos_token = bos_token.into();
+        self
+    }
+
+    /// Returns the phrases that end an assistant message in this template.
+    pub fn stop_words(&self) -> &[String] {
+        &self.stop_words
+    }
+
+    /// Renders the prompt for `args`, ending where the model must start generating.
+    ///
+    /// Instructions for tools and response formats are added to the system message. If the
+    /// template rejects the messages (e.g. because it does not support system messages), they
+    /// are rendered again after merging system and tool messages into user messages.
+    pub fn apply(&self, args: &CompletionArgs) -> Result<Prompt, TemplateError> {
+        let messages = messages(args);
+        let (text, messages) = match self.render(&messages) {
+            Ok(text) => (text, messages),
+            Err(e) => {
+                let simple = simplify(messages);
+                match self.render(&simple) {
+                    Ok(text) => (text, simple),
+                    Err(_) => return Err(e),
+                }
+            }
+        };
+
+        let history_len = match messages.iter().rposition(|m| m.role == "assistant") {
+            Some(idx) => {
+                let mut marked = messages;
+                marked[idx].content = SENTINEL.to_string();
+                let marked_text = self.render(&marked)?;
+                match marked_text.find(SENTINEL) {
+                    Some(pos) => {
+                        let after = marked_text.len() - pos - SENTINEL.len();
+                        text.len().saturating_sub(after)
+                    }
+                    None => 0,
+                }
+            }
+            None => 0,
+        };
+
+        // a template that renders the content differently could place the index anywhere
+        let history_len = if text.is_char_boundary(history_len) {
+            history_len
+        } else {
+            0
+        };
+
+        Ok(Prompt { text, history_len })
+    }
+
+    /// Renders `messages`, followed by the start of an assistant message.
+    fn render(&self, messages: &[Message]) -> Result<String, TemplateError> {
+        let mut env = Environment::new();
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function(
+            "raise_exception",
+            |message: String| -> Result<String, Error> {
+                Err(Error::new(ErrorKind::InvalidOperation, message))
+            },
+        );
+
+        let template = env
+            .template_from_str(&self.source)
+            .map_err(move |e| TemplateError::Syntax(e.to_string()))?;
+        template
+            .render(context! {
+                messages => messages,
+                add_generation_prompt => true,
+                bos_token => self.bos_token,
+                eos_token => self.eos_token,
+            })
+            .map_err(move |e| TemplateError::Render(e.to_string()))
+    }
+}
+
+impl Default for ChatTemplate {
+    fn default() -> Self {
+        Self::from_source(LEGACY, "", "")
+    }
+}
+
+/// Converts the messages of `args` into template messages, adding instructions for tools and
+/// response formats to the system message.
+fn messages(args: &CompletionArgs) -> Vec<Message> {
+    let mut messages: Vec<Message> = args
+        .messages
+        .iter()
+        .map(|message| match message {
+            ChatMessage::System { content, .. } => Message {
+                role: "system",
+                content: content.clone().unwrap_or_default(),
+            },
+            ChatMessage::User { content, .. } => Message {
+                role: "user",
+                content: match content {
+                    Either::Left(text) => text.clone(),
+                    Either::Right(parts) => parts.iter().map(|part| part.to_string()).collect(),
+                },
+            },
+            ChatMessage::Assistant {
+                content,
+                tool_calls,
+                ..
+            } => {
+                let mut text = content.clone().unwrap_or_default();
+                if let Some(calls) = tool_calls {
+                    text.push_str(&crate::tools::render_tool_calls(calls));
+                }
+                Message {
+                    role: "assistant",
+                    content: text,
+                }
+            }
+            ChatMessage::Tool { content, .. } => Message {
+                role: "tool",
+                content: content.clone().unwrap_or_default(),
+            },
+        })
+        .collect();
+
+    if let Some(instructions) = args.instructions() {
+        match messages.first_mut() {
+            Some(first) if first.role == "system" && !first.content.is_empty() => {
+                first.content = format!("{}\n\n{instructions}", first.content);
+            }
+            _ => messages.insert(
+                0,
+                Message {
+                    role: "system",
+                    content: instructions,
+                },
+            ),
+        }
+    }
+
+    messages
+}
+
+/// Simplifies `messages` for templates that only support alternating user and assistant
+/// messages: system messages are merged into the next user message, tool messages become user
+/// messages and consecutive messages of the same role are merged.
+fn simplify(messages: Vec<Message>) -> Vec<Message> {
+    let mut simple: Vec<Message> = vec![];
+    let mut system: Option<String> = None;
+
+    for mut message in messages {
+        match message.role {
+            "system" => {
+                system = Some(match system {
+                    Some(text) => format!("{text}\n\n{}", message.content),
+                    None => message.content,
+                });
+                continue;
+            }
+            "tool" => message.role = "user",
+            _ => {}
+        }
+
+        if message.role == "user" {
+            if let Some(text) = system.take() {
+                message.content = format!("{text}\n\n{}", message.content);
+            }
+        }
+
+        match simple.last_mut() {
+            Some(last) if last.role == message.role => {
+                last.content = format!("{}\n\n{}", last.content, message.content);
+            }
+            _ => simple.push(message),
+        }
+    }
+
+    if let Some(text) = system {
+        simple.push(Message {
+            role: "user",
+            content: text,
+        });
+    }
+
+    simple
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::gguf::GgufValue;
+    use crate::llm::{ChatMessages, ResponseFormat};
+
+    fn args(messages: Vec<ChatMessage>) -> CompletionArgs {
+        CompletionArgs {
+            messages: ChatMessages(messages),
+            ..Default::default()
+        }
+    }
+
+    fn system(text: &str) -> ChatMessage {
+        ChatMessage::System {
+            content: Some(text.to_string()),
+            name: None,
+        }
+    }
+
+    fn user(text: &str) -> ChatMessage {
+        ChatMessage::User {
+            content: Either::Left(text.to_string()),
+            name: None,
+        }
+    }
+
+    fn assistant(text: &str) -> ChatMessage {
+        ChatMessage::Assistant {
+            content: Some(text.to_string()),
+            name: None,
+            tool_calls: None,
+        }
+    }
+
+    #[test]
+    fn legacy_matches_transcript() {
+        let args = args(vec![
+            system("Be brief."),
+            user("Hi!"),
+            assistant("Hello."),
+            user("Bye!"),
+        ]);
+        let prompt = ChatTemplate::default().apply(&args).unwrap();
+
+        assert_eq!(prompt.text, format!("{}{ASSISTANT_TAG}", args.messages));
+        assert_eq!(
+            &prompt.text[..prompt.history_len],
+            "<|SYSTEM|>Be brief.<|USER|>Hi!<|ASSISTANT|>Hello."
+        );
+    }
+
+    #[test]
+    fn built_in_templates() {
+        let args = args(vec![
+            system("Be brief."),
+            user("Hi!"),
+            assistant("Hello."),
+            user("Bye!"),
+        ]);
+
+        let chatml = ChatTemplate::named("chatml").unwrap();
+        let prompt = chatml.apply(&args).unwrap();
+        assert_eq!(
+            prompt.text,
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi!<|im_end|>\n\
+             <|im_start|>assistant\nHello.<|im_end|>\n<|im_start|>user\nBye!<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+        assert!(prompt.text[..prompt.history_len].ends_with("Hello."));
+        assert_eq!(chatml.stop_words(), ["<|im_end|>"]);
+
+        let llama2 = ChatTemplate::named("llama2").unwrap().with_bos_token("");
+        assert_eq!(
+            llama2.apply(&args).unwrap().text,
+            "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi! [/INST] Hello. </s>[INST] Bye! [/INST]"
+        );
+
+        // mistral does not support system messages, which are merged into the user message
+        let mistral = ChatTemplate::named("mistral").unwrap();
+        let prompt = mistral.apply(&args).unwrap();
+        assert_eq!(
+            prompt.text,
+            "<s>[INST] Be brief.\n\nHi! [/INST]Hello.</s>[INST] Bye! [/INST]"
+        );
+        assert!(prompt.text[..prompt.history_len].ends_with("Hello."));
+        assert_eq!(mistral.stop_words(), ["[INST]", "</s>"]);
+
+        for name in TEMPLATE_NAMES {
+            let prompt = ChatTemplate::named(name).unwrap().apply(&args).unwrap();
+            assert!(prompt.text.contains("Bye!"), "{name}: {}", prompt.text);
+        }
+    }
+
+    #[test]
+    fn first_prompt_has_no_history() {
+        let args = args(vec![user("Hi!")]);
+        for name in TEMPLATE_NAMES {
+            let prompt = ChatTemplate::named(name).unwrap().apply(&args).unwrap();
+            assert_eq!(prompt.history_len, 0);
+        }
+    }
+
+    #[test]
+    fn instructions_join_system_message() {
+        let mut with_system = args(vec![system("Be brief."), user("Hi!")]);
+        with_system.response_format = Some(ResponseFormat::JsonObject);
+        let prompt = ChatTemplate::named("chatml")
+            .unwrap()
+            .apply(&with_system)
+            .unwrap();
+        assert!(prompt.text.starts_with(
+            "<|im_start|>system\nBe brief.\n\nAnswer only with a JSON object.<|im_end|>\n"
+        ));
+
+        let mut without_system = args(vec![user("Hi!")]);
+        without_system.response_format = Some(ResponseFormat::JsonObject);
+        let prompt = ChatTemplate::named("chatml")
+            .unwrap()
+            .apply(&without_system)
+            .unwrap();
+        assert!(prompt
+            .text
+            .starts_with("<|im_start|>system\nAnswer only with a JSON object.<|im_end|>\n"));
+    }
+
+    #[test]
+    fn model_template() {
+        let source = "{% for message in messages %}{{ bos_token }}{{ message['content'].strip() }}\
+                      {{ eos_token }}{% endfor %}";
+        let metadata = GgufMetadata::from(HashMap::from([
+            (
+                "tokenizer.chat_template".to_string(),
+                GgufValue::String(source.to_string()),
+            ),
+            (
+                "tokenizer.ggml.tokens".to_string(),
+                GgufValue::Array(vec![
+                    GgufValue::String("<s>".to_string()),
+                    GgufValue::String("</s>".to_string()),
+                ]),
+            ),
+            (
+                "tokenizer.ggml.bos_token_id".to_string(),
+                GgufValue::Unsigned(0),
+            ),
+            (
+                "tokenizer.ggml.eos_token_id".to_string(),
+                GgufValue::Unsigned(1),
+            ),
+        ]));
+
+        let args = args(vec![user(" Hi! ")]);
+
+        let template = ChatTemplate::for_model(None, Some(&metadata));
+        assert_eq!(template.apply(&args).unwrap().text, "<s>Hi!</s>");
+        assert_eq!(template.stop_words(), ["</s>"]);
+
+        let template = ChatTemplate::for_model(Some("chatml"), Some(&metadata));
+        assert!(template
+            .apply(&args)
+            .unwrap()
+            .text
+            .starts_with("<|im_start|>"));
+
+        let template = ChatTemplate::for_model(Some("{{ eos_token }}"), Some(&metadata));
+        assert_eq!(template.apply(&args).unwrap().text, "</s>");
+
+        let template = ChatTemplate::for_model(None, None);
+        assert_eq!(
+            template.apply(&args).unwrap().text,
+            "<|USER|> Hi! <|ASSISTANT|>"
+        );
+    }
+
+    #[test]
+    fn invalid_templates() {
+        let args = args(vec![user("Hi!")]);
+
+        let template = ChatTemplate::from_source("{% for %}", "", "");
+        assert!(matches!(
+            template.apply(&args),
+            Err(TemplateError::Syntax(_))
+        ));
+
+        let template = ChatTemplate::from_source("{{ raise_exception('no') }}", "", "");
+        assert!(matches!(
+            template.apply(&args),
+            Err(TemplateError::Render(_))
+        ));
+    }
+}
@@ -0,0 +1,284 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Fitting chats that are too long into the context of a model.
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::chat_template::{ChatTemplate, Prompt};
+use crate::llm::{ChatMessage, CompletionArgs, LLMEndpointError};
+
+/// What to do with a chat whose prompt does not fit in the context of a model.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextOverflowPolicy {
+    /// Fail with [`LLMEndpointError::ContextOverflow`].
+    #[default]
+    Reject,
+
+    /// Drop the oldest messages that are not system messages, one at a time, until the prompt
+    /// fits.
+    DropOldest,
+
+    /// Keep only the first system message and the most recent messages that fit, starting at a
+    /// user message.
+    SlidingWindow,
+}
+
+/// How a chat was fitted into the context of a model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ContextOverflow {
+    /// The policy that was applied.
+    pub policy: ContextOverflowPolicy,
+
+    /// The number of messages that were dropped from the chat.
+    pub dropped_messages: u32,
+}
+
+/// A prompt that fits in the context of a model.
+#[derive(Debug)]
+pub struct FittedPrompt {
+    /// The prompt.
+    pub prompt: Prompt,
+
+    /// The number of tokens in `prompt`.
+    pub prompt_tokens: u32,
+
+    /// How the chat was fitted into the context.
+    pub overflow: ContextOverflow,
+}
+
+/// Renders the prompt for `args` with `template`, applying `policy` until the prompt fits in a
+/// context of `context_size` tokens, leaving room for at least one generated token.
+///
+/// Messages dropped by `policy` are removed from `args`. Tokens are counted with
+/// `count_tokens`.
+pub fn fit_prompt<F>(
+    args: &mut CompletionArgs,
+    template: &ChatTemplate,
+    policy: ContextOverflowPolicy,
+    context_size: u32,
+    mut count_tokens: F,
+) -> Result<FittedPrompt, LLMEndpointError>
+where
+    F: FnMut(&str) -> Result<u32, LLMEndpointError>,
+{
+    let mut dropped_messages = 0;
+
+    loop {
+        let prompt = args.prompt(template)?;
+        let prompt_tokens = count_tokens(&prompt.text)?;
+        if prompt_tokens < context_size {
+            return Ok(FittedPrompt {
+                prompt,
+                prompt_tokens,
+                overflow: ContextOverflow {
+                    policy,
+                    dropped_messages,
+                },
+            });
+        }
+
+        let dropped = match policy {
+            ContextOverflowPolicy::Reject => 0,
+            ContextOverflowPolicy::DropOldest => drop_oldest(&mut args.messages),
+            ContextOverflowPolicy::SlidingWindow => slide_window(&mut args.messages),
+        };
+        if dropped == 0 {
+            return Err(LLMEndpointError::ContextOverflow {
+                prompt_tokens,
+                context_size,
+            });
+        }
+        dropped_messages += dropped;
+    }
+}
+
+/// Drops the oldest message that is not a system message, unless it is the last message.
+///
+/// Returns the number of dropped messages.
+fn drop_oldest(messages: &mut Vec<ChatMessage>) -> u32 {
+    let oldest = messages
+        .iter()
+        .position(|message| !matches!(message, ChatMessage::System { .. }));
+
+    match oldest {
+        Some(idx) if idx + 1 < messages.len() => {
+            messages.remove(idx);
+            1
+        }
+        _ => 0,
+    }
+}
+
+/// Slides the window of messages after the pinned system prompt, dropping the oldest message and
+/// any message before the next user message, but never the last message.
+///
+/// Returns the number of dropped messages.
+fn slide_window(messages: &mut Vec<ChatMessage>) -> u32 {
+    let start = match messages.first() {
+        Some(ChatMessage::System { .. }) => 1,
+        _ => 0,
+    };
+
+    let mut dropped = 0;
+    while start + 1 < messages.len()
+        && (dropped == 0 || !matches!(messages[start], ChatMessage::User { .. }))
+    {
+        messages.remove(start);
+        dropped += 1;
+    }
+
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use either::Either;
+
+    use super::*;
+    use crate::llm::ChatMessages;
+
+    fn args(messages: Vec<ChatMessage>) -> CompletionArgs {
+        CompletionArgs {
+            messages: ChatMessages(messages),
+            ..Default::default()
+        }
+    }
+
+    fn system(text: &str) -> ChatMessage {
+        ChatMessage::System {
+            content: Some(text.to_string()),
+            name: None,
+        }
+    }
+
+    fn user(text: &str) -> ChatMessage {
+        ChatMessage::User {
+            content: Either::Left(text.to_string()),
+            name: None,
+        }
+    }
+
+    fn assistant(text: &str) -> ChatMessage {
+        ChatMessage::Assistant {
+            content: Some(text.to_string()),
+            name: None,
+            tool_calls: None,
+        }
+    }
+
+    /// A chat of 14 words, every word being counted as a token.
+    fn chat() -> CompletionArgs {
+        args(vec![
+            system("be brief"),
+            user("one two"),
+            assistant("three four"),
+            system("be nice"),
+            user("five six"),
+            assistant("seven eight"),
+            user("nine ten"),
+        ])
+    }
+
+    fn fit(
+        args: &mut CompletionArgs,
+        policy: ContextOverflowPolicy,
+        context_size: u32,
+    ) -> Result<FittedPrompt, LLMEndpointError> {
+        // a template without tags, so that the prompt only has the words of the messages
+        let template = ChatTemplate::from_source(
+            "{% for message in messages %}{{ message.content }}\n{% endfor %}",
+            "",
+            "",
+        );
+        fit_prompt(args, &template, policy, context_size, |text| {
+            Ok(text.split_whitespace().count() as u32)
+        })
+    }
+
+    #[test]
+    fn fitting_chats_are_untouched() {
+        for policy in [
+            ContextOverflowPolicy::Reject,
+            ContextOverflowPolicy::DropOldest,
+            ContextOverflowPolicy::SlidingWindow,
+        ] {
+            let mut args = chat();
+            let fitted = fit(&mut args, policy, 100).unwrap();
+
+            assert_eq!(fitted.prompt_tokens, 14);
+            assert_eq!(fitted.overflow.dropped_messages, 0);
+            assert_eq!(args.messages.len(), 7);
+        }
+    }
+
+    #[test]
+    fn reject() {
+        let mut args = chat();
+        let error = fit(&mut args, ContextOverflowPolicy::Reject, 14).unwrap_err();
+
+        assert!(matches!(
+            error,
+            LLMEndpointError::ContextOverflow {
+                prompt_tokens: 14,
+                context_size: 14
+            }
+        ));
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let mut args = chat();
+        let fitted = fit(&mut args, ContextOverflowPolicy::DropOldest, 10).unwrap();
+
+        assert_eq!(fitted.overflow.dropped_messages, 3);
+        assert_eq!(fitted.prompt_tokens, 8);
+        assert_eq!(
+            fitted.prompt.text,
+            "be brief\nbe nice\nseven eight\nnine ten\n"
+        );
+
+        // neither system messages nor the last message are dropped
+        let mut args = chat();
+        let error = fit(&mut args, ContextOverflowPolicy::DropOldest, 5).unwrap_err();
+        assert!(matches!(
+            error,
+            LLMEndpointError::ContextOverflow {
+                prompt_tokens: 6,
+                context_size: 5
+            }
+        ));
+    }
+
+    #[test]
+    fn sliding_window() {
+        let mut args = chat();
+        let fitted = fit(&mut args, ContextOverflowPolicy::SlidingWindow, 10).unwrap();
+
+        assert_eq!(fitted.overflow.dropped_messages, 3);
+        assert_eq!(fitted.prompt_tokens, 8);
+        assert_eq!(
+            fitted.prompt.text,
+            "be brief\nfive six\nseven eight\nnine ten\n"
+        );
+
+        let mut args = chat();
+        let fitted = fit(&mut args, ContextOverflowPolicy::SlidingWindow, 8).unwrap();
+        assert_eq!(fitted.overflow.dropped_messages, 5);
+        assert_eq!(fitted.prompt.text, "be brief\nnine ten\n");
+
+        let mut args = chat();
+        assert!(fit(&mut args, ContextOverflowPolicy::SlidingWindow, 4).is_err());
+    }
+}
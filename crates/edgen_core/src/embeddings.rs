@@ -0,0 +1,415 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Embedding of inputs that do not fit in the context of a model, and post-processing of
+//! embedding vectors: shortening them to fewer dimensions, and quantizing them for compact
+//! storage.
+
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+use crate::llm::LLMEndpointError;
+
+/// What to do with an input to embed that does not fit in the context of a model.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingsOverflowPolicy {
+    /// Fail with [`LLMEndpointError::ContextOverflow`].
+    #[default]
+    Reject,
+
+    /// Embed only the first tokens of the input that fit.
+    Truncate,
+
+    /// Split the input into chunks of about the same length that fit, and average their
+    /// embeddings, weighted by their lengths.
+    Chunk,
+}
+
+/// How the inputs that did not fit in the context of a model were embedded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EmbeddingsOverflow {
+    /// The policy that was applied.
+    pub policy: EmbeddingsOverflowPolicy,
+
+    /// The indices of the inputs that did not fit.
+    pub inputs: Vec<usize>,
+}
+
+/// A piece of an input, which is embedded on its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmbeddingsPiece {
+    /// The index of the input.
+    pub input: usize,
+
+    /// The range of the tokens of the input in this piece.
+    pub tokens: Range<usize>,
+}
+
+/// How a list of inputs is embedded: in pieces that fit in the context of a model, which are then
+/// pooled back into one embedding per input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmbeddingsPlan {
+    /// The pieces of the inputs, in order.
+    pub pieces: Vec<EmbeddingsPiece>,
+
+    /// How the inputs that did not fit were split, if any.
+    pub overflow: Option<EmbeddingsOverflow>,
+}
+
+impl EmbeddingsPlan {
+    /// Plans the embedding of inputs of `lengths` tokens into pieces of at most `max_tokens`
+    /// tokens, applying `policy` to the inputs that are longer.
+    pub fn new(
+        lengths: &[usize],
+        max_tokens: usize,
+        policy: EmbeddingsOverflowPolicy,
+    ) -> Result<Self, LLMEndpointError> {
+        let max_tokens = max_tokens.max(1);
+        let mut pieces = vec![];
+        let mut overflowing = vec![];
+
+        for (input, &len) in lengths.iter().enumerate() {
+            if len <= max_tokens {
+                pieces.push(EmbeddingsPiece {
+                    input,
+                    tokens: 0..len,
+                });
+                continue;
+            }
+
+            overflowing.push(input);
+            match policy {
+                EmbeddingsOverflowPolicy::Reject => {
+                    return Err(LLMEndpointError::ContextOverflow {
+                        prompt_tokens: u32::try_from(len).unwrap_or(u32::MAX),
+                        context_size: u32::try_from(max_tokens).unwrap_or(u32::MAX),
+                    })
+                }
+                EmbeddingsOverflowPolicy::Truncate => pieces.push(EmbeddingsPiece {
+                    input,
+                    tokens: 0..max_tokens,
+                }),
+                EmbeddingsOverflowPolicy::Chunk => {
+                    let chunks = len.div_ceil(max_tokens);
+                    pieces.extend((0..chunks).map(|chunk| EmbeddingsPiece {
+                        input,
+                        tokens: chunk * len / chunks..(chunk + 1) * len / chunks,
+                    }));
+                }
+            }
+        }
+
+        let overflow = (!overflowing.is_empty()).then_some(EmbeddingsOverflow {
+            policy,
+            inputs: overflowing,
+        });
+        Ok(Self { pieces, overflow })
+    }
+
+    /// Returns the number of tokens in all the pieces.
+    pub fn tokens(&self) -> usize {
+        self.pieces.iter().map(|piece| piece.tokens.len()).sum()
+    }
+
+    /// Groups the pieces into consecutive batches of at most `batch_tokens` tokens, returning the
+    /// range of the pieces of each batch. A piece longer than that is a batch of its own.
+    pub fn batches(&self, batch_tokens: usize) -> Vec<Range<usize>> {
+        let mut batches = vec![];
+        let mut start = 0;
+        let mut tokens = 0;
+
+        for (index, piece) in self.pieces.iter().enumerate() {
+            let len = piece.tokens.len();
+            if index > start && tokens + len > batch_tokens {
+                batches.push(start..index);
+                start = index;
+                tokens = 0;
+            }
+            tokens += len;
+        }
+        if start < self.pieces.len() {
+            batches.push(start..self.pieces.len());
+        }
+
+        batches
+    }
+
+    /// Pools the `embeddings` of the pieces, in order, into one embedding per input.
+    ///
+    /// The embeddings of the chunks of an input are averaged, weighted by their lengths, and
+    /// scaled to unit length.
+    pub fn pool(&self, embeddings: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+        let mut pooled = vec![];
+        let mut pieces = self.pieces.iter().zip(embeddings).peekable();
+
+        while let Some((piece, embedding)) = pieces.next() {
+            let mut chunks = vec![(piece.tokens.len(), embedding)];
+            while let Some((next, embedding)) =
+                pieces.next_if(|(next, _)| next.input == piece.input)
+            {
+                chunks.push((next.tokens.len(), embedding));
+            }
+            pooled.push(mean_pool(chunks));
+        }
+
+        pooled
+    }
+}
+
+/// Averages the embeddings of `chunks`, weighted by the number of tokens of each, and scales the
+/// result to unit length. A single embedding is returned as is.
+fn mean_pool(mut chunks: Vec<(usize, Vec<f32>)>) -> Vec<f32> {
+    if chunks.len() == 1 {
+        return chunks.remove(0).1;
+    }
+
+    let dimensions = chunks.iter().map(|(_, embedding)| embedding.len()).max();
+    let mut mean = vec![0.0; dimensions.unwrap_or(0)];
+    for (len, embedding) in &chunks {
+        for (sum, value) in mean.iter_mut().zip(embedding) {
+            *sum += value * *len as f32;
+        }
+    }
+
+    let norm = mean.iter().map(|value| value * value).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        mean.iter_mut().for_each(|value| *value /= norm);
+    }
+
+    mean
+}
+
+/// How the embeddings of a response are encoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingEncoding {
+    /// A list of numbers.
+    #[default]
+    Float,
+
+    /// A base64 string of the little-endian bytes of the values.
+    Base64,
+}
+
+/// How the values of embeddings are quantized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingQuantization {
+    /// Signed bytes, scaled so that the value of largest magnitude is ±127.
+    Int8,
+
+    /// One bit per value, set if the value is positive, packed into bytes with the first value in
+    /// the most significant bit.
+    Binary,
+}
+
+/// The values of an embedding, possibly quantized.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum EmbeddingValues {
+    /// The values as generated.
+    Float(Vec<f32>),
+
+    /// The values quantized with [`EmbeddingQuantization::Int8`].
+    Int8(Vec<i8>),
+
+    /// The values quantized with [`EmbeddingQuantization::Binary`].
+    Binary(Vec<u8>),
+}
+
+impl EmbeddingValues {
+    /// Quantizes `embedding` with `quantization`, if any.
+    pub fn new(embedding: Vec<f32>, quantization: Option<EmbeddingQuantization>) -> Self {
+        match quantization {
+            None => Self::Float(embedding),
+            Some(EmbeddingQuantization::Int8) => {
+                let max = embedding
+                    .iter()
+                    .fold(0.0f32, |max, value| max.max(value.abs()));
+                let scale = if max > 0.0 { 127.0 / max } else { 0.0 };
+                Self::Int8(
+                    embedding
+                        .iter()
+                        .map(|value| (value * scale).round().clamp(-127.0, 127.0) as i8)
+                        .collect(),
+                )
+            }
+            Some(EmbeddingQuantization::Binary) => Self::Binary(
+                embedding
+                    .chunks(8)
+                    .map(|values| {
+                        values.iter().enumerate().fold(0u8, |byte, (bit, value)| {
+                            if *value > 0.0 {
+                                byte | (0x80 >> bit)
+                            } else {
+                                byte
+                            }
+                        })
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Returns the little-endian bytes of the values.
+    pub fn to_le_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Float(values) => values
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect(),
+            Self::Int8(values) => values.iter().map(|value| *value as u8).collect(),
+            Self::Binary(bytes) => bytes.clone(),
+        }
+    }
+}
+
+/// An error in the requested format of embeddings.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum EmbeddingFormatError {
+    #[error("embeddings of {available} dimensions cannot be shortened to {requested}")]
+    Dimensions {
+        /// The number of dimensions requested.
+        requested: usize,
+
+        /// The number of dimensions of the embeddings of the model.
+        available: usize,
+    },
+}
+
+/// Shortens `embedding` to its first `dimensions` values, and scales it back to unit length.
+///
+/// This is only meaningful for models trained with Matryoshka representation learning, whose
+/// first values carry the most information.
+pub fn shorten(embedding: &mut Vec<f32>, dimensions: usize) -> Result<(), EmbeddingFormatError> {
+    if dimensions == 0 || dimensions > embedding.len() {
+        return Err(EmbeddingFormatError::Dimensions {
+            requested: dimensions,
+            available: embedding.len(),
+        });
+    }
+
+    embedding.truncate(dimensions);
+    let norm = embedding
+        .iter()
+        .map(|value| value * value)
+        .sum::<f32>()
+        .sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|value| *value /= norm);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_inputs() {
+        let lengths = [3, 10, 7];
+
+        let plan = EmbeddingsPlan::new(&lengths, 4, EmbeddingsOverflowPolicy::Chunk).unwrap();
+        let ranges: Vec<_> = plan
+            .pieces
+            .iter()
+            .map(|piece| (piece.input, piece.tokens.clone()))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![
+                (0, 0..3),
+                (1, 0..3),
+                (1, 3..6),
+                (1, 6..10),
+                (2, 0..3),
+                (2, 3..7)
+            ]
+        );
+        assert_eq!(
+            plan.overflow,
+            Some(EmbeddingsOverflow {
+                policy: EmbeddingsOverflowPolicy::Chunk,
+                inputs: vec![1, 2],
+            })
+        );
+        assert_eq!(plan.tokens(), 20);
+
+        let plan = EmbeddingsPlan::new(&lengths, 4, EmbeddingsOverflowPolicy::Truncate).unwrap();
+        assert_eq!(plan.tokens(), 11);
+
+        assert!(matches!(
+            EmbeddingsPlan::new(&lengths, 4, EmbeddingsOverflowPolicy::Reject),
+            Err(LLMEndpointError::ContextOverflow {
+                prompt_tokens: 10,
+                context_size: 4
+            })
+        ));
+        let plan = EmbeddingsPlan::new(&lengths, 10, EmbeddingsOverflowPolicy::Reject).unwrap();
+        assert_eq!(plan.overflow, None);
+    }
+
+    #[test]
+    fn pieces_are_batched() {
+        let plan = EmbeddingsPlan::new(&[3, 10, 7], 4, EmbeddingsOverflowPolicy::Chunk).unwrap();
+        assert_eq!(plan.batches(8), vec![0..2, 2..4, 4..6]);
+        assert_eq!(plan.batches(2), vec![0..1, 1..2, 2..3, 3..4, 4..5, 5..6]);
+        assert_eq!(plan.batches(100), vec![0..6]);
+    }
+
+    #[test]
+    fn chunks_are_pooled() {
+        let plan = EmbeddingsPlan::new(&[2, 7], 4, EmbeddingsOverflowPolicy::Chunk).unwrap();
+        let pooled = plan.pool(vec![vec![2.0, 0.0], vec![1.0, 0.0], vec![0.0, 1.0]]);
+
+        assert_eq!(pooled, vec![vec![2.0, 0.0], vec![0.6, 0.8]]);
+    }
+
+    #[test]
+    fn shortened_embeddings_are_normalized() {
+        let mut embedding = vec![3.0, 4.0, 12.0];
+        shorten(&mut embedding, 2).unwrap();
+        assert_eq!(embedding, vec![0.6, 0.8]);
+
+        assert_eq!(
+            shorten(&mut embedding, 3),
+            Err(EmbeddingFormatError::Dimensions {
+                requested: 3,
+                available: 2
+            })
+        );
+        assert!(shorten(&mut embedding, 0).is_err());
+    }
+
+    #[test]
+    fn quantization() {
+        let embedding = vec![0.5, -0.25, 0.0, 0.1, -0.5, 0.3, 0.2, -0.1, 0.4];
+
+        assert_eq!(
+            EmbeddingValues::new(embedding.clone(), Some(EmbeddingQuantization::Int8)),
+            EmbeddingValues::Int8(vec![127, -64, 0, 25, -127, 76, 51, -25, 102])
+        );
+        assert_eq!(
+            EmbeddingValues::new(embedding.clone(), Some(EmbeddingQuantization::Binary)),
+            EmbeddingValues::Binary(vec![0b1001_0110, 0b1000_0000])
+        );
+        assert_eq!(
+            EmbeddingValues::new(vec![1.0, -2.0], None).to_le_bytes(),
+            vec![0, 0, 0x80, 0x3f, 0, 0, 0, 0xc0]
+        );
+    }
+}
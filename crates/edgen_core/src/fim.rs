@@ -0,0 +1,130 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Fill-in-the-middle (FIM) prompts, used by code models to generate the text between a prefix
+//! and a suffix.
+
+use crate::gguf::GgufMetadata;
+
+/// The GGUF keys of the ids of the prefix, suffix and middle tokens, in the order they are looked
+/// up. Older model files use the second set of names.
+const TOKEN_KEYS: [[&str; 3]; 2] = [
+    [
+        "tokenizer.ggml.fim_pre_token_id",
+        "tokenizer.ggml.fim_suf_token_id",
+        "tokenizer.ggml.fim_mid_token_id",
+    ],
+    [
+        "tokenizer.ggml.prefix_token_id",
+        "tokenizer.ggml.suffix_token_id",
+        "tokenizer.ggml.middle_token_id",
+    ],
+];
+
+/// The special tokens that delimit the parts of a fill-in-the-middle prompt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FimTokens {
+    /// The token before the text preceding the completion.
+    pub prefix: String,
+
+    /// The token before the text following the completion.
+    pub suffix: String,
+
+    /// The token after which the completion is generated.
+    pub middle: String,
+}
+
+impl FimTokens {
+    /// Returns the fill-in-the-middle tokens of the model with the given `metadata`, if it was
+    /// trained with them.
+    pub fn for_model(metadata: &GgufMetadata) -> Option<Self> {
+        TOKEN_KEYS.iter().find_map(|[prefix, suffix, middle]| {
+            Some(Self {
+                prefix: token_text(metadata.token(prefix)?),
+                suffix: token_text(metadata.token(suffix)?),
+                middle: token_text(metadata.token(middle)?),
+            })
+        })
+    }
+
+    /// Returns the prompt to generate the text between `prefix` and `suffix`.
+    ///
+    /// The prompt lists the prefix, then the suffix and then the middle token (the "PSM" order),
+    /// which is the order that most code models are trained with.
+    pub fn prompt(&self, prefix: &str, suffix: &str) -> String {
+        format!(
+            "{}{prefix}{}{suffix}{}",
+            self.prefix, self.suffix, self.middle
+        )
+    }
+}
+
+/// Returns the text of a token of the vocabulary, where SentencePiece vocabularies represent
+/// spaces with `▁`.
+fn token_text(token: &str) -> String {
+    token.replace('\u{2581}', " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::gguf::GgufValue;
+
+    fn metadata(keys: [&str; 3]) -> GgufMetadata {
+        let tokens = ["<unk>", "<PRE>", "\u{2581}<SUF>", "<MID>"]
+            .into_iter()
+            .map(|token| GgufValue::String(token.to_string()))
+            .collect();
+
+        let mut values = HashMap::from([(
+            "tokenizer.ggml.tokens".to_string(),
+            GgufValue::Array(tokens),
+        )]);
+        for (id, key) in keys.into_iter().enumerate() {
+            values.insert(key.to_string(), GgufValue::Unsigned(id as u64 + 1));
+        }
+
+        values.into()
+    }
+
+    #[test]
+    fn fim_tokens() {
+        let expected = FimTokens {
+            prefix: "<PRE>".to_string(),
+            suffix: " <SUF>".to_string(),
+            middle: "<MID>".to_string(),
+        };
+        for keys in TOKEN_KEYS {
+            assert_eq!(
+                FimTokens::for_model(&metadata(keys)),
+                Some(expected.clone())
+            );
+        }
+
+        assert_eq!(
+            expected.prompt("fn main() {", "}"),
+            "<PRE>fn main() { <SUF>}<MID>"
+        );
+    }
+
+    #[test]
+    fn no_fim_tokens() {
+        assert_eq!(FimTokens::for_model(&GgufMetadata::default()), None);
+
+        // every token is needed
+        let mut keys = TOKEN_KEYS[0];
+        keys[2] = "tokenizer.ggml.eos_token_id";
+        assert_eq!(FimTokens::for_model(&metadata(keys)), None);
+    }
+}
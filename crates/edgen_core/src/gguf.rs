@@ -0,0 +1,309 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Reading of the metadata stored in the header of [GGUF] model files.
+//!
+//! [GGUF]: https://github.com/ggerganov/ggml/blob/master/docs/gguf.md
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use thiserror::Error;
+
+/// The magic number at the start of every GGUF file.
+const MAGIC: &[u8; 4] = b"GGUF";
+
+/// The largest string that is read from a GGUF file, to avoid huge allocations on corrupted
+/// files.
+const MAX_STRING_LEN: u64 = 64 * 1024 * 1024;
+
+/// An error that occurred while reading a GGUF file.
+#[derive(Debug, Error)]
+pub enum GgufError {
+    #[error("failed to read the GGUF file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("not a GGUF file")]
+    NotGguf,
+    #[error("unsupported GGUF version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("invalid GGUF metadata: {0}")]
+    Invalid(String),
+}
+
+/// A metadata value of a GGUF file.
+///
+/// Integers are widened to 64 bits.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+    /// An unsigned integer.
+    Unsigned(u64),
+    /// A signed integer.
+    Signed(i64),
+    /// A floating point number.
+    Float(f64),
+    /// A boolean.
+    Bool(bool),
+    /// A string.
+    String(String),
+    /// An array of values of the same type.
+    Array(Vec<GgufValue>),
+}
+
+/// The metadata of a GGUF file, as key-value pairs.
+#[derive(Debug, Clone, Default)]
+pub struct GgufMetadata {
+    values: HashMap<String, GgufValue>,
+}
+
+impl GgufMetadata {
+    /// Reads the metadata of the GGUF file at `path`.
+    ///
+    /// This blocks while reading the file header, which can be several megabytes long.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, GgufError> {
+        let file = File::open(path)?;
+        Self::read_from(BufReader::new(file))
+    }
+
+    /// Reads GGUF metadata from `reader`, which must be positioned at the start of the file.
+    pub fn read_from(mut reader: impl Read) -> Result<Self, GgufError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(GgufError::NotGguf);
+        }
+
+        // version 1 used 32 bit lengths, and is no longer produced by llama.cpp
+        let version = read_u32(&mut reader)?;
+        if !(2..=3).contains(&version) {
+            return Err(GgufError::UnsupportedVersion(version));
+        }
+
+        let _tensor_count = read_u64(&mut reader)?;
+        let kv_count = read_u64(&mut reader)?;
+
+        let mut values = HashMap::new();
+        for _ in 0..kv_count {
+            let key = read_string(&mut reader)?;
+            let type_ = read_u32(&mut reader)?;
+            let value = read_value(&mut reader, type_)?;
+            values.insert(key, value);
+        }
+
+        Ok(Self { values })
+    }
+
+    /// Returns the value of `key`, if present.
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.values.get(key)
+    }
+
+    /// Returns the value of `key`, if present and a string.
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        match self.get(key)? {
+            GgufValue::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of `key`, if present and a non-negative integer.
+    pub fn get_u64(&self, key: &str) -> Option<u64> {
+        match self.get(key)? {
+            GgufValue::Unsigned(value) => Some(*value),
+            GgufValue::Signed(value) => u64::try_from(*value).ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of tokens in the context the model was trained with (`n_ctx_train`), if
+    /// present.
+    pub fn context_length(&self) -> Option<u64> {
+        let architecture = self.get_str("general.architecture")?;
+        self.get_u64(&format!("{architecture}.context_length"))
+    }
+
+    /// Returns the text of the token with id `key`, looked up in the tokenizer vocabulary.
+    pub fn token(&self, key: &str) -> Option<&str> {
+        let id = self.get_u64(key)? as usize;
+        match self.get("tokenizer.ggml.tokens")? {
+            GgufValue::Array(tokens) => match tokens.get(id)? {
+                GgufValue::String(token) => Some(token),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+impl From<HashMap<String, GgufValue>> for GgufMetadata {
+    fn from(values: HashMap<String, GgufValue>) -> Self {
+        Self { values }
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, GgufError> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64, GgufError> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_string(reader: &mut impl Read) -> Result<String, GgufError> {
+    let len = read_u64(reader)?;
+    if len > MAX_STRING_LEN {
+        return Err(GgufError::Invalid(format!("string of {len} bytes")));
+    }
+
+    let mut buf = vec![0; len as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// Reads a value of GGUF type `type_`.
+fn read_value(reader: &mut impl Read, type_: u32) -> Result<GgufValue, GgufError> {
+    let mut buf = [0; 8];
+    let value = match type_ {
+        0 => {
+            reader.read_exact(&mut buf[..1])?;
+            GgufValue::Unsigned(buf[0] as u64)
+        }
+        1 => {
+            reader.read_exact(&mut buf[..1])?;
+            GgufValue::Signed(buf[0] as i8 as i64)
+        }
+        2 => {
+            reader.read_exact(&mut buf[..2])?;
+            GgufValue::Unsigned(u16::from_le_bytes([buf[0], buf[1]]) as u64)
+        }
+        3 => {
+            reader.read_exact(&mut buf[..2])?;
+            GgufValue::Signed(i16::from_le_bytes([buf[0], buf[1]]) as i64)
+        }
+        4 => GgufValue::Unsigned(read_u32(reader)? as u64),
+        5 => GgufValue::Signed(read_u32(reader)? as i32 as i64),
+        6 => GgufValue::Float(f32::from_bits(read_u32(reader)?) as f64),
+        7 => {
+            reader.read_exact(&mut buf[..1])?;
+            GgufValue::Bool(buf[0] != 0)
+        }
+        8 => GgufValue::String(read_string(reader)?),
+        9 => {
+            let item_type = read_u32(reader)?;
+            let len = read_u64(reader)?;
+
+            // the length is not trusted for the allocation, the file could be corrupted
+            let mut items = Vec::with_capacity(len.min(1024 * 1024) as usize);
+            for _ in 0..len {
+                items.push(read_value(reader, item_type)?);
+            }
+            GgufValue::Array(items)
+        }
+        10 => GgufValue::Unsigned(read_u64(reader)?),
+        11 => GgufValue::Signed(read_u64(reader)? as i64),
+        12 => GgufValue::Float(f64::from_bits(read_u64(reader)?)),
+        _ => return Err(GgufError::Invalid(format!("unknown value type {type_}"))),
+    };
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds the header of a GGUF file with the given metadata.
+    fn gguf(kvs: &[(&str, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend(3u32.to_le_bytes());
+        out.extend(0u64.to_le_bytes());
+        out.extend((kvs.len() as u64).to_le_bytes());
+        for (key, type_, value) in kvs {
+            out.extend(string(key));
+            out.extend(type_.to_le_bytes());
+            out.extend(value);
+        }
+        out
+    }
+
+    fn string(text: &str) -> Vec<u8> {
+        let mut out = (text.len() as u64).to_le_bytes().to_vec();
+        out.extend(text.as_bytes());
+        out
+    }
+
+    #[test]
+    fn read_metadata() {
+        let mut tokens = 8u32.to_le_bytes().to_vec();
+        tokens.extend(3u64.to_le_bytes());
+        for token in ["<unk>", "<s>", "</s>"] {
+            tokens.extend(string(token));
+        }
+
+        let data = gguf(&[
+            ("general.architecture", 8, string("llama")),
+            ("llama.context_length", 4, 32768u32.to_le_bytes().to_vec()),
+            ("tokenizer.ggml.tokens", 9, tokens),
+            (
+                "tokenizer.ggml.bos_token_id",
+                4,
+                1u32.to_le_bytes().to_vec(),
+            ),
+            (
+                "tokenizer.ggml.eos_token_id",
+                5,
+                2i32.to_le_bytes().to_vec(),
+            ),
+            ("general.file_type", 1, vec![0xff]),
+        ]);
+        let metadata = GgufMetadata::read_from(&data[..]).unwrap();
+
+        assert_eq!(metadata.get_str("general.architecture"), Some("llama"));
+        assert_eq!(metadata.get_u64("llama.context_length"), Some(32768));
+        assert_eq!(metadata.context_length(), Some(32768));
+        assert_eq!(metadata.token("tokenizer.ggml.bos_token_id"), Some("<s>"));
+        assert_eq!(metadata.token("tokenizer.ggml.eos_token_id"), Some("</s>"));
+        assert_eq!(
+            metadata.get("general.file_type"),
+            Some(&GgufValue::Signed(-1))
+        );
+        assert_eq!(metadata.get_u64("general.file_type"), None);
+        assert!(metadata.get("tokenizer.chat_template").is_none());
+    }
+
+    #[test]
+    fn reject_other_files() {
+        assert!(matches!(
+            GgufMetadata::read_from(&b"PK\x03\x04 not a model"[..]),
+            Err(GgufError::NotGguf)
+        ));
+
+        let mut data = gguf(&[]);
+        data[4..8].copy_from_slice(&1u32.to_le_bytes());
+        assert!(matches!(
+            GgufMetadata::read_from(&data[..]),
+            Err(GgufError::UnsupportedVersion(1))
+        ));
+
+        let mut data = gguf(&[("general.name", 8, string("truncated"))]);
+        data.truncate(data.len() - 2);
+        assert!(matches!(
+            GgufMetadata::read_from(&data[..]),
+            Err(GgufError::Io(_))
+        ));
+    }
+}
@@ -22,6 +22,24 @@ pub struct ModelFiles {
     pub unet_weights: PathBuf,
 }
 
+impl ModelFiles {
+    /// Returns the total size, in bytes, of the model files, skipping those that cannot be read.
+    pub fn size(&self) -> u64 {
+        [
+            Some(&self.tokenizer),
+            Some(&self.clip_weights),
+            self.clip2_weights.as_ref(),
+            Some(&self.vae_weights),
+            Some(&self.unet_weights),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|path| std::fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+    }
+}
+
 #[derive(Serialize, Error, Debug)]
 pub enum ImageGenerationEndpointError {
     #[error("Could not load model: {0}")]
@@ -32,6 +50,8 @@ pub enum ImageGenerationEndpointError {
     Generation(String),
     #[error("Could not convert the output tensor into an encoded image")]
     Encoding(String),
+    #[error("The generation was cancelled")]
+    Cancelled,
 }
 
 #[async_trait::async_trait]
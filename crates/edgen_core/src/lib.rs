@@ -20,13 +20,26 @@ extern crate alloc;
 
 use std::time::Duration;
 
+pub mod admission;
+pub mod cancellation;
+pub mod chat_template;
+pub mod context_overflow;
+pub mod embeddings;
+pub mod fim;
+pub mod gguf;
 pub mod llm;
+pub mod memory;
 pub mod whisper;
 
 pub mod settings;
 
 pub mod image_generation;
 pub mod perishable;
+pub mod session_cache;
+pub mod speculative;
+pub mod stopping_stream;
+pub mod tools;
+pub mod vision;
 
 /// Return the [`Duration`] that cleanup threads should wait before looking for and freeing unused
 /// resources, after last doing so.
@@ -13,13 +13,28 @@
 use core::fmt::{Display, Formatter};
 use core::time::Duration;
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 use derive_more::{Deref, DerefMut, From};
 use either::Either;
 use futures::Stream;
 use serde::Serialize;
 use thiserror::Error;
+use uuid::Uuid;
+
+use crate::chat_template::{ChatTemplate, Prompt};
+use crate::context_overflow::{ContextOverflow, ContextOverflowPolicy};
+use crate::embeddings::{EmbeddingsOverflow, EmbeddingsOverflowPolicy};
+use crate::fim::FimTokens;
+use crate::vision::ImageInput;
+
+/// The maximum number of most likely tokens whose log probabilities can be returned for every
+/// position of a completion.
+pub const MAX_TOP_LOGPROBS: u32 = 20;
+
+/// The maximum number of choices that can be generated for a single prompt.
+pub const MAX_CHOICES: u32 = 128;
 
 /// The context tag marking the start of generated dialogue.
 pub const ASSISTANT_TAG: &str = "<|ASSISTANT|>";
@@ -45,11 +60,37 @@ pub enum LLMEndpointError {
     Embeddings(String), // Embeddings may involve session creation, advancing, and other things, so it should have its own error
     #[error("unsuitable endpoint for model: {0}")]
     UnsuitableEndpoint(String),
+    #[error("failed to create a grammar: {0}")]
+    Grammar(String),
+    #[error("failed to apply the chat template: {0}")]
+    Template(String),
+    #[error("the prompt has {prompt_tokens} tokens, which do not fit in the context of {context_size} tokens")]
+    ContextOverflow {
+        /// The number of tokens in the prompt.
+        prompt_tokens: u32,
+
+        /// The number of tokens in the context of the model.
+        context_size: u32,
+    },
+    #[error("no matching session found")]
+    SessionNotFound,
+    #[error("the session is busy generating another completion")]
+    SessionBusy,
+    #[error("the model has no adapter named {0}")]
+    UnknownAdapter(String),
+    #[error("explicit chat sessions cannot be used with an adapter")]
+    AdapterSession,
+    #[error("images cannot be used {0}")]
+    ImagesUnsupported(String),
+    #[error("token {0} is not in the vocabulary of the model")]
+    UnknownToken(u32),
+    #[error("the deadline passed before the completions started")]
+    Timeout,
 }
 
 /// The plaintext or image content of a [`ChatMessage`] within a [`CreateChatCompletionRequest`].
 ///
-/// This can be plain text or a URL to an image.
+/// This can be plain text, a URL to an image, or the image behind such a URL.
 #[derive(Debug)]
 pub enum ContentPart {
     /// Plain text.
@@ -65,6 +106,8 @@ pub enum ContentPart {
         /// A description of the image behind the URL, if any.
         detail: Option<String>,
     },
+    /// An image, which stands in the prompt as its [placeholder](ImageInput::placeholder).
+    Image(ImageInput),
 }
 
 impl Display for ContentPart {
@@ -78,6 +121,7 @@ impl Display for ContentPart {
                     write!(f, "<IMAGE {}>", url)
                 }
             }
+            ContentPart::Image(image) => write!(f, "{}", image.placeholder()),
         }
     }
 }
@@ -86,7 +130,7 @@ impl Display for ContentPart {
 /// with the outside world.
 ///
 /// This is included in [`AssistantToolCall`]s within [`ChatMessage`]s.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AssistantFunctionStub {
     /// The name of the function from the assistant's point of view.
     pub name: String,
@@ -98,7 +142,7 @@ pub struct AssistantFunctionStub {
 /// A description of a function that an assistant called.
 ///
 /// This is included in [`ChatMessage`]s when the `tool_calls` field is present.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AssistantToolCall {
     /// A unique identifier for the invocation of this function.
     pub id: String,
@@ -158,18 +202,16 @@ pub enum ChatMessage {
     },
 }
 
-/*
-
 /// A tool made available to an assistant that invokes a named function.
 ///
-/// This is included in [`ToolStub`]s within [`CreateChatCompletionRequest`]s.
-#[derive(Debug)]
-pub struct FunctionStub<'a> {
+/// This is included in [`ToolStub`]s within [`CompletionArgs`].
+#[derive(Debug, Clone)]
+pub struct FunctionStub {
     /// A human-readable description of what the tool does.
-    pub description: Option<Cow<'a, str>>,
+    pub description: Option<String>,
 
     /// The name of the tool.
-    pub name: Cow<'a, str>,
+    pub name: String,
 
     /// A [JSON schema][json-schema] describing the parameters that the tool accepts.
     ///
@@ -182,18 +224,60 @@ pub struct FunctionStub<'a> {
 /// At present, this can only be a [`FunctionStub`], but this enum is marked `#[non_exhaustive]`
 /// for the (likely) event that more variants are added in the future.
 ///
-/// This is included in [`CreateChatCompletionRequest`]s.
-#[derive(Debug)]
+/// This is included in [`CompletionArgs`].
+#[derive(Debug, Clone)]
 #[non_exhaustive]
-pub enum ToolStub<'a> {
+pub enum ToolStub {
     /// A named function that can be invoked by an assistant.
     Function {
         /// The named function.
-        function: FunctionStub<'a>,
+        function: FunctionStub,
     },
 }
 
-*/
+impl ToolStub {
+    /// Returns the name of the tool.
+    pub fn name(&self) -> &str {
+        match self {
+            ToolStub::Function { function } => &function.name,
+        }
+    }
+}
+
+/// How an assistant may use the tools made available to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolChoice {
+    /// No tool can be called; the assistant answers with a message.
+    None,
+
+    /// The assistant decides whether to answer with a message or to call one or more tools.
+    Auto,
+
+    /// The assistant must call one or more tools.
+    Required,
+
+    /// The assistant must call the named function.
+    Function(String),
+}
+
+/// The format that completions must follow.
+#[derive(Debug, Clone, Default)]
+pub enum ResponseFormat {
+    /// Plain text, which is not constrained in any way.
+    #[default]
+    Text,
+
+    /// Any valid JSON object.
+    JsonObject,
+
+    /// A JSON value that is valid against a [JSON schema][json-schema].
+    ///
+    /// [json-schema]: https://json-schema.org/
+    JsonSchema {
+        /// The JSON schema. If absent, any JSON object is valid.
+        schema: Option<serde_json::Value>,
+    },
+}
 
 /// A sequence of chat messages in a [`CreateChatCompletionRequest`].
 ///
@@ -233,10 +317,18 @@ impl Display for ChatMessages {
                     }
                 }
                 ChatMessage::Assistant {
-                    content: Some(data),
+                    content,
+                    tool_calls,
                     ..
-                } => {
-                    write!(f, "{ASSISTANT_TAG}{data}")?;
+                } if content.is_some() || tool_calls.is_some() => {
+                    write!(f, "{ASSISTANT_TAG}")?;
+
+                    if let Some(data) = content {
+                        write!(f, "{data}")?;
+                    }
+                    if let Some(calls) = tool_calls {
+                        write!(f, "{}", crate::tools::render_tool_calls(calls))?;
+                    }
                 }
                 ChatMessage::Tool {
                     content: Some(data),
@@ -253,7 +345,7 @@ impl Display for ChatMessages {
 }
 
 /// A request to generate chat completions for the provided context.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CompletionArgs {
     /// The messages that have been sent in the dialogue so far.
     pub messages: ChatMessages,
@@ -268,11 +360,20 @@ pub struct CompletionArgs {
     /// You could use this to, for example, prevent the model from emitting profanity.
     pub logit_bias: Option<HashMap<u32, f32>>,
 
-    /// The maximum number of tokens to generate. If `None`, terminates at the first stop token
-    /// or the end of sentence.
+    /// If `true`, the log probability of every generated token is returned along with the
+    /// completion. `false` by default.
+    pub logprobs: Option<bool>,
+
+    /// The number of most likely tokens, in `[0, 20]`, whose log probabilities are returned for
+    /// every position of the completion. Requires [`logprobs`](Self::logprobs).
+    pub top_logprobs: Option<u32>,
+
+    /// The maximum number of tokens to generate. If `None`, the server-wide default is used.
+    ///
+    /// Either way, this is capped by the maximum configured for the model, if any.
     pub max_tokens: Option<u32>,
 
-    /// How many choices to generate for each token in the output. `1` by default. You can use
+    /// How many choices to generate for the prompt, in `[1, 128]`. `1` by default. You can use
     /// this to generate several sets of completions for the same prompt.
     pub n: Option<u32>,
 
@@ -299,53 +400,460 @@ pub struct CompletionArgs {
     pub top_p: Option<f32>,
 
     /// A list of tools made available to the model.
-    // pub tools: Option<Vec<ToolStub<'a>>>,
+    pub tools: Option<Vec<ToolStub>>,
 
-    /// If present, the tool that the user has chosen to use.
-    ///
-    /// OpenAI states:
+    /// If present, how the model may use `tools`. [`ToolChoice::Auto`] by default, or
+    /// [`ToolChoice::None`] if there are no tools.
+    pub tool_choice: Option<ToolChoice>,
+
+    /// The format that completions must follow. [`ResponseFormat::Text`] by default.
+    pub response_format: Option<ResponseFormat>,
+
+    /// A [GBNF] grammar that completions must follow, taking precedence over `response_format`
+    /// and `tools`.
     ///
-    /// - `none` prevents any tool from being used,
-    /// - `auto` allows any tool to be used, or
-    /// - you can provide a description of the tool entirely instead of a name.
-    // pub tool_choice: Option<Either<Cow<'a, str>, ToolStub<'a>>>,
+    /// [GBNF]: https://github.com/ggerganov/llama.cpp/blob/master/grammars/README.md
+    pub grammar: Option<String>,
+
+    /// What to do if the prompt does not fit in the context of the model. If `None`, the policy
+    /// configured for the model is used.
+    pub context_overflow: Option<ContextOverflowPolicy>,
 
     /// Indicate if this is an isolated request, with no associated past or future context. This may allow for
     /// optimisations in some implementations. Default: `false`
     pub one_shot: Option<bool>,
 
-    /// A hint for how big a context will be.
+    /// A hint for how big a context will be. For [`one_shot`](Self::one_shot) requests, this
+    /// overrides the context size of the model.
     ///
     /// # Warning
     /// An unsound hint may severely drop performance and/or inference quality, and in some cases even cause Edgen
     /// to crash. Do not set this value unless you know what you are doing.
     pub context_hint: Option<u32>,
+
+    /// If present, the explicit [`ChatSession`] to generate completions in, instead of a session
+    /// matching the chat history. Takes precedence over [`one_shot`](Self::one_shot).
+    pub session: Option<ChatSession>,
+
+    /// The instant past which completions are no longer generated, finishing with
+    /// [`FinishReason::Timeout`]. If `None`, there is no time limit.
+    pub deadline: Option<Instant>,
+
+    /// If present, the name of the LoRA adapter of the model, declared in its settings, that is
+    /// applied to generate completions. Chats with an adapter never share sessions with chats
+    /// without it, or with another adapter.
+    pub adapter: Option<String>,
+}
+
+/// An explicit chat session, which is kept under an id chosen by the client instead of being
+/// matched by the chat history, and is never shared with other chats.
+///
+/// The messages of a request continuing a session must extend those of its previous request,
+/// followed by the completion that was generated for it. Otherwise, the session starts over with
+/// the new messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatSession {
+    /// Creates a new session with the id.
+    Create(Uuid),
+
+    /// Continues the existing session with the id.
+    Continue(Uuid),
+}
+
+impl ChatSession {
+    /// Returns the id of the session.
+    pub fn id(&self) -> Uuid {
+        match self {
+            ChatSession::Create(id) | ChatSession::Continue(id) => *id,
+        }
+    }
+}
+
+/// The state of an explicit [`ChatSession`] kept by an [`LLMEndpoint`].
+#[derive(Debug, Clone)]
+pub struct ChatSessionInfo {
+    /// The id of the session.
+    pub id: Uuid,
+
+    /// The path of the model that the session belongs to.
+    pub model_path: PathBuf,
+
+    /// The number of tokens in the context of the session, or `None` while it generates
+    /// completions.
+    pub tokens: Option<u32>,
+
+    /// How long the session has left to live if it is not used, or `None` while it generates
+    /// completions.
+    pub expires_in: Option<Duration>,
+}
+
+impl CompletionArgs {
+    /// Returns every phrase that completions must stop at: the user-provided stop phrases, followed
+    /// by the phrases that end an assistant message in `template`.
+    pub fn stop_words(&self, template: &ChatTemplate) -> Vec<String> {
+        let mut stop_words = self.requested_stop_words();
+        stop_words.extend(template.stop_words().iter().cloned());
+
+        stop_words
+    }
+
+    /// Returns the user-provided stop phrases.
+    pub fn requested_stop_words(&self) -> Vec<String> {
+        match &self.stop {
+            Some(Either::Left(word)) => vec![word.clone()],
+            Some(Either::Right(words)) => words.clone(),
+            None => vec![],
+        }
+    }
+
+    /// Returns the number of choices to generate for the prompt, which is [`n`](Self::n) capped
+    /// to [`MAX_CHOICES`].
+    pub fn choices(&self) -> usize {
+        self.n.unwrap_or(1).clamp(1, MAX_CHOICES) as usize
+    }
+
+    /// Returns the number of most likely tokens whose log probabilities are returned for every
+    /// position of the completion, if log probabilities were requested at all.
+    pub fn requested_logprobs(&self) -> Option<usize> {
+        match self.logprobs {
+            Some(true) => Some(self.top_logprobs.unwrap_or(0).min(MAX_TOP_LOGPROBS) as usize),
+            _ => None,
+        }
+    }
+
+    /// Returns the tools that the model may call, which are none if [`ToolChoice::None`] was
+    /// chosen.
+    pub fn active_tools(&self) -> &[ToolStub] {
+        match (&self.tools, &self.tool_choice) {
+            (Some(_), Some(ToolChoice::None)) | (None, _) => &[],
+            (Some(tools), _) => tools,
+        }
+    }
+
+    /// Returns the instructions given to the model alongside the system message: the description
+    /// of the [active tools](Self::active_tools) and of the [`ResponseFormat`], if any.
+    pub fn instructions(&self) -> Option<String> {
+        let tools = crate::tools::tools_prompt(self.active_tools(), self.tool_choice.as_ref());
+        let format = match &self.response_format {
+            Some(ResponseFormat::JsonObject)
+            | Some(ResponseFormat::JsonSchema { schema: None }) => {
+                "Answer only with a JSON object.".to_string()
+            }
+            Some(ResponseFormat::JsonSchema {
+                schema: Some(schema),
+            }) => format!("Answer only with a JSON value that follows this JSON schema: {schema}"),
+            Some(ResponseFormat::Text) | None => String::new(),
+        };
+
+        let instructions: Vec<String> = [tools, format]
+            .into_iter()
+            .filter(|text| !text.is_empty())
+            .collect();
+        if instructions.is_empty() {
+            None
+        } else {
+            Some(instructions.join("\n\n"))
+        }
+    }
+
+    /// Returns the images in the messages of these arguments.
+    pub fn images(&self) -> Vec<ImageInput> {
+        self.messages
+            .iter()
+            .filter_map(|message| match message {
+                ChatMessage::User {
+                    content: Either::Right(parts),
+                    ..
+                } => Some(parts),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|part| match part {
+                ContentPart::Image(image) => Some(image.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the full prompt for these arguments, rendered with `template` and ending where the
+    /// completion starts.
+    pub fn prompt(&self, template: &ChatTemplate) -> Result<Prompt, LLMEndpointError> {
+        template
+            .apply(self)
+            .map_err(move |e| LLMEndpointError::Template(e.to_string()))
+    }
+}
+
+/// A request to complete a raw prompt, which is given to the model as is, without applying a chat
+/// template.
+#[derive(Debug)]
+pub struct TextCompletionArgs {
+    /// The text to complete.
+    pub prompt: String,
+
+    /// If present, the text that follows the completion. The completion is then generated with a
+    /// fill-in-the-middle prompt, which the model must support.
+    pub suffix: Option<String>,
+
+    /// The sampling and stopping parameters of the completion.
+    ///
+    /// Only the parameters unrelated to chats are used, the `messages`, `tools`, `tool_choice`
+    /// and `context_overflow` are ignored.
+    pub sampling: CompletionArgs,
+}
+
+impl TextCompletionArgs {
+    /// Returns every phrase that completions must stop at, which are the user-provided ones.
+    pub fn stop_words(&self) -> Vec<String> {
+        self.sampling.requested_stop_words()
+    }
+
+    /// Returns the prompt for these arguments, which is a fill-in-the-middle prompt made with
+    /// `fim` if there is a suffix.
+    pub fn prompt(&self, fim: Option<&FimTokens>) -> Result<String, LLMEndpointError> {
+        match (&self.suffix, fim) {
+            (None, _) => Ok(self.prompt.clone()),
+            (Some(suffix), Some(fim)) => Ok(fim.prompt(&self.prompt, suffix)),
+            (Some(_), None) => Err(LLMEndpointError::UnsuitableEndpoint(
+                "the model does not support fill-in-the-middle completions".to_string(),
+            )),
+        }
+    }
+}
+
+/// The reason a large language model stopped generating completions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model finished on its own, or a stop phrase was found.
+    Stop,
+
+    /// The maximum number of tokens was reached.
+    Length,
+
+    /// The model called one or more tools.
+    ToolCalls,
+
+    /// The request timed out before the model finished.
+    Timeout,
+}
+
+impl Display for FinishReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FinishReason::Stop => write!(f, "stop"),
+            FinishReason::Length => write!(f, "length"),
+            FinishReason::ToolCalls => write!(f, "tool_calls"),
+            FinishReason::Timeout => write!(f, "timeout"),
+        }
+    }
+}
+
+/// The number of tokens processed by a large language model, as counted by its tokenizer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TokenUsage {
+    /// The number of tokens in the prompt.
+    pub prompt_tokens: u32,
+
+    /// The number of generated tokens.
+    pub completion_tokens: u32,
+}
+
+/// The log probability of a token generated by a large language model.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenLogprob {
+    /// The text of the token.
+    pub token: String,
+
+    /// The natural logarithm of the probability of the token.
+    pub logprob: f32,
+
+    /// The most likely tokens at the position of this one, most likely first, as requested by
+    /// [`CompletionArgs::top_logprobs`].
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+/// One of the most likely tokens at a position of a completion.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TopLogprob {
+    /// The text of the token.
+    pub token: String,
+
+    /// The natural logarithm of the probability of the token.
+    pub logprob: f32,
+}
+
+/// A chat or text completion generated by an [`LLMEndpoint`].
+#[derive(Debug, Clone)]
+pub struct Completion {
+    /// The generated message.
+    pub content: String,
+
+    /// Why generation stopped.
+    pub finish_reason: FinishReason,
+
+    /// The number of tokens processed to generate this completion.
+    pub usage: TokenUsage,
+
+    /// If present, the tools that the model called instead of answering with a message.
+    pub tool_calls: Option<Vec<AssistantToolCall>>,
+
+    /// If present, how the chat was fitted into the context of the model.
+    pub context_overflow: Option<ContextOverflow>,
+
+    /// If present, the log probabilities of the generated tokens, as requested by
+    /// [`CompletionArgs::logprobs`].
+    pub logprobs: Option<Vec<TokenLogprob>>,
+}
+
+/// A chunk of a chat or text completion streamed by an [`LLMEndpoint`].
+#[derive(Debug)]
+pub struct CompletionChunk {
+    /// The index of the choice that this chunk belongs to, in `[0, n)` where `n` is
+    /// [`CompletionArgs::choices`].
+    pub index: u32,
+
+    /// The content added to the end of the completion.
+    pub content: String,
+
+    /// If present, generation stopped after this chunk, for the given reason.
+    ///
+    /// Only the last chunk of each choice has this set.
+    pub finish_reason: Option<FinishReason>,
+
+    /// If present, the number of tokens processed to generate the whole completion of this
+    /// choice.
+    ///
+    /// Only the last chunk of each choice has this set.
+    pub usage: Option<TokenUsage>,
+
+    /// If present, the tools that the model called instead of answering with a message.
+    ///
+    /// Only the last chunk of each choice can have this set.
+    pub tool_calls: Option<Vec<AssistantToolCall>>,
+
+    /// If present, how the chat was fitted into the context of the model.
+    ///
+    /// Only the last chunk of each choice has this set.
+    pub context_overflow: Option<ContextOverflow>,
+
+    /// If present, the log probabilities of the tokens generated since the previous chunk, as
+    /// requested by [`CompletionArgs::logprobs`].
+    pub logprobs: Option<Vec<TokenLogprob>>,
+}
+
+/// An input to embed, given to an [`LLMEndpoint`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmbeddingsInput {
+    /// Text, which the model tokenizes.
+    Text(String),
+
+    /// The ids of tokens in the vocabulary of the model.
+    Tokens(Vec<u32>),
+}
+
+/// The arguments to generate embeddings with an [`LLMEndpoint`].
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddingsArgs {
+    /// The inputs to embed.
+    pub inputs: Vec<EmbeddingsInput>,
+
+    /// What to do with inputs that do not fit in the context of the model.
+    pub context_overflow: EmbeddingsOverflowPolicy,
+}
+
+/// Embeddings generated by an [`LLMEndpoint`].
+#[derive(Debug)]
+pub struct Embeddings {
+    /// An embedding vector for each of the inputs, in the same order.
+    pub embeddings: Vec<Vec<f32>>,
+
+    /// The total number of tokens embedded.
+    pub prompt_tokens: u32,
+
+    /// How the inputs that did not fit in the context of the model were embedded, if any.
+    pub overflow: Option<EmbeddingsOverflow>,
 }
 
 /// A large language model endpoint, that is, an object that provides various ways to interact with
 /// a large language model.
 #[async_trait::async_trait]
 pub trait LLMEndpoint {
-    /// Given a prompt with several arguments, return a prompt completion in [`String`] form.
+    /// Given a prompt with several arguments, return a [`Completion`] for each of the
+    /// [`CompletionArgs::choices`], in order.
+    ///
+    /// Completions end at the first of [`CompletionArgs::stop_words`], which is never included,
+    /// or after [`SettingsParams::max_tokens`] tokens.
+    ///
+    /// [`SettingsParams::max_tokens`]: crate::settings::SettingsParams::max_tokens
     async fn chat_completions(
         &self,
         model_path: impl AsRef<Path> + Send,
         args: CompletionArgs,
-    ) -> Result<String, LLMEndpointError>;
+    ) -> Result<Vec<Completion>, LLMEndpointError>;
 
-    /// Given a prompt with several arguments, return a [`Stream`] of [`String`] chunks of the
+    /// Given a prompt with several arguments, return a [`Stream`] of [`CompletionChunk`]s of the
     /// prompt completion, acquired as they get processed.
+    ///
+    /// The chunks of every one of the [`CompletionArgs::choices`] are interleaved, each choice
+    /// ending at the first of [`CompletionArgs::stop_words`], which is never emitted, or after
+    /// [`SettingsParams::max_tokens`] tokens.
+    ///
+    /// [`SettingsParams::max_tokens`]: crate::settings::SettingsParams::max_tokens
     async fn stream_chat_completions(
         &self,
         model_path: impl AsRef<Path> + Send,
         args: CompletionArgs,
-    ) -> Result<Box<dyn Stream<Item = String> + Unpin + Send>, LLMEndpointError>;
+    ) -> Result<Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>, LLMEndpointError>;
+
+    /// Given a raw prompt with several arguments, return a [`Completion`] for each of the
+    /// [`CompletionArgs::choices`], in order, without applying a chat template.
+    ///
+    /// Completions end at the first of [`TextCompletionArgs::stop_words`], which is never
+    /// included, or after [`SettingsParams::max_tokens`] tokens.
+    ///
+    /// [`SettingsParams::max_tokens`]: crate::settings::SettingsParams::max_tokens
+    async fn completions(
+        &self,
+        model_path: impl AsRef<Path> + Send,
+        args: TextCompletionArgs,
+    ) -> Result<Vec<Completion>, LLMEndpointError>;
+
+    /// Given a raw prompt with several arguments, return a [`Stream`] of [`CompletionChunk`]s of
+    /// its completion, without applying a chat template.
+    ///
+    /// The chunks of every one of the [`CompletionArgs::choices`] are interleaved, each choice
+    /// ending at the first of [`TextCompletionArgs::stop_words`], which is never emitted, or
+    /// after [`SettingsParams::max_tokens`] tokens.
+    ///
+    /// [`SettingsParams::max_tokens`]: crate::settings::SettingsParams::max_tokens
+    async fn stream_completions(
+        &self,
+        model_path: impl AsRef<Path> + Send,
+        args: TextCompletionArgs,
+    ) -> Result<Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>, LLMEndpointError>;
+
+    /// Return the prompt that the model would complete for the provided arguments, rendered with
+    /// its chat template.
+    async fn render_prompt(
+        &self,
+        model_path: impl AsRef<Path> + Send,
+        args: CompletionArgs,
+    ) -> Result<String, LLMEndpointError>;
 
+    /// Given several inputs, return an embedding vector for each of them.
     async fn embeddings(
         &self,
         model_path: impl AsRef<Path> + Send,
-        inputs: Vec<String>,
-    ) -> Result<Vec<Vec<f32>>, LLMEndpointError>;
+        args: EmbeddingsArgs,
+    ) -> Result<Embeddings, LLMEndpointError>;
+
+    /// Returns every explicit [`ChatSession`] kept by this endpoint, across all models.
+    async fn chat_sessions(&self) -> Vec<ChatSessionInfo>;
+
+    /// Deletes the explicit [`ChatSession`] with `id`. A session that is generating completions is
+    /// discarded once it finishes.
+    async fn delete_chat_session(&self, id: Uuid) -> Result<(), LLMEndpointError>;
 
     /// Unloads everything from memory.
     fn reset(&self);
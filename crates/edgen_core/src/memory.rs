@@ -0,0 +1,315 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Accounting of the memory taken by models and sessions, which are evicted, least recently used
+//! first, when a global budget is exceeded.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+/// The memory budget shared by every runtime.
+pub static MEMORY_BUDGET: Lazy<Arc<MemoryBudget>> = Lazy::new(Default::default);
+
+/// A function that evicts the value of a [`MemoryReservation`], dropping the reservation, and
+/// returns **`true`**, or returns **`false`** if the value cannot be evicted at the moment (e.g.
+/// because it is in use).
+type Evictor = Arc<dyn Fn() -> bool + Send + Sync>;
+
+/// The memory in use, as accounted by a [`MemoryBudget`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct MemoryUsage {
+    /// The number of bytes taken by loaded models, sessions and running generations.
+    pub used: u64,
+
+    /// The maximum number of bytes that should be used, or `0` if there is no limit.
+    pub budget: u64,
+}
+
+/// A budget for the memory taken by models and sessions.
+///
+/// Memory is accounted with [`MemoryReservation`]s. Whenever a reservation would exceed the budget,
+/// the least recently used reservations that can be evicted are evicted until the budget is met
+/// again, if possible.
+#[derive(Default)]
+pub struct MemoryBudget {
+    /// The maximum number of bytes in use, or `0` for no limit.
+    limit: AtomicU64,
+
+    /// The current reservations.
+    state: Mutex<BudgetState>,
+}
+
+#[derive(Default)]
+struct BudgetState {
+    /// The total size of `entries`.
+    used: u64,
+
+    /// The id of the next reservation.
+    next_id: u64,
+
+    /// The reservations, by id.
+    entries: HashMap<u64, Entry>,
+}
+
+struct Entry {
+    /// The size of the reservation.
+    size: u64,
+
+    /// When the reserved value was last used.
+    last_used: Instant,
+
+    /// How to evict the reserved value, if it can be evicted.
+    evict: Option<Evictor>,
+}
+
+impl MemoryBudget {
+    /// Creates a budget of `limit` bytes, or an unlimited budget if `limit` is `0`.
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit: AtomicU64::new(limit),
+            state: Default::default(),
+        }
+    }
+
+    /// Changes the limit of this budget to `limit` bytes, or to no limit if `limit` is `0`,
+    /// evicting reservations if they no longer fit.
+    pub fn set_limit(&self, limit: u64) {
+        self.limit.store(limit, Ordering::SeqCst);
+        self.make_room(None);
+    }
+
+    /// Returns how much memory is in use.
+    pub fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            used: self.state.lock().unwrap().used,
+            budget: self.limit.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Reserves `size` bytes for a value that cannot be evicted, such as the memory taken while
+    /// running a generation. Other reservations are evicted if needed.
+    pub fn reserve(self: &Arc<Self>, size: u64) -> MemoryReservation {
+        self.insert(size, None)
+    }
+
+    /// Reserves `size` bytes for a value that is evicted with `evict` when memory is needed by
+    /// another reservation. Other reservations are evicted if needed.
+    ///
+    /// `evict` must drop the returned reservation and return **`true`**, or return **`false`** if
+    /// the value cannot be evicted at the moment. It must not block on the value being used.
+    pub fn reserve_evictable(
+        self: &Arc<Self>,
+        size: u64,
+        evict: impl Fn() -> bool + Send + Sync + 'static,
+    ) -> MemoryReservation {
+        self.insert(size, Some(Arc::new(evict)))
+    }
+
+    fn insert(self: &Arc<Self>, size: u64, evict: Option<Evictor>) -> MemoryReservation {
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            let id = state.next_id;
+            state.next_id += 1;
+            state.used += size;
+            state.entries.insert(
+                id,
+                Entry {
+                    size,
+                    last_used: Instant::now(),
+                    evict,
+                },
+            );
+            id
+        };
+
+        self.make_room(Some(id));
+
+        MemoryReservation {
+            budget: self.clone(),
+            id,
+        }
+    }
+
+    /// Evicts the least recently used reservations, other than `keep`, until the memory in use
+    /// fits the budget.
+    fn make_room(&self, keep: Option<u64>) {
+        let limit = self.limit.load(Ordering::SeqCst);
+        if limit == 0 {
+            return;
+        }
+
+        let mut candidates: Vec<_> = {
+            let state = self.state.lock().unwrap();
+            if state.used <= limit {
+                return;
+            }
+
+            state
+                .entries
+                .iter()
+                .filter(|(id, _)| Some(**id) != keep)
+                .filter_map(|(_, entry)| Some((entry.last_used, entry.evict.clone()?)))
+                .collect()
+        };
+        candidates.sort_unstable_by_key(|(last_used, _)| *last_used);
+
+        // evictors drop reservations, which lock the state, so they are called without the lock
+        for (_, evict) in candidates {
+            if self.state.lock().unwrap().used <= limit {
+                return;
+            }
+            if evict() {
+                info!("Evicted a value to stay within the memory budget");
+            }
+        }
+
+        let used = self.state.lock().unwrap().used;
+        if used > limit {
+            warn!("The memory budget is exceeded by {} bytes", used - limit);
+        }
+    }
+
+    fn touch(&self, id: u64) {
+        if let Some(entry) = self.state.lock().unwrap().entries.get_mut(&id) {
+            entry.last_used = Instant::now();
+        }
+    }
+
+    fn release(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.entries.remove(&id) {
+            state.used -= entry.size;
+        }
+    }
+}
+
+/// Memory reserved in a [`MemoryBudget`], which is released when this is dropped.
+pub struct MemoryReservation {
+    budget: Arc<MemoryBudget>,
+    id: u64,
+}
+
+impl MemoryReservation {
+    /// Marks the reserved value as recently used, so that it is evicted after the values that
+    /// were used before it.
+    pub fn touch(&self) {
+        self.budget.touch(self.id);
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.budget.release(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reserves `size` bytes for a value that is evicted by dropping the returned reservation.
+    fn reserve(budget: &Arc<MemoryBudget>, size: u64) -> Arc<Mutex<Option<MemoryReservation>>> {
+        let slot = Arc::new(Mutex::new(None));
+        let slot_clone = slot.clone();
+        let reservation = budget.reserve_evictable(size, move || {
+            // `try_lock`, since the slot is locked while it is being filled
+            match slot_clone.try_lock() {
+                Ok(mut reservation) => reservation.take().is_some(),
+                Err(_) => false,
+            }
+        });
+        *slot.lock().unwrap() = Some(reservation);
+        slot
+    }
+
+    fn evicted(slot: &Arc<Mutex<Option<MemoryReservation>>>) -> bool {
+        slot.lock().unwrap().is_none()
+    }
+
+    #[test]
+    fn unlimited() {
+        let budget = Arc::new(MemoryBudget::new(0));
+        let a = reserve(&budget, 100);
+        let b = reserve(&budget, 200);
+
+        assert!(!evicted(&a) && !evicted(&b));
+        assert_eq!(
+            budget.usage(),
+            MemoryUsage {
+                used: 300,
+                budget: 0
+            }
+        );
+
+        drop(a.lock().unwrap().take());
+        assert_eq!(budget.usage().used, 200);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let a = reserve(&budget, 40);
+        let b = reserve(&budget, 40);
+
+        // using "a" makes "b" the least recently used
+        a.lock().unwrap().as_ref().unwrap().touch();
+        let c = reserve(&budget, 40);
+
+        assert!(!evicted(&a));
+        assert!(evicted(&b));
+        assert!(!evicted(&c));
+        assert_eq!(budget.usage().used, 80);
+    }
+
+    #[test]
+    fn keeps_values_in_use() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let a = reserve(&budget, 60);
+
+        let in_use = a.lock().unwrap();
+        let generation = budget.reserve(60);
+        assert_eq!(budget.usage().used, 120);
+        drop(in_use);
+
+        // a generation cannot be evicted, but the budget is met again once "a" is no longer used
+        let b = reserve(&budget, 10);
+        assert!(evicted(&a));
+        assert!(!evicted(&b));
+        assert_eq!(budget.usage().used, 70);
+        drop(generation);
+    }
+
+    #[test]
+    fn lowering_the_limit_evicts() {
+        let budget = Arc::new(MemoryBudget::new(0));
+        let a = reserve(&budget, 60);
+        let b = reserve(&budget, 60);
+
+        budget.set_limit(100);
+        assert!(evicted(&a));
+        assert!(!evicted(&b));
+        assert_eq!(
+            budget.usage(),
+            MemoryUsage {
+                used: 60,
+                budget: 100
+            }
+        );
+    }
+}
@@ -24,6 +24,8 @@ use tokio::select;
 use tokio::sync::{oneshot, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use tracing::{info, span, Level};
 
+use crate::memory::{MemoryBudget, MemoryReservation};
+
 /// An asynchronous `OnceCell` with expiration semantics.
 ///
 /// A `Perishable` resembles an `RwLock<OnceCell<T>>`, but with the added property that the inner
@@ -32,6 +34,9 @@ use tracing::{info, span, Level};
 ///
 /// Creating a `Perishable` spawns a new asynchronous thread that watches the inner value for
 /// inactivity.
+///
+/// The inner value can also be accounted in a [`MemoryBudget`], which evicts it early when memory
+/// is needed elsewhere (see [`Perishable::with_memory_budget`]).
 pub struct Perishable<T> {
     /// The inner state and contents of this [`Perishable`].
     inner: Arc<PerishableInner<T>>,
@@ -44,8 +49,18 @@ struct PerishableInner<T> {
     current_value: RwLock<Option<T>>,
     active_signal: ActiveSignal,
     state: Arc<RwLock<PerishableState>>,
+    ttl: Duration,
+    finalizer: Mutex<Option<Finalizer<T>>>,
+    memory: Mutex<Option<(Arc<MemoryBudget>, MemorySize<T>)>>,
+    reservation: Mutex<Option<MemoryReservation>>,
 }
 
+/// A function that is passed the value of a [`Perishable`] when it perishes.
+type Finalizer<T> = Box<dyn FnOnce(T) + Send>;
+
+/// A function that returns the number of bytes of memory a value takes.
+type MemorySize<T> = Box<dyn Fn(&T) -> u64 + Send + Sync>;
+
 struct PerishableState {
     active: bool,
     last_accessed: Instant,
@@ -142,6 +157,10 @@ where
                 },
             ),
             state,
+            ttl,
+            finalizer: Mutex::new(None),
+            memory: Mutex::new(None),
+            reservation: Mutex::new(None),
         });
 
         let watched_inner = Arc::clone(&inner);
@@ -173,8 +192,12 @@ where
                 select! {
                     _ = &mut drop_rx => break,
                     _ = yield_until(check_date) => {
-                        if watched_inner.state.read().await.last_accessed == accessed && watched_inner.current_value.write().await.take().is_some() {
+                        if watched_inner.state.read().await.last_accessed != accessed {
+                            continue;
+                        }
+                        if let Some(value) = watched_inner.current_value.write().await.take() {
                             info!("A {} has perished", std::any::type_name::<T>());
+                            watched_inner.dispose(value);
                         }
                     }
                 }
@@ -186,20 +209,137 @@ where
             _drop_tx: drop_tx,
         }
     }
+
+    /// Accounts the inner value in `budget` while it is initialized, taking up the number of bytes
+    /// returned by `size`.
+    ///
+    /// When the budget is exceeded, the value may be evicted before its time to live expires, if
+    /// it is not in use and was used less recently than the other values in the budget. An
+    /// evicted value is passed to the finalizer, like a value that perished.
+    pub fn with_memory_budget(
+        self,
+        budget: Arc<MemoryBudget>,
+        size: impl Fn(&T) -> u64 + Send + Sync + 'static,
+    ) -> Self {
+        *self.inner.memory.lock().unwrap() = Some((budget, Box::new(size)));
+        self
+    }
+
+    /// Reserves memory for the newly initialized `value`, if this is accounted in a
+    /// [`MemoryBudget`].
+    fn reserve_memory(&self, value: &T) {
+        let Some((budget, size)) = self
+            .inner
+            .memory
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(budget, size)| (budget.clone(), size(value)))
+        else {
+            return;
+        };
+
+        let inner = Arc::downgrade(&self.inner);
+        let reservation = budget.reserve_evictable(size, move || {
+            inner.upgrade().is_some_and(|inner| inner.try_evict())
+        });
+        *self.inner.reservation.lock().unwrap() = Some(reservation);
+    }
+}
+
+impl<T> PerishableInner<T>
+where
+    T: Send + 'static,
+{
+    /// Releases the memory reserved for a value that was taken out of `current_value`, and passes
+    /// it to the finalizer in the background, if there is one.
+    fn dispose(&self, value: T) {
+        self.reservation.lock().unwrap().take();
+
+        if let Some(finalizer) = self.finalizer.lock().unwrap().take() {
+            match tokio::runtime::Handle::try_current() {
+                Ok(handle) => {
+                    handle.spawn_blocking(move || finalizer(value));
+                }
+                Err(_) => finalizer(value),
+            }
+        }
+    }
+
+    /// Evicts the value, unless it is in use or not initialized, and returns **`true`** if it was
+    /// evicted.
+    fn try_evict(&self) -> bool {
+        match self.state.try_read() {
+            Ok(state) if !state.active => {}
+            _ => return false,
+        }
+        let Some(value) = self
+            .current_value
+            .try_write()
+            .ok()
+            .and_then(|mut value| value.take())
+        else {
+            return false;
+        };
+
+        info!("A {} has been evicted", std::any::type_name::<T>());
+        self.dispose(value);
+        true
+    }
 }
 
-impl<T: 'static> Perishable<T> {
+impl<T> Perishable<T>
+where
+    T: Send + Sync + 'static,
+{
     /// Indicates that the value has been accessed recently.
     fn touch(&self) -> ActiveSignal {
+        if let Some(reservation) = self.inner.reservation.lock().unwrap().as_ref() {
+            reservation.touch();
+        }
         self.inner.active_signal.clone()
     }
 
+    /// Sets a function that is passed the inner value when it perishes, or when this [`Perishable`]
+    /// is dropped, instead of the value simply being dropped. This replaces any previous finalizer.
+    ///
+    /// The finalizer is called at most once, and may block.
+    pub fn set_finalizer(&self, finalizer: impl FnOnce(T) + Send + 'static) {
+        *self.inner.finalizer.lock().unwrap() = Some(Box::new(finalizer));
+    }
+
+    /// Removes the finalizer set with [`Perishable::set_finalizer`], if any.
+    pub fn clear_finalizer(&self) {
+        self.inner.finalizer.lock().unwrap().take();
+    }
+
     /// Returns `true` if the value inside of this wrapper is currently live (i.e., has been
     /// initialized and has not expired).
     pub async fn is_alive(&self) -> bool {
         self.inner.current_value.read().await.is_some()
     }
 
+    /// Returns how long the value has left to live if it is not accessed again, or `None` if it is
+    /// not initialized. A value that is in use has its whole time to live left.
+    pub async fn expires_in(&self) -> Option<Duration> {
+        if !self.is_alive().await {
+            return None;
+        }
+
+        let state = self.inner.state.read().await;
+        if state.active {
+            Some(self.inner.ttl)
+        } else {
+            Some((state.last_accessed + self.inner.ttl).saturating_duration_since(Instant::now()))
+        }
+    }
+
+    /// Gets a RAII read guard for the value if it is initialized, without marking it as accessed.
+    pub async fn peek(&self) -> Option<PerishableReadGuard<'_, T>> {
+        let guard = self.inner.current_value.read().await;
+        guard.is_some().then(|| PerishableReadGuard(guard))
+    }
+
     /// Gets a RAII read guard for the value, possibly initializing it.
     ///
     /// If the value is not initialized, it will be initialized, which may be expensive.
@@ -221,7 +361,9 @@ impl<T: 'static> Perishable<T> {
         let mut guard = self.inner.current_value.write().await;
 
         info!("(Re)Creating a new {}", std::any::type_name::<T>());
-        *guard = Some(constructor.construct().await);
+        let value = constructor.construct().await;
+        self.reserve_memory(&value);
+        *guard = Some(value);
 
         (signal, PerishableReadGuard(guard.downgrade()))
     }
@@ -248,7 +390,9 @@ impl<T: 'static> Perishable<T> {
         let mut guard = self.inner.current_value.write().await;
 
         info!("(Re)Creating a new {}", std::any::type_name::<T>());
-        *guard = Some(constructor.construct().await?);
+        let value = constructor.construct().await?;
+        self.reserve_memory(&value);
+        *guard = Some(value);
 
         Ok((signal, PerishableReadGuard(guard.downgrade())))
     }
@@ -265,7 +409,9 @@ impl<T: 'static> Perishable<T> {
 
         if guard.is_none() {
             info!("(Re)Creating a new {}", std::any::type_name::<T>());
-            *guard = Some(constructor.construct().await);
+            let value = constructor.construct().await;
+            self.reserve_memory(&value);
+            *guard = Some(value);
         }
 
         (signal, PerishableWriteGuard(guard))
@@ -284,13 +430,33 @@ impl<T: 'static> Perishable<T> {
 
         if guard.is_none() {
             info!("(Re)Creating a new {}", std::any::type_name::<T>());
-            *guard = Some(constructor.construct().await?);
+            let value = constructor.construct().await?;
+            self.reserve_memory(&value);
+            *guard = Some(value);
         }
 
         Ok((signal, PerishableWriteGuard(guard)))
     }
 }
 
+impl<T> Drop for Perishable<T> {
+    fn drop(&mut self) {
+        // no guards can be alive, since they borrow this perishable
+        let value = self
+            .inner
+            .current_value
+            .try_write()
+            .ok()
+            .and_then(|mut value| value.take());
+        self.inner.reservation.lock().unwrap().take();
+
+        if let (Some(value), Some(finalizer)) = (value, self.inner.finalizer.lock().unwrap().take())
+        {
+            finalizer(value);
+        }
+    }
+}
+
 /// The inner state of an [`ActiveSignal`].
 struct ActiveSignalInner {
     /// The current number of references.
@@ -377,6 +543,9 @@ pub async fn yield_until(t: Instant) {
 
 #[cfg(test)]
 mod test {
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+
     use super::*;
 
     #[tokio::test]
@@ -423,4 +592,86 @@ mod test {
         assert_eq!(*perishable.get_or_init(|| async { 0 }).await.1, 0);
         assert!(perishable.is_alive().await);
     }
+
+    #[tokio::test]
+    async fn perishable_peek() {
+        let perishable = Perishable::with_ttl(Duration::from_millis(200));
+
+        assert!(perishable.peek().await.is_none());
+        assert_eq!(perishable.expires_in().await, None);
+
+        perishable.get_or_init(|| async { 0 }).await;
+        assert_eq!(*perishable.peek().await.unwrap(), 0);
+        {
+            let (_signal, _value) = perishable.get_or_init(|| async { 0 }).await;
+            assert_eq!(
+                perishable.expires_in().await,
+                Some(Duration::from_millis(200))
+            );
+        }
+
+        // peeking does not keep the value alive
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(perishable.peek().await.is_some());
+        assert!(perishable.expires_in().await.unwrap() <= Duration::from_millis(100));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(perishable.peek().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn finalizer() {
+        let (tx, rx) = channel();
+
+        let perishable = Perishable::with_ttl(Duration::from_millis(50));
+        let tx_clone = tx.clone();
+        perishable.set_finalizer(move |value| tx_clone.send(value).unwrap());
+        perishable.get_or_init(|| async { 1 }).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(rx.try_recv(), Ok(1));
+
+        // the finalizer only runs once
+        perishable.get_or_init(|| async { 2 }).await;
+        drop(perishable);
+        assert!(rx.try_recv().is_err());
+
+        let perishable = Perishable::with_ttl(Duration::from_secs(60));
+        perishable.set_finalizer(move |value| tx.send(value).unwrap());
+        perishable.get_or_init(|| async { 3 }).await;
+        drop(perishable);
+        assert_eq!(rx.try_recv(), Ok(3));
+    }
+
+    #[tokio::test]
+    async fn memory_budget() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let (tx, rx) = channel();
+
+        let first = Perishable::with_ttl(Duration::from_secs(60))
+            .with_memory_budget(budget.clone(), |value| *value);
+        first.set_finalizer(move |value| tx.send(value).unwrap());
+        let second = Perishable::with_ttl(Duration::from_secs(60))
+            .with_memory_budget(budget.clone(), |value| *value);
+
+        {
+            let (_signal, _value) = first.get_or_init(|| async { 60 }).await;
+
+            // values in use are not evicted
+            second.get_or_init(|| async { 50 }).await;
+            assert!(first.is_alive().await);
+            assert_eq!(budget.usage().used, 110);
+        }
+
+        let third = Perishable::with_ttl(Duration::from_secs(60))
+            .with_memory_budget(budget.clone(), |value| *value);
+        third.get_or_init(|| async { 10 }).await;
+        assert!(!first.is_alive().await);
+        assert!(second.is_alive().await);
+        assert_eq!(budget.usage().used, 60);
+
+        // the evicted value is passed to the finalizer in the background
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(60));
+
+        drop(second);
+        assert_eq!(budget.usage().used, 10);
+    }
 }
@@ -0,0 +1,237 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A directory of saved session states, which outlive the process that saved them.
+
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use thiserror::Error;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// The file extension of a saved session state.
+const FILE_EXTENSION: &str = "session";
+
+/// An error that occurred while saving a session state to a [`SessionCache`].
+#[derive(Debug, Error)]
+pub enum SessionCacheError {
+    #[error("failed to access the session cache: {0}")]
+    Io(#[from] io::Error),
+    #[error("failed to save the session state: {0}")]
+    Save(String),
+}
+
+/// A directory of saved session states, keyed by arbitrary file-name-safe strings.
+///
+/// The total size of the saved states is kept under a budget by removing the least recently used
+/// states, where a state is used when it is saved or found with [`SessionCache::get`]. Recency is
+/// tracked with the modification times of the files, so that it survives restarts.
+///
+/// Every method blocks while accessing the file system.
+#[derive(Debug, Clone)]
+pub struct SessionCache {
+    /// The directory holding the saved states.
+    dir: PathBuf,
+
+    /// The maximum total size, in bytes, of the saved states.
+    max_size: u64,
+}
+
+impl SessionCache {
+    /// Creates a cache of session states in `dir`, which is created when the first state is
+    /// saved, taking up at most `max_size` bytes.
+    pub fn new(dir: impl Into<PathBuf>, max_size: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            max_size,
+        }
+    }
+
+    /// Returns the path of the state saved with `key`, if there is one, and marks it as recently
+    /// used.
+    pub fn get(&self, key: &str) -> Option<PathBuf> {
+        let path = self.path(key);
+        let file = File::options().write(true).open(&path).ok()?;
+        if let Err(e) = file.set_modified(SystemTime::now()) {
+            warn!("Failed to mark {} as recently used: {e}", path.display());
+        }
+
+        Some(path)
+    }
+
+    /// Saves a session state with `key`, replacing any state saved with the same key, and then
+    /// removes the least recently used states that exceed the size budget. A state that is larger
+    /// than the whole budget is discarded instead.
+    ///
+    /// `save` must write the state to the path it is given. The state only replaces the previous
+    /// one once it is completely written.
+    pub fn store<E: Display>(
+        &self,
+        key: &str,
+        save: impl FnOnce(&Path) -> Result<(), E>,
+    ) -> Result<(), SessionCacheError> {
+        fs::create_dir_all(&self.dir)?;
+
+        let tmp_path = self.dir.join(format!("{key}.{}.tmp", Uuid::new_v4()));
+        if let Err(e) = save(&tmp_path) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(SessionCacheError::Save(e.to_string()));
+        }
+        let path = self.path(key);
+        fs::rename(&tmp_path, &path)?;
+
+        // a state larger than the whole budget would only evict every other state before being
+        // evicted itself
+        if fs::metadata(&path)?.len() > self.max_size {
+            info!("Not caching session {key}, which is larger than the cache");
+            fs::remove_file(&path)?;
+            return Ok(());
+        }
+
+        self.evict()?;
+        Ok(())
+    }
+
+    /// Removes the state saved with `key`, if there is one.
+    pub fn remove(&self, key: &str) {
+        match fs::remove_file(self.path(key)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                warn!("Failed to remove the cached session {key}: {e}")
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the path of the state saved with `key`.
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.{FILE_EXTENSION}"))
+    }
+
+    /// Removes the least recently used states until the rest fit in the size budget.
+    fn evict(&self) -> io::Result<()> {
+        let mut entries = vec![];
+        let mut total_size = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension() != Some(OsStr::new(FILE_EXTENSION)) {
+                continue;
+            }
+
+            // another thread may have removed the file in the meantime
+            let Ok(metadata) = fs::metadata(&path) else {
+                continue;
+            };
+            total_size += metadata.len();
+            entries.push((metadata.modified()?, metadata.len(), path));
+        }
+
+        entries.sort_unstable_by_key(|(modified, _, _)| *modified);
+        for (_, size, path) in entries {
+            if total_size <= self.max_size {
+                break;
+            }
+
+            info!("Evicting cached session {}", path.display());
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => total_size -= size,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn store(cache: &SessionCache, key: &str, size: usize) {
+        cache
+            .store(key, |path| fs::write(path, vec![0u8; size]))
+            .unwrap();
+    }
+
+    /// Makes the state saved with `key` look like it was last used `secs` seconds ago.
+    fn age(cache: &SessionCache, key: &str, secs: u64) {
+        File::options()
+            .write(true)
+            .open(cache.path(key))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn store_and_get() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = SessionCache::new(tmp.path().join("sessions"), 1024);
+
+        assert_eq!(cache.get("a"), None);
+
+        store(&cache, "a", 16);
+        let path = cache.get("a").unwrap();
+        assert_eq!(fs::read(path).unwrap().len(), 16);
+
+        cache.remove("a");
+        assert_eq!(cache.get("a"), None);
+    }
+
+    #[test]
+    fn failed_save_keeps_the_previous_state() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = SessionCache::new(tmp.path(), 1024);
+
+        store(&cache, "a", 16);
+        let result = cache.store("a", |path| {
+            fs::write(path, [0u8; 8]).unwrap();
+            Err("out of memory")
+        });
+        assert!(matches!(result, Err(SessionCacheError::Save(_))));
+
+        let path = cache.get("a").unwrap();
+        assert_eq!(fs::read(path).unwrap().len(), 16);
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = SessionCache::new(tmp.path(), 100);
+
+        store(&cache, "a", 40);
+        age(&cache, "a", 30);
+        store(&cache, "b", 40);
+        age(&cache, "b", 20);
+
+        // using "a" makes "b" the least recently used
+        cache.get("a").unwrap();
+        store(&cache, "c", 40);
+
+        assert!(cache.get("a").is_some());
+        assert_eq!(cache.get("b"), None);
+        assert!(cache.get("c").is_some());
+
+        // a state larger than the budget is not kept, and evicts nothing
+        store(&cache, "d", 101);
+        assert_eq!(cache.get("d"), None);
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("c").is_some());
+    }
+}
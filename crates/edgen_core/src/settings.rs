@@ -10,9 +10,11 @@
  * limitations under the License.
  */
 
+use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use dashmap::DashMap;
 use directories::ProjectDirs;
@@ -26,6 +28,9 @@ use tokio::sync::RwLock;
 use tracing::{error, info, warn};
 use uuid::Uuid;
 
+use crate::admission::AdmissionLimits;
+use crate::context_overflow::ContextOverflowPolicy;
+
 /// The file extension of a YAML file, which is the format used to store settings.
 const FILE_EXTENSION: &str = ".yaml";
 const FILE_NAME: &str = "edgen.conf";
@@ -309,8 +314,131 @@ pub struct SettingsParams {
     /// The maximum size, in bytes, any request can have. This is most relevant in requests with files, such as audio
     /// transcriptions.
     pub max_request_size: usize,
+
+    /// The maximum number of tokens generated in a chat completion, if a request does not specify
+    /// `max_tokens`.
+    #[serde(default = "default_max_tokens")]
+    pub chat_completions_max_tokens: u32,
+
+    /// The maximum number of one-shot chat and text completions of a model decoded together in a
+    /// single context. `0` or `1` disables batching.
+    #[serde(default)]
+    pub chat_completions_batch_sequences: u32,
+
+    /// The directory where the states of chat sessions are saved, so that they can be restored
+    /// after a restart.
+    #[serde(default = "default_session_cache_dir")]
+    pub session_cache_dir: String,
+
+    /// The maximum total size, in bytes, of the saved states of chat sessions, past which the
+    /// least recently used ones are deleted. `0`, the default, disables saving sessions, which hold
+    /// the conversations of the chats.
+    #[serde(default)]
+    pub session_cache_size: u64,
+
+    /// The maximum number of bytes of memory taken by loaded models and sessions, past which the
+    /// least recently used ones are unloaded. `0` means there is no limit.
+    #[serde(default)]
+    pub memory_budget: u64,
+
+    /// How many chat and text completions requests are handled at once, and how many more can
+    /// wait for their turn.
+    #[serde(default)]
+    pub chat_completions_admission: AdmissionLimits,
+
+    /// How many audio transcriptions requests are handled at once, and how many more can wait for
+    /// their turn.
+    #[serde(default)]
+    pub audio_transcriptions_admission: AdmissionLimits,
+
+    /// How many embeddings requests are handled at once, and how many more can wait for their
+    /// turn.
+    #[serde(default)]
+    pub embeddings_admission: AdmissionLimits,
+
+    /// How many image generations requests are handled at once, and how many more can wait for
+    /// their turn.
+    #[serde(default)]
+    pub image_generations_admission: AdmissionLimits,
+
+    /// The number of seconds a request to an AI endpoint may take, if the request does not
+    /// specify a `timeout`. `0` means there is no limit.
+    #[serde(default)]
+    pub request_timeout: u64,
+
+    /// The hosts from which remote images in chat messages may be fetched, or `*` for any host.
+    /// Remote images are rejected by default.
+    #[serde(default)]
+    pub image_url_hosts: Vec<String>,
+
+    /// The maximum size, in bytes, of a remote image in a chat message, past which it is rejected.
+    #[serde(default = "default_image_url_max_size")]
+    pub image_url_max_size: u64,
+
+    /// Settings for individual models, keyed by the model's file name.
+    #[serde(default)]
+    pub models: HashMap<String, ModelSettings>,
+}
+
+/// Settings that only apply to a single model, overriding the server-wide settings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelSettings {
+    /// The maximum number of tokens generated in a chat completion of this model, regardless of
+    /// what a request specifies.
+    pub max_tokens: Option<u32>,
+
+    /// The chat template of this model, overriding the one in the model file. This is either the
+    /// name of a built-in template (see [`TEMPLATE_NAMES`]) or a Jinja template.
+    ///
+    /// [`TEMPLATE_NAMES`]: crate::chat_template::TEMPLATE_NAMES
+    pub chat_template: Option<String>,
+
+    /// The number of tokens in the context of this model's sessions, overriding the context size
+    /// the model was trained with.
+    pub context_size: Option<u32>,
+
+    /// What to do if the prompt of a chat does not fit in the context of this model, if a request
+    /// does not specify it.
+    pub context_overflow: Option<ContextOverflowPolicy>,
+
+    /// The GGUF file of a smaller model sharing this model's vocabulary, which drafts the tokens
+    /// of one-shot completions for this model to verify (speculative decoding). A relative path is
+    /// resolved against the directory of this model.
+    pub draft_model: Option<String>,
+
+    /// The number of tokens drafted at every step of speculative decoding, if a `draft_model` is
+    /// set. Defaults to [`DRAFT_TOKENS`].
+    pub draft_tokens: Option<u32>,
+
+    /// The LoRA adapters of this model, by name, which requests select with a model name like
+    /// `model+adapter`.
+    pub adapters: HashMap<String, AdapterSettings>,
+
+    /// The multimodal projector (mmproj) GGUF file of this model, which embeds the images of chat
+    /// messages. A relative path is resolved against the directory of this model.
+    pub mmproj: Option<String>,
+}
+
+/// A LoRA adapter of a model.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdapterSettings {
+    /// The adapter file. A relative path is resolved against the directory of the model.
+    pub path: String,
+
+    /// How strongly the adapter is applied. Defaults to `1.0`.
+    #[serde(default = "default_adapter_scale")]
+    pub scale: f32,
 }
 
+fn default_adapter_scale() -> f32 {
+    1.0
+}
+
+/// The number of tokens drafted at every step of speculative decoding, if a model doesn't specify
+/// it.
+pub const DRAFT_TOKENS: u32 = 8;
+
 impl SettingsParams {
     pub fn auto_threads(&self, physical: bool) -> u32 {
         let max_threads = if physical {
@@ -326,6 +454,101 @@ impl SettingsParams {
             self.threads
         }
     }
+
+    /// Returns the [`ModelSettings`] of the model at `model_path`, which are the defaults if none
+    /// are configured.
+    pub fn model_settings(&self, model_path: impl AsRef<Path>) -> ModelSettings {
+        model_path
+            .as_ref()
+            .file_name()
+            .and_then(|name| self.models.get(name.to_string_lossy().as_ref()))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns the maximum number of tokens generated in a chat completion of the model at
+    /// `model_path`, given the `max_tokens` of the request.
+    ///
+    /// If the request doesn't specify `max_tokens`, [`Self::chat_completions_max_tokens`] is used.
+    /// Either way, the maximum configured for the model is never exceeded.
+    pub fn max_tokens(&self, model_path: impl AsRef<Path>, requested: Option<u32>) -> u32 {
+        let max_tokens = requested.unwrap_or(self.chat_completions_max_tokens);
+
+        match self.model_settings(model_path).max_tokens {
+            Some(model_max) => max_tokens.min(model_max),
+            None => max_tokens,
+        }
+    }
+
+    /// Returns what to do if the prompt of a chat does not fit in the context of the model at
+    /// `model_path`, given the policy of the request.
+    ///
+    /// The policy of the request takes precedence over the one configured for the model, which
+    /// defaults to [`ContextOverflowPolicy::Reject`].
+    pub fn context_overflow(
+        &self,
+        model_path: impl AsRef<Path>,
+        requested: Option<ContextOverflowPolicy>,
+    ) -> ContextOverflowPolicy {
+        requested
+            .or(self.model_settings(model_path).context_overflow)
+            .unwrap_or_default()
+    }
+
+    /// Returns the path of the draft model of the model at `model_path` and the number of tokens
+    /// it drafts at every step, or [`None`] if the model is not decoded speculatively.
+    ///
+    /// A relative draft model path is resolved against the directory of `model_path`.
+    pub fn draft_model(&self, model_path: impl AsRef<Path>) -> Option<(PathBuf, u32)> {
+        let settings = self.model_settings(&model_path);
+        let draft = settings.draft_model.filter(|draft| !draft.is_empty())?;
+        let path = beside_model(model_path, draft);
+
+        Some((path, settings.draft_tokens.unwrap_or(DRAFT_TOKENS).max(1)))
+    }
+
+    /// Returns the path of the multimodal projector of the model at `model_path`, or [`None`] if
+    /// the model cannot embed images.
+    ///
+    /// A relative projector path is resolved against the directory of `model_path`.
+    pub fn mmproj(&self, model_path: impl AsRef<Path>) -> Option<PathBuf> {
+        let mmproj = self
+            .model_settings(&model_path)
+            .mmproj
+            .filter(|mmproj| !mmproj.is_empty())?;
+
+        Some(beside_model(model_path, mmproj))
+    }
+
+    /// Returns **`true`** if remote images may be fetched from `host`.
+    pub fn image_host_allowed(&self, host: &str) -> bool {
+        self.image_url_hosts
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(host))
+    }
+
+    /// Returns the LoRA adapter named `name` of the model at `model_path`, with its path resolved
+    /// against the directory of `model_path`, or [`None`] if the model has no such adapter.
+    pub fn adapter(&self, model_path: impl AsRef<Path>, name: &str) -> Option<AdapterSettings> {
+        let mut adapter = self.model_settings(&model_path).adapters.remove(name)?;
+        if let Some(dir) = model_path.as_ref().parent() {
+            adapter.path = dir.join(&adapter.path).to_string_lossy().to_string();
+        }
+
+        Some(adapter)
+    }
+
+    /// Returns how long a request may take, given the `timeout` of the request in seconds, or
+    /// `None` if there is no limit.
+    ///
+    /// If the request doesn't specify a timeout, [`Self::request_timeout`] is used. A timeout that
+    /// is not positive means there is no limit.
+    pub fn timeout(&self, requested: Option<f32>) -> Option<Duration> {
+        let seconds = requested.unwrap_or(self.request_timeout as f32);
+        Duration::try_from_secs_f32(seconds)
+            .ok()
+            .filter(|timeout| !timeout.is_zero())
+    }
 }
 
 impl Default for SettingsParams {
@@ -372,10 +595,49 @@ impl Default for SettingsParams {
                 overflow_to_cpu: true,
             },
             max_request_size: 1024 * 1014 * 100, // 100 MB
+            chat_completions_max_tokens: default_max_tokens(),
+            chat_completions_batch_sequences: 0,
+            session_cache_dir: default_session_cache_dir(),
+            session_cache_size: 0,
+            memory_budget: 0,
+            chat_completions_admission: AdmissionLimits::default(),
+            audio_transcriptions_admission: AdmissionLimits::default(),
+            embeddings_admission: AdmissionLimits::default(),
+            image_generations_admission: AdmissionLimits::default(),
+            request_timeout: 0,
+            image_url_hosts: vec![],
+            image_url_max_size: default_image_url_max_size(),
+            models: HashMap::new(),
         }
     }
 }
 
+/// Resolves `path`, which is relative to the directory of the model at `model_path` unless it is
+/// absolute.
+fn beside_model(model_path: impl AsRef<Path>, path: String) -> PathBuf {
+    match model_path.as_ref().parent() {
+        Some(dir) => dir.join(path),
+        None => PathBuf::from(path),
+    }
+}
+
+fn default_max_tokens() -> u32 {
+    4096
+}
+
+fn default_image_url_max_size() -> u64 {
+    20 * 1024 * 1024
+}
+
+fn default_session_cache_dir() -> String {
+    PROJECT_DIRS
+        .cache_dir()
+        .join("sessions")
+        .into_os_string()
+        .into_string()
+        .unwrap()
+}
+
 fn join_path_components(comps: &[&str]) -> PathBuf {
     comps.iter().collect::<PathBuf>()
 }
@@ -718,6 +980,226 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_max_tokens() {
+        let params = SettingsParams {
+            chat_completions_max_tokens: 100,
+            models: HashMap::from([(
+                "capped.gguf".to_string(),
+                ModelSettings {
+                    max_tokens: Some(50),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+
+        let free = Path::new("models").join("free.gguf");
+        let capped = Path::new("models").join("capped.gguf");
+
+        assert_eq!(params.max_tokens(&free, None), 100);
+        assert_eq!(params.max_tokens(&free, Some(200)), 200);
+        assert_eq!(params.max_tokens(&capped, None), 50);
+        assert_eq!(params.max_tokens(&capped, Some(20)), 20);
+        assert_eq!(params.max_tokens(&capped, Some(200)), 50);
+    }
+
+    #[test]
+    fn test_draft_model() {
+        let params = SettingsParams {
+            models: HashMap::from([
+                (
+                    "large.gguf".to_string(),
+                    ModelSettings {
+                        draft_model: Some("small.gguf".to_string()),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "other.gguf".to_string(),
+                    ModelSettings {
+                        draft_model: Some("/drafts/tiny.gguf".to_string()),
+                        draft_tokens: Some(4),
+                        ..Default::default()
+                    },
+                ),
+            ]),
+            ..Default::default()
+        };
+
+        let models = Path::new("models");
+        assert_eq!(params.draft_model(models.join("free.gguf")), None);
+        assert_eq!(
+            params.draft_model(models.join("large.gguf")),
+            Some((models.join("small.gguf"), DRAFT_TOKENS))
+        );
+        assert_eq!(
+            params.draft_model(models.join("other.gguf")),
+            Some((PathBuf::from("/drafts/tiny.gguf"), 4))
+        );
+    }
+
+    #[test]
+    fn test_mmproj() {
+        let params = SettingsParams {
+            models: HashMap::from([(
+                "llava.gguf".to_string(),
+                ModelSettings {
+                    mmproj: Some("llava-mmproj.gguf".to_string()),
+                    ..Default::default()
+                },
+            )]),
+            image_url_hosts: vec!["images.example.com".to_string()],
+            ..Default::default()
+        };
+
+        let models = Path::new("models");
+        assert_eq!(params.mmproj(models.join("text.gguf")), None);
+        assert_eq!(
+            params.mmproj(models.join("llava.gguf")),
+            Some(models.join("llava-mmproj.gguf"))
+        );
+
+        assert!(params.image_host_allowed("Images.Example.com"));
+        assert!(!params.image_host_allowed("example.com"));
+        assert!(!SettingsParams::default().image_host_allowed("images.example.com"));
+    }
+
+    #[test]
+    fn test_adapter() {
+        let yaml = "adapters:\n  support:\n    path: support-lora.gguf\n  \
+                    legal:\n    path: /adapters/legal-lora.gguf\n    scale: 0.5\n";
+        let params = SettingsParams {
+            models: HashMap::from([(
+                "base.gguf".to_string(),
+                from_slice(yaml.as_bytes()).unwrap(),
+            )]),
+            ..Default::default()
+        };
+
+        let base = Path::new("models").join("base.gguf");
+        let support = params.adapter(&base, "support").unwrap();
+        assert_eq!(
+            Path::new(&support.path),
+            Path::new("models").join("support-lora.gguf")
+        );
+        assert_eq!(support.scale, 1.0);
+        assert_eq!(
+            params.adapter(&base, "legal"),
+            Some(AdapterSettings {
+                path: "/adapters/legal-lora.gguf".to_string(),
+                scale: 0.5,
+            })
+        );
+
+        assert_eq!(params.adapter(&base, "sales"), None);
+        assert_eq!(
+            params.adapter(Path::new("models").join("other.gguf"), "support"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_timeout() {
+        let params = SettingsParams {
+            request_timeout: 60,
+            ..Default::default()
+        };
+
+        assert_eq!(params.timeout(None), Some(Duration::from_secs(60)));
+        assert_eq!(params.timeout(Some(0.5)), Some(Duration::from_millis(500)));
+        assert_eq!(params.timeout(Some(0.0)), None);
+        assert_eq!(params.timeout(Some(-1.0)), None);
+
+        let params = SettingsParams::default();
+        assert_eq!(params.timeout(None), None);
+    }
+
+    #[test]
+    fn test_context_overflow() {
+        let params = SettingsParams {
+            models: HashMap::from([(
+                "windowed.gguf".to_string(),
+                ModelSettings {
+                    context_overflow: Some(ContextOverflowPolicy::SlidingWindow),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+
+        let free = Path::new("models").join("free.gguf");
+        let windowed = Path::new("models").join("windowed.gguf");
+
+        assert_eq!(
+            params.context_overflow(&free, None),
+            ContextOverflowPolicy::Reject
+        );
+        assert_eq!(
+            params.context_overflow(&windowed, None),
+            ContextOverflowPolicy::SlidingWindow
+        );
+        assert_eq!(
+            params.context_overflow(&windowed, Some(ContextOverflowPolicy::DropOldest)),
+            ContextOverflowPolicy::DropOldest
+        );
+    }
+
+    #[test]
+    fn test_model_settings() {
+        let yaml = "max_tokens: 256\ncontext_size: 32768\nchat_template: chatml\n\
+                    context_overflow: drop_oldest\n";
+        let settings: ModelSettings = from_slice(yaml.as_bytes()).unwrap();
+
+        assert_eq!(settings.max_tokens, Some(256));
+        assert_eq!(settings.context_size, Some(32768));
+        assert_eq!(settings.chat_template.as_deref(), Some("chatml"));
+        assert_eq!(
+            settings.context_overflow,
+            Some(ContextOverflowPolicy::DropOldest)
+        );
+
+        let settings: ModelSettings = from_slice(b"max_tokens: 256\n").unwrap();
+        assert_eq!(settings.context_size, None);
+        assert_eq!(settings.chat_template, None);
+        assert_eq!(settings.draft_model, None);
+    }
+
+    #[test]
+    fn test_missing_fields_use_defaults() {
+        let yaml = to_string(&SettingsParams::default()).unwrap();
+        let yaml: String = yaml
+            .lines()
+            .filter(|line| {
+                !line.starts_with("chat_completions_max_tokens")
+                    && !line.starts_with("chat_completions_batch_sequences")
+                    && !line.starts_with("session_cache")
+                    && !line.starts_with("memory_budget")
+                    && !line.starts_with("request_timeout")
+                    && !line.starts_with("image_url_max_size")
+                    && !line.contains("_admission")
+                    && !line.starts_with("  max_")
+                    && !line.starts_with("models")
+            })
+            .map(|line| format!("{line}\n"))
+            .collect();
+
+        let params: SettingsParams = from_slice(yaml.as_bytes()).unwrap();
+        assert_eq!(params.chat_completions_max_tokens, 4096);
+        assert_eq!(params.chat_completions_batch_sequences, 0);
+        assert_eq!(params.session_cache_dir, default_session_cache_dir());
+        assert_eq!(params.session_cache_size, 0);
+        assert_eq!(params.memory_budget, 0);
+        assert_eq!(params.embeddings_admission, AdmissionLimits::default());
+        assert_eq!(
+            params.image_generations_admission,
+            AdmissionLimits::default()
+        );
+        assert_eq!(params.request_timeout, 0);
+        assert_eq!(params.image_url_max_size, 20 * 1024 * 1024);
+        assert!(params.models.is_empty());
+    }
+
     // Trying to avoid doing too many disk writes in unit tests by performing every test using the
     // same file.
     #[tokio::test]
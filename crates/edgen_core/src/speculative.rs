@@ -0,0 +1,102 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Statistics of speculative decoding, where a small draft model proposes the next tokens of a
+//! completion and the model being completed verifies them all at once.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// The statistics of speculative decoding shared by every runtime.
+pub static SPECULATIVE_STATS: Lazy<SpeculativeStats> = Lazy::new(Default::default);
+
+/// Counters of the tokens drafted and accepted by speculative decoding.
+#[derive(Debug, Default)]
+pub struct SpeculativeStats {
+    /// The number of tokens proposed by draft models.
+    drafted: AtomicU64,
+
+    /// The number of drafted tokens that were accepted.
+    accepted: AtomicU64,
+}
+
+/// How well speculative decoding has been going, as reported by [`SpeculativeStats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct SpeculativeStatus {
+    /// The number of tokens proposed by draft models.
+    pub drafted_tokens: u64,
+
+    /// The number of drafted tokens that were accepted.
+    pub accepted_tokens: u64,
+
+    /// The fraction of the drafted tokens that were accepted, in `[0, 1]`.
+    pub acceptance_rate: f32,
+}
+
+impl SpeculativeStats {
+    /// Records that `accepted` of `drafted` tokens were accepted.
+    pub fn record(&self, drafted: usize, accepted: usize) {
+        self.drafted.fetch_add(drafted as u64, Ordering::Relaxed);
+        self.accepted.fetch_add(accepted as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the statistics recorded so far, or [`None`] if no tokens were drafted yet.
+    pub fn status(&self) -> Option<SpeculativeStatus> {
+        let drafted = self.drafted.load(Ordering::Relaxed);
+        let accepted = self.accepted.load(Ordering::Relaxed);
+        (drafted > 0).then(|| SpeculativeStatus {
+            drafted_tokens: drafted,
+            accepted_tokens: accepted,
+            acceptance_rate: acceptance_rate(drafted, accepted),
+        })
+    }
+}
+
+/// Returns the fraction of `drafted` tokens that were `accepted`, or `0` if none were drafted.
+pub fn acceptance_rate(drafted: u64, accepted: u64) -> f32 {
+    if drafted == 0 {
+        0.0
+    } else {
+        accepted as f32 / drafted as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_reports_the_acceptance_rate() {
+        let stats = SpeculativeStats::default();
+        assert_eq!(stats.status(), None);
+
+        stats.record(8, 6);
+        stats.record(8, 0);
+        assert_eq!(
+            stats.status(),
+            Some(SpeculativeStatus {
+                drafted_tokens: 16,
+                accepted_tokens: 6,
+                acceptance_rate: 0.375,
+            })
+        );
+    }
+
+    #[test]
+    fn nothing_drafted_is_nothing_accepted() {
+        assert_eq!(acceptance_rate(0, 0), 0.0);
+        assert_eq!(acceptance_rate(4, 4), 1.0);
+    }
+}
@@ -10,6 +10,8 @@
  * limitations under the License.
  */
 
+//! A [`Stream`] adapter that terminates text completions at stop words.
+
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
@@ -37,6 +39,9 @@ pub struct StoppingStream<T> {
 
     /// If `true`, the inner stream has completed, and this wrapper shouldn't yield any more values.
     is_fused: bool,
+
+    /// If `true`, a stop word was found in the inner stream.
+    stopped: bool,
 }
 
 impl<T> StoppingStream<T>
@@ -48,13 +53,22 @@ where
     /// The stop words are never emitted, and the stream will yield `None` when a stop word is
     /// generated by the inner stream.
     pub fn wrap_with_stop_words(inner: T, stop_words: impl Into<Vec<String>>) -> Self {
+        let mut stop_words = stop_words.into();
+        stop_words.retain(|word| !word.is_empty());
+
         Self {
             inner,
-            stop_words: stop_words.into(),
+            stop_words,
             working_buf: String::new(),
             is_fused: false,
+            stopped: false,
         }
     }
+
+    /// Returns `true` if this stream terminated because a stop word was generated.
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
 }
 
 impl<T> Stream for StoppingStream<T>
@@ -63,8 +77,8 @@ where
 {
     type Item = String;
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let mut this = self.as_mut().project();
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
 
         if *this.is_fused {
             return Poll::Ready(None);
@@ -74,39 +88,75 @@ where
             let token = match this.inner.as_mut().poll_next(cx) {
                 Poll::Ready(Some(token)) => token,
                 Poll::Ready(None) => {
-                    self.is_fused = true;
-
-                    return Poll::Ready(None);
+                    *this.is_fused = true;
+
+                    // Whatever is left in the buffer turned out not to be a stop word.
+                    return if this.working_buf.is_empty() {
+                        Poll::Ready(None)
+                    } else {
+                        Poll::Ready(Some(std::mem::take(this.working_buf)))
+                    };
                 }
                 Poll::Pending => return Poll::Pending,
             };
 
             this.working_buf.push_str(&token);
 
-            let mut should_emit = true;
+            if let Some(idx) = find_stop_word(this.working_buf, this.stop_words) {
+                *this.is_fused = true;
+                *this.stopped = true;
+                this.working_buf.truncate(idx);
 
-            'stop_words: for stop_word in &*this.stop_words {
-                if this.working_buf.starts_with(stop_word) {
-                    return Poll::Ready(None);
-                }
+                return if this.working_buf.is_empty() {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Ready(Some(std::mem::take(this.working_buf)))
+                };
+            }
 
-                if stop_word.starts_with(&*this.working_buf) {
-                    // We may currently be generating this stop word.
-                    //
-                    // Stall emission of the working buffer until we can be sure that we're not.
-                    should_emit = false;
+            // We may currently be generating a stop word.
+            //
+            // Stall emission of the part of the working buffer that could be its start until we
+            // can be sure that we're not.
+            let held = partial_stop_word_len(this.working_buf, this.stop_words);
+            let emit_len = this.working_buf.len() - held;
 
-                    break 'stop_words;
-                }
+            if emit_len > 0 {
+                let held_buf = this.working_buf.split_off(emit_len);
+                let out_buf = std::mem::replace(this.working_buf, held_buf);
+
+                return Poll::Ready(Some(out_buf));
             }
+        }
+    }
+}
 
-            if should_emit {
-                let out_buf = std::mem::take(this.working_buf);
+/// Returns the index of the first stop word found in `text`, if any.
+fn find_stop_word(text: &str, stop_words: &[String]) -> Option<usize> {
+    stop_words
+        .iter()
+        .filter_map(|stop_word| text.find(stop_word.as_str()))
+        .min()
+}
 
-                return Poll::Ready(Some(out_buf));
+/// Returns the length of the longest suffix of `text` that is the start of a stop word.
+fn partial_stop_word_len(text: &str, stop_words: &[String]) -> usize {
+    let mut longest = 0;
+
+    for stop_word in stop_words {
+        for (idx, _) in text.char_indices() {
+            let suffix = &text[idx..];
+            if suffix.len() <= longest {
+                break;
+            }
+            if stop_word.starts_with(suffix) {
+                longest = suffix.len();
+                break;
             }
         }
     }
+
+    longest
 }
 
 #[cfg(test)]
@@ -126,8 +176,8 @@ mod test {
         );
 
         assert_eq!(
-            stopping_stream.collect::<Vec<_>>().await,
-            vec!["apple", "banana"]
+            stopping_stream.collect::<Vec<_>>().await.concat(),
+            "applebanana"
         );
     }
 
@@ -160,8 +210,8 @@ mod test {
             StoppingStream::wrap_with_stop_words(content_stream, vec!["eggplant".to_string()]);
 
         assert_eq!(
-            stopping_stream.collect::<Vec<_>>().await,
-            vec!["apple", "banana", "coconut", "dill"],
+            stopping_stream.collect::<Vec<_>>().await.concat(),
+            "applebananacoconutdill",
         );
     }
 
@@ -186,7 +236,56 @@ mod test {
 
             println!("expected for {}: {:?}", v[i], expected);
 
-            assert_eq!(stopping_stream.collect::<Vec<_>>().await, expected,);
+            // chunks may be split where they could start a stop word
+            assert_eq!(
+                stopping_stream.collect::<Vec<_>>().await.concat(),
+                expected.concat(),
+            );
         }
     }
+
+    #[tokio::test]
+    async fn stopping_stream_inside_chunk() {
+        let content_stream = futures::stream::iter(
+            ["Hello", " there<|USER|>", "How are you?"].map(|s| s.to_string()),
+        );
+
+        let mut stopping_stream =
+            StoppingStream::wrap_with_stop_words(content_stream, vec!["<|USER|>".to_string()]);
+
+        let mut out = vec![];
+        while let Some(chunk) = stopping_stream.next().await {
+            out.push(chunk);
+        }
+
+        assert_eq!(out.concat(), "Hello there");
+        assert!(stopping_stream.stopped());
+    }
+
+    #[tokio::test]
+    async fn stopping_stream_across_chunks() {
+        let content_stream =
+            futures::stream::iter(["one <", "|US", "ER|> two"].map(|s| s.to_string()));
+
+        let stopping_stream =
+            StoppingStream::wrap_with_stop_words(content_stream, vec!["<|USER|>".to_string()]);
+
+        assert_eq!(stopping_stream.collect::<Vec<_>>().await.concat(), "one ");
+    }
+
+    #[tokio::test]
+    async fn stopping_stream_flushes_partial_stop_word() {
+        let content_stream = futures::stream::iter(["apple", " <|"].map(|s| s.to_string()));
+
+        let mut stopping_stream =
+            StoppingStream::wrap_with_stop_words(content_stream, vec!["<|USER|>".to_string()]);
+
+        let mut out = vec![];
+        while let Some(chunk) = stopping_stream.next().await {
+            out.push(chunk);
+        }
+
+        assert_eq!(out.concat(), "apple <|");
+        assert!(!stopping_stream.stopped());
+    }
 }
@@ -0,0 +1,436 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Tool calling for large language models.
+//!
+//! Tools are described to the model in the system message of the prompt. The model calls tools by
+//! answering with a single JSON object of the form
+//! `{"tool_calls": [{"name": "<tool name>", "arguments": {...}}]}`, which is parsed into
+//! [`AssistantToolCall`]s.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Stream;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::llm::{
+    AssistantFunctionStub, AssistantToolCall, CompletionChunk, FinishReason, TokenLogprob,
+    ToolChoice, ToolStub,
+};
+
+/// Returns the instructions describing `tools` to the model, or an empty string if there are no
+/// tools.
+pub fn tools_prompt(tools: &[ToolStub], choice: Option<&ToolChoice>) -> String {
+    if tools.is_empty() {
+        return String::new();
+    }
+
+    let mut prompt = "You have access to the following tools:\n".to_string();
+    for tool in tools {
+        let ToolStub::Function { function } = tool;
+        let mut description = json!({
+            "name": function.name,
+            "parameters": function.parameters,
+        });
+        if let Some(text) = &function.description {
+            description["description"] = Value::String(text.clone());
+        }
+        prompt.push_str(&description.to_string());
+        prompt.push('\n');
+    }
+
+    let format = r#"{"tool_calls": [{"name": "<tool name>", "arguments": {<arguments>}}]}"#;
+    match choice {
+        Some(ToolChoice::Required) => prompt.push_str(&format!(
+            "You must call one or more tools, answering only with a JSON object of the form {format}."
+        )),
+        Some(ToolChoice::Function(name)) => prompt.push_str(&format!(
+            "You must call the \"{name}\" tool, answering only with a JSON object of the form {format}."
+        )),
+        _ => prompt.push_str(&format!(
+            "To call one or more tools, answer only with a JSON object of the form {format}. Otherwise, answer normally."
+        )),
+    }
+
+    prompt
+}
+
+/// Renders tool calls made by the model in the same format it is asked to call tools with.
+pub fn render_tool_calls(calls: &[AssistantToolCall]) -> String {
+    let calls: Vec<Value> = calls
+        .iter()
+        .map(|call| {
+            let arguments = serde_json::from_str(&call.function.arguments)
+                .unwrap_or_else(|_| Value::String(call.function.arguments.clone()));
+            json!({
+                "name": call.function.name,
+                "arguments": arguments,
+            })
+        })
+        .collect();
+
+    json!({ "tool_calls": calls }).to_string()
+}
+
+/// Parses a completion into the tool calls it contains.
+///
+/// Returns [`None`] if the completion is not a tool call object, or if it calls any tool not
+/// found in `tools`.
+pub fn parse_tool_calls(text: &str, tools: &[ToolStub]) -> Option<Vec<AssistantToolCall>> {
+    #[derive(Deserialize)]
+    struct Calls {
+        tool_calls: Vec<Call>,
+    }
+
+    #[derive(Deserialize)]
+    struct Call {
+        name: String,
+        #[serde(default)]
+        arguments: Value,
+    }
+
+    let calls: Calls = serde_json::from_str(text.trim()).ok()?;
+    if calls.tool_calls.is_empty()
+        || !calls
+            .tool_calls
+            .iter()
+            .all(|call| tools.iter().any(|tool| tool.name() == call.name))
+    {
+        return None;
+    }
+
+    let calls = calls
+        .tool_calls
+        .into_iter()
+        .map(|call| AssistantToolCall {
+            id: format!("call_{}", Uuid::new_v4().simple()),
+            type_: "function".to_string(),
+            function: AssistantFunctionStub {
+                name: call.name,
+                arguments: match call.arguments {
+                    Value::String(arguments) => arguments,
+                    Value::Null => "{}".to_string(),
+                    arguments => arguments.to_string(),
+                },
+            },
+        })
+        .collect();
+
+    Some(calls)
+}
+
+/// Where a [`ToolCallStream`] is at in deciding whether the completion is a tool call.
+enum ToolCallState {
+    /// Nothing but whitespace was generated so far, which is held in the buffer.
+    Undecided(String),
+
+    /// The completion looks like a tool call, and is held in the buffer until it finishes.
+    Call(String),
+
+    /// The completion is a plain message, and chunks are forwarded as they are.
+    Content,
+}
+
+/// A [`Stream`] adapter that turns completions calling tools into a single [`CompletionChunk`]
+/// carrying the parsed [`AssistantToolCall`]s.
+///
+/// Completions starting with a JSON object are held back until generation finishes. If they
+/// turn out not to be valid tool calls, they are emitted as plain content.
+///
+/// The inner stream must only carry the chunks of a single choice.
+#[pin_project::pin_project]
+pub struct ToolCallStream<S> {
+    /// The inner stream.
+    #[pin]
+    inner: S,
+
+    /// The tools that the model can call.
+    tools: Vec<ToolStub>,
+
+    /// Whether the completion is a tool call.
+    state: ToolCallState,
+
+    /// The log probabilities of the tokens held in the buffer of [`ToolCallState`], if they were
+    /// requested.
+    logprobs: Option<Vec<TokenLogprob>>,
+
+    /// The index of the choice generated by the inner stream, as seen in its last chunk.
+    index: u32,
+}
+
+impl<S> ToolCallStream<S>
+where
+    S: Stream<Item = CompletionChunk>,
+{
+    /// Wraps a [`Stream`] of completions that can call any of `tools`.
+    pub fn new(inner: S, tools: Vec<ToolStub>) -> Self {
+        Self {
+            inner,
+            tools,
+            state: ToolCallState::Undecided(String::new()),
+            logprobs: None,
+            index: 0,
+        }
+    }
+}
+
+impl<S> Stream for ToolCallStream<S>
+where
+    S: Stream<Item = CompletionChunk>,
+{
+    type Item = CompletionChunk;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+
+        loop {
+            let mut chunk = match this.inner.as_mut().poll_next(cx) {
+                Poll::Ready(Some(chunk)) => {
+                    *this.index = chunk.index;
+                    chunk
+                }
+                Poll::Ready(None) => {
+                    // The stream ended without a final chunk; emit whatever was held back.
+                    return match std::mem::replace(this.state, ToolCallState::Content) {
+                        ToolCallState::Undecided(buf) | ToolCallState::Call(buf)
+                            if !buf.is_empty() =>
+                        {
+                            Poll::Ready(Some(CompletionChunk {
+                                index: *this.index,
+                                content: buf,
+                                finish_reason: None,
+                                usage: None,
+                                tool_calls: None,
+                                context_overflow: None,
+                                logprobs: this.logprobs.take(),
+                            }))
+                        }
+                        _ => Poll::Ready(None),
+                    };
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+
+            let buf = match this.state {
+                ToolCallState::Content => return Poll::Ready(Some(chunk)),
+                ToolCallState::Undecided(buf) | ToolCallState::Call(buf) => buf,
+            };
+            buf.push_str(&chunk.content);
+            if let Some(logprobs) = chunk.logprobs.take() {
+                this.logprobs.get_or_insert_with(Vec::new).extend(logprobs);
+            }
+
+            if chunk.finish_reason.is_some() {
+                let text = std::mem::take(buf);
+                *this.state = ToolCallState::Content;
+
+                match parse_tool_calls(&text, this.tools) {
+                    Some(calls) => {
+                        chunk.content = String::new();
+                        chunk.finish_reason = Some(FinishReason::ToolCalls);
+                        chunk.tool_calls = Some(calls);
+                    }
+                    None => {
+                        chunk.content = text;
+                        chunk.logprobs = this.logprobs.take();
+                    }
+                }
+
+                return Poll::Ready(Some(chunk));
+            }
+
+            if let ToolCallState::Undecided(buf) = this.state {
+                let start = buf.trim_start();
+                if start.starts_with('{') {
+                    *this.state = ToolCallState::Call(std::mem::take(buf));
+                } else if !start.is_empty() {
+                    chunk.content = std::mem::take(buf);
+                    chunk.logprobs = this.logprobs.take();
+                    *this.state = ToolCallState::Content;
+
+                    return Poll::Ready(Some(chunk));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use crate::llm::FunctionStub;
+
+    use super::*;
+
+    fn weather_tool() -> ToolStub {
+        ToolStub::Function {
+            function: FunctionStub {
+                description: Some("Gets the current weather in a city.".to_string()),
+                name: "get_weather".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {"city": {"type": "string"}},
+                    "required": ["city"],
+                }),
+            },
+        }
+    }
+
+    fn chunks(contents: &[&str]) -> Vec<CompletionChunk> {
+        let mut chunks: Vec<CompletionChunk> = contents
+            .iter()
+            .map(|content| CompletionChunk {
+                index: 0,
+                content: content.to_string(),
+                finish_reason: None,
+                usage: None,
+                tool_calls: None,
+                context_overflow: None,
+                logprobs: None,
+            })
+            .collect();
+        chunks.push(CompletionChunk {
+            index: 0,
+            content: String::new(),
+            finish_reason: Some(FinishReason::Stop),
+            usage: None,
+            tool_calls: None,
+            context_overflow: None,
+            logprobs: None,
+        });
+        chunks
+    }
+
+    #[test]
+    fn prompt_lists_tools() {
+        let prompt = tools_prompt(&[weather_tool()], Some(&ToolChoice::Auto));
+
+        assert!(prompt.starts_with("You have access to the following tools:"));
+        assert!(prompt.contains(r#""name":"get_weather""#));
+        assert!(prompt.contains("Gets the current weather in a city."));
+        assert!(prompt.contains("Otherwise, answer normally."));
+
+        let prompt = tools_prompt(&[weather_tool()], Some(&ToolChoice::Required));
+        assert!(prompt.contains("You must call one or more tools"));
+
+        assert!(tools_prompt(&[], Some(&ToolChoice::Required)).is_empty());
+    }
+
+    #[test]
+    fn parse_calls() {
+        let text = r#" {"tool_calls": [{"name": "get_weather", "arguments": {"city": "Lisbon"}}]}"#;
+        let calls = parse_tool_calls(text, &[weather_tool()]).expect("should be a tool call");
+
+        assert_eq!(calls.len(), 1);
+        assert!(calls[0].id.starts_with("call_"));
+        assert_eq!(calls[0].type_, "function");
+        assert_eq!(calls[0].function.name, "get_weather");
+        assert_eq!(calls[0].function.arguments, r#"{"city":"Lisbon"}"#);
+    }
+
+    #[test]
+    fn parse_rejects_unknown_tools() {
+        let text = r#"{"tool_calls": [{"name": "get_time", "arguments": {}}]}"#;
+        assert!(parse_tool_calls(text, &[weather_tool()]).is_none());
+        assert!(parse_tool_calls(r#"{"tool_calls": []}"#, &[weather_tool()]).is_none());
+        assert!(parse_tool_calls("The answer is 42.", &[weather_tool()]).is_none());
+    }
+
+    #[test]
+    fn render_round_trips() {
+        let text = r#"{"tool_calls": [{"name": "get_weather", "arguments": {"city": "Lisbon"}}]}"#;
+        let calls = parse_tool_calls(text, &[weather_tool()]).unwrap();
+        let rendered = render_tool_calls(&calls);
+
+        assert_eq!(
+            serde_json::from_str::<Value>(&rendered).unwrap(),
+            serde_json::from_str::<Value>(text).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_parses_calls() {
+        let inner = futures::stream::iter(chunks(&[
+            " {\"tool_calls\": [",
+            "{\"name\": \"get_weather\", ",
+            "\"arguments\": {\"city\": \"Lisbon\"}}]}",
+        ]));
+        let out: Vec<_> = ToolCallStream::new(inner, vec![weather_tool()])
+            .collect()
+            .await;
+
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].content, "");
+        assert_eq!(out[0].finish_reason, Some(FinishReason::ToolCalls));
+        let calls = out[0].tool_calls.as_ref().unwrap();
+        assert_eq!(calls[0].function.name, "get_weather");
+    }
+
+    #[tokio::test]
+    async fn stream_forwards_messages() {
+        let inner = futures::stream::iter(chunks(&[" ", "The", " answer"]));
+        let out: Vec<_> = ToolCallStream::new(inner, vec![weather_tool()])
+            .collect()
+            .await;
+
+        let contents: Vec<_> = out.iter().map(|chunk| chunk.content.as_str()).collect();
+        assert_eq!(contents, [" The", " answer", ""]);
+        assert_eq!(out[2].finish_reason, Some(FinishReason::Stop));
+        assert!(out.iter().all(|chunk| chunk.tool_calls.is_none()));
+    }
+
+    #[tokio::test]
+    async fn stream_falls_back_to_content() {
+        let inner = futures::stream::iter(chunks(&["{\"answer\":", " 42}"]));
+        let out: Vec<_> = ToolCallStream::new(inner, vec![weather_tool()])
+            .collect()
+            .await;
+
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].content, "{\"answer\": 42}");
+        assert_eq!(out[0].finish_reason, Some(FinishReason::Stop));
+    }
+
+    #[tokio::test]
+    async fn stream_holds_back_logprobs() {
+        let logprob = |token: &str| TokenLogprob {
+            token: token.to_string(),
+            logprob: -0.5,
+            top_logprobs: vec![],
+        };
+        let mut inner = chunks(&[" ", "The", " answer"]);
+        for chunk in &mut inner[..3] {
+            chunk.logprobs = Some(vec![logprob(&chunk.content)]);
+        }
+
+        let out: Vec<_> = ToolCallStream::new(futures::stream::iter(inner), vec![weather_tool()])
+            .collect()
+            .await;
+
+        let tokens: Vec<Vec<_>> = out
+            .iter()
+            .map(|chunk| {
+                chunk
+                    .logprobs
+                    .iter()
+                    .flatten()
+                    .map(|logprob| logprob.token.as_str())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(tokens, [vec![" ", "The"], vec![" answer"], vec![]]);
+    }
+}
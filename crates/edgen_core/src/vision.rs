@@ -0,0 +1,236 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Images given to multimodal chat models, which stand in the prompt as placeholders until a
+//! runtime replaces them with their embeddings.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use thiserror::Error;
+
+/// The start of the placeholder of an image in a prompt, followed by the 16 hexadecimal digits of
+/// its id and [`PLACEHOLDER_END`].
+const PLACEHOLDER_START: &str = "<image:";
+
+/// The end of the placeholder of an image in a prompt.
+const PLACEHOLDER_END: &str = ">";
+
+/// The encoded bytes of an image (PNG, JPEG, etc.) in a chat message.
+#[derive(Clone)]
+pub struct ImageInput {
+    bytes: Arc<[u8]>,
+
+    /// A hash of the bytes, which identifies the image in prompts.
+    id: u64,
+}
+
+impl ImageInput {
+    /// Creates an image from its encoded `bytes`.
+    pub fn new(bytes: Vec<u8>) -> Self {
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+
+        Self {
+            bytes: bytes.into(),
+            id: hasher.finish(),
+        }
+    }
+
+    /// Returns the encoded bytes of this image.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Returns an identifier of this image, which is the same for images with the same bytes.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the text that stands for this image in a prompt.
+    pub fn placeholder(&self) -> String {
+        format!("{PLACEHOLDER_START}{:016x}{PLACEHOLDER_END}", self.id)
+    }
+}
+
+impl Debug for ImageInput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImageInput")
+            .field("id", &format_args!("{:016x}", self.id))
+            .field("len", &self.bytes.len())
+            .finish()
+    }
+}
+
+/// A part of a prompt split by [`split_images`].
+#[derive(Debug, Clone)]
+pub enum PromptPart<'a> {
+    /// Text, which is tokenized.
+    Text(&'a str),
+
+    /// An image, which is embedded.
+    Image(&'a ImageInput),
+}
+
+/// Splits `prompt` at the placeholders of `images`, in order.
+///
+/// Text that looks like a placeholder but doesn't match any of `images` is kept as text, so that
+/// users cannot refer to images that were not given.
+pub fn split_images<'a>(prompt: &'a str, images: &'a [ImageInput]) -> Vec<PromptPart<'a>> {
+    let mut parts = vec![];
+    let mut text_start = 0;
+    let mut search_start = 0;
+
+    while let Some(offset) = prompt[search_start..].find(PLACEHOLDER_START) {
+        let start = search_start + offset;
+        let id_start = start + PLACEHOLDER_START.len();
+        let image = prompt
+            .get(id_start..id_start + 16)
+            .filter(|_| prompt[id_start + 16..].starts_with(PLACEHOLDER_END))
+            .and_then(|id| u64::from_str_radix(id, 16).ok())
+            .and_then(|id| images.iter().find(|image| image.id == id));
+
+        match image {
+            Some(image) => {
+                if text_start < start {
+                    parts.push(PromptPart::Text(&prompt[text_start..start]));
+                }
+                parts.push(PromptPart::Image(image));
+                text_start = id_start + 16 + PLACEHOLDER_END.len();
+                search_start = text_start;
+            }
+            None => search_start = id_start,
+        }
+    }
+
+    if text_start < prompt.len() {
+        parts.push(PromptPart::Text(&prompt[text_start..]));
+    }
+
+    parts
+}
+
+/// Where the bytes of an image given by URL are found.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImageSource<'a> {
+    /// A `data:` URL, with the base64 encoded bytes of the image.
+    Data(&'a str),
+
+    /// An `http://` or `https://` URL, which is only fetched if its host is allowed.
+    Remote(&'a str),
+}
+
+/// An error in the URL of an image.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ImageSourceError {
+    #[error("only base64 data URLs are supported")]
+    NotBase64,
+    #[error("unsupported URL scheme {0}")]
+    Scheme(String),
+    #[error("images cannot be read from local files")]
+    LocalFile,
+}
+
+/// Returns where the bytes of the image at `url` are found.
+///
+/// Images are not read from local files, given by `file://` URLs or by their paths, as any client
+/// could then read any file of the server.
+pub fn image_source(url: &str) -> Result<ImageSource<'_>, ImageSourceError> {
+    if let Some(data) = url.strip_prefix("data:") {
+        return match data.split_once(',') {
+            Some((header, payload)) if header.ends_with(";base64") => {
+                Ok(ImageSource::Data(payload))
+            }
+            _ => Err(ImageSourceError::NotBase64),
+        };
+    }
+    match url.split_once("://") {
+        Some((scheme, _)) if scheme.eq_ignore_ascii_case("http") => Ok(ImageSource::Remote(url)),
+        Some((scheme, _)) if scheme.eq_ignore_ascii_case("https") => Ok(ImageSource::Remote(url)),
+        Some((scheme, _)) if scheme.eq_ignore_ascii_case("file") => {
+            Err(ImageSourceError::LocalFile)
+        }
+        Some((scheme, _)) => Err(ImageSourceError::Scheme(scheme.to_string())),
+        None => Err(ImageSourceError::LocalFile),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_split_the_prompt() {
+        let images = [ImageInput::new(vec![1, 2, 3]), ImageInput::new(vec![4, 5])];
+        let prompt = format!(
+            "USER: {} What is this? <image:0000000000000000> {}\nASSISTANT:",
+            images[0].placeholder(),
+            images[1].placeholder()
+        );
+
+        let parts = split_images(&prompt, &images);
+        assert_eq!(parts.len(), 5);
+        assert!(matches!(parts[0], PromptPart::Text("USER: ")));
+        assert!(matches!(parts[1], PromptPart::Image(image) if image.id() == images[0].id()));
+        assert!(matches!(
+            parts[2],
+            PromptPart::Text(" What is this? <image:0000000000000000> ")
+        ));
+        assert!(matches!(parts[3], PromptPart::Image(image) if image.id() == images[1].id()));
+        assert!(matches!(parts[4], PromptPart::Text("\nASSISTANT:")));
+
+        let parts = split_images("no images <image:", &images);
+        assert!(matches!(parts[..], [PromptPart::Text("no images <image:")]));
+    }
+
+    #[test]
+    fn same_bytes_same_image() {
+        assert_eq!(
+            ImageInput::new(vec![1, 2, 3]).placeholder(),
+            ImageInput::new(vec![1, 2, 3]).placeholder()
+        );
+        assert_ne!(
+            ImageInput::new(vec![1, 2, 3]).id(),
+            ImageInput::new(vec![3, 2, 1]).id()
+        );
+    }
+
+    #[test]
+    fn image_sources() {
+        assert_eq!(
+            image_source("data:image/png;base64,iVBORw0KGgo="),
+            Ok(ImageSource::Data("iVBORw0KGgo="))
+        );
+        assert_eq!(
+            image_source("data:text/plain,hello"),
+            Err(ImageSourceError::NotBase64)
+        );
+        assert_eq!(
+            image_source("file:///etc/passwd"),
+            Err(ImageSourceError::LocalFile)
+        );
+        assert_eq!(
+            image_source("images/cat.png"),
+            Err(ImageSourceError::LocalFile)
+        );
+        assert_eq!(
+            image_source("HTTPS://example.com/cat.png"),
+            Ok(ImageSource::Remote("HTTPS://example.com/cat.png"))
+        );
+        assert_eq!(
+            image_source("ftp://example.com/cat.png"),
+            Err(ImageSourceError::Scheme("ftp".to_string()))
+        );
+    }
+}
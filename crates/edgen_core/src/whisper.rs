@@ -32,6 +32,8 @@ pub enum WhisperEndpointError {
     SessionNotFound,
     #[error("failed to parse audio file data: {0}")]
     Audio(#[from] AudioError),
+    #[error("the transcription was cancelled")]
+    Cancelled,
 }
 
 pub struct TranscriptionArgs {
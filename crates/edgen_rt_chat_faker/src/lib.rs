@@ -12,52 +12,288 @@
 
 //! A fake model RT for chat completions that answers with predefined strings
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Instant;
 
 use dashmap::DashMap;
-use futures::Stream;
+use futures::{Stream, StreamExt};
 use tracing::info;
+use uuid::Uuid;
 
-use edgen_core::llm::{CompletionArgs, LLMEndpoint, LLMEndpointError};
+use edgen_core::chat_template::ChatTemplate;
+use edgen_core::context_overflow::{fit_prompt, FittedPrompt};
+use edgen_core::fim::FimTokens;
+use edgen_core::llm::{
+    inactive_llm_session_ttl, ChatSession, ChatSessionInfo, Completion, CompletionArgs,
+    CompletionChunk, Embeddings, EmbeddingsArgs, EmbeddingsInput, FinishReason, LLMEndpoint,
+    LLMEndpointError, ResponseFormat, TextCompletionArgs, TokenLogprob, TokenUsage, ToolChoice,
+    TopLogprob,
+};
+use edgen_core::settings::SETTINGS;
+use edgen_core::stopping_stream::StoppingStream;
+use edgen_core::tools::{parse_tool_calls, ToolCallStream};
 
 pub const CAPITAL: &str = "The capital of Canada is Ottawa.";
 pub const CAPITAL_OF_PORTUGAL: &str = "The capital of Portugal is Lisbon.";
 pub const DEFAULT_ANSWER: &str = "The answer is 42.";
 pub const LONG_ANSWER: &str = "Call me Ishmael. Some years ago—never mind how long precisely—having little or no money in my purse, and nothing particular to interest me on shore, I thought I would sail about a little and see the watery part of the world. It is a way I have of driving off the spleen and regulating circulation. Whenever I find myself growing grim about the mouth; whenever it is a damp, drizzly November in my soul; whenever I find myself involuntarily pausing before coffin warehouses, and bringing up the rear of every funeral I meet; and especially whenever my hypos get such an upper hand of me, that it requires a strong moral principle to prevent me from deliberately stepping into the street, and methodically knocking people’s hats off—then, I account it high time to get to sea as soon as I can. There is nothing surprising in this. If they but knew it, almost all men in their degree, some time or other, cherish very nearly the same feelings towards the ocean with me.";
 
-struct ChatFakerModel {}
+/// The number of words in the context of a fake model, if neither the request nor the settings
+/// specify it.
+const CONTEXT_SIZE: u32 = 4096;
+
+struct ChatFakerModel {
+    path: PathBuf,
+}
 
 impl ChatFakerModel {
-    async fn new(_path: impl AsRef<Path>) -> Self {
-        Self {}
+    async fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns the maximum number of words that a completion can have, every word being treated
+    /// as a token.
+    async fn max_tokens(&self, args: &CompletionArgs) -> usize {
+        SETTINGS
+            .read()
+            .await
+            .read()
+            .await
+            .max_tokens(&self.path, args.max_tokens) as usize
+    }
+
+    /// Returns the chat template configured for this model, since there is no model file to read
+    /// one from.
+    async fn template(&self) -> ChatTemplate {
+        let settings = SETTINGS
+            .read()
+            .await
+            .read()
+            .await
+            .model_settings(&self.path);
+        ChatTemplate::for_model(settings.chat_template.as_deref(), None)
+    }
+
+    /// Returns the number of words in the context of this model.
+    async fn context_size(&self, args: &CompletionArgs) -> u32 {
+        let configured = SETTINGS
+            .read()
+            .await
+            .read()
+            .await
+            .model_settings(&self.path)
+            .context_size;
+        args.context_hint.or(configured).unwrap_or(CONTEXT_SIZE)
+    }
+
+    /// Renders the prompt of `args` with `template`, fitting it into the context of this model,
+    /// every word being treated as a token.
+    async fn fit_prompt(
+        &self,
+        args: &mut CompletionArgs,
+        template: &ChatTemplate,
+    ) -> Result<FittedPrompt, LLMEndpointError> {
+        let context_size = self.context_size(args).await;
+        let policy = SETTINGS
+            .read()
+            .await
+            .read()
+            .await
+            .context_overflow(&self.path, args.context_overflow);
+
+        fit_prompt(args, template, policy, context_size, |text| {
+            Ok(count_tokens(text))
+        })
+    }
+
+    /// Returns the raw prompt of `args` and its number of words, which must fit into the context
+    /// of this model.
+    async fn text_prompt(
+        &self,
+        args: &TextCompletionArgs,
+    ) -> Result<(String, u32), LLMEndpointError> {
+        let prompt = args.prompt(Some(&fim_tokens()))?;
+        let prompt_tokens = count_tokens(&prompt);
+        let context_size = self.context_size(&args.sampling).await;
+        if prompt_tokens >= context_size {
+            return Err(LLMEndpointError::ContextOverflow {
+                prompt_tokens,
+                context_size,
+            });
+        }
+
+        Ok((prompt, prompt_tokens))
     }
 
-    async fn chat_completions(&self, args: &CompletionArgs) -> Result<String, LLMEndpointError> {
+    async fn chat_completions(
+        &self,
+        args: &mut CompletionArgs,
+    ) -> Result<Vec<Completion>, LLMEndpointError> {
         info!("faking chat completions");
-        let prompt = format!("{}<|ASSISTANT|>", args.messages);
-        Ok(completions_for(&prompt))
+        let template = self.template().await;
+        let fitted = self.fit_prompt(args, &template).await?;
+        let msg = completions_for(args, &fitted.prompt.text);
+
+        let max_tokens = self.max_tokens(args).await;
+        let (content, finish_reason, completion_tokens) =
+            complete(msg, max_tokens, &args.stop_words(&template));
+
+        let tool_calls = parse_tool_calls(&content, args.active_tools());
+        let (content, finish_reason, logprobs) = match tool_calls {
+            Some(_) => (String::new(), FinishReason::ToolCalls, None),
+            None => {
+                let logprobs = args
+                    .requested_logprobs()
+                    .map(|top| logprobs_for(&content, top));
+                (content, finish_reason, logprobs)
+            }
+        };
+
+        let completion = Completion {
+            content,
+            finish_reason,
+            usage: TokenUsage {
+                prompt_tokens: fitted.prompt_tokens,
+                completion_tokens,
+            },
+            tool_calls,
+            context_overflow: Some(fitted.overflow),
+            logprobs,
+        };
+
+        Ok(vec![completion; args.choices()])
     }
 
     async fn stream_chat_completions(
         &self,
-        args: &CompletionArgs,
-    ) -> Result<Box<dyn Stream<Item = String> + Unpin + Send>, LLMEndpointError> {
+        args: &mut CompletionArgs,
+    ) -> Result<Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>, LLMEndpointError> {
         info!("faking stream chat completions");
-        let prompt = format!("{}<|ASSISTANT|>", args.messages);
-        let msg = completions_for(&prompt);
-        let toks = streamify(&msg);
-        Ok(Box::new(futures::stream::iter(toks.into_iter())))
+        let template = self.template().await;
+        let fitted = self.fit_prompt(args, &template).await?;
+        let msg = completions_for(args, &fitted.prompt.text);
+
+        let max_tokens = self.max_tokens(args).await;
+        let stop_words = args.stop_words(&template);
+        let tools = args.active_tools();
+
+        Ok(stream_choices(args.choices(), || {
+            let chunks = stream(
+                &msg,
+                max_tokens,
+                stop_words.clone(),
+                fitted.prompt_tokens,
+                args.requested_logprobs(),
+            )
+            .map(move |mut chunk| {
+                if chunk.finish_reason.is_some() {
+                    chunk.context_overflow = Some(fitted.overflow);
+                }
+                chunk
+            });
+
+            if tools.is_empty() {
+                Box::new(chunks)
+            } else {
+                Box::new(ToolCallStream::new(chunks, tools.to_vec()))
+            }
+        }))
+    }
+
+    async fn completions(
+        &self,
+        args: &TextCompletionArgs,
+    ) -> Result<Vec<Completion>, LLMEndpointError> {
+        info!("faking text completions");
+        let (prompt, prompt_tokens) = self.text_prompt(args).await?;
+        let msg = answer_for(&prompt);
+
+        let max_tokens = self.max_tokens(&args.sampling).await;
+        let (content, finish_reason, completion_tokens) =
+            complete(msg, max_tokens, &args.stop_words());
+        let logprobs = args
+            .sampling
+            .requested_logprobs()
+            .map(|top| logprobs_for(&content, top));
+
+        let completion = Completion {
+            content,
+            finish_reason,
+            usage: TokenUsage {
+                prompt_tokens,
+                completion_tokens,
+            },
+            tool_calls: None,
+            context_overflow: None,
+            logprobs,
+        };
+
+        Ok(vec![completion; args.sampling.choices()])
+    }
+
+    async fn stream_completions(
+        &self,
+        args: &TextCompletionArgs,
+    ) -> Result<Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>, LLMEndpointError> {
+        info!("faking stream text completions");
+        let (prompt, prompt_tokens) = self.text_prompt(args).await?;
+        let msg = answer_for(&prompt);
+
+        let max_tokens = self.max_tokens(&args.sampling).await;
+        let stop_words = args.stop_words();
+
+        Ok(stream_choices(args.sampling.choices(), || {
+            Box::new(stream(
+                &msg,
+                max_tokens,
+                stop_words.clone(),
+                prompt_tokens,
+                args.sampling.requested_logprobs(),
+            ))
+        }))
+    }
+
+    async fn render_prompt(&self, args: &CompletionArgs) -> Result<String, LLMEndpointError> {
+        let template = self.template().await;
+        Ok(args.prompt(&template)?.text)
     }
 
     //TODO: implement
-    async fn embeddings(&self, _inputs: &[String]) -> Result<Vec<Vec<f32>>, LLMEndpointError> {
+    async fn embeddings(&self, args: &EmbeddingsArgs) -> Result<Embeddings, LLMEndpointError> {
         info!("faking emeddings");
-        Ok(vec![])
+        Ok(Embeddings {
+            embeddings: vec![],
+            prompt_tokens: args
+                .inputs
+                .iter()
+                .map(|input| match input {
+                    EmbeddingsInput::Text(text) => count_tokens(text),
+                    EmbeddingsInput::Tokens(tokens) => tokens.len() as u32,
+                })
+                .sum(),
+            overflow: None,
+        })
+    }
+}
+
+fn completions_for(args: &CompletionArgs, prompt: &str) -> String {
+    if let Some(call) = tool_call_for(args) {
+        return call;
+    }
+
+    let answer = answer_for(prompt);
+    match args.response_format {
+        Some(ResponseFormat::JsonObject) | Some(ResponseFormat::JsonSchema { .. }) => {
+            format!(r#"{{ "answer": {} }}"#, serde_json::Value::String(answer))
+        }
+        Some(ResponseFormat::Text) | None => answer,
     }
 }
 
-fn completions_for(prompt: &str) -> String {
+fn answer_for(prompt: &str) -> String {
     let prompt = prompt.to_lowercase();
     if prompt.contains("capital") {
         if prompt.contains("portugal") {
@@ -72,14 +308,181 @@ fn completions_for(prompt: &str) -> String {
     }
 }
 
+/// Returns a call to one of the tools in `args` if the model has to call one, or if the messages
+/// ask about the weather.
+///
+/// The tool is called without arguments, with a space after every JSON token so that it can be
+/// streamed word by word.
+fn tool_call_for(args: &CompletionArgs) -> Option<String> {
+    let tools = args.active_tools();
+    let name = match &args.tool_choice {
+        Some(ToolChoice::Function(name)) => name.as_str(),
+        Some(ToolChoice::Required) => tools.first()?.name(),
+        _ if args.messages.to_string().to_lowercase().contains("weather") => tools.first()?.name(),
+        _ => return None,
+    };
+
+    Some(format!(
+        r#"{{ "tool_calls": [ {{ "name": "{name}", "arguments": {{ }} }} ] }}"#
+    ))
+}
+
+/// Counts the tokens in `text`, every word being treated as a token.
+fn count_tokens(text: &str) -> u32 {
+    text.split_whitespace().count() as u32
+}
+
 fn streamify(msg: &str) -> Vec<String> {
     msg.split_whitespace().map(|s| s.to_string()).collect()
 }
 
+/// Returns the fill-in-the-middle tokens of the fake models, in the style of Code Llama.
+fn fim_tokens() -> FimTokens {
+    FimTokens {
+        prefix: "<PRE> ".to_string(),
+        suffix: " <SUF>".to_string(),
+        middle: " <MID>".to_string(),
+    }
+}
+
+/// Truncates `msg` to `max_tokens` words and then at the first of `stop_words`, returning the
+/// completion, why it ended and its number of words.
+fn complete(msg: String, max_tokens: usize, stop_words: &[String]) -> (String, FinishReason, u32) {
+    let words: Vec<&str> = msg.split_whitespace().collect();
+    let completion_tokens = words.len().min(max_tokens) as u32;
+    let (msg, finish_reason) = if words.len() > max_tokens {
+        (words[..max_tokens].join(" "), FinishReason::Length)
+    } else {
+        (msg, FinishReason::Stop)
+    };
+
+    let stop = stop_words
+        .iter()
+        .filter_map(|word| msg.find(word.as_str()))
+        .min();
+    match stop {
+        Some(idx) => (
+            msg[..idx].to_string(),
+            FinishReason::Stop,
+            completion_tokens,
+        ),
+        None => (msg, finish_reason, completion_tokens),
+    }
+}
+
+/// Returns the log probabilities of the words of `content`, each followed by its whitespace,
+/// along with the `top` most likely words at each position.
+///
+/// The fake models are certain of every word they generate, so each word has a log probability
+/// of `0.0` and is the only likely word at its position.
+fn logprobs_for(content: &str, top: usize) -> Vec<TokenLogprob> {
+    content
+        .split_inclusive(char::is_whitespace)
+        .map(|word| TokenLogprob {
+            token: word.to_string(),
+            logprob: 0.0,
+            top_logprobs: std::iter::once(TopLogprob {
+                token: word.to_string(),
+                logprob: 0.0,
+            })
+            .take(top)
+            .collect(),
+        })
+        .collect()
+}
+
+/// Streams the words of `msg`, up to `max_tokens` words and stopping at the first of
+/// `stop_words`, followed by a closing chunk with the finish reason and usage.
+///
+/// If `logprobs` is present, every chunk carries the log probabilities of its words, along with
+/// that many most likely words.
+fn stream(
+    msg: &str,
+    max_tokens: usize,
+    stop_words: Vec<String>,
+    prompt_tokens: u32,
+    logprobs: Option<usize>,
+) -> impl Stream<Item = CompletionChunk> + Unpin + Send {
+    let mut toks = streamify(msg);
+    let truncated = toks.len() > max_tokens;
+    toks.truncate(max_tokens);
+    let usage = TokenUsage {
+        prompt_tokens,
+        completion_tokens: toks.len() as u32,
+    };
+
+    // the stream is stopped if a stop word is found anywhere in the concatenated tokens
+    let text = toks.concat();
+    let finish_reason = if truncated && !stop_words.iter().any(|word| text.contains(word)) {
+        FinishReason::Length
+    } else {
+        FinishReason::Stop
+    };
+
+    StoppingStream::wrap_with_stop_words(futures::stream::iter(toks), stop_words)
+        .map(move |content| CompletionChunk {
+            index: 0,
+            logprobs: logprobs.map(|top| logprobs_for(&content, top)),
+            content,
+            finish_reason: None,
+            usage: None,
+            tool_calls: None,
+            context_overflow: None,
+        })
+        .chain(futures::stream::iter([CompletionChunk {
+            index: 0,
+            content: String::new(),
+            finish_reason: Some(finish_reason),
+            usage: Some(usage),
+            tool_calls: None,
+            context_overflow: None,
+            logprobs: None,
+        }]))
+}
+
+/// Streams `n` choices made by `choice`, one after the other, setting the index of their chunks.
+fn stream_choices(
+    n: usize,
+    choice: impl Fn() -> Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>,
+) -> Box<dyn Stream<Item = CompletionChunk> + Unpin + Send> {
+    let choices: Vec<_> = (0..n as u32)
+        .map(|index| {
+            choice().map(move |mut chunk| {
+                chunk.index = index;
+                chunk
+            })
+        })
+        .collect();
+
+    Box::new(futures::stream::iter(choices).flatten())
+}
+
+/// An explicit chat session of a fake model, which only keeps track of its length.
+struct FakeSession {
+    /// The path of the model that the session belongs to.
+    model_path: PathBuf,
+
+    /// The number of words in the context of the session.
+    tokens: u32,
+
+    /// When the session was last used.
+    last_used: Instant,
+}
+
+impl FakeSession {
+    /// Returns `true` if the session has not expired yet.
+    fn is_alive(&self) -> bool {
+        self.last_used.elapsed() < inactive_llm_session_ttl()
+    }
+}
+
 /// Faking a large language model endpoint, implementing [`LLMEndpoint`].
 pub struct ChatFakerEndpoint {
     /// A map of the models currently loaded into memory, with their path as the key.
     models: Arc<DashMap<String, ChatFakerModel>>,
+
+    /// The explicit chat sessions, by id.
+    sessions: Arc<DashMap<Uuid, FakeSession>>,
 }
 
 impl ChatFakerEndpoint {
@@ -99,6 +502,44 @@ impl ChatFakerEndpoint {
         // PANIC SAFETY: Just inserted the element if it isn't already inside the map, so must be present in the map
         self.models.get(&key).unwrap()
     }
+
+    /// Fails if `session` is to be continued but does not exist.
+    fn check_session(&self, session: Option<ChatSession>) -> Result<(), LLMEndpointError> {
+        if let Some(ChatSession::Continue(id)) = session {
+            if !self
+                .sessions
+                .get(&id)
+                .is_some_and(|session| session.is_alive())
+            {
+                return Err(LLMEndpointError::SessionNotFound);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Marks `session`, if any, as used by the model at `model_path`, creating it if needed, and
+/// records its number of words if known.
+fn use_session(
+    sessions: &DashMap<Uuid, FakeSession>,
+    session: Option<ChatSession>,
+    model_path: &Path,
+    tokens: Option<u32>,
+) {
+    let Some(session) = session else {
+        return;
+    };
+
+    let mut entry = sessions.entry(session.id()).or_insert_with(|| FakeSession {
+        model_path: model_path.to_path_buf(),
+        tokens: 0,
+        last_used: Instant::now(),
+    });
+    entry.last_used = Instant::now();
+    if let Some(tokens) = tokens {
+        entry.tokens = tokens;
+    }
 }
 
 #[async_trait::async_trait]
@@ -106,38 +547,112 @@ impl LLMEndpoint for ChatFakerEndpoint {
     async fn chat_completions(
         &self,
         model_path: impl AsRef<Path> + Send,
-        args: CompletionArgs,
-    ) -> Result<String, LLMEndpointError> {
+        mut args: CompletionArgs,
+    ) -> Result<Vec<Completion>, LLMEndpointError> {
+        self.check_session(args.session)?;
+        let path = model_path.as_ref().to_path_buf();
         let model = self.get(model_path).await;
-        model.chat_completions(&args).await
+        let completions = model.chat_completions(&mut args).await?;
+
+        let tokens = completions
+            .first()
+            .map(|completion| completion.usage.prompt_tokens + completion.usage.completion_tokens);
+        use_session(&self.sessions, args.session, &path, tokens);
+        Ok(completions)
     }
 
     async fn stream_chat_completions(
+        &self,
+        model_path: impl AsRef<Path> + Send,
+        mut args: CompletionArgs,
+    ) -> Result<Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>, LLMEndpointError> {
+        self.check_session(args.session)?;
+        let path = model_path.as_ref().to_path_buf();
+        let model = self.get(model_path).await;
+        let stream = model.stream_chat_completions(&mut args).await?;
+
+        // the session holds the first choice, whose length is known once it finishes
+        let sessions = self.sessions.clone();
+        let session = args.session;
+        use_session(&sessions, session, &path, None);
+        Ok(Box::new(stream.inspect(move |chunk| {
+            if let (0, Some(usage)) = (chunk.index, chunk.usage) {
+                let tokens = usage.prompt_tokens + usage.completion_tokens;
+                use_session(&sessions, session, &path, Some(tokens));
+            }
+        })))
+    }
+
+    async fn completions(
+        &self,
+        model_path: impl AsRef<Path> + Send,
+        args: TextCompletionArgs,
+    ) -> Result<Vec<Completion>, LLMEndpointError> {
+        let model = self.get(model_path).await;
+        model.completions(&args).await
+    }
+
+    async fn stream_completions(
+        &self,
+        model_path: impl AsRef<Path> + Send,
+        args: TextCompletionArgs,
+    ) -> Result<Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>, LLMEndpointError> {
+        let model = self.get(model_path).await;
+        model.stream_completions(&args).await
+    }
+
+    async fn render_prompt(
         &self,
         model_path: impl AsRef<Path> + Send,
         args: CompletionArgs,
-    ) -> Result<Box<dyn Stream<Item = String> + Unpin + Send>, LLMEndpointError> {
+    ) -> Result<String, LLMEndpointError> {
         let model = self.get(model_path).await;
-        model.stream_chat_completions(&args).await
+        model.render_prompt(&args).await
     }
 
     async fn embeddings(
         &self,
         model_path: impl AsRef<Path> + Send,
-        inputs: Vec<String>,
-    ) -> Result<Vec<Vec<f32>>, LLMEndpointError> {
+        args: EmbeddingsArgs,
+    ) -> Result<Embeddings, LLMEndpointError> {
         let model = self.get(model_path).await;
-        model.embeddings(&inputs).await
+        model.embeddings(&args).await
+    }
+
+    async fn chat_sessions(&self) -> Vec<ChatSessionInfo> {
+        self.sessions.retain(|_, session| session.is_alive());
+        self.sessions
+            .iter()
+            .map(|session| ChatSessionInfo {
+                id: *session.key(),
+                model_path: session.model_path.clone(),
+                tokens: Some(session.tokens),
+                expires_in: Some(
+                    inactive_llm_session_ttl().saturating_sub(session.last_used.elapsed()),
+                ),
+            })
+            .collect()
+    }
+
+    async fn delete_chat_session(&self, id: Uuid) -> Result<(), LLMEndpointError> {
+        match self.sessions.remove(&id) {
+            Some(_) => Ok(()),
+            None => Err(LLMEndpointError::SessionNotFound),
+        }
     }
 
     fn reset(&self) {
         self.models.clear();
+        self.sessions.clear();
     }
 }
 
 impl Default for ChatFakerEndpoint {
     fn default() -> Self {
         let models: Arc<DashMap<String, ChatFakerModel>> = Default::default();
-        Self { models }
+        Self {
+            models,
+            sessions: Default::default(),
+        }
     }
 }
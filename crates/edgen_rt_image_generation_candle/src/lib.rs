@@ -10,11 +10,14 @@ use image::{ImageBuffer, ImageError, ImageFormat, Rgb};
 use rand::random;
 use thiserror::Error;
 use tokenizers::Tokenizer;
+use tokio::task::spawn_blocking;
 use tracing::{debug, info, info_span, warn};
 
+use edgen_core::cancellation::CancellationToken;
 use edgen_core::image_generation::{
     ImageGenerationArgs, ImageGenerationEndpoint, ImageGenerationEndpointError, ModelFiles,
 };
+use edgen_core::memory::MEMORY_BUDGET;
 use edgen_core::settings::{DevicePolicy, SETTINGS};
 
 #[derive(Error, Debug)]
@@ -35,6 +38,8 @@ enum CandleError {
     EncodeProcessFailed(#[from] ImageError),
     #[error(transparent)]
     EncodeWriteFailed(#[from] IntoInnerError<BufWriter<Cursor<Vec<u8>>>>),
+    #[error("The generation was cancelled")]
+    Cancelled,
 }
 
 fn sd_text_embeddings(
@@ -141,6 +146,7 @@ fn sd_generate_image(
     model: ModelFiles,
     args: ImageGenerationArgs,
     device: Device,
+    cancel: &CancellationToken,
 ) -> Result<Vec<Vec<u8>>, CandleError> {
     let _span = info_span!("sd_gen_image", images = args.images, steps = args.steps).entered();
     let config = stable_diffusion::StableDiffusionConfig::v2_1(None, args.height, args.width);
@@ -203,6 +209,10 @@ fn sd_generate_image(
 
         for (timestep_index, &timestep) in timesteps.iter().enumerate() {
             debug!("Image generation step {timestep_index}");
+            if cancel.is_cancelled() {
+                info!("Image generation cancelled");
+                return Err(CandleError::Cancelled);
+            }
             if timestep_index < t_start {
                 continue;
             }
@@ -460,7 +470,21 @@ impl ImageGenerationEndpoint for CandleImageGenerationEndpoint {
             }
         };
 
-        Ok(sd_generate_image(model, args, device)?)
+        // the models are loaded for every generation, taking about as much memory as their files
+        let memory = MEMORY_BUDGET.reserve(model.size());
+
+        // the generation runs on a blocking thread, which is cancelled between steps if this
+        // future is dropped (e.g. because the peer disconnected)
+        let cancel = CancellationToken::new();
+        let _cancel_on_drop = cancel.clone().drop_guard();
+        let generation = spawn_blocking(move || {
+            let _memory = memory;
+            sd_generate_image(model, args, device, &cancel)
+        });
+
+        Ok(generation
+            .await
+            .map_err(|e| ImageGenerationEndpointError::Generation(e.to_string()))??)
     }
 }
 
@@ -483,6 +507,7 @@ impl From<CandleError> for ImageGenerationEndpointError {
             CandleError::EncodeWriteFailed(_) => {
                 ImageGenerationEndpointError::Encoding(value.to_string())
             }
+            CandleError::Cancelled => ImageGenerationEndpointError::Cancelled,
         }
     }
 }
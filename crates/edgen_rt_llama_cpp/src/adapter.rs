@@ -0,0 +1,265 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Decoding of completions of a model with a LoRA adapter applied to it.
+//!
+//! [`llama_cpp`] cannot apply adapters, so the decoder loads its own copy of the model through
+//! [`llama_cpp_sys`] directly.
+
+use std::ffi::CString;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use futures::channel::mpsc::UnboundedReceiver;
+use llama_cpp::Token;
+use thiserror::Error;
+use tracing::error;
+
+use crate::sampler::EdgenSampler;
+use crate::sequence::{Job, SequenceAdapter, SequenceContext, SequenceError, SequenceParams};
+
+/// An error that occurred while starting an [`AdapterDecoder`].
+#[derive(Debug, Error)]
+pub enum AdapterError {
+    #[error("invalid path: {0}")]
+    InvalidPath(String),
+    #[error("failed to load the model with the adapter: {0}")]
+    Context(#[from] SequenceError),
+    #[error("the adapter decoding thread stopped")]
+    Stopped,
+}
+
+/// How an [`AdapterDecoder`] is set up.
+#[derive(Debug, Clone, Copy)]
+pub struct AdapterParams {
+    /// The number of tokens in the context.
+    pub context_size: u32,
+
+    /// How strongly the adapter is applied.
+    pub scale: f32,
+
+    /// The number of threads used to decode.
+    pub threads: u32,
+
+    /// The number of model layers offloaded to the GPU.
+    pub gpu_layers: u32,
+}
+
+/// Decodes completions of a model with a LoRA adapter applied to it, on a dedicated thread.
+///
+/// Completions are decoded one at a time, in order of arrival. The context keeps the tokens of the
+/// last completion, so that a completion whose prompt starts with them, such as the next turn of a
+/// chat, only decodes the rest of its prompt. The context is never shared with completions of the
+/// model without the adapter, or with another adapter.
+///
+/// The adapter changes the weights of the model, so the decoder reads a whole copy of the model
+/// into memory.
+pub struct AdapterDecoder {
+    /// Where new completions are sent to the decoding thread.
+    jobs_tx: Sender<Job>,
+
+    /// How the decoder was set up.
+    params: AdapterParams,
+
+    /// The number of bytes taken by the model and the context.
+    memory_size: usize,
+}
+
+impl AdapterDecoder {
+    /// Loads the model at `path`, applies the adapter at `adapter_path` to it, and starts a decoder
+    /// of its completions, blocking until the context is ready.
+    pub fn new(
+        path: impl AsRef<Path>,
+        adapter_path: impl AsRef<Path>,
+        params: AdapterParams,
+    ) -> Result<Self, AdapterError> {
+        let model_size = std::fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        let path = c_path(path.as_ref())?;
+        let adapter_path = c_path(adapter_path.as_ref())?;
+        let (jobs_tx, jobs_rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+
+        thread::spawn(move || {
+            let adapter = SequenceAdapter {
+                path: &adapter_path,
+                scale: params.scale,
+            };
+            let sequence = SequenceParams {
+                context_size: params.context_size,
+                sequences: 1,
+                threads: params.threads,
+                gpu_layers: params.gpu_layers,
+                embeddings: false,
+            };
+            let context = match SequenceContext::new(&path, sequence, Some(adapter)) {
+                Ok(context) => context,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+            let _ = ready_tx.send(Ok(context.state_size()));
+
+            Decoder {
+                context,
+                cached: vec![],
+            }
+            .run(jobs_rx);
+        });
+
+        let state_size = ready_rx.recv().map_err(|_| AdapterError::Stopped)??;
+
+        Ok(Self {
+            jobs_tx,
+            params,
+            memory_size: model_size as usize + state_size,
+        })
+    }
+
+    /// Returns the number of bytes taken by the model and the context of this decoder.
+    pub fn memory_size(&self) -> usize {
+        self.memory_size
+    }
+
+    /// Returns the maximum number of tokens that a completion of a prompt of `prompt_len` tokens
+    /// can generate, given the `max_tokens` requested, or [`None`] if the completion cannot be
+    /// decoded by this decoder.
+    pub fn max_tokens(&self, prompt_len: usize, max_tokens: usize) -> Option<usize> {
+        let context_size = self.params.context_size as usize;
+        if prompt_len == 0 || prompt_len >= context_size {
+            return None;
+        }
+
+        Some(max_tokens.min(context_size - prompt_len))
+    }
+
+    /// Queues a completion of `prompt` with `sampler`, returning a stream of its tokens. Dropping
+    /// the stream stops the completion.
+    ///
+    /// `max_tokens` must not exceed what [`AdapterDecoder::max_tokens`] allows.
+    pub fn submit(
+        &self,
+        prompt: Vec<Token>,
+        sampler: EdgenSampler,
+        max_tokens: usize,
+    ) -> Result<UnboundedReceiver<Token>, AdapterError> {
+        let (job, stream) = Job::new(prompt, sampler, max_tokens);
+        self.jobs_tx.send(job).map_err(|_| AdapterError::Stopped)?;
+
+        Ok(stream)
+    }
+}
+
+/// Helper function to convert `path` into a [`CString`].
+fn c_path(path: &Path) -> Result<CString, AdapterError> {
+    CString::new(path.to_string_lossy().as_bytes())
+        .map_err(|_| AdapterError::InvalidPath(path.to_string_lossy().to_string()))
+}
+
+/// The state of the decoding thread.
+struct Decoder {
+    context: SequenceContext,
+
+    /// The tokens in the context.
+    cached: Vec<Token>,
+}
+
+impl Decoder {
+    /// Decodes completions until every [`AdapterDecoder`] handle is dropped and the last
+    /// completion is finished.
+    fn run(mut self, jobs_rx: Receiver<Job>) {
+        while let Ok(job) = jobs_rx.recv() {
+            if job.output.is_closed() {
+                continue;
+            }
+
+            if let Err(code) = self.complete(job) {
+                error!("Failed to decode with an adapter (error {code}), stopping the completion");
+                self.cached.clear();
+            }
+        }
+    }
+
+    /// Generates the tokens of `job`, reusing the tokens of the context that its prompt starts
+    /// with.
+    ///
+    /// Fails with the error code of llama.cpp if decoding fails.
+    fn complete(&mut self, mut job: Job) -> Result<(), i32> {
+        let eos = self.context.eos();
+
+        // the last prompt token is always decoded, for the logits of the first generated token
+        let reused = common_prefix(&self.cached, &job.history).min(job.history.len() - 1);
+        self.context.truncate(reused);
+        self.cached.truncate(reused);
+
+        let mut logits = self
+            .context
+            .decode(&job.history[reused..], reused, false)?
+            .remove(0);
+        self.cached.extend_from_slice(&job.history[reused..]);
+
+        loop {
+            let token = self.context.sample(&mut job, &logits);
+            if !job.push(token, eos) {
+                return Ok(());
+            }
+
+            logits = self
+                .context
+                .decode(&[token], self.cached.len(), false)?
+                .remove(0);
+            self.cached.push(token);
+        }
+    }
+}
+
+/// Returns the number of tokens at the start of `a` and `b` that are the same.
+fn common_prefix(a: &[Token], b: &[Token]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a.0 == b.0).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_tokens_fit_the_context() {
+        let decoder = AdapterDecoder {
+            jobs_tx: channel().0,
+            params: AdapterParams {
+                context_size: 1024,
+                scale: 1.0,
+                threads: 1,
+                gpu_layers: 0,
+            },
+            memory_size: 0,
+        };
+
+        assert_eq!(decoder.max_tokens(24, 100), Some(100));
+        assert_eq!(decoder.max_tokens(24, 4096), Some(1000));
+        assert_eq!(decoder.max_tokens(1024, 100), None);
+        assert_eq!(decoder.max_tokens(0, 100), None);
+    }
+
+    #[test]
+    fn common_prefix_of_prompts() {
+        let tokens = |ids: &[i32]| ids.iter().map(|id| Token(*id)).collect::<Vec<_>>();
+
+        assert_eq!(
+            common_prefix(&tokens(&[1, 2, 3]), &tokens(&[1, 2, 3, 4])),
+            3
+        );
+        assert_eq!(common_prefix(&tokens(&[1, 2, 3]), &tokens(&[1, 5, 3])), 1);
+        assert_eq!(common_prefix(&tokens(&[]), &tokens(&[1])), 0);
+    }
+}
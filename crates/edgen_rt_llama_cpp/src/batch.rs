@@ -0,0 +1,519 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Continuous batching of one-shot completions, which decodes the sequences of several requests
+//! together in a single llama.cpp context.
+//!
+//! [`llama_cpp`] only decodes one sequence per context at a time, so the scheduler drives the
+//! context through a [`SequenceContext`].
+
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use llama_cpp::Token;
+use thiserror::Error;
+use tracing::{error, info};
+
+use crate::sampler::EdgenSampler;
+use crate::sequence::{
+    BatchToken, Job, SequenceContext, SequenceError, SequenceParams, BATCH_TOKENS,
+};
+
+/// An error that occurred while starting a [`BatchScheduler`].
+#[derive(Debug, Error)]
+pub enum BatchError {
+    #[error("failed to create the batching context: {0}")]
+    Context(#[from] SequenceError),
+    #[error("the batching thread stopped")]
+    Stopped,
+}
+
+/// How a [`BatchScheduler`] is set up.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchParams {
+    /// The number of tokens in the context, shared by every sequence.
+    pub context_size: u32,
+
+    /// The maximum number of sequences decoded together.
+    pub sequences: u32,
+
+    /// The number of threads used to decode.
+    pub threads: u32,
+
+    /// The number of model layers offloaded to the GPU.
+    pub gpu_layers: u32,
+}
+
+/// A one-shot completion waiting to be decoded by a [`BatchScheduler`].
+struct BatchJob {
+    /// The tokens of the prompt, shared by every choice.
+    prompt: Vec<Token>,
+
+    /// The sampler of each choice.
+    samplers: Vec<EdgenSampler>,
+
+    /// The maximum number of tokens generated for each choice.
+    max_tokens: usize,
+
+    /// Where the tokens of each choice are sent.
+    outputs: Vec<UnboundedSender<Token>>,
+}
+
+impl BatchJob {
+    /// The number of context cells this job may take up at most.
+    fn cells(&self) -> usize {
+        self.prompt.len() + self.samplers.len() * self.max_tokens
+    }
+}
+
+/// Decodes one-shot completions of a model continuously batched in a single context, on a
+/// dedicated thread.
+///
+/// Every step decodes the next token of every running sequence, along with as much of the prompts
+/// of newly admitted completions as fits in the batch, so that new completions start without
+/// waiting for the running ones to finish. The context cells are shared by every sequence, and a
+/// completion is only admitted once its prompt and all of its tokens are sure to fit.
+///
+/// The scheduler loads its own copy of the model weights, which are memory-mapped and so shared
+/// with the other copies when running on the CPU. Dropping the scheduler lets the running
+/// completions finish before the context is freed.
+pub struct BatchScheduler {
+    /// Where new completions are sent to the scheduling thread.
+    jobs_tx: Sender<BatchJob>,
+
+    /// How the scheduler was set up.
+    params: BatchParams,
+
+    /// The number of bytes taken by the context.
+    memory_size: usize,
+}
+
+impl BatchScheduler {
+    /// Loads the model at `path` and starts a scheduler decoding its completions, blocking until
+    /// the context is ready.
+    pub fn new(path: impl AsRef<Path>, mut params: BatchParams) -> Result<Self, BatchError> {
+        // every running sequence decodes a token in every batch
+        params.sequences = params.sequences.clamp(1, BATCH_TOKENS as u32);
+        let path = CString::new(path.as_ref().to_string_lossy().as_bytes())
+            .map_err(|_| SequenceError::LoadModel)?;
+        let (jobs_tx, jobs_rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+
+        thread::spawn(move || {
+            let sequence = SequenceParams {
+                context_size: params.context_size,
+                sequences: params.sequences,
+                threads: params.threads,
+                gpu_layers: params.gpu_layers,
+                embeddings: false,
+            };
+            let context = match SequenceContext::new(&path, sequence, None) {
+                Ok(context) => context,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(BatchError::from(e)));
+                    return;
+                }
+            };
+            let _ = ready_tx.send(Ok(context.state_size()));
+
+            Scheduler::new(context, params).run(jobs_rx);
+        });
+
+        let memory_size = ready_rx.recv().map_err(|_| BatchError::Stopped)??;
+        info!(
+            "Started decoding up to {} sequences in a batch",
+            params.sequences
+        );
+
+        Ok(Self {
+            jobs_tx,
+            params,
+            memory_size,
+        })
+    }
+
+    /// Returns the number of bytes taken by the context of this scheduler.
+    pub fn memory_size(&self) -> usize {
+        self.memory_size
+    }
+
+    /// Returns the maximum number of tokens that each of `choices` choices of a completion of a
+    /// prompt of `prompt_len` tokens can generate, given the `max_tokens` requested, or [`None`]
+    /// if the completion cannot be decoded by this scheduler.
+    pub fn max_tokens(
+        &self,
+        prompt_len: usize,
+        choices: usize,
+        max_tokens: usize,
+    ) -> Option<usize> {
+        let context_size = self.params.context_size as usize;
+        if choices == 0
+            || choices > self.params.sequences as usize
+            || prompt_len == 0
+            || prompt_len >= context_size
+        {
+            return None;
+        }
+
+        let room = (context_size - prompt_len) / choices;
+        (room > 0).then_some(max_tokens.min(room))
+    }
+
+    /// Queues a completion of `prompt` for each of `samplers`, returning a stream of the tokens
+    /// of each choice. Dropping a stream stops its choice.
+    ///
+    /// `max_tokens` must not exceed what [`BatchScheduler::max_tokens`] allows.
+    pub fn submit(
+        &self,
+        prompt: Vec<Token>,
+        samplers: Vec<EdgenSampler>,
+        max_tokens: usize,
+    ) -> Result<Vec<UnboundedReceiver<Token>>, BatchError> {
+        let (outputs, streams) = samplers.iter().map(|_| unbounded()).unzip();
+        self.jobs_tx
+            .send(BatchJob {
+                prompt,
+                samplers,
+                max_tokens,
+                outputs,
+            })
+            .map_err(|_| BatchError::Stopped)?;
+
+        Ok(streams)
+    }
+}
+
+/// A job whose prompt is being decoded, in the first of `seqs`.
+struct Prefill {
+    job: BatchJob,
+
+    /// The sequences reserved for the choices of the job, one each.
+    seqs: Vec<i32>,
+
+    /// The number of prompt tokens decoded so far.
+    decoded: usize,
+
+    /// The context cells reserved for the whole job.
+    cells: Rc<usize>,
+}
+
+/// A choice being generated, in sequence `seq`.
+struct Sequence {
+    seq: i32,
+    job: Job,
+
+    /// The context cells reserved for the job of this choice, released once every choice of the
+    /// job is finished.
+    cells: Rc<usize>,
+}
+
+/// The state of the scheduling thread.
+struct Scheduler {
+    context: SequenceContext,
+
+    /// The sequence ids not in use.
+    free_seqs: Vec<i32>,
+
+    /// The number of context cells reserved by admitted jobs.
+    reserved_cells: usize,
+
+    /// The number of context cells.
+    context_size: usize,
+
+    /// Jobs waiting to be admitted, oldest first.
+    queue: VecDeque<BatchJob>,
+
+    /// Admitted jobs whose prompts are being decoded.
+    prefills: Vec<Prefill>,
+
+    /// Choices being generated.
+    sequences: Vec<Sequence>,
+}
+
+impl Scheduler {
+    fn new(context: SequenceContext, params: BatchParams) -> Self {
+        Self {
+            context,
+            free_seqs: (0..params.sequences as i32).rev().collect(),
+            reserved_cells: 0,
+            context_size: params.context_size as usize,
+            queue: VecDeque::new(),
+            prefills: vec![],
+            sequences: vec![],
+        }
+    }
+
+    /// Decodes completions until every [`BatchScheduler`] handle is dropped and the running
+    /// completions are finished.
+    fn run(mut self, jobs_rx: Receiver<BatchJob>) {
+        loop {
+            if self.queue.is_empty() && self.prefills.is_empty() && self.sequences.is_empty() {
+                match jobs_rx.recv() {
+                    Ok(job) => self.queue.push_back(job),
+                    Err(_) => return,
+                }
+            }
+            while let Ok(job) = jobs_rx.try_recv() {
+                self.queue.push_back(job);
+            }
+
+            self.admit();
+            self.step();
+        }
+    }
+
+    /// Admits the queued jobs that fit, oldest first. A job that does not fit holds back the
+    /// younger ones, so that large jobs are not starved.
+    fn admit(&mut self) {
+        while let Some(job) = self.queue.front() {
+            if job.outputs.iter().all(UnboundedSender::is_closed) {
+                self.queue.pop_front();
+                continue;
+            }
+
+            let cells = job.cells();
+            if job.samplers.len() > self.free_seqs.len()
+                || self.reserved_cells + cells > self.context_size
+            {
+                return;
+            }
+
+            let Some(job) = self.queue.pop_front() else {
+                return;
+            };
+            self.reserved_cells += cells;
+            let seqs = self
+                .free_seqs
+                .split_off(self.free_seqs.len() - job.samplers.len());
+            self.prefills.push(Prefill {
+                job,
+                seqs,
+                decoded: 0,
+                cells: Rc::new(cells),
+            });
+        }
+    }
+
+    /// Decodes the next token of every running choice, and as many prompt tokens as fit in the
+    /// batch after those.
+    fn step(&mut self) {
+        // stop the work of the completions that were dropped before it is decoded
+        for index in (0..self.sequences.len()).rev() {
+            if self.sequences[index].job.output.is_closed() {
+                let sequence = self.sequences.swap_remove(index);
+                self.finish(sequence);
+            }
+        }
+        for index in (0..self.prefills.len()).rev() {
+            if self.prefills[index]
+                .job
+                .outputs
+                .iter()
+                .all(UnboundedSender::is_closed)
+            {
+                let prefill = self.prefills.swap_remove(index);
+                self.abandon(prefill);
+            }
+        }
+
+        let mut items: Vec<BatchToken> = self
+            .sequences
+            .iter()
+            .map(|sequence| BatchToken {
+                // PANIC SAFETY: a sequence is only running once its first token is sampled.
+                token: *sequence.job.history.last().unwrap(),
+                pos: sequence.job.history.len() as i32 - 1,
+                seq: sequence.seq,
+                logits: true,
+            })
+            .collect();
+
+        // the prompts whose last tokens are in this batch, by index in `self.prefills`
+        let mut prefilled = vec![];
+        for (index, prefill) in self.prefills.iter_mut().enumerate() {
+            let room = BATCH_TOKENS.saturating_sub(items.len());
+            if room == 0 {
+                break;
+            }
+
+            let end = prefill.job.prompt.len().min(prefill.decoded + room);
+            for pos in prefill.decoded..end {
+                items.push(BatchToken {
+                    token: prefill.job.prompt[pos],
+                    pos: pos as i32,
+                    seq: prefill.seqs[0],
+                    logits: pos + 1 == prefill.job.prompt.len(),
+                });
+            }
+            prefill.decoded = end;
+            if end == prefill.job.prompt.len() {
+                prefilled.push(index);
+            }
+        }
+
+        let logits = match decode(&mut self.context, &items) {
+            Ok(logits) => logits,
+            Err(code) => {
+                error!("Failed to decode a batch (error {code}), stopping its completions");
+                for sequence in std::mem::take(&mut self.sequences) {
+                    self.finish(sequence);
+                }
+                for prefill in std::mem::take(&mut self.prefills) {
+                    self.abandon(prefill);
+                }
+                return;
+            }
+        };
+        let mut logits = logits.into_iter();
+
+        // the running choices come first in the batch, in order
+        let eos = self.context.eos();
+        let mut finished = vec![];
+        for (index, logits) in logits.by_ref().take(self.sequences.len()).enumerate() {
+            let sequence = &mut self.sequences[index];
+            let token = self.context.sample(&mut sequence.job, &logits);
+            if !sequence.job.push(token, eos) {
+                finished.push(index);
+            }
+        }
+        for index in finished.into_iter().rev() {
+            let sequence = self.sequences.swap_remove(index);
+            self.finish(sequence);
+        }
+
+        // every choice of a prefilled prompt samples its first token from the same logits
+        for (index, logits) in prefilled.iter().rev().zip(logits.rev()) {
+            let prefill = self.prefills.swap_remove(*index);
+            self.start(prefill, &logits, eos);
+        }
+    }
+
+    /// Starts generating every choice of a job whose prompt was decoded, given the logits of its
+    /// last prompt token.
+    fn start(&mut self, prefill: Prefill, logits: &[f32], eos: Token) {
+        let Prefill {
+            job, seqs, cells, ..
+        } = prefill;
+        let choices = seqs.into_iter().zip(job.samplers).zip(job.outputs);
+
+        let mut started = vec![];
+        let mut prompt_seq = None;
+        for ((seq, sampler), output) in choices {
+            // the first sequence holds the prompt, which the others share
+            match prompt_seq {
+                None => prompt_seq = Some(seq),
+                Some(prompt_seq) => self.context.copy(prompt_seq, seq),
+            }
+
+            let mut sequence = Sequence {
+                seq,
+                job: Job {
+                    sampler,
+                    output,
+                    history: job.prompt.clone(),
+                    generated: 0,
+                    max_tokens: job.max_tokens,
+                },
+                cells: cells.clone(),
+            };
+            let token = self.context.sample(&mut sequence.job, logits);
+            let running = sequence.job.push(token, eos);
+            started.push((sequence, running));
+        }
+
+        // the cells are released by the last choice to finish, which may be any of these
+        drop(cells);
+        for (sequence, running) in started {
+            if running {
+                self.sequences.push(sequence);
+            } else {
+                self.finish(sequence);
+            }
+        }
+    }
+
+    /// Frees the sequences and the cells of a job whose prompt will not be decoded further.
+    fn abandon(&mut self, prefill: Prefill) {
+        self.context.clear(prefill.seqs[0]);
+        self.free_seqs.extend(prefill.seqs);
+        self.reserved_cells -= *prefill.cells;
+    }
+
+    /// Frees the sequence of a finished choice, and the cells of its job if it was the last
+    /// choice running.
+    fn finish(&mut self, sequence: Sequence) {
+        self.context.clear(sequence.seq);
+        self.free_seqs.push(sequence.seq);
+        if Rc::strong_count(&sequence.cells) == 1 {
+            self.reserved_cells -= *sequence.cells;
+        }
+    }
+}
+
+/// Decodes `items` in `context`, returning the logits of the items that asked for them, in order.
+///
+/// If the context has no contiguous room for the whole batch, it is split in halves, down to
+/// single tokens, which always fit as long as the admitted completions fit in the context.
+fn decode(context: &mut SequenceContext, items: &[BatchToken]) -> Result<Vec<Vec<f32>>, i32> {
+    if items.is_empty() {
+        return Ok(vec![]);
+    }
+
+    match context.decode_batch(items) {
+        // `1` means that no room was found for the batch
+        Err(1) if items.len() > 1 => {
+            let (first, second) = items.split_at(items.len() / 2);
+            let mut logits = decode(context, first)?;
+            logits.extend(decode(context, second)?);
+            Ok(logits)
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler(context_size: u32, sequences: u32) -> BatchScheduler {
+        BatchScheduler {
+            jobs_tx: channel().0,
+            params: BatchParams {
+                context_size,
+                sequences,
+                threads: 1,
+                gpu_layers: 0,
+            },
+            memory_size: 0,
+        }
+    }
+
+    #[test]
+    fn max_tokens_fit_the_context() {
+        let batch = scheduler(1024, 4);
+
+        assert_eq!(batch.max_tokens(24, 1, 100), Some(100));
+        assert_eq!(batch.max_tokens(24, 1, 4096), Some(1000));
+        // the prompt is shared, but every choice generates its own tokens
+        assert_eq!(batch.max_tokens(24, 4, 4096), Some(250));
+
+        assert_eq!(batch.max_tokens(24, 5, 100), None);
+        assert_eq!(batch.max_tokens(1024, 1, 100), None);
+        assert_eq!(batch.max_tokens(1022, 4, 100), None);
+        assert_eq!(batch.max_tokens(0, 1, 100), None);
+    }
+}
@@ -0,0 +1,340 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A CLIP image encoder with a LLaVA projector, loaded from a multimodal projector (mmproj) GGUF
+//! file, which turns images into embeddings of the language model that the projector was trained
+//! with.
+//!
+//! llama.cpp only implements this in its examples, which [`llama_cpp_sys`] doesn't build, so the
+//! encoder is implemented with [`candle_core`], following `clip.cpp`.
+
+use std::fs::File;
+use std::path::Path;
+
+use candle_core::quantized::gguf_file::{Content, Value};
+use candle_core::{Device, Module, Tensor};
+use candle_nn::{LayerNorm, Linear};
+use image::imageops::FilterType;
+use image::{Rgb, RgbImage};
+use thiserror::Error;
+
+/// The mean of the pixel values of the images CLIP was trained with, if the projector doesn't
+/// specify it.
+const IMAGE_MEAN: [f32; 3] = [0.481_454_66, 0.457_827_5, 0.408_210_73];
+
+/// The standard deviation of the pixel values of the images CLIP was trained with, if the
+/// projector doesn't specify it.
+const IMAGE_STD: [f32; 3] = [0.268_629_54, 0.261_302_58, 0.275_777_1];
+
+/// An error that occurred while loading a [`ClipEncoder`] or encoding an image.
+#[derive(Debug, Error)]
+pub enum ClipError {
+    #[error("failed to read the projector: {0}")]
+    Read(#[from] std::io::Error),
+    #[error("the projector has no {0}")]
+    Missing(String),
+    #[error("unsupported projector type {0}")]
+    ProjectorType(String),
+    #[error("failed to decode the image: {0}")]
+    Image(#[from] image::ImageError),
+    #[error(transparent)]
+    Candle(#[from] candle_core::Error),
+}
+
+/// An encoder layer of the vision transformer.
+struct Block {
+    ln1: LayerNorm,
+    q: Linear,
+    k: Linear,
+    v: Linear,
+    out: Linear,
+    ln2: LayerNorm,
+    fc1: Linear,
+    fc2: Linear,
+}
+
+impl Block {
+    fn forward(&self, x: &Tensor, heads: usize, gelu: bool) -> candle_core::Result<Tensor> {
+        let (batch, len, hidden) = x.dims3()?;
+        let head_dim = hidden / heads;
+        let split_heads = |t: Tensor| {
+            t.reshape((batch, len, heads, head_dim))?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+
+        let h = self.ln1.forward(x)?;
+        let q = split_heads((self.q.forward(&h)? * (head_dim as f64).powf(-0.5))?)?;
+        let k = split_heads(self.k.forward(&h)?)?;
+        let v = split_heads(self.v.forward(&h)?)?;
+        let attention = candle_nn::ops::softmax_last_dim(&q.matmul(&k.t()?)?)?;
+        let h = attention
+            .matmul(&v)?
+            .transpose(1, 2)?
+            .contiguous()?
+            .reshape((batch, len, hidden))?;
+        let x = (x + self.out.forward(&h)?)?;
+
+        let h = self.fc1.forward(&self.ln2.forward(&x)?)?;
+        let h = if gelu { h.gelu()? } else { quick_gelu(&h)? };
+        x + self.fc2.forward(&h)?
+    }
+}
+
+/// The activation of the original CLIP models: `x * sigmoid(1.702 * x)`.
+fn quick_gelu(x: &Tensor) -> candle_core::Result<Tensor> {
+    let sigmoid = x.affine(-1.702, 0.0)?.exp()?.affine(1.0, 1.0)?.recip()?;
+    x.mul(&sigmoid)
+}
+
+/// A CLIP vision transformer followed by a LLaVA projector.
+pub struct ClipEncoder {
+    device: Device,
+    patch_embd: Tensor,
+    class_embd: Tensor,
+    position_embd: Tensor,
+    pre_ln: LayerNorm,
+    blocks: Vec<Block>,
+    mm0: Linear,
+    mm2: Linear,
+
+    /// The width and height, in pixels, that images are resized to.
+    image_size: u32,
+    patch_size: usize,
+    heads: usize,
+
+    /// Whether the activation is GELU instead of quick GELU.
+    gelu: bool,
+    mean: [f32; 3],
+    std: [f32; 3],
+
+    /// The number of bytes taken by the weights.
+    memory_size: usize,
+}
+
+impl ClipEncoder {
+    /// Loads the encoder from the multimodal projector at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ClipError> {
+        let device = Device::Cpu;
+        let mut file = File::open(path)?;
+        let content = Content::read(&mut file)?;
+
+        let projector = match content.metadata.get("clip.projector_type") {
+            Some(value) => value.to_string()?.clone(),
+            None => "mlp".to_string(),
+        };
+        if projector != "mlp" {
+            return Err(ClipError::ProjectorType(projector));
+        }
+
+        let value = |key: &str| {
+            content
+                .metadata
+                .get(key)
+                .ok_or_else(|| ClipError::Missing(key.to_string()))
+        };
+        let pixel_stats = |key: &str, default: [f32; 3]| -> Result<[f32; 3], ClipError> {
+            let Some(values) = content.metadata.get(key) else {
+                return Ok(default);
+            };
+            let values = values.to_vec()?;
+            if values.len() != 3 {
+                return Err(ClipError::Missing(key.to_string()));
+            }
+
+            let mut stats = [0.0; 3];
+            for (stat, value) in stats.iter_mut().zip(values) {
+                *stat = value.to_f32()?;
+            }
+            Ok(stats)
+        };
+
+        let image_size = value("clip.vision.image_size")?.to_u32()?;
+        let patch_size = value("clip.vision.patch_size")?.to_u32()? as usize;
+        let heads = value("clip.vision.attention.head_count")?.to_u32()? as usize;
+        let block_count = value("clip.vision.block_count")?.to_u32()? as usize;
+        let eps = value("clip.vision.attention.layer_norm_epsilon")?.to_f32()? as f64;
+        let gelu = content
+            .metadata
+            .get("clip.use_gelu")
+            .map(Value::to_bool)
+            .transpose()?
+            .unwrap_or(false);
+        let mean = pixel_stats("clip.vision.image_mean", IMAGE_MEAN)?;
+        let std = pixel_stats("clip.vision.image_std", IMAGE_STD)?;
+
+        let mut weights = Weights {
+            content: &content,
+            file: &mut file,
+            device: &device,
+            memory_size: 0,
+        };
+
+        // LLaVA uses the features of the next to last layer, so the last one is never run
+        let mut blocks = Vec::with_capacity(block_count.saturating_sub(1));
+        for index in 0..block_count.saturating_sub(1) {
+            let prefix = format!("v.blk.{index}");
+            blocks.push(Block {
+                ln1: weights.layer_norm(&format!("{prefix}.ln1"), eps)?,
+                q: weights.linear(&format!("{prefix}.attn_q"))?,
+                k: weights.linear(&format!("{prefix}.attn_k"))?,
+                v: weights.linear(&format!("{prefix}.attn_v"))?,
+                out: weights.linear(&format!("{prefix}.attn_out"))?,
+                ln2: weights.layer_norm(&format!("{prefix}.ln2"), eps)?,
+                // `clip.cpp` names the first feed-forward layer `ffn_down`
+                fc1: weights.linear(&format!("{prefix}.ffn_down"))?,
+                fc2: weights.linear(&format!("{prefix}.ffn_up"))?,
+            });
+        }
+        let patch_embd = weights.tensor("v.patch_embd.weight")?;
+        let class_embd = weights.tensor("v.class_embd")?;
+        let position_embd = weights.tensor("v.position_embd.weight")?;
+        let pre_ln = weights.layer_norm("v.pre_ln", eps)?;
+        let mm0 = weights.linear("mm.0")?;
+        let mm2 = weights.linear("mm.2")?;
+        let memory_size = weights.memory_size;
+
+        Ok(Self {
+            device,
+            patch_embd,
+            class_embd,
+            position_embd,
+            pre_ln,
+            blocks,
+            mm0,
+            mm2,
+            image_size,
+            patch_size,
+            heads,
+            gelu,
+            mean,
+            std,
+            memory_size,
+        })
+    }
+
+    /// Returns the number of bytes taken by the weights of this encoder.
+    pub fn memory_size(&self) -> usize {
+        self.memory_size
+    }
+
+    /// Returns the number of embeddings that every image is encoded into.
+    pub fn image_tokens(&self) -> usize {
+        let patches = self.image_size as usize / self.patch_size;
+        patches * patches
+    }
+
+    /// Returns the number of values in each embedding, which is that of the embeddings of the
+    /// language model.
+    pub fn n_embd(&self) -> usize {
+        self.mm2.weight().dims()[0]
+    }
+
+    /// Encodes the image in `bytes` (PNG, JPEG, etc.) into [`ClipEncoder::image_tokens`]
+    /// embeddings of [`ClipEncoder::n_embd`] values each, one after another.
+    pub fn encode(&self, bytes: &[u8]) -> Result<Vec<f32>, ClipError> {
+        let pixels = self.preprocess(bytes)?;
+
+        let patches = pixels
+            .conv2d(&self.patch_embd, 0, self.patch_size, 1, 1)?
+            .flatten_from(2)?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let class = self.class_embd.reshape((1, 1, ()))?;
+        let x = Tensor::cat(&[&class, &patches], 1)?.broadcast_add(&self.position_embd)?;
+
+        let mut x = self.pre_ln.forward(&x)?;
+        for block in &self.blocks {
+            x = block.forward(&x, self.heads, self.gelu)?;
+        }
+
+        // the class embedding is not projected
+        let x = x.narrow(1, 1, self.image_tokens())?;
+        let x = self.mm2.forward(&self.mm0.forward(&x)?.gelu()?)?;
+
+        Ok(x.flatten_all()?.to_vec1::<f32>()?)
+    }
+
+    /// Returns the normalized pixels of the image in `bytes`, padded to a square with the mean
+    /// color and resized as LLaVA expects.
+    fn preprocess(&self, bytes: &[u8]) -> Result<Tensor, ClipError> {
+        let image = image::load_from_memory(bytes)?.to_rgb8();
+
+        let side = image.width().max(image.height());
+        let fill = Rgb(self.mean.map(|mean| (mean * 255.0).round() as u8));
+        let mut square = RgbImage::from_pixel(side, side, fill);
+        image::imageops::overlay(
+            &mut square,
+            &image,
+            ((side - image.width()) / 2) as i64,
+            ((side - image.height()) / 2) as i64,
+        );
+        let resized = image::imageops::resize(
+            &square,
+            self.image_size,
+            self.image_size,
+            FilterType::CatmullRom,
+        );
+
+        let size = self.image_size as usize;
+        let mut data = vec![0.0; 3 * size * size];
+        for (x, y, pixel) in resized.enumerate_pixels() {
+            for channel in 0..3 {
+                let value = pixel[channel] as f32 / 255.0;
+                data[channel * size * size + y as usize * size + x as usize] =
+                    (value - self.mean[channel]) / self.std[channel];
+            }
+        }
+
+        Ok(Tensor::from_vec(data, (1, 3, size, size), &self.device)?)
+    }
+}
+
+/// The weights in a multimodal projector file, which are read as they are needed.
+struct Weights<'a> {
+    content: &'a Content,
+    file: &'a mut File,
+    device: &'a Device,
+
+    /// The number of bytes taken by the weights read so far.
+    memory_size: usize,
+}
+
+impl Weights<'_> {
+    fn tensor(&mut self, name: &str) -> Result<Tensor, ClipError> {
+        if !self.content.tensor_infos.contains_key(name) {
+            return Err(ClipError::Missing(name.to_string()));
+        }
+
+        let tensor = self
+            .content
+            .tensor(self.file, name, self.device)?
+            .dequantize(self.device)?;
+        self.memory_size += tensor.elem_count() * tensor.dtype().size_in_bytes();
+        Ok(tensor)
+    }
+
+    fn linear(&mut self, name: &str) -> Result<Linear, ClipError> {
+        Ok(Linear::new(
+            self.tensor(&format!("{name}.weight"))?,
+            Some(self.tensor(&format!("{name}.bias"))?),
+        ))
+    }
+
+    fn layer_norm(&mut self, name: &str, eps: f64) -> Result<LayerNorm, ClipError> {
+        Ok(LayerNorm::new(
+            self.tensor(&format!("{name}.weight"))?,
+            self.tensor(&format!("{name}.bias"))?,
+            eps,
+        ))
+    }
+}
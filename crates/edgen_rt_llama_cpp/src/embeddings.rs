@@ -0,0 +1,241 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Embeddings of tokenized inputs.
+//!
+//! [`llama_cpp`] only embeds text, which would have to be tokenized again, so the decoder embeds
+//! the tokens as they are in its own context of the model, through [`llama_cpp_sys`] directly.
+
+use std::ffi::CString;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use edgen_core::cancellation::CancellationToken;
+use futures::channel::oneshot;
+use llama_cpp::Token;
+use thiserror::Error;
+
+use crate::sequence::{BatchToken, SequenceContext, SequenceError, SequenceParams};
+
+/// The maximum number of inputs embedded together in a single step.
+const SEQUENCES: usize = 64;
+
+/// An error that occurred while embedding with an [`EmbeddingsDecoder`].
+#[derive(Debug, Error)]
+pub enum EmbeddingsError {
+    #[error("invalid path: {0}")]
+    InvalidPath(String),
+    #[error("failed to load the model: {0}")]
+    Context(#[from] SequenceError),
+    #[error("failed to decode the inputs (error {0})")]
+    Decode(i32),
+    #[error("the embeddings were cancelled")]
+    Cancelled,
+    #[error("the embeddings decoding thread stopped")]
+    Stopped,
+}
+
+/// How an [`EmbeddingsDecoder`] is set up.
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddingsParams {
+    /// The number of tokens in the context, which no input may exceed.
+    pub context_size: u32,
+
+    /// The number of threads used to decode.
+    pub threads: u32,
+
+    /// The number of model layers offloaded to the GPU.
+    pub gpu_layers: u32,
+}
+
+/// Inputs to embed, and where their embeddings are sent.
+struct EmbeddingsJob {
+    inputs: Vec<Vec<Token>>,
+    cancel: CancellationToken,
+    output: oneshot::Sender<Result<Vec<Vec<f32>>, EmbeddingsError>>,
+}
+
+/// Embeds tokenized inputs of a model, on a dedicated thread.
+///
+/// Inputs are embedded in order of arrival, as many together as fit in the context.
+pub struct EmbeddingsDecoder {
+    /// Where new inputs are sent to the decoding thread.
+    jobs_tx: Sender<EmbeddingsJob>,
+
+    /// The number of tokens in the vocabulary of the model.
+    n_vocab: usize,
+
+    /// The token that starts an input, if the model has one.
+    bos: Option<Token>,
+
+    /// The number of bytes taken by the context.
+    memory_size: usize,
+}
+
+impl EmbeddingsDecoder {
+    /// Loads the model at `path` and starts a decoder of its embeddings, blocking until the
+    /// context is ready.
+    pub fn new(path: impl AsRef<Path>, params: EmbeddingsParams) -> Result<Self, EmbeddingsError> {
+        let path = path.as_ref();
+        let path = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|_| EmbeddingsError::InvalidPath(path.to_string_lossy().to_string()))?;
+        let (jobs_tx, jobs_rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+
+        thread::spawn(move || {
+            let sequence = SequenceParams {
+                context_size: params.context_size,
+                sequences: SEQUENCES as u32,
+                threads: params.threads,
+                gpu_layers: params.gpu_layers,
+                embeddings: true,
+            };
+            let context = match SequenceContext::new(&path, sequence, None) {
+                Ok(context) => context,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(EmbeddingsError::from(e)));
+                    return;
+                }
+            };
+            let _ = ready_tx.send(Ok((context.n_vocab(), context.bos(), context.state_size())));
+
+            Decoder {
+                context,
+                context_size: params.context_size as usize,
+            }
+            .run(jobs_rx);
+        });
+
+        let (n_vocab, bos, memory_size) =
+            ready_rx.recv().map_err(|_| EmbeddingsError::Stopped)??;
+
+        Ok(Self {
+            jobs_tx,
+            n_vocab,
+            bos,
+            memory_size,
+        })
+    }
+
+    /// Returns the number of bytes taken by the context of this decoder. The model itself is
+    /// memory-mapped, and shared with the other contexts of the model.
+    pub fn memory_size(&self) -> usize {
+        self.memory_size
+    }
+
+    /// Returns the number of tokens in the vocabulary of the model, whose ids are below it.
+    pub fn n_vocab(&self) -> usize {
+        self.n_vocab
+    }
+
+    /// Returns the token that should start every input, if the model has one.
+    pub fn bos(&self) -> Option<Token> {
+        self.bos
+    }
+
+    /// Embeds each of `inputs`, exactly as they are tokenized, returning their embeddings in
+    /// order.
+    ///
+    /// No input may have more tokens than the context of the decoder. Cancelling `cancel` stops
+    /// the decoding thread before its next step.
+    pub async fn embed(
+        &self,
+        inputs: Vec<Vec<Token>>,
+        cancel: CancellationToken,
+    ) -> Result<Vec<Vec<f32>>, EmbeddingsError> {
+        let (output, embeddings) = oneshot::channel();
+        self.jobs_tx
+            .send(EmbeddingsJob {
+                inputs,
+                cancel,
+                output,
+            })
+            .map_err(|_| EmbeddingsError::Stopped)?;
+
+        embeddings.await.map_err(|_| EmbeddingsError::Stopped)?
+    }
+}
+
+/// The state of the decoding thread.
+struct Decoder {
+    context: SequenceContext,
+
+    /// The number of tokens in the context.
+    context_size: usize,
+}
+
+impl Decoder {
+    /// Embeds inputs until every [`EmbeddingsDecoder`] handle is dropped.
+    fn run(mut self, jobs_rx: Receiver<EmbeddingsJob>) {
+        while let Ok(job) = jobs_rx.recv() {
+            if job.output.is_canceled() || job.cancel.is_cancelled() {
+                continue;
+            }
+
+            let embeddings = self.embed(&job.inputs, &job.cancel);
+            let _ = job.output.send(embeddings);
+        }
+    }
+
+    /// Embeds `inputs`, decoding as many of them together as fit in the context, unless `cancel`
+    /// is cancelled before a step.
+    fn embed(
+        &mut self,
+        inputs: &[Vec<Token>],
+        cancel: &CancellationToken,
+    ) -> Result<Vec<Vec<f32>>, EmbeddingsError> {
+        let mut embeddings = Vec::with_capacity(inputs.len());
+        let mut items = vec![];
+        let mut sequences = 0;
+        for input in inputs {
+            if sequences == SEQUENCES || items.len() + input.len() > self.context_size {
+                embeddings.extend(self.decode(&items, sequences, cancel)?);
+                items.clear();
+                sequences = 0;
+            }
+
+            items.extend(input.iter().enumerate().map(|(pos, token)| BatchToken {
+                token: *token,
+                pos: pos as i32,
+                seq: sequences as i32,
+                logits: true,
+            }));
+            sequences += 1;
+        }
+        if sequences > 0 {
+            embeddings.extend(self.decode(&items, sequences, cancel)?);
+        }
+
+        Ok(embeddings)
+    }
+
+    /// Embeds the inputs in `items`, of sequences `0..sequences`, and removes them from the
+    /// context.
+    fn decode(
+        &mut self,
+        items: &[BatchToken],
+        sequences: usize,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<Vec<f32>>, EmbeddingsError> {
+        if cancel.is_cancelled() {
+            return Err(EmbeddingsError::Cancelled);
+        }
+
+        let embeddings = self.context.embed(items, sequences);
+        for seq in 0..sequences {
+            self.context.clear(seq as i32);
+        }
+
+        embeddings.map_err(EmbeddingsError::Decode)
+    }
+}
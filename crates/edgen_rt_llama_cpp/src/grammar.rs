@@ -0,0 +1,673 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Compilation of [JSON schemas][json-schema] into [GBNF] grammars, used to constrain the output
+//! of [`llama_cpp`] models.
+//!
+//! Every value rule also consumes the (optional) whitespace following it.
+//!
+//! [json-schema]: https://json-schema.org/
+//! [GBNF]: https://github.com/ggerganov/llama.cpp/blob/master/grammars/README.md
+
+use std::collections::HashMap;
+
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use edgen_core::llm::{CompletionArgs, ResponseFormat, ToolChoice, ToolStub};
+
+/// The rules matching primitive JSON values, along with the rules they depend on.
+const PRIMITIVES: &[(&str, &str, &[&str])] = &[
+    ("ws", r#"[ \t\n]?"#, &[]),
+    (
+        "string",
+        r#""\"" ( [^"\\\x7F\x00-\x1F] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F]) )* "\"" ws"#,
+        &["ws"],
+    ),
+    (
+        "number",
+        r#""-"? ("0" | [1-9] [0-9]*) ("." [0-9]+)? ([eE] [-+]? [0-9]+)? ws"#,
+        &["ws"],
+    ),
+    ("integer", r#""-"? ("0" | [1-9] [0-9]*) ws"#, &["ws"]),
+    ("boolean", r#"("true" | "false") ws"#, &["ws"]),
+    ("null", r#""null" ws"#, &["ws"]),
+    (
+        "value",
+        "object | array | string | number | boolean | null",
+        &["object", "array", "string", "number", "boolean", "null"],
+    ),
+    (
+        "object",
+        r#""{" ws ( string ":" ws value ( "," ws string ":" ws value )* )? "}" ws"#,
+        &["ws", "string", "value"],
+    ),
+    (
+        "array",
+        r#""[" ws ( value ( "," ws value )* )? "]" ws"#,
+        &["ws", "value"],
+    ),
+];
+
+/// An error that occurred while compiling a grammar.
+#[derive(Debug, Error)]
+pub enum GrammarError {
+    #[error("unsupported JSON schema: {0}")]
+    Unsupported(String),
+    #[error("unresolved JSON schema reference: {0}")]
+    UnresolvedRef(String),
+    #[error("unknown tool: {0}")]
+    UnknownTool(String),
+}
+
+/// Returns the grammar that completions for `args` must follow, if any.
+///
+/// This is, in order of precedence:
+///
+/// - the raw grammar in `args`,
+/// - a grammar matching tool calls, if [`ToolChoice::Required`] or [`ToolChoice::Function`] is
+///   chosen, or
+/// - a grammar matching the [`ResponseFormat`], if it is JSON.
+pub fn grammar_for(args: &CompletionArgs) -> Result<Option<String>, GrammarError> {
+    if let Some(grammar) = &args.grammar {
+        return Ok(Some(grammar.clone()));
+    }
+
+    if let Some(choice) = &args.tool_choice {
+        if let Some(grammar) = tool_calls_grammar(args.active_tools(), choice)? {
+            return Ok(Some(grammar));
+        }
+    }
+
+    match &args.response_format {
+        Some(ResponseFormat::JsonObject) | Some(ResponseFormat::JsonSchema { schema: None }) => {
+            Ok(Some(json_object_grammar()))
+        }
+        Some(ResponseFormat::JsonSchema {
+            schema: Some(schema),
+        }) => json_schema_grammar(schema).map(Some),
+        Some(ResponseFormat::Text) | None => Ok(None),
+    }
+}
+
+/// Returns a grammar matching any JSON object.
+pub fn json_object_grammar() -> String {
+    let mut grammar = Grammar::default();
+    let root = grammar.primitive("object");
+
+    grammar.build(root)
+}
+
+/// Returns a grammar matching the JSON values that are valid against `schema`.
+pub fn json_schema_grammar(schema: &Value) -> Result<String, GrammarError> {
+    let mut grammar = Grammar::default();
+    let root = grammar.schema("root-value", schema)?;
+
+    Ok(grammar.build(root))
+}
+
+/// Returns a grammar matching calls to `tools`, in the format described by
+/// [`edgen_core::tools`], or [`None`] if the model is not required to call a tool.
+pub fn tool_calls_grammar(
+    tools: &[ToolStub],
+    choice: &ToolChoice,
+) -> Result<Option<String>, GrammarError> {
+    if tools.is_empty() {
+        return Ok(None);
+    }
+
+    let tools: Vec<&ToolStub> = match choice {
+        ToolChoice::Required => tools.iter().collect(),
+        ToolChoice::Function(name) => {
+            let tool = tools
+                .iter()
+                .find(|tool| tool.name() == name)
+                .ok_or_else(|| GrammarError::UnknownTool(name.clone()))?;
+            vec![tool]
+        }
+        ToolChoice::None | ToolChoice::Auto => return Ok(None),
+    };
+
+    let mut grammar = Grammar::default();
+    let ws = grammar.primitive("ws");
+
+    let mut calls = vec![];
+    for tool in &tools {
+        let ToolStub::Function { function } = tool else {
+            continue;
+        };
+        // Tools without parameters accept any arguments.
+        let arguments = if function.parameters.is_null() {
+            grammar.primitive("object")
+        } else {
+            grammar.schema(
+                &format!("{}-arguments", function.name),
+                &function.parameters,
+            )?
+        };
+        let body = format!(
+            r#""{{" {ws} "\"name\"" {ws} ":" {ws} {} {ws} "," {ws} "\"arguments\"" {ws} ":" {ws} {arguments} "}}" {ws}"#,
+            literal(&Value::String(function.name.clone()).to_string()),
+        );
+        calls.push(grammar.rule(&format!("{}-call", function.name), body));
+    }
+    let call = grammar.rule("call", calls.join(" | "));
+
+    let list = match choice {
+        ToolChoice::Function(_) => format!(r#""[" {ws} {call} "]""#),
+        _ => format!(r#""[" {ws} {call} ( "," {ws} {call} )* "]""#),
+    };
+    let root = format!(r#""{{" {ws} "\"tool_calls\"" {ws} ":" {ws} {list} {ws} "}}" {ws}"#);
+
+    Ok(Some(grammar.build(root)))
+}
+
+/// A GBNF grammar under construction.
+#[derive(Default)]
+struct Grammar {
+    /// The rules of the grammar, in the order they were added, as names and bodies.
+    rules: Vec<(String, String)>,
+
+    /// The names of the rules generated for the schema references resolved so far.
+    refs: HashMap<String, String>,
+}
+
+impl Grammar {
+    /// Adds a rule to the grammar, returning its name.
+    ///
+    /// The name is derived from `name`, but made unique, valid and distinct from the names of
+    /// the [`PRIMITIVES`].
+    fn rule(&mut self, name: &str, body: String) -> String {
+        let name = self.reserve(name);
+        self.set(&name, body);
+        name
+    }
+
+    /// Adds a rule with an empty body to the grammar, returning its name.
+    ///
+    /// The body must be [set](Self::set) before the grammar is built.
+    fn reserve(&mut self, name: &str) -> String {
+        let base: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        let base = if base.is_empty() {
+            "rule".to_string()
+        } else {
+            base
+        };
+
+        let mut name = base.clone();
+        let mut i = 1;
+        while name == "root"
+            || PRIMITIVES
+                .iter()
+                .any(|(primitive, _, _)| *primitive == name)
+            || self.rules.iter().any(|(taken, _)| *taken == name)
+        {
+            name = format!("{base}-{i}");
+            i += 1;
+        }
+
+        self.rules.push((name.clone(), String::new()));
+        name
+    }
+
+    /// Sets the body of the rule `name`.
+    fn set(&mut self, name: &str, body: String) {
+        if let Some((_, rule)) = self.rules.iter_mut().find(|(taken, _)| taken == name) {
+            *rule = body;
+        }
+    }
+
+    /// Adds the primitive rule `name`, along with the rules it depends on, returning its name.
+    fn primitive(&mut self, name: &str) -> String {
+        if self.rules.iter().any(|(taken, _)| taken == name) {
+            return name.to_string();
+        }
+
+        // PANIC SAFETY: only called with the names in `PRIMITIVES`
+        let (_, body, deps) = PRIMITIVES
+            .iter()
+            .find(|(primitive, _, _)| *primitive == name)
+            .unwrap();
+        self.rules.push((name.to_string(), body.to_string()));
+        for dep in *deps {
+            self.primitive(dep);
+        }
+
+        name.to_string()
+    }
+
+    /// Adds the rules matching the values that are valid against `schema`, returning the name of
+    /// the rule matching them.
+    fn schema(&mut self, name: &str, schema: &Value) -> Result<String, GrammarError> {
+        self.refs.clear();
+        self.visit(name, schema, schema)
+    }
+
+    /// Returns an expression matching the values valid against `schema`, adding any rules
+    /// needed to the grammar.
+    ///
+    /// `root` is the schema document that references are resolved against.
+    fn visit(&mut self, name: &str, schema: &Value, root: &Value) -> Result<String, GrammarError> {
+        let obj = match schema {
+            Value::Object(obj) => obj,
+            Value::Bool(true) => return Ok(self.primitive("value")),
+            _ => return Err(GrammarError::Unsupported(schema.to_string())),
+        };
+
+        if let Some(reference) = obj.get("$ref").and_then(Value::as_str) {
+            return self.reference(reference, root);
+        }
+
+        if let Some(value) = obj.get("const") {
+            let ws = self.primitive("ws");
+            return Ok(self.rule(name, format!("{} {ws}", literal(&value.to_string()))));
+        }
+
+        if let Some(values) = obj.get("enum").and_then(Value::as_array) {
+            let ws = self.primitive("ws");
+            let alternatives: Vec<String> = values
+                .iter()
+                .map(|value| literal(&value.to_string()))
+                .collect();
+            return Ok(self.rule(name, format!("({}) {ws}", alternatives.join(" | "))));
+        }
+
+        if let Some(schemas) = obj
+            .get("anyOf")
+            .or_else(|| obj.get("oneOf"))
+            .and_then(Value::as_array)
+        {
+            let mut alternatives = vec![];
+            for (i, schema) in schemas.iter().enumerate() {
+                alternatives.push(self.visit(&format!("{name}-{i}"), schema, root)?);
+            }
+            return Ok(self.rule(name, alternatives.join(" | ")));
+        }
+
+        match obj.get("type") {
+            Some(Value::String(type_)) => self.typed(name, type_, obj, root),
+            Some(Value::Array(types)) => {
+                let mut alternatives = vec![];
+                for type_ in types {
+                    let type_ = type_
+                        .as_str()
+                        .ok_or_else(|| GrammarError::Unsupported(type_.to_string()))?;
+                    alternatives.push(self.typed(&format!("{name}-{type_}"), type_, obj, root)?);
+                }
+                Ok(self.rule(name, alternatives.join(" | ")))
+            }
+            Some(type_) => Err(GrammarError::Unsupported(format!("type {type_}"))),
+            None if obj.contains_key("properties") => self.object(name, obj, root),
+            None if obj.contains_key("items") => self.array(name, obj, root),
+            None => Ok(self.primitive("value")),
+        }
+    }
+
+    /// Returns an expression matching the values of JSON type `type_` that are valid against
+    /// the schema `obj`.
+    fn typed(
+        &mut self,
+        name: &str,
+        type_: &str,
+        obj: &Map<String, Value>,
+        root: &Value,
+    ) -> Result<String, GrammarError> {
+        match type_ {
+            "object" => self.object(name, obj, root),
+            "array" => self.array(name, obj, root),
+            "string" | "number" | "integer" | "boolean" | "null" => Ok(self.primitive(type_)),
+            _ => Err(GrammarError::Unsupported(format!("type {type_}"))),
+        }
+    }
+
+    /// Returns an expression matching the objects that are valid against the schema `obj`.
+    ///
+    /// Properties are generated in the order they are listed in the schema, and optional
+    /// properties may be left out. Objects without listed properties can have any property.
+    fn object(
+        &mut self,
+        name: &str,
+        obj: &Map<String, Value>,
+        root: &Value,
+    ) -> Result<String, GrammarError> {
+        let properties = match obj.get("properties").and_then(Value::as_object) {
+            Some(properties) if !properties.is_empty() => properties,
+            _ => return Ok(self.primitive("object")),
+        };
+        let required: Vec<&str> = obj
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|required| required.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        let ws = self.primitive("ws");
+        let mut required_pairs = vec![];
+        let mut optional_pairs = vec![];
+        for (property, schema) in properties {
+            let value = self.visit(&format!("{name}-{property}"), schema, root)?;
+            let pair = format!(
+                r#"{} {ws} ":" {ws} {value}"#,
+                literal(&Value::String(property.clone()).to_string())
+            );
+
+            if required.contains(&property.as_str()) {
+                required_pairs.push(pair);
+            } else {
+                optional_pairs.push(pair);
+            }
+        }
+
+        let separator = format!(r#" "," {ws} "#);
+        let mut body = required_pairs.join(&separator);
+        if !optional_pairs.is_empty() {
+            let optional = self.optional_pairs(name, &optional_pairs, &separator);
+            if body.is_empty() {
+                body = format!("{optional}?");
+            } else {
+                body = format!("{body} ({separator}{optional})?");
+            }
+        }
+
+        Ok(self.rule(name, format!(r#""{{" {ws} {body} "}}" {ws}"#)))
+    }
+
+    /// Returns an expression matching any non-empty, ordered subset of `pairs`.
+    fn optional_pairs(&mut self, name: &str, pairs: &[String], separator: &str) -> String {
+        // PANIC SAFETY: only called with non-empty `pairs`
+        let (last, rest) = pairs.split_last().unwrap();
+        let mut next = self.rule(&format!("{name}-optional"), last.clone());
+
+        for pair in rest.iter().rev() {
+            next = self.rule(
+                &format!("{name}-optional"),
+                format!("{pair} ({separator}{next})? | {next}"),
+            );
+        }
+
+        next
+    }
+
+    /// Returns an expression matching the arrays that are valid against the schema `obj`.
+    fn array(
+        &mut self,
+        name: &str,
+        obj: &Map<String, Value>,
+        root: &Value,
+    ) -> Result<String, GrammarError> {
+        let item = match obj.get("items") {
+            Some(items) => self.visit(&format!("{name}-item"), items, root)?,
+            None => self.primitive("value"),
+        };
+        let min_items = obj.get("minItems").and_then(Value::as_u64).unwrap_or(0);
+
+        let ws = self.primitive("ws");
+        let items = format!(r#"{item} ( "," {ws} {item} )*"#);
+        let body = if min_items > 0 {
+            format!(r#""[" {ws} {items} "]" {ws}"#)
+        } else {
+            format!(r#""[" {ws} ( {items} )? "]" {ws}"#)
+        };
+
+        Ok(self.rule(name, body))
+    }
+
+    /// Returns the name of the rule matching the values valid against the schema referenced by
+    /// `reference`, which must point into the `root` document.
+    fn reference(&mut self, reference: &str, root: &Value) -> Result<String, GrammarError> {
+        if let Some(name) = self.refs.get(reference) {
+            return Ok(name.clone());
+        }
+
+        let schema = reference
+            .strip_prefix('#')
+            .and_then(|pointer| root.pointer(pointer))
+            .ok_or_else(|| GrammarError::UnresolvedRef(reference.to_string()))?;
+
+        // The rule is reserved before visiting the schema, so that recursive schemas terminate.
+        let name = self.reserve(reference.rsplit('/').next().unwrap_or("ref"));
+        self.refs.insert(reference.to_string(), name.clone());
+
+        let body = self.visit(&format!("{name}-value"), schema, root)?;
+        self.set(&name, body);
+
+        Ok(name)
+    }
+
+    /// Returns the full text of the grammar, whose root rule has the given body.
+    fn build(self, root: String) -> String {
+        let mut text = format!("root ::= {root}\n");
+        for (name, body) in self.rules {
+            text.push_str(&format!("{name} ::= {body}\n"));
+        }
+
+        text
+    }
+}
+
+/// Returns a GBNF literal matching exactly `text`.
+fn literal(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str(r#"\""#),
+            '\\' => out.push_str(r"\\"),
+            '\n' => out.push_str(r"\n"),
+            '\r' => out.push_str(r"\r"),
+            '\t' => out.push_str(r"\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use edgen_core::llm::FunctionStub;
+
+    use super::*;
+
+    fn rule<'a>(grammar: &'a str, name: &str) -> &'a str {
+        let prefix = format!("{name} ::= ");
+        grammar
+            .lines()
+            .find_map(|line| line.strip_prefix(prefix.as_str()))
+            .unwrap_or_else(|| panic!("missing rule {name} in:\n{grammar}"))
+    }
+
+    #[test]
+    fn literals_are_escaped() {
+        assert_eq!(literal(r#""a\b""#), r#""\"a\\b\"""#);
+        assert_eq!(literal("line\n"), r#""line\n""#);
+    }
+
+    #[test]
+    fn primitive_schemas() {
+        let grammar = json_schema_grammar(&json!({"type": "integer"})).unwrap();
+        assert_eq!(rule(&grammar, "root"), "integer");
+        assert!(grammar.contains("\nws ::= "));
+
+        let grammar = json_schema_grammar(&json!({})).unwrap();
+        assert_eq!(rule(&grammar, "root"), "value");
+        assert!(grammar.contains("\nobject ::= "));
+        assert!(grammar.contains("\narray ::= "));
+    }
+
+    #[test]
+    fn object_properties() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "city": {"type": "string"},
+                "unit": {"enum": ["celsius", "fahrenheit"]},
+            },
+            "required": ["city"],
+        });
+        let grammar = json_schema_grammar(&schema).unwrap();
+
+        assert_eq!(
+            rule(&grammar, "root-value"),
+            r#""{" ws "\"city\"" ws ":" ws string ( "," ws root-value-optional)? "}" ws"#
+        );
+        assert_eq!(
+            rule(&grammar, "root-value-optional"),
+            r#""\"unit\"" ws ":" ws root-value-unit"#
+        );
+        assert_eq!(
+            rule(&grammar, "root-value-unit"),
+            r#"("\"celsius\"" | "\"fahrenheit\"") ws"#
+        );
+    }
+
+    #[test]
+    fn optional_properties_keep_order() {
+        let schema = json!({
+            "properties": {
+                "a": {"type": "boolean"},
+                "b": {"type": "null"},
+            },
+        });
+        let grammar = json_schema_grammar(&schema).unwrap();
+
+        assert_eq!(
+            rule(&grammar, "root-value"),
+            r#""{" ws root-value-optional-1? "}" ws"#
+        );
+        assert_eq!(
+            rule(&grammar, "root-value-optional-1"),
+            r#""\"a\"" ws ":" ws boolean ( "," ws root-value-optional)? | root-value-optional"#
+        );
+    }
+
+    #[test]
+    fn arrays_and_alternatives() {
+        let schema = json!({
+            "type": "array",
+            "items": {"type": ["string", "null"]},
+            "minItems": 1,
+        });
+        let grammar = json_schema_grammar(&schema).unwrap();
+
+        assert_eq!(
+            rule(&grammar, "root-value"),
+            r#""[" ws root-value-item ( "," ws root-value-item )* "]" ws"#
+        );
+        assert_eq!(rule(&grammar, "root-value-item"), "string | null");
+    }
+
+    #[test]
+    fn recursive_references() {
+        let schema = json!({
+            "$ref": "#/$defs/node",
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {"children": {"type": "array", "items": {"$ref": "#/$defs/node"}}},
+                },
+            },
+        });
+        let grammar = json_schema_grammar(&schema).unwrap();
+
+        assert_eq!(rule(&grammar, "root"), "node");
+        assert_eq!(rule(&grammar, "node"), "node-value");
+        assert!(rule(&grammar, "node-value-children").contains("\"[\" ws ( node"));
+
+        let missing = json!({"$ref": "#/$defs/missing"});
+        assert!(matches!(
+            json_schema_grammar(&missing),
+            Err(GrammarError::UnresolvedRef(_))
+        ));
+    }
+
+    #[test]
+    fn json_object() {
+        let grammar = json_object_grammar();
+
+        assert_eq!(rule(&grammar, "root"), "object");
+        assert!(grammar.contains("\nvalue ::= "));
+    }
+
+    #[test]
+    fn grammar_precedence() {
+        let mut args = CompletionArgs::default();
+        assert!(grammar_for(&args).unwrap().is_none());
+
+        args.response_format = Some(ResponseFormat::Text);
+        assert!(grammar_for(&args).unwrap().is_none());
+
+        args.response_format = Some(ResponseFormat::JsonSchema {
+            schema: Some(json!({"type": "boolean"})),
+        });
+        let grammar = grammar_for(&args).unwrap().unwrap();
+        assert_eq!(rule(&grammar, "root"), "boolean");
+
+        // tools are not required, so the response format still applies
+        args.tools = Some(vec![ToolStub::Function {
+            function: FunctionStub {
+                description: None,
+                name: "get_time".to_string(),
+                parameters: Value::Null,
+            },
+        }]);
+        args.tool_choice = Some(ToolChoice::Auto);
+        let grammar = grammar_for(&args).unwrap().unwrap();
+        assert_eq!(rule(&grammar, "root"), "boolean");
+
+        args.tool_choice = Some(ToolChoice::Required);
+        let grammar = grammar_for(&args).unwrap().unwrap();
+        assert_eq!(rule(&grammar, "get-time-call").matches("object").count(), 1);
+
+        args.grammar = Some("root ::= \"yes\" | \"no\"\n".to_string());
+        assert_eq!(
+            grammar_for(&args).unwrap().as_deref(),
+            Some("root ::= \"yes\" | \"no\"\n")
+        );
+    }
+
+    #[test]
+    fn tool_calls() {
+        let tools = vec![ToolStub::Function {
+            function: FunctionStub {
+                description: None,
+                name: "get_weather".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {"city": {"type": "string"}},
+                    "required": ["city"],
+                }),
+            },
+        }];
+
+        assert!(tool_calls_grammar(&tools, &ToolChoice::Auto)
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            tool_calls_grammar(&tools, &ToolChoice::Function("get_time".to_string())),
+            Err(GrammarError::UnknownTool(_))
+        ));
+
+        let grammar = tool_calls_grammar(&tools, &ToolChoice::Required)
+            .unwrap()
+            .unwrap();
+        assert!(rule(&grammar, "root").contains(r#""\"tool_calls\"""#));
+        assert_eq!(rule(&grammar, "call"), "get-weather-call");
+        assert!(rule(&grammar, "get-weather-call").contains(r#""\"get_weather\"""#));
+        assert!(rule(&grammar, "get-weather-call").contains("get-weather-arguments"));
+    }
+}
@@ -10,39 +10,74 @@
  * limitations under the License.
  */
 
+use std::future::Future;
 use std::mem::take;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
 
 use blake3::Hasher;
 use dashmap::DashMap;
+use futures::channel::mpsc::UnboundedReceiver;
 use futures::executor::block_on;
-use futures::Stream;
-use llama_cpp::standard_sampler::StandardSampler;
-use llama_cpp::{
-    CompletionHandle, EmbeddingsParams, LlamaModel, LlamaParams, LlamaSession, SessionParams,
-    TokensToStrings,
-};
+use futures::{Stream, StreamExt};
+use llama_cpp::{LlamaModel, LlamaParams, LlamaSession, SessionParams, Token, TokensToStrings};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
-use tokio::task::JoinHandle;
-use tokio::time::{interval, MissedTickBehavior};
+use tokio::sync::OnceCell;
+use tokio::task::{spawn_blocking, JoinHandle};
+use tokio::time::{interval, sleep_until, Instant, MissedTickBehavior, Sleep};
 use tokio::{select, spawn};
-use tracing::{error, info};
+use tracing::{error, info, warn};
+use uuid::Uuid;
 
+use edgen_core::cancellation::CancellationToken;
+use edgen_core::chat_template::{ChatTemplate, Prompt};
 use edgen_core::cleanup_interval;
+use edgen_core::context_overflow::{fit_prompt, ContextOverflow, FittedPrompt};
+use edgen_core::embeddings::EmbeddingsPlan;
+use edgen_core::fim::FimTokens;
+use edgen_core::gguf::GgufMetadata;
 use edgen_core::llm::{
-    inactive_llm_session_ttl, inactive_llm_ttl, CompletionArgs, LLMEndpoint, LLMEndpointError,
-    ASSISTANT_TAG, SYSTEM_TAG, TOOL_TAG, USER_TAG,
+    inactive_llm_session_ttl, inactive_llm_ttl, ChatSession, ChatSessionInfo, Completion,
+    CompletionArgs, CompletionChunk, Embeddings, EmbeddingsArgs, EmbeddingsInput, FinishReason,
+    LLMEndpoint, LLMEndpointError, TextCompletionArgs, TokenLogprob, TokenUsage, ToolStub,
+    TopLogprob,
 };
+use edgen_core::memory::{MemoryReservation, MEMORY_BUDGET};
 use edgen_core::perishable::{ActiveSignal, Perishable, PerishableReadGuard, PerishableWriteGuard};
+use edgen_core::session_cache::SessionCache;
 use edgen_core::settings::{DevicePolicy, SETTINGS};
-
-// TODO this should be in settings
-const SINGLE_MESSAGE_LIMIT: usize = 4096;
+use edgen_core::stopping_stream::StoppingStream;
+use edgen_core::tools::ToolCallStream;
+use edgen_core::vision::{split_images, ImageInput, PromptPart};
+
+use crate::adapter::{AdapterDecoder, AdapterParams};
+use crate::batch::{BatchParams, BatchScheduler};
+use crate::embeddings::{EmbeddingsDecoder, EmbeddingsParams};
+use crate::sampler::{EdgenSampler, SampledLogprobs};
+use crate::speculative::{SpeculativeDecoder, SpeculativeParams};
+use crate::vision::{PromptChunk, VisionDecoder, VisionParams};
+
+mod adapter;
+mod batch;
+mod clip;
+mod embeddings;
+mod grammar;
+mod sampler;
+mod sequence;
+mod speculative;
+mod vision;
+
+/// The number of tokens in the context of a session, if the model doesn't specify how many it was
+/// trained with.
 const CONTEXT_SIZE: u32 = 4096;
 
+/// The number of tokens of the context left free when embedding, for the BOS token.
+const EMBEDDINGS_RESERVED_TOKENS: usize = 1;
+
 /// A large language model endpoint, implementing [`LLMEndpoint`] using a [`llama_cpp`] backend.
 pub struct LlamaCppEndpoint {
     /// A map of the models currently loaded into memory, with their path as the key.
@@ -78,7 +113,7 @@ impl LLMEndpoint for LlamaCppEndpoint {
         &self,
         model_path: impl AsRef<Path> + Send,
         args: CompletionArgs,
-    ) -> Result<String, LLMEndpointError> {
+    ) -> Result<Vec<Completion>, LLMEndpointError> {
         let model = self.get(model_path).await;
         model.chat_completions(args).await
     }
@@ -87,18 +122,65 @@ impl LLMEndpoint for LlamaCppEndpoint {
         &self,
         model_path: impl AsRef<Path> + Send,
         args: CompletionArgs,
-    ) -> Result<Box<dyn Stream<Item = String> + Unpin + Send>, LLMEndpointError> {
+    ) -> Result<Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>, LLMEndpointError> {
         let model = self.get(model_path).await;
         model.stream_chat_completions(args).await
     }
 
+    async fn completions(
+        &self,
+        model_path: impl AsRef<Path> + Send,
+        args: TextCompletionArgs,
+    ) -> Result<Vec<Completion>, LLMEndpointError> {
+        let model = self.get(model_path).await;
+        model.completions(args).await
+    }
+
+    async fn stream_completions(
+        &self,
+        model_path: impl AsRef<Path> + Send,
+        args: TextCompletionArgs,
+    ) -> Result<Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>, LLMEndpointError> {
+        let model = self.get(model_path).await;
+        model.stream_completions(args).await
+    }
+
+    async fn render_prompt(
+        &self,
+        model_path: impl AsRef<Path> + Send,
+        args: CompletionArgs,
+    ) -> Result<String, LLMEndpointError> {
+        let model = self.get(model_path).await;
+        Ok(args.prompt(&model.template().await)?.text)
+    }
+
     async fn embeddings(
         &self,
         model_path: impl AsRef<Path> + Send,
-        inputs: Vec<String>,
-    ) -> Result<Vec<Vec<f32>>, LLMEndpointError> {
+        args: EmbeddingsArgs,
+    ) -> Result<Embeddings, LLMEndpointError> {
         let model = self.get(model_path).await;
-        model.embeddings(inputs).await
+        model.embeddings(args).await
+    }
+
+    async fn chat_sessions(&self) -> Vec<ChatSessionInfo> {
+        let mut sessions = vec![];
+        for model in self.models.iter() {
+            sessions.extend(model.chat_sessions().await);
+        }
+        sessions
+    }
+
+    async fn delete_chat_session(&self, id: Uuid) -> Result<(), LLMEndpointError> {
+        if self
+            .models
+            .iter()
+            .any(|model| model.delete_chat_session(id))
+        {
+            Ok(())
+        } else {
+            Err(LLMEndpointError::SessionNotFound)
+        }
     }
 
     fn reset(&self) {
@@ -135,12 +217,34 @@ impl Drop for LlamaCppEndpoint {
 
 /// A [`LlamaModel`] (as well as its associated [`LlamaSession`]s) that unloads itself from memory after not being used
 /// for a period of time.
+///
+/// Chat sessions that are not in use are saved to the session cache, if enabled, when they expire
+/// or when this model is dropped, and are restored from it when a matching chat continues.
+///
+/// Explicit [`ChatSession`]s are kept apart, by id, and are never saved to the session cache.
+///
+/// If batching is enabled, one-shot completions are decoded together by a [`BatchScheduler`],
+/// which also unloads itself after not being used for a period of time. If the model has a draft
+/// model, one-shot completions of a single choice are instead decoded by a
+/// [`SpeculativeDecoder`], which unloads itself likewise.
+///
+/// Completions with a LoRA adapter are decoded by an [`AdapterDecoder`] per adapter, by name,
+/// and chats with images by a [`VisionDecoder`], which also unload themselves likewise. Embeddings
+/// are decoded by an [`EmbeddingsDecoder`], which unloads itself likewise.
 struct UnloadingModel {
     model: Perishable<LlamaModel>,
+    batch: Perishable<BatchScheduler>,
+    speculative: Perishable<SpeculativeDecoder>,
+    adapters: DashMap<String, Arc<Perishable<AdapterDecoder>>>,
+    vision: Perishable<VisionDecoder>,
+    embeddings: Perishable<EmbeddingsDecoder>,
     path: PathBuf,
+    metadata: OnceCell<Option<GgufMetadata>>,
     sessions: Arc<DashMap<SessionId, Perishable<LlamaSession>>>,
+    named_sessions: Arc<DashMap<Uuid, NamedSession>>,
+    cache: Option<SessionCache>,
     maintenance_thread: JoinHandle<()>,
-    finished_tx: UnboundedSender<(SessionId, Perishable<LlamaSession>)>,
+    finished_tx: UnboundedSender<FinishedSession>,
 }
 
 impl UnloadingModel {
@@ -150,18 +254,55 @@ impl UnloadingModel {
     /// order to be loaded.
     async fn new(model_path: impl AsRef<Path>) -> Self {
         let sessions: Arc<DashMap<SessionId, Perishable<LlamaSession>>> = Default::default();
+        let named_sessions: Arc<DashMap<Uuid, NamedSession>> = Default::default();
         let (tx, mut rx) = unbounded_channel();
 
+        let cache = {
+            let settings = SETTINGS.read().await;
+            let settings = settings.read().await;
+            (settings.session_cache_size > 0).then(|| {
+                SessionCache::new(&settings.session_cache_dir, settings.session_cache_size)
+            })
+        };
+
         let sessions_clone = sessions.clone();
+        let named_clone = named_sessions.clone();
+        let cache_clone = cache.clone();
+        let path = model_path.as_ref().to_path_buf();
         let maintenance_thread = spawn(async move {
             let mut interval = interval(cleanup_interval());
             interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
             loop {
                 select! {
-                    _ = interval.tick() => sessions_clone.retain(move |_, session| block_on(session.is_alive())),
+                    _ = interval.tick() => {
+                        sessions_clone.retain(move |_, session| block_on(session.is_alive()));
+                        named_clone.retain(move |_, session| match session {
+                            NamedSession::Idle { session, .. } => block_on(session.is_alive()),
+                            NamedSession::Busy => true,
+                        });
+                    }
                     item = rx.recv() => {
-                        if let Some((id, session)) = item {
+                        if let Some(FinishedSession { id, name, session, trim }) = item {
+                            if let Some(trim) = trim {
+                                if let Err(e) = trim_session(&session, trim).await {
+                                    warn!("Discarding session that could not be trimmed: {e}");
+                                    if let Some(name) = name {
+                                        named_clone.remove(&name);
+                                    }
+                                    continue;
+                                }
+                            }
+                            if let Some(name) = name {
+                                // the session is gone if it was deleted while in use
+                                if let Some(mut named) = named_clone.get_mut(&name) {
+                                    *named = NamedSession::Idle { id, session };
+                                }
+                                continue;
+                            }
+                            if let Some(cache) = &cache_clone {
+                                save_on_perish(&session, cache.clone(), id.cache_key(&path));
+                            }
                             sessions_clone.insert(id, session);
                         }
                     }
@@ -169,10 +310,32 @@ impl UnloadingModel {
             }
         });
 
+        // the weights are loaded whole, so the model takes about as much memory as its file
+        let model_size = std::fs::metadata(&model_path).map_or(0, |metadata| metadata.len());
+
         Self {
-            model: Perishable::with_ttl(inactive_llm_ttl()),
+            model: Perishable::with_ttl(inactive_llm_ttl())
+                .with_memory_budget(MEMORY_BUDGET.clone(), move |_| model_size),
+            batch: Perishable::with_ttl(inactive_llm_session_ttl())
+                .with_memory_budget(MEMORY_BUDGET.clone(), |batch| batch.memory_size() as u64),
+            speculative: Perishable::with_ttl(inactive_llm_session_ttl())
+                .with_memory_budget(MEMORY_BUDGET.clone(), |decoder| {
+                    decoder.memory_size() as u64
+                }),
+            adapters: Default::default(),
+            vision: Perishable::with_ttl(inactive_llm_session_ttl())
+                .with_memory_budget(MEMORY_BUDGET.clone(), |decoder| {
+                    decoder.memory_size() as u64
+                }),
+            embeddings: Perishable::with_ttl(inactive_llm_session_ttl())
+                .with_memory_budget(MEMORY_BUDGET.clone(), |decoder| {
+                    decoder.memory_size() as u64
+                }),
             path: model_path.as_ref().to_path_buf(),
+            metadata: OnceCell::new(),
             sessions,
+            named_sessions,
+            cache,
             maintenance_thread,
             finished_tx: tx,
         }
@@ -183,158 +346,903 @@ impl UnloadingModel {
         self.model.is_alive().await
     }
 
+    /// Returns the metadata of the model file, if it could be read.
+    ///
+    /// The metadata is only read once, without loading the model.
+    async fn metadata(&self) -> Option<&GgufMetadata> {
+        self.metadata
+            .get_or_init(|| async {
+                let path = self.path.clone();
+                match spawn_blocking(move || GgufMetadata::read(path)).await {
+                    Ok(Ok(metadata)) => Some(metadata),
+                    Ok(Err(e)) => {
+                        warn!(
+                            "Failed to read the metadata of {}: {e}",
+                            self.path.display()
+                        );
+                        None
+                    }
+                    Err(e) => {
+                        error!("Failed to join the metadata reading thread: {e}");
+                        None
+                    }
+                }
+            })
+            .await
+            .as_ref()
+    }
+
+    /// Returns the chat template of this model, which is read from the model file unless the
+    /// settings override it.
+    async fn template(&self) -> ChatTemplate {
+        let setting = SETTINGS
+            .read()
+            .await
+            .read()
+            .await
+            .model_settings(&self.path)
+            .chat_template;
+        let metadata = self.metadata().await;
+
+        // llama.cpp adds the BOS token by itself when tokenizing the prompt
+        ChatTemplate::for_model(setting.as_deref(), metadata).with_bos_token("")
+    }
+
+    /// Returns the number of tokens in the context of this model's sessions.
+    ///
+    /// This is the size configured for the model, or else the size the model was trained with
+    /// (`n_ctx_train`), or else [`CONTEXT_SIZE`].
+    async fn context_size(&self) -> u32 {
+        let configured = SETTINGS
+            .read()
+            .await
+            .read()
+            .await
+            .model_settings(&self.path)
+            .context_size;
+        if let Some(size) = configured {
+            return size;
+        }
+
+        match self.metadata().await.and_then(|m| m.context_length()) {
+            Some(trained) => u32::try_from(trained).unwrap_or(u32::MAX),
+            None => CONTEXT_SIZE,
+        }
+    }
+
     /// Either takes an existing chat [`LlamaSession`] compatible with the provided prompt from the
     /// `sessions` collection, or creates a new one.
     ///
     /// The matching [`SessionId`] and the new context derived from `prompt` are also returned.
     async fn take_chat_session<'a>(
         &self,
-        prompt: &'a str,
+        prompt: &'a Prompt,
     ) -> (Perishable<LlamaSession>, SessionId, &'a str) {
-        let (id, new_context) = SessionId::chat(prompt);
+        let (id, new_context) = SessionId::chat(&prompt.text, prompt.history_len);
+
+        let mut live = None;
+        if let Some((_, session)) = self.sessions.remove(&id) {
+            // a session evicted to stay within the memory budget may have been saved to the disk
+            // cache by its finalizer, so it can still be restored below
+            if session.is_alive().await {
+                live = Some(session);
+            } else {
+                info!("Matching session has perished");
+            }
+        }
 
-        let session_perishable = if let Some((_, session)) = self.sessions.remove(&id) {
+        let session_perishable = if let Some(session) = live {
             info!("Matching session found, continuing");
+
+            // the session is about to change, and would no longer match its cache key
+            session.clear_finalizer();
+            session
+        } else if let Some(session) = self.restore_session(&id).await {
+            info!("Matching session restored from the cache, continuing");
             session
         } else {
             info!("No matching session found, creating new one");
-            Perishable::with_ttl(inactive_llm_session_ttl())
+            new_chat_session()
         };
 
         (session_perishable, id, new_context)
     }
 
-    /// Computes the full chat completions for the provided [`CompletionArgs`].
-    async fn chat_completions(&self, args: CompletionArgs) -> Result<String, LLMEndpointError> {
-        let (_model_signal, model_guard) = get_or_init_model(&self.model, &self.path).await?;
+    /// Takes the [`LlamaSession`] of an explicit [`ChatSession`], marking it as busy, or creates a
+    /// new one.
+    ///
+    /// If the chat in `prompt` does not continue the context of an existing session, a new session
+    /// takes its place. The matching [`SessionId`] and the new context derived from `prompt` are
+    /// also returned.
+    async fn take_named_session<'a>(
+        &self,
+        chat_session: ChatSession,
+        prompt: &'a Prompt,
+    ) -> Result<(Perishable<LlamaSession>, SessionId, &'a str), LLMEndpointError> {
+        let previous = match chat_session {
+            ChatSession::Create(name) => {
+                info!("Creating chat session {name}");
+                self.named_sessions.insert(name, NamedSession::Busy);
+                None
+            }
+            ChatSession::Continue(name) => {
+                let mut named = self
+                    .named_sessions
+                    .get_mut(&name)
+                    .ok_or(LLMEndpointError::SessionNotFound)?;
+                match std::mem::replace(&mut *named, NamedSession::Busy) {
+                    NamedSession::Idle { id, session } => Some((name, id, session)),
+                    NamedSession::Busy => return Err(LLMEndpointError::SessionBusy),
+                }
+            }
+        };
 
-        let prompt = format!("{}<|ASSISTANT|>", args.messages);
+        if let Some((name, previous_id, session)) = previous {
+            if !session.is_alive().await {
+                self.named_sessions.remove(&name);
+                return Err(LLMEndpointError::SessionNotFound);
+            }
 
-        if args.one_shot.unwrap_or(false) {
-            info!("Allocating one-shot LLM session");
-            let mut params = SessionParams::default();
-            let threads = SETTINGS.read().await.read().await.auto_threads(false);
+            let (id, new_context) = SessionId::chat(&prompt.text, prompt.history_len);
+            if id == previous_id {
+                info!("Continuing chat session {name}");
+                return Ok((session, id, new_context));
+            }
+            info!("The chat no longer matches the context of session {name}, starting over");
+        }
 
-            // TODO handle optional params
-            //params.seed = args.seed;
-            params.n_threads = threads;
-            params.n_threads_batch = threads;
-            params.n_ctx = args.context_hint.unwrap_or(CONTEXT_SIZE);
+        let (id, new_context) = SessionId::chat(&prompt.text, 0);
+        Ok((new_chat_session(), id, new_context))
+    }
 
-            let mut session = model_guard
-                .create_session(params)
-                .map_err(move |e| LLMEndpointError::SessionCreationFailed(e.to_string()))?;
+    /// Returns the state of the explicit [`ChatSession`]s of this model.
+    async fn chat_sessions(&self) -> Vec<ChatSessionInfo> {
+        let mut infos = vec![];
+        for named in self.named_sessions.iter() {
+            let (tokens, expires_in) = match named.value() {
+                NamedSession::Idle { session, .. } => {
+                    let Some(tokens) = session.peek().await.map(|s| s.context_size() as u32) else {
+                        continue;
+                    };
+                    (Some(tokens), session.expires_in().await)
+                }
+                NamedSession::Busy => (None, None),
+            };
+
+            infos.push(ChatSessionInfo {
+                id: *named.key(),
+                model_path: self.path.clone(),
+                tokens,
+                expires_in,
+            });
+        }
+
+        infos
+    }
+
+    /// Deletes the explicit [`ChatSession`] with `id`, returning **`true`** if this model had it.
+    fn delete_chat_session(&self, id: Uuid) -> bool {
+        self.named_sessions.remove(&id).is_some()
+    }
 
+    /// Restores the chat [`LlamaSession`] matching `id` from the session cache, if it was saved
+    /// there.
+    ///
+    /// A saved state that cannot be restored is removed from the cache.
+    async fn restore_session(&self, id: &SessionId) -> Option<Perishable<LlamaSession>> {
+        let cache = self.cache.as_ref()?;
+        let key = id.cache_key(&self.path);
+        let state_path = cache.get(&key)?;
+
+        let session = new_chat_session();
+        let restored = async {
+            let (_model_signal, model_guard) = get_or_init_model(&self.model, &self.path).await?;
+            let model = model_guard.clone();
+            let params = session_params(self.context_size().await).await;
+
+            // loading a large state takes a while, so it is done on a blocking thread
             session
-                .advance_context_async(prompt)
+                .get_or_try_init(move || async move {
+                    info!(
+                        "Restoring LLM session from {}",
+                        state_path.to_string_lossy()
+                    );
+                    spawn_blocking(move || {
+                        let mut session = model.create_session(params).map_err(move |e| {
+                            LLMEndpointError::SessionCreationFailed(e.to_string())
+                        })?;
+                        session.load_state(state_path).map_err(move |e| {
+                            LLMEndpointError::SessionCreationFailed(e.to_string())
+                        })?;
+                        Ok::<_, LLMEndpointError>(session)
+                    })
+                    .await
+                    .map_err(move |e| LLMEndpointError::SessionCreationFailed(e.to_string()))?
+                })
                 .await
-                .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?;
+                .map(|_| ())
+        }
+        .await;
+
+        match restored {
+            Ok(()) => Some(session),
+            Err(e) => {
+                warn!("Discarding cached session that could not be restored: {e}");
+                cache.remove(&key);
+                None
+            }
+        }
+    }
 
-            let sampler = StandardSampler::default();
-            let handle = session
-                .start_completing_with(sampler, SINGLE_MESSAGE_LIMIT)
-                .map_err(|e| LLMEndpointError::Advance(e.to_string()))?;
+    /// Computes the full chat completions for the provided [`CompletionArgs`], one for each
+    /// choice.
+    async fn chat_completions(
+        &self,
+        args: CompletionArgs,
+    ) -> Result<Vec<Completion>, LLMEndpointError> {
+        let choices = args.choices();
+        let stream = self.stream_chat_completions(args).await?;
+        Ok(collect_completions(stream, choices).await)
+    }
 
-            Ok(handle.into_string_async().await)
-        } else {
-            let (session, mut id, new_context) = self.take_chat_session(&prompt).await;
+    /// Return a [`Box`]ed [`Stream`] of chat completions computed for the provided
+    /// [`CompletionArgs`].
+    ///
+    /// Fails with [`LLMEndpointError::Timeout`] if the deadline of the completions passes before
+    /// they start (see [`UnloadingModel::start_chat_completions`]).
+    async fn stream_chat_completions(
+        &self,
+        args: CompletionArgs,
+    ) -> Result<Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>, LLMEndpointError> {
+        let deadline = args.deadline.map(Instant::from_std);
+        before_deadline(deadline, self.start_chat_completions(args)).await
+    }
+
+    /// Starts the chat completions for the provided [`CompletionArgs`], loading the model and
+    /// evaluating the prompt.
+    ///
+    /// The prompt is evaluated once, and every choice after the first is generated in a copy of
+    /// the resulting session. Completions with an adapter are generated by its [`AdapterDecoder`]
+    /// instead (see [`UnloadingModel::start_adapter`]), and chats with images by the
+    /// [`VisionDecoder`] (see [`UnloadingModel::start_vision`]).
+    async fn start_chat_completions(
+        &self,
+        mut args: CompletionArgs,
+    ) -> Result<Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>, LLMEndpointError> {
+        // sessions are decoded without the adapter, so they cannot be continued with one
+        if args.adapter.is_some() && args.session.is_some() {
+            return Err(LLMEndpointError::AdapterSession);
+        }
 
-            let (_session_signal, handle) = {
-                let (session_signal, mut session_guard) =
-                    get_or_init_session(&session, model_guard.clone()).await?;
+        let deadline = args.deadline.map(Instant::from_std);
+        let (model_signal, model_guard) = get_or_init_model(&self.model, &self.path).await?;
 
-                session_guard
-                    .advance_context_async(new_context)
-                    .await
-                    .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?;
-                id.advance(new_context);
+        let template = self.template().await;
+        let stop_words = args.stop_words(&template);
+        let context_size = self.context_size().await;
+        let tools = args.active_tools().to_vec();
+        let (max_tokens, policy) = {
+            let settings = SETTINGS.read().await;
+            let settings = settings.read().await;
+            (
+                settings.max_tokens(&self.path, args.max_tokens) as usize,
+                settings.context_overflow(&self.path, args.context_overflow),
+            )
+        };
 
-                let sampler = StandardSampler::default();
-                let handle = session_guard
-                    .start_completing_with(sampler, SINGLE_MESSAGE_LIMIT)
-                    .map_err(|e| LLMEndpointError::Advance(e.to_string()))?;
+        let images = args.images();
+        if !images.is_empty() && args.adapter.is_some() {
+            return Err(LLMEndpointError::ImagesUnsupported(
+                "with an adapter".to_string(),
+            ));
+        }
+        if !images.is_empty() && args.session.is_some() {
+            return Err(LLMEndpointError::ImagesUnsupported(
+                "in an explicit chat session".to_string(),
+            ));
+        }
 
-                (session_signal, handle)
+        // explicit sessions are kept, even if the request is marked as one-shot
+        let one_shot = args.session.is_none()
+            && args.adapter.is_none()
+            && images.is_empty()
+            && args.one_shot.unwrap_or(false);
+        let n_ctx = if one_shot {
+            args.context_hint.unwrap_or(context_size)
+        } else {
+            context_size
+        };
+        let FittedPrompt {
+            prompt, overflow, ..
+        } = fit_prompt(&mut args, &template, policy, n_ctx, |text| {
+            model_guard
+                .tokenize_bytes(text, true, false)
+                .map(|tokens| tokens.len() as u32)
+                .map_err(move |e| LLMEndpointError::Advance(e.to_string()))
+        })?;
+        if overflow.dropped_messages > 0 {
+            info!(
+                "Dropped {} messages to fit the chat into the context",
+                overflow.dropped_messages
+            );
+        }
+
+        let samplers = EdgenSampler::for_choices(&args)?;
+        let streams = if let Some(adapter) = &args.adapter {
+            self.start_adapter(
+                adapter,
+                &prompt.text,
+                &model_guard,
+                model_signal,
+                samplers,
+                max_tokens,
+                stop_words,
+            )
+            .await?
+        } else if !images.is_empty() {
+            self.start_vision(
+                &prompt.text,
+                &images,
+                &model_guard,
+                model_signal,
+                samplers,
+                max_tokens,
+                stop_words,
+            )
+            .await?
+        } else if one_shot {
+            self.start_oneshot(
+                &prompt.text,
+                n_ctx,
+                args.seed,
+                &model_guard,
+                model_signal,
+                samplers,
+                max_tokens,
+                stop_words,
+            )
+            .await?
+        } else {
+            let (session, id, new_context) = match args.session {
+                Some(chat_session) => self.take_named_session(chat_session, &prompt).await?,
+                None => self.take_chat_session(&prompt).await,
             };
+            let name = args.session.map(|chat_session| chat_session.id());
+            let busy = BusySession {
+                sessions: &self.named_sessions,
+                name,
+            };
+            let tx = self.finished_tx.clone();
 
-            let res = handle.into_string_async().await;
+            let streams = CompletionStream::new(
+                session,
+                id,
+                name,
+                new_context,
+                model_guard.clone(),
+                model_signal,
+                context_size,
+                samplers,
+                max_tokens,
+                stop_words,
+                tx,
+            )
+            .await?;
 
-            self.sessions.insert(id, session);
+            // the streams hand the session back once they are finished
+            busy.keep();
+            streams
+        };
 
-            Ok(res)
-        }
+        let streams = streams
+            .into_iter()
+            .map(|stream| {
+                stream
+                    .with_context_overflow(overflow)
+                    .with_deadline(deadline)
+            })
+            .collect();
+        Ok(merge_choices(streams, &tools))
     }
 
-    /// Return a [`Box`]ed [`Stream`] of chat completions computed for the provided
-    /// [`CompletionArgs`].
-    async fn stream_chat_completions(
+    /// Computes the full completion of the raw prompt in the provided [`TextCompletionArgs`], one
+    /// for each choice.
+    async fn completions(
         &self,
-        args: CompletionArgs,
-    ) -> Result<Box<dyn Stream<Item = String> + Unpin + Send>, LLMEndpointError> {
-        let (model_signal, model_guard) = get_or_init_model(&self.model, &self.path).await?;
+        args: TextCompletionArgs,
+    ) -> Result<Vec<Completion>, LLMEndpointError> {
+        let choices = args.sampling.choices();
+        let stream = self.stream_completions(args).await?;
+        Ok(collect_completions(stream, choices).await)
+    }
 
-        let prompt = format!("{}<|ASSISTANT|>", args.messages);
+    /// Return a [`Box`]ed [`Stream`] of the completion of the raw prompt in the provided
+    /// [`TextCompletionArgs`].
+    ///
+    /// Fails with [`LLMEndpointError::Timeout`] if the deadline of the completions passes before
+    /// they start (see [`UnloadingModel::start_completions`]).
+    async fn stream_completions(
+        &self,
+        args: TextCompletionArgs,
+    ) -> Result<Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>, LLMEndpointError> {
+        let deadline = args.sampling.deadline.map(Instant::from_std);
+        before_deadline(deadline, self.start_completions(args)).await
+    }
 
-        if args.one_shot.unwrap_or(false) {
-            info!("Allocating one-shot LLM session");
-            let mut params = SessionParams::default();
-            let threads = SETTINGS.read().await.read().await.auto_threads(false);
+    /// Starts the completion of the raw prompt in the provided [`TextCompletionArgs`], loading
+    /// the model and evaluating the prompt.
+    ///
+    /// There is no chat history to continue in text completions, so they are always one-shot (see
+    /// [`UnloadingModel::start_oneshot`]), unless they have an adapter (see
+    /// [`UnloadingModel::start_adapter`]).
+    async fn start_completions(
+        &self,
+        args: TextCompletionArgs,
+    ) -> Result<Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>, LLMEndpointError> {
+        let deadline = args.sampling.deadline.map(Instant::from_std);
+        let (model_signal, model_guard) = get_or_init_model(&self.model, &self.path).await?;
 
-            // TODO handle optional params
-            //params.seed = args.seed;
-            params.n_threads = threads;
-            params.n_threads_batch = threads;
-            params.n_ctx = args.context_hint.unwrap_or(CONTEXT_SIZE);
+        let fim = self.metadata().await.and_then(FimTokens::for_model);
+        let prompt = args.prompt(fim.as_ref())?;
+        let stop_words = args.stop_words();
+        // adapters are decoded in a context of the size of the model's
+        let context_hint = args
+            .sampling
+            .context_hint
+            .filter(|_| args.sampling.adapter.is_none());
+        let context_size = match context_hint {
+            Some(hint) => hint,
+            None => self.context_size().await,
+        };
+        let max_tokens = SETTINGS
+            .read()
+            .await
+            .read()
+            .await
+            .max_tokens(&self.path, args.sampling.max_tokens) as usize;
+
+        let prompt_tokens = model_guard
+            .tokenize_bytes(&prompt, true, false)
+            .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?
+            .len() as u32;
+        if prompt_tokens >= context_size {
+            return Err(LLMEndpointError::ContextOverflow {
+                prompt_tokens,
+                context_size,
+            });
+        }
 
-            let session = model_guard
-                .create_session(params)
-                .map_err(move |e| LLMEndpointError::SessionCreationFailed(e.to_string()))?;
-            let sampler = StandardSampler::default();
+        let samplers = EdgenSampler::for_choices(&args.sampling)?;
+        let streams = match &args.sampling.adapter {
+            Some(adapter) => {
+                self.start_adapter(
+                    adapter,
+                    &prompt,
+                    &model_guard,
+                    model_signal,
+                    samplers,
+                    max_tokens,
+                    stop_words,
+                )
+                .await?
+            }
+            None => {
+                self.start_oneshot(
+                    &prompt,
+                    context_size,
+                    args.sampling.seed,
+                    &model_guard,
+                    model_signal,
+                    samplers,
+                    max_tokens,
+                    stop_words,
+                )
+                .await?
+            }
+        };
+        let streams = streams
+            .into_iter()
+            .map(|stream| stream.with_deadline(deadline))
+            .collect();
+        Ok(merge_choices(streams, &[]))
+    }
 
-            Ok(Box::new(
-                CompletionStream::new_oneshot(session, &prompt, model_signal, sampler).await?,
-            ))
+    /// Starts generating one-shot completions of `prompt`, one for each of `samplers`.
+    ///
+    /// If the model has a draft model, a single completion that fits in the context of the
+    /// [`SpeculativeDecoder`] is decoded by it. Otherwise, if batching is enabled and the
+    /// completions fit in the context of the [`BatchScheduler`], they are decoded by it, along with
+    /// those of other requests. Otherwise, they are generated in a one-shot session with a context
+    /// of `context_size` tokens and an optional RNG `seed`.
+    #[allow(clippy::too_many_arguments)]
+    async fn start_oneshot(
+        &self,
+        prompt: &str,
+        context_size: u32,
+        seed: Option<u32>,
+        model: &LlamaModel,
+        model_signal: ActiveSignal,
+        samplers: Vec<EdgenSampler>,
+        max_tokens: usize,
+        stop_words: Vec<String>,
+    ) -> Result<Vec<CompletionStream>, LLMEndpointError> {
+        let (sequences, threads, draft) = {
+            let settings = SETTINGS.read().await;
+            let settings = settings.read().await;
+            (
+                settings.chat_completions_batch_sequences,
+                settings.auto_threads(false),
+                settings.draft_model(&self.path),
+            )
+        };
+        let draft = draft.filter(|_| samplers.len() == 1);
+
+        let tokens = if draft.is_some() || sequences > 1 {
+            model
+                .tokenize_bytes(prompt, true, false)
+                .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?
         } else {
-            let (session, id, new_context) = self.take_chat_session(&prompt).await;
+            vec![]
+        };
 
-            let sampler = StandardSampler::default();
-            let tx = self.finished_tx.clone();
+        if let Some((draft_path, draft_tokens)) = draft {
+            let params = SpeculativeParams {
+                context_size: self.context_size().await,
+                draft_tokens,
+                threads,
+                gpu_layers: gpu_layers().await,
+            };
+            let (decoder_signal, decoder) =
+                get_or_init_speculative(&self.speculative, &self.path, draft_path, params).await?;
+
+            if let Some(max_tokens) = decoder.max_tokens(tokens.len(), max_tokens) {
+                return CompletionStream::new_speculative(
+                    &decoder,
+                    decoder_signal,
+                    tokens,
+                    model.clone(),
+                    model_signal,
+                    samplers,
+                    max_tokens,
+                    stop_words,
+                );
+            }
+            info!("Not decoding speculatively a completion that does not fit in the context");
+        }
 
-            Ok(Box::new(
-                CompletionStream::new(
-                    session,
-                    id,
-                    new_context,
-                    model_guard.clone(),
+        if sequences > 1 {
+            let params = BatchParams {
+                context_size: self.context_size().await,
+                sequences,
+                threads,
+                gpu_layers: gpu_layers().await,
+            };
+            let (batch_signal, batch) = get_or_init_batch(&self.batch, &self.path, params).await?;
+
+            if let Some(max_tokens) = batch.max_tokens(tokens.len(), samplers.len(), max_tokens) {
+                return CompletionStream::new_batched(
+                    &batch,
+                    batch_signal,
+                    tokens,
+                    model.clone(),
                     model_signal,
-                    sampler,
-                    tx,
-                )
-                .await?,
-            ))
+                    samplers,
+                    max_tokens,
+                    stop_words,
+                );
+            }
+            info!("Not batching a completion that does not fit in the batch context");
         }
+
+        let session = create_oneshot_session(model, context_size, seed).await?;
+        CompletionStream::new_oneshot(
+            session,
+            prompt,
+            model.clone(),
+            model_signal,
+            samplers,
+            max_tokens,
+            stop_words,
+        )
+        .await
+    }
+
+    /// Starts generating completions of `prompt` with the adapter called `name` applied to the
+    /// model, one for each of `samplers`, decoded one after another by its [`AdapterDecoder`].
+    #[allow(clippy::too_many_arguments)]
+    async fn start_adapter(
+        &self,
+        name: &str,
+        prompt: &str,
+        model: &LlamaModel,
+        model_signal: ActiveSignal,
+        samplers: Vec<EdgenSampler>,
+        max_tokens: usize,
+        stop_words: Vec<String>,
+    ) -> Result<Vec<CompletionStream>, LLMEndpointError> {
+        let (adapter, threads) = {
+            let settings = SETTINGS.read().await;
+            let settings = settings.read().await;
+            (
+                settings.adapter(&self.path, name),
+                settings.auto_threads(false),
+            )
+        };
+        let adapter = adapter.ok_or_else(|| LLMEndpointError::UnknownAdapter(name.to_string()))?;
+
+        let tokens = model
+            .tokenize_bytes(prompt, true, false)
+            .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?;
+        let params = AdapterParams {
+            context_size: self.context_size().await,
+            scale: adapter.scale,
+            threads,
+            gpu_layers: gpu_layers().await,
+        };
+
+        let decoder = self
+            .adapters
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                Arc::new(
+                    Perishable::with_ttl(inactive_llm_session_ttl())
+                        .with_memory_budget(MEMORY_BUDGET.clone(), |decoder: &AdapterDecoder| {
+                            decoder.memory_size() as u64
+                        }),
+                )
+            })
+            .clone();
+        let (decoder_signal, decoder) =
+            get_or_init_adapter(&decoder, &self.path, PathBuf::from(adapter.path), params).await?;
+
+        let Some(max_tokens) = decoder.max_tokens(tokens.len(), max_tokens) else {
+            return Err(LLMEndpointError::ContextOverflow {
+                prompt_tokens: tokens.len() as u32,
+                context_size: params.context_size,
+            });
+        };
+        CompletionStream::new_adapter(
+            &decoder,
+            decoder_signal,
+            tokens,
+            model.clone(),
+            model_signal,
+            samplers,
+            max_tokens,
+            stop_words,
+        )
     }
 
-    async fn embeddings(&self, inputs: Vec<String>) -> Result<Vec<Vec<f32>>, LLMEndpointError> {
-        let threads = SETTINGS.read().await.read().await.auto_threads(false);
-        let mut params = EmbeddingsParams::default();
-        params.n_threads = threads;
-        params.n_threads_batch = threads;
+    /// Starts generating completions of `prompt`, whose placeholders of `images` are replaced by
+    /// the embeddings of the images, one for each of `samplers`, decoded one after another by the
+    /// [`VisionDecoder`] of the model.
+    #[allow(clippy::too_many_arguments)]
+    async fn start_vision(
+        &self,
+        prompt: &str,
+        images: &[ImageInput],
+        model: &LlamaModel,
+        model_signal: ActiveSignal,
+        samplers: Vec<EdgenSampler>,
+        max_tokens: usize,
+        stop_words: Vec<String>,
+    ) -> Result<Vec<CompletionStream>, LLMEndpointError> {
+        let (mmproj, threads) = {
+            let settings = SETTINGS.read().await;
+            let settings = settings.read().await;
+            (settings.mmproj(&self.path), settings.auto_threads(false))
+        };
+        let mmproj = mmproj.ok_or_else(|| {
+            LLMEndpointError::ImagesUnsupported(
+                "with a model that has no multimodal projector".to_string(),
+            )
+        })?;
+
+        let mut chunks = vec![];
+        for (index, part) in split_images(prompt, images).into_iter().enumerate() {
+            match part {
+                PromptPart::Text(text) => {
+                    let tokens = model
+                        .tokenize_bytes(text, index == 0, false)
+                        .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?;
+                    chunks.push(PromptChunk::Tokens(tokens));
+                }
+                PromptPart::Image(image) => chunks.push(PromptChunk::Image(image.clone())),
+            }
+        }
+        if !matches!(chunks.last(), Some(PromptChunk::Tokens(tokens)) if !tokens.is_empty()) {
+            return Err(LLMEndpointError::ImagesUnsupported(
+                "at the end of the prompt".to_string(),
+            ));
+        }
 
+        let params = VisionParams {
+            context_size: self.context_size().await,
+            threads,
+            gpu_layers: gpu_layers().await,
+        };
+        let (decoder_signal, decoder) =
+            get_or_init_vision(&self.vision, &self.path, mmproj, params).await?;
+
+        let prompt_len = decoder.prompt_len(&chunks);
+        let Some(max_tokens) = decoder.max_tokens(prompt_len, max_tokens) else {
+            return Err(LLMEndpointError::ContextOverflow {
+                prompt_tokens: prompt_len as u32,
+                context_size: params.context_size,
+            });
+        };
+        CompletionStream::new_vision(
+            &decoder,
+            decoder_signal,
+            chunks,
+            prompt_len,
+            model.clone(),
+            model_signal,
+            samplers,
+            max_tokens,
+            stop_words,
+        )
+    }
+
+    /// Embeds the inputs of `args`, in batches of pieces that fit in the context of the model,
+    /// applying the overflow policy of `args` to the inputs that don't fit.
+    ///
+    /// The pieces are embedded by the [`EmbeddingsDecoder`] of the model, exactly as they are
+    /// tokenized, each preceded by the BOS token of the model if it has one.
+    async fn embeddings(&self, args: EmbeddingsArgs) -> Result<Embeddings, LLMEndpointError> {
         let (_model_signal, model_guard) = get_or_init_model(&self.model, &self.path).await?;
-        model_guard
-            .embeddings_async(&inputs, params)
-            .await
-            .map_err(move |e| LLMEndpointError::Embeddings(e.to_string()))
+        let context_size = self.context_size().await;
+        let params = EmbeddingsParams {
+            context_size,
+            threads: SETTINGS.read().await.read().await.auto_threads(false),
+            gpu_layers: gpu_layers().await,
+        };
+        let (_decoder_signal, decoder) =
+            get_or_init_embeddings(&self.embeddings, &self.path, params).await?;
+
+        // the pieces are embedded on the decoding thread, which stops before its next step if this
+        // future is dropped (e.g. because the peer disconnected or the request timed out)
+        let cancel = CancellationToken::new();
+        let _cancel_on_drop = cancel.clone().drop_guard();
+
+        let mut inputs = Vec::with_capacity(args.inputs.len());
+        for input in args.inputs {
+            let tokens = match input {
+                EmbeddingsInput::Text(text) => model_guard
+                    .tokenize_bytes(text, false, false)
+                    .map_err(move |e| LLMEndpointError::Embeddings(e.to_string()))?,
+                EmbeddingsInput::Tokens(ids) => ids
+                    .into_iter()
+                    .map(|id| match (id as usize) < decoder.n_vocab() {
+                        true => Ok(Token(id as i32)),
+                        false => Err(LLMEndpointError::UnknownToken(id)),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            };
+            inputs.push(tokens);
+        }
+
+        let max_tokens = (context_size as usize).saturating_sub(EMBEDDINGS_RESERVED_TOKENS);
+        let lengths: Vec<_> = inputs.iter().map(Vec::len).collect();
+        let plan = EmbeddingsPlan::new(&lengths, max_tokens, args.context_overflow)?;
+
+        let mut embeddings = Vec::with_capacity(plan.pieces.len());
+        let mut prompt_tokens = 0;
+        for batch in plan.batches(max_tokens) {
+            let pieces: Vec<Vec<Token>> = plan.pieces[batch]
+                .iter()
+                .map(|piece| {
+                    let tokens = &inputs[piece.input][piece.tokens.clone()];
+                    decoder
+                        .bos()
+                        .into_iter()
+                        .chain(tokens.iter().copied())
+                        .collect()
+                })
+                .collect();
+            prompt_tokens += pieces.iter().map(Vec::len).sum::<usize>();
+
+            embeddings.extend(
+                decoder
+                    .embed(pieces, cancel.clone())
+                    .await
+                    .map_err(move |e| LLMEndpointError::Embeddings(e.to_string()))?,
+            );
+        }
+
+        Ok(Embeddings {
+            embeddings: plan.pool(embeddings),
+            prompt_tokens: prompt_tokens as u32,
+            overflow: plan.overflow,
+        })
     }
 }
 
 impl Drop for UnloadingModel {
     fn drop(&mut self) {
-        self.maintenance_thread.abort()
+        self.maintenance_thread.abort();
+
+        // dropping the sessions saves them to the cache, before the aborted thread drops them
+        self.sessions.clear();
+    }
+}
+
+/// Helper function to collect a [`Stream`] of [`CompletionChunk`]s of `choices` choices into a
+/// full [`Completion`] for each choice.
+async fn collect_completions(
+    mut stream: Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>,
+    choices: usize,
+) -> Vec<Completion> {
+    let empty = Completion {
+        content: String::new(),
+        finish_reason: FinishReason::Stop,
+        usage: TokenUsage::default(),
+        tool_calls: None,
+        context_overflow: None,
+        logprobs: None,
+    };
+    let mut completions = vec![empty; choices];
+
+    while let Some(chunk) = stream.next().await {
+        let completion = match completions.get_mut(chunk.index as usize) {
+            Some(completion) => completion,
+            None => continue,
+        };
+
+        completion.content.push_str(&chunk.content);
+        if let Some(reason) = chunk.finish_reason {
+            completion.finish_reason = reason;
+        }
+        if let Some(usage) = chunk.usage {
+            completion.usage = usage;
+        }
+        if chunk.tool_calls.is_some() {
+            completion.tool_calls = chunk.tool_calls;
+        }
+        if chunk.context_overflow.is_some() {
+            completion.context_overflow = chunk.context_overflow;
+        }
+        if let Some(logprobs) = chunk.logprobs {
+            completion
+                .logprobs
+                .get_or_insert_with(Vec::new)
+                .extend(logprobs);
+        }
+    }
+
+    completions
+}
+
+/// Helper function to box a [`CompletionStream`], parsing calls to any of `tools` out of its
+/// completions.
+fn with_tools(
+    stream: CompletionStream,
+    tools: Vec<ToolStub>,
+) -> Box<dyn Stream<Item = CompletionChunk> + Unpin + Send> {
+    if tools.is_empty() {
+        Box::new(stream)
+    } else {
+        Box::new(ToolCallStream::new(stream, tools))
     }
 }
 
+/// Helper function to merge the [`CompletionStream`]s of every choice into a single boxed
+/// [`Stream`], in which the chunks of each choice carry its index. Calls to any of `tools` are
+/// parsed out of the completions.
+fn merge_choices(
+    streams: Vec<CompletionStream>,
+    tools: &[ToolStub],
+) -> Box<dyn Stream<Item = CompletionChunk> + Unpin + Send> {
+    let streams = streams.into_iter().enumerate().map(|(index, stream)| {
+        with_tools(stream, tools.to_vec()).map(move |mut chunk| {
+            chunk.index = index as u32;
+            chunk
+        })
+    });
+
+    Box::new(futures::stream::select_all(streams))
+}
+
 /// Helper function to acquire a read guard to a [`LlamaModel`] (and its associated
 /// [`ActiveSignal`]).
 async fn get_or_init_model(
@@ -346,18 +1254,7 @@ async fn get_or_init_model(
         .get_or_try_init(move || async move {
             info!("Loading {} into memory", path.to_string_lossy());
             let mut args = LlamaParams::default();
-
-            match SETTINGS.read().await.read().await.gpu_policy {
-                DevicePolicy::AlwaysCpu { .. } => {
-                    args.n_gpu_layers = 0;
-                }
-                DevicePolicy::AlwaysDevice { .. } => {
-                    args.n_gpu_layers = i32::MAX as u32;
-                }
-                _ => {
-                    unimplemented!()
-                }
-            }
+            args.n_gpu_layers = gpu_layers().await;
 
             LlamaModel::load_from_file_async(path, args)
                 .await
@@ -366,31 +1263,240 @@ async fn get_or_init_model(
         .await
 }
 
+/// Helper function that returns the number of model layers offloaded to the GPU, according to
+/// the [`DevicePolicy`] in the settings.
+async fn gpu_layers() -> u32 {
+    match SETTINGS.read().await.read().await.gpu_policy {
+        DevicePolicy::AlwaysCpu { .. } => 0,
+        DevicePolicy::AlwaysDevice { .. } => i32::MAX as u32,
+        _ => {
+            unimplemented!()
+        }
+    }
+}
+
+/// Helper function to acquire a read guard to the [`BatchScheduler`] of the model at `path` (and
+/// its associated [`ActiveSignal`]), starting it with `params` if needed.
+async fn get_or_init_batch(
+    batch: &Perishable<BatchScheduler>,
+    path: impl AsRef<Path>,
+    params: BatchParams,
+) -> Result<(ActiveSignal, PerishableReadGuard<BatchScheduler>), LLMEndpointError> {
+    let path = path.as_ref().to_path_buf();
+    batch
+        .get_or_try_init(move || async move {
+            info!("Starting batched decoding of {}", path.to_string_lossy());
+            spawn_blocking(move || BatchScheduler::new(path, params))
+                .await
+                .map_err(move |e| LLMEndpointError::SessionCreationFailed(e.to_string()))?
+                .map_err(move |e| LLMEndpointError::SessionCreationFailed(e.to_string()))
+        })
+        .await
+}
+
+/// Helper function to acquire a read guard to the [`SpeculativeDecoder`] of the model at `path`
+/// (and its associated [`ActiveSignal`]), starting it with the draft model at `draft_path` and
+/// `params` if needed.
+async fn get_or_init_speculative(
+    decoder: &Perishable<SpeculativeDecoder>,
+    path: impl AsRef<Path>,
+    draft_path: PathBuf,
+    params: SpeculativeParams,
+) -> Result<(ActiveSignal, PerishableReadGuard<SpeculativeDecoder>), LLMEndpointError> {
+    let path = path.as_ref().to_path_buf();
+    decoder
+        .get_or_try_init(move || async move {
+            info!(
+                "Loading {} to draft completions of {}",
+                draft_path.to_string_lossy(),
+                path.to_string_lossy()
+            );
+            spawn_blocking(move || SpeculativeDecoder::new(path, draft_path, params))
+                .await
+                .map_err(move |e| LLMEndpointError::Load(e.to_string()))?
+                .map_err(move |e| LLMEndpointError::Load(e.to_string()))
+        })
+        .await
+}
+
+/// Helper function to acquire a read guard to the [`AdapterDecoder`] of the model at `path` with
+/// the adapter at `adapter_path` (and its associated [`ActiveSignal`]), starting it with `params`
+/// if needed.
+async fn get_or_init_adapter(
+    decoder: &Perishable<AdapterDecoder>,
+    path: impl AsRef<Path>,
+    adapter_path: PathBuf,
+    params: AdapterParams,
+) -> Result<(ActiveSignal, PerishableReadGuard<AdapterDecoder>), LLMEndpointError> {
+    let path = path.as_ref().to_path_buf();
+    decoder
+        .get_or_try_init(move || async move {
+            info!(
+                "Applying {} to {}",
+                adapter_path.to_string_lossy(),
+                path.to_string_lossy()
+            );
+            spawn_blocking(move || AdapterDecoder::new(path, adapter_path, params))
+                .await
+                .map_err(move |e| LLMEndpointError::Load(e.to_string()))?
+                .map_err(move |e| LLMEndpointError::Load(e.to_string()))
+        })
+        .await
+}
+
+/// Helper function to acquire a read guard to the [`VisionDecoder`] of the model at `path` (and
+/// its associated [`ActiveSignal`]), starting it with the multimodal projector at `mmproj_path`
+/// and `params` if needed.
+async fn get_or_init_vision(
+    decoder: &Perishable<VisionDecoder>,
+    path: impl AsRef<Path>,
+    mmproj_path: PathBuf,
+    params: VisionParams,
+) -> Result<(ActiveSignal, PerishableReadGuard<VisionDecoder>), LLMEndpointError> {
+    let path = path.as_ref().to_path_buf();
+    decoder
+        .get_or_try_init(move || async move {
+            info!(
+                "Loading {} to embed images for {}",
+                mmproj_path.to_string_lossy(),
+                path.to_string_lossy()
+            );
+            spawn_blocking(move || VisionDecoder::new(path, mmproj_path, params))
+                .await
+                .map_err(move |e| LLMEndpointError::Load(e.to_string()))?
+                .map_err(move |e| LLMEndpointError::Load(e.to_string()))
+        })
+        .await
+}
+
+/// Helper function to acquire a read guard to the [`EmbeddingsDecoder`] of the model at `path`
+/// (and its associated [`ActiveSignal`]), starting it with `params` if needed.
+async fn get_or_init_embeddings(
+    decoder: &Perishable<EmbeddingsDecoder>,
+    path: impl AsRef<Path>,
+    params: EmbeddingsParams,
+) -> Result<(ActiveSignal, PerishableReadGuard<EmbeddingsDecoder>), LLMEndpointError> {
+    let path = path.as_ref().to_path_buf();
+    decoder
+        .get_or_try_init(move || async move {
+            info!("Starting to embed with {}", path.to_string_lossy());
+            spawn_blocking(move || EmbeddingsDecoder::new(path, params))
+                .await
+                .map_err(move |e| LLMEndpointError::Load(e.to_string()))?
+                .map_err(move |e| LLMEndpointError::Load(e.to_string()))
+        })
+        .await
+}
+
+/// Helper function to create a [`LlamaSession`] for a one-shot request, with a context of
+/// `context_size` tokens and an optional RNG `seed`.
+async fn create_oneshot_session(
+    model: &LlamaModel,
+    context_size: u32,
+    seed: Option<u32>,
+) -> Result<LlamaSession, LLMEndpointError> {
+    info!("Allocating one-shot LLM session");
+    let mut params = SessionParams::default();
+    let threads = SETTINGS.read().await.read().await.auto_threads(false);
+
+    if let Some(seed) = seed {
+        params.seed = seed;
+    }
+    params.n_threads = threads;
+    params.n_threads_batch = threads;
+    params.n_ctx = context_size;
+
+    model
+        .create_session(params)
+        .map_err(move |e| LLMEndpointError::SessionCreationFailed(e.to_string()))
+}
+
 /// Helper function to acquire a write guard to a [`LlamaSession`] (and its associated
 /// [`ActiveSignal`]).
 async fn get_or_init_session(
     session: &Perishable<LlamaSession>,
     model: LlamaModel,
+    context_size: u32,
 ) -> Result<(ActiveSignal, PerishableWriteGuard<LlamaSession>), LLMEndpointError> {
     session
         .get_or_try_init_mut(move || async move {
             info!("Allocating new LLM session");
-            let mut params = SessionParams::default();
-            let threads = SETTINGS.read().await.read().await.auto_threads(false);
-
-            // TODO handle optional params
-            //params.seed = args.seed;
-            params.n_threads = threads;
-            params.n_threads_batch = threads;
-            params.n_ctx = CONTEXT_SIZE;
-
             model
-                .create_session(params)
+                .create_session(session_params(context_size).await)
                 .map_err(move |e| LLMEndpointError::SessionCreationFailed(e.to_string()))
         })
         .await
 }
 
+/// Helper function to build the [`SessionParams`] of a chat [`LlamaSession`] of `context_size`
+/// tokens.
+async fn session_params(context_size: u32) -> SessionParams {
+    let mut params = SessionParams::default();
+    let threads = SETTINGS.read().await.read().await.auto_threads(false);
+
+    params.n_threads = threads;
+    params.n_threads_batch = threads;
+    params.n_ctx = context_size;
+    params
+}
+
+/// Helper function to await the `setup` of completions, failing with [`LLMEndpointError::Timeout`]
+/// if `deadline` passes first, in which case the setup is dropped.
+async fn before_deadline<T>(
+    deadline: Option<Instant>,
+    setup: impl Future<Output = Result<T, LLMEndpointError>>,
+) -> Result<T, LLMEndpointError> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, setup)
+            .await
+            .map_err(|_| LLMEndpointError::Timeout)?,
+        None => setup.await,
+    }
+}
+
+/// Helper function to create an uninitialized chat [`LlamaSession`], which is accounted in the
+/// memory budget.
+fn new_chat_session() -> Perishable<LlamaSession> {
+    Perishable::with_ttl(inactive_llm_session_ttl())
+        .with_memory_budget(MEMORY_BUDGET.clone(), |session| {
+            session.memory_size() as u64
+        })
+}
+
+/// Helper function to make a [`LlamaSession`] save its state to `cache`, under `key`, when it
+/// perishes or when it is dropped.
+fn save_on_perish(session: &Perishable<LlamaSession>, cache: SessionCache, key: String) {
+    session.set_finalizer(move |session: LlamaSession| {
+        info!("Saving session {key} to the cache");
+        if let Err(e) = cache.store(&key, |path| session.save_state(path)) {
+            warn!("Failed to save session {key} to the cache: {e}");
+        }
+    });
+}
+
+/// Helper function to reset the context of a [`LlamaSession`] to the provided [`SessionTrim`].
+///
+/// The session is truncated to the context it had before generating completions, after which it is
+/// advanced with the completions that were actually emitted.
+async fn trim_session(
+    session: &Perishable<LlamaSession>,
+    trim: SessionTrim,
+) -> Result<(), LLMEndpointError> {
+    let (_session_signal, mut session_guard) = session
+        .get_or_try_init_mut(move || async move {
+            Err(LLMEndpointError::SessionCreationFailed(
+                "session perished before being trimmed".to_string(),
+            ))
+        })
+        .await?;
+
+    session_guard.truncate_context(trim.context_len);
+    session_guard
+        .advance_context_async(trim.completion)
+        .await
+        .map_err(move |e| LLMEndpointError::Advance(e.to_string()))
+}
+
 /// An object representing an unique identifier for a session context.
 #[derive(Default, Clone)]
 struct SessionId {
@@ -402,40 +1508,22 @@ struct SessionId {
 }
 
 impl SessionId {
-    /// Creates a [`SessionId`] from a prompt.
-    ///
-    /// This function makes a few assumptions about the provided prompt, given that it is dedicated
-    /// to chat sessions. It is assumed that the prompt ends with [`ASSISTANT_TAG`], and that it
-    /// probably contains [`ASSISTANT_TAG`], [`USER_TAG`], [`TOOL_TAG`] and/or [`SYSTEM_TAG`].
+    /// Creates a [`SessionId`] from a chat prompt, whose first `history_len` bytes are the context
+    /// of a previous prompt, up to the end of the last assistant message (see [`Prompt`]).
     ///
     /// Besides returning the new [`SessionId`] instance, the new context in the prompt is also
-    /// returned, found based to the positions of the tags.
+    /// returned, which is everything after the history.
     ///
     /// # Note
     ///
     /// The new [`SessionId`] returned by this function must be advanced using the returned new context,
     /// before being advanced with inference content. The reason it isn't already advance with the
     /// new context, is for the purpose of finding matching [`SessionId`]s in the endpoint.
-    fn chat(prompt: &str) -> (Self, &str) {
-        let idx = if prompt.ends_with(ASSISTANT_TAG) {
-            if let Some(start) = prompt[..prompt.len() - ASSISTANT_TAG.len()].rfind(ASSISTANT_TAG) {
-                // Another assistant tag is found, the is previous context
-                if let Some(tag_idx) = find_any(
-                    &prompt[start + ASSISTANT_TAG.len()..],
-                    &[ASSISTANT_TAG, USER_TAG, TOOL_TAG, SYSTEM_TAG],
-                ) {
-                    start + ASSISTANT_TAG.len() + tag_idx
-                } else {
-                    // This should be unreachable
-                    error!("Could not find any tags after the last assistant message");
-                    0
-                }
-            } else {
-                // No other assistant tag is found, this is the first prompt
-                0
-            }
+    fn chat(prompt: &str, history_len: usize) -> (Self, &str) {
+        let idx = if prompt.is_char_boundary(history_len) {
+            history_len
         } else {
-            error!("Chat prompt doesn't end with the assistant tag");
+            error!("Chat prompt history doesn't end at a character boundary");
             0
         };
 
@@ -461,6 +1549,16 @@ impl SessionId {
         self.hasher.update(new_context.as_bytes());
         self.len += new_context.len();
     }
+
+    /// Returns the key that the state of this session is saved with in a [`SessionCache`], which
+    /// also identifies the model at `model_path`.
+    fn cache_key(&self, model_path: &Path) -> String {
+        let mut hasher = Hasher::new();
+        hasher.update(model_path.as_os_str().as_encoded_bytes());
+        hasher.update(self.hasher.finalize().as_bytes());
+        hasher.update(&self.len.to_le_bytes());
+        hasher.finalize().to_hex().to_string()
+    }
 }
 
 impl core::hash::Hash for SessionId {
@@ -480,36 +1578,161 @@ impl PartialEq for SessionId {
 
 impl Eq for SessionId {}
 
-/// Helper function that finds the first of several substrings in a string, returning the index if
-/// one was found
+/// An explicit [`ChatSession`] of an [`UnloadingModel`].
+enum NamedSession {
+    /// The session is waiting for its chat to continue.
+    Idle {
+        /// The id of the `session`'s context.
+        id: SessionId,
+
+        /// The session.
+        session: Perishable<LlamaSession>,
+    },
+
+    /// The session is generating completions, and is sent back once it finishes.
+    Busy,
+}
+
+/// An explicit [`ChatSession`] marked as [`NamedSession::Busy`] while its completions start.
 ///
-/// # Note
-/// Internally, calls `find` from [`core::str`].
-fn find_any(text: &str, patterns: &[&str]) -> Option<usize> {
-    let mut idxs = vec![];
-    for pattern in patterns {
-        if let Some(idx) = text.find(pattern) {
-            idxs.push(idx);
+/// If the completions fail to start, or are dropped before they do, the [`LlamaSession`] is lost,
+/// so the chat session is removed when this is dropped, unless it is [kept](BusySession::keep).
+struct BusySession<'a> {
+    sessions: &'a DashMap<Uuid, NamedSession>,
+
+    /// The id of the chat session, if there is one.
+    name: Option<Uuid>,
+}
+
+impl BusySession<'_> {
+    /// Keeps the chat session, once its completions started.
+    fn keep(mut self) {
+        self.name = None;
+    }
+}
+
+impl Drop for BusySession<'_> {
+    fn drop(&mut self) {
+        if let Some(name) = self.name.take() {
+            self.sessions.remove(&name);
         }
     }
+}
+
+/// A [`LlamaSession`] that finished generating completions, sent back to the maintenance thread of
+/// an [`UnloadingModel`] so that it can be reused.
+struct FinishedSession {
+    /// The `session`'s id.
+    id: SessionId,
+
+    /// If present, the id of the explicit [`ChatSession`] that `session` belongs to.
+    name: Option<Uuid>,
+
+    /// The session that generated completions.
+    session: Perishable<LlamaSession>,
+
+    /// If present, how the context of `session` must be trimmed before being reused.
+    trim: Option<SessionTrim>,
+}
+
+/// The context a [`LlamaSession`] must be reset to, after generation stopped before the model
+/// finished on its own (e.g. because a stop word was found, or the stream was dropped).
+///
+/// In that case, the session context may contain tokens that were never emitted, and the context
+/// would no longer match its [`SessionId`].
+struct SessionTrim {
+    /// The number of tokens in the context before any completions were generated.
+    context_len: usize,
+
+    /// The completions that were emitted, which must remain in the context.
+    completion: String,
+}
 
-    if idxs.len() > 0 {
-        let mut min = usize::MAX;
-        for idx in idxs {
-            if idx < min {
-                min = idx;
+/// A [`Stream`] of [`Token`]s that counts how many tokens went through it.
+struct CountingTokens {
+    /// The inner stream of tokens, either a [`llama_cpp::CompletionHandle`] or the tokens of a sequence
+    /// decoded by a [`BatchScheduler`] or a [`SpeculativeDecoder`].
+    inner: Box<dyn Stream<Item = Token> + Send + Unpin>,
+
+    /// The number of tokens yielded by `inner` so far.
+    count: Arc<AtomicUsize>,
+
+    /// If present, the receiver of the log probabilities sent by the [`EdgenSampler`] of
+    /// `inner`, and where those of the tokens yielded so far are collected.
+    logprobs: Option<(Receiver<SampledLogprobs>, Arc<Mutex<Vec<SampledLogprobs>>>)>,
+}
+
+impl Stream for CountingTokens {
+    type Item = Token;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let res = std::pin::pin!(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(_)) = res {
+            self.count.fetch_add(1, Ordering::Relaxed);
+
+            // The sampler sends the log probabilities of a token before handing it over, so they
+            // are always available by now.
+            if let Some((rx, sampled)) = &self.logprobs {
+                if let Ok(logprobs) = rx.try_recv() {
+                    sampled
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push(logprobs);
+                }
             }
         }
-        Some(min)
-    } else {
-        None
+        res
+    }
+}
+
+/// The log probabilities of the tokens generated for a [`CompletionStream`], collected by its
+/// [`CountingTokens`].
+struct LogprobsBuffer {
+    /// The model generating the tokens, used to get their text.
+    model: LlamaModel,
+
+    /// The log probabilities collected since they were last taken.
+    sampled: Arc<Mutex<Vec<SampledLogprobs>>>,
+}
+
+impl LogprobsBuffer {
+    /// Takes the log probabilities collected so far.
+    fn take(&self) -> Vec<TokenLogprob> {
+        let sampled = take(&mut *self.sampled.lock().unwrap_or_else(|e| e.into_inner()));
+        sampled
+            .into_iter()
+            .map(|logprobs| TokenLogprob {
+                token: self.model.token_to_piece(logprobs.token),
+                logprob: logprobs.logprob,
+                top_logprobs: logprobs
+                    .top
+                    .into_iter()
+                    .map(|(token, logprob)| TopLogprob {
+                        token: self.model.token_to_piece(token),
+                        logprob,
+                    })
+                    .collect(),
+            })
+            .collect()
     }
 }
 
-/// A [`Stream`] of [`Token`]s returned by a [`LlamaCppSession::stream_complete`] call.
+/// A [`Stream`] of [`CompletionChunk`]s returned by a [`LlamaSession::start_completing_with`]
+/// call, or decoded by a [`BatchScheduler`] or a [`SpeculativeDecoder`].
+///
+/// The last chunk is always empty, and carries the [`FinishReason`].
 struct CompletionStream {
     /// Handle to the model completions handle.
-    handle: TokensToStrings<CompletionHandle>,
+    handle: StoppingStream<TokensToStrings<CountingTokens>>,
+
+    /// The number of tokens generated so far.
+    tokens: Arc<AtomicUsize>,
+
+    /// The maximum number of tokens that can be generated.
+    max_tokens: usize,
+
+    /// Whether the last chunk, carrying the [`FinishReason`], was already emitted.
+    ended: bool,
 
     /// The session used for generation completions.
     session: SessionOption,
@@ -517,39 +1740,84 @@ struct CompletionStream {
     /// The `session`'s id.
     session_id: Option<SessionId>,
 
+    /// If present, the id of the explicit [`ChatSession`] that `session` belongs to.
+    session_name: Option<Uuid>,
+
+    /// The number of tokens in the `session` context before completions started, which includes
+    /// the whole prompt.
+    context_len: usize,
+
+    /// The completions emitted so far, used to trim `session` if generation is stopped early.
+    completion: String,
+
+    /// Whether the model has finished generating completions on its own.
+    finished: bool,
+
+    /// How the chat was fitted into the context, reported in the last chunk.
+    context_overflow: Option<ContextOverflow>,
+
+    /// If present, the log probabilities of the generated tokens, reported in every chunk.
+    logprobs: Option<LogprobsBuffer>,
+
+    /// If present, the deadline past which generation finishes with [`FinishReason::Timeout`].
+    deadline: Option<Pin<Box<Sleep>>>,
+
+    /// The memory reserved for a one-shot `session`, which is accounted while generating.
+    _memory: Option<MemoryReservation>,
+
     /// A sender used to send both `session` and `session_id` once generation is completion
-    finished_tx: Option<UnboundedSender<(SessionId, Perishable<LlamaSession>)>>,
+    finished_tx: Option<UnboundedSender<FinishedSession>>,
 
     /// The object signaling that `model` is currently active.
     _model_signal: ActiveSignal,
 
-    /// The object signaling that `session` is currently active.
+    /// The object signaling that `session`, or the [`BatchScheduler`] or [`SpeculativeDecoder`]
+    /// decoding the completions, is currently active.
     _session_signal: Option<ActiveSignal>,
 }
 
 impl CompletionStream {
-    /// Constructs a new [`CompletionStream`].
+    /// Constructs a new [`CompletionStream`] for each of `samplers`.
+    ///
+    /// The first stream generates completions in `session`, which is sent back once generation
+    /// finishes. The others generate them in one-shot copies of `session`, made once the new
+    /// context was evaluated.
     ///
     /// ## Arguments
     /// * `session` - The session used to generate completions.
     /// * `session_id` - The [`SessionId`] associated with `session`.
+    /// * `session_name` - The id of the explicit [`ChatSession`] that `session` belongs to, if any.
     /// * `new_context` - The context used to advance the session.
     /// * `model` - The [`LlamaModel`] that `session` is associated with.
     /// * `model_signal` - The `model`'s associated [`ActiveSignal`].
-    /// * `sample` - The [`StandardSampler`] used to generate completions.
-    /// * `end_token` - An [`UnboundedSender`] used to send both `session` and `session` once
+    /// * `context_size` - The number of tokens in the context of `session`, if it is created.
+    /// * `samplers` - The [`EdgenSampler`]s used to generate the completions of each choice.
+    /// * `max_tokens` - The maximum number of tokens to generate.
+    /// * `stop_words` - The phrases at which generation stops.
+    /// * `finished_tx` - An [`UnboundedSender`] used to send both `session` and `session_id` once
     /// generation finishes.
     async fn new(
         session: Perishable<LlamaSession>,
         mut session_id: SessionId,
+        session_name: Option<Uuid>,
         new_context: &str,
         model: LlamaModel,
         model_signal: ActiveSignal,
-        sampler: StandardSampler,
-        finished_tx: UnboundedSender<(SessionId, Perishable<LlamaSession>)>,
-    ) -> Result<Self, LLMEndpointError> {
-        let (session_signal, handle) = {
-            let (session_signal, mut session_guard) = get_or_init_session(&session, model).await?;
+        context_size: u32,
+        samplers: Vec<EdgenSampler>,
+        max_tokens: usize,
+        stop_words: Vec<String>,
+        finished_tx: UnboundedSender<FinishedSession>,
+    ) -> Result<Vec<Self>, LLMEndpointError> {
+        let mut samplers = samplers.into_iter();
+        let mut sampler = samplers
+            .next()
+            .ok_or_else(|| LLMEndpointError::Advance("no choices to generate".to_string()))?;
+        let logprobs_rx = sampler.logprobs();
+
+        let (session_signal, context_len, handle, forks) = {
+            let (session_signal, mut session_guard) =
+                get_or_init_session(&session, model.clone(), context_size).await?;
 
             session_guard
                 .advance_context_async(new_context)
@@ -557,61 +1825,445 @@ impl CompletionStream {
                 .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?;
             session_id.advance(new_context);
 
+            // the copies must be made before generation starts changing the context
+            let forks = fork_session(&session_guard, samplers.len())?;
+
             (
                 session_signal,
+                session_guard.context_size(),
                 session_guard
-                    .start_completing_with(sampler, SINGLE_MESSAGE_LIMIT)
+                    .start_completing_with(sampler, max_tokens)
                     .map_err(|e| LLMEndpointError::Advance(e.to_string()))?,
+                forks,
             )
         };
-
-        Ok(Self {
-            handle: handle.into_strings(),
+        let (handle, tokens, logprobs) =
+            count_and_stop(handle, model.clone(), stop_words.clone(), logprobs_rx);
+
+        let mut streams = vec![Self {
+            handle,
+            tokens,
+            max_tokens,
+            ended: false,
             session: SessionOption::Perishable(session),
             session_id: Some(session_id),
+            session_name,
+            context_len,
+            completion: String::new(),
+            finished: false,
+            context_overflow: None,
+            logprobs,
+            deadline: None,
+            _memory: None,
             finished_tx: Some(finished_tx),
-            _model_signal: model_signal,
+            _model_signal: model_signal.clone(),
             _session_signal: Some(session_signal),
-        })
+        }];
+        for (fork, sampler) in forks.into_iter().zip(samplers) {
+            streams.push(Self::start_oneshot(
+                fork,
+                model.clone(),
+                model_signal.clone(),
+                sampler,
+                max_tokens,
+                stop_words.clone(),
+            )?);
+        }
+
+        Ok(streams)
     }
 
+    /// Constructs a new [`CompletionStream`] for each of `samplers`, all of them generating
+    /// completions in one-shot sessions that are dropped once generation finishes.
+    ///
+    /// The first stream generates completions in `session`, and the others in copies of it, made
+    /// once `new_context` was evaluated.
     async fn new_oneshot(
         mut session: LlamaSession,
         new_context: &str,
+        model: LlamaModel,
         model_signal: ActiveSignal,
-        sampler: StandardSampler,
-    ) -> Result<Self, LLMEndpointError> {
+        samplers: Vec<EdgenSampler>,
+        max_tokens: usize,
+        stop_words: Vec<String>,
+    ) -> Result<Vec<Self>, LLMEndpointError> {
         session
             .advance_context_async(new_context)
             .await
             .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?;
+
+        // the copies must be made before generation starts changing the context
+        let forks = fork_session(&session, samplers.len().saturating_sub(1))?;
+
+        std::iter::once(session)
+            .chain(forks)
+            .zip(samplers)
+            .map(|(session, sampler)| {
+                Self::start_oneshot(
+                    session,
+                    model.clone(),
+                    model_signal.clone(),
+                    sampler,
+                    max_tokens,
+                    stop_words.clone(),
+                )
+            })
+            .collect()
+    }
+
+    /// Constructs a new [`CompletionStream`], generating completions in a one-shot `session`
+    /// whose context was already evaluated.
+    fn start_oneshot(
+        mut session: LlamaSession,
+        model: LlamaModel,
+        model_signal: ActiveSignal,
+        mut sampler: EdgenSampler,
+        max_tokens: usize,
+        stop_words: Vec<String>,
+    ) -> Result<Self, LLMEndpointError> {
+        let logprobs_rx = sampler.logprobs();
         let handle = session
-            .start_completing_with(sampler, SINGLE_MESSAGE_LIMIT)
+            .start_completing_with(sampler, max_tokens)
             .map_err(|e| LLMEndpointError::Advance(e.to_string()))?;
+        let (handle, tokens, logprobs) = count_and_stop(handle, model, stop_words, logprobs_rx);
+        let memory = MEMORY_BUDGET.reserve(session.memory_size() as u64);
 
         Ok(Self {
-            handle: handle.into_strings(),
+            handle,
+            tokens,
+            max_tokens,
+            ended: false,
+            context_len: session.context_size(),
             session: SessionOption::OneShot(session),
             session_id: None,
+            session_name: None,
+            completion: String::new(),
+            finished: false,
+            context_overflow: None,
+            logprobs,
+            deadline: None,
+            _memory: Some(memory),
             finished_tx: None,
             _model_signal: model_signal,
             _session_signal: None,
         })
     }
+
+    /// Constructs a new [`CompletionStream`] for each of `samplers`, all of them generating
+    /// completions of the `prompt` tokens in sequences decoded by `batch`.
+    ///
+    /// `max_tokens` must not exceed what [`BatchScheduler::max_tokens`] allows.
+    #[allow(clippy::too_many_arguments)]
+    fn new_batched(
+        batch: &BatchScheduler,
+        batch_signal: ActiveSignal,
+        prompt: Vec<Token>,
+        model: LlamaModel,
+        model_signal: ActiveSignal,
+        mut samplers: Vec<EdgenSampler>,
+        max_tokens: usize,
+        stop_words: Vec<String>,
+    ) -> Result<Vec<Self>, LLMEndpointError> {
+        let context_len = prompt.len();
+        let logprobs_rxs: Vec<_> = samplers.iter_mut().map(EdgenSampler::logprobs).collect();
+        let handles = batch
+            .submit(prompt, samplers, max_tokens)
+            .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?;
+
+        Ok(handles
+            .into_iter()
+            .zip(logprobs_rxs)
+            .map(|(handle, logprobs_rx)| {
+                Self::decoded(
+                    handle,
+                    logprobs_rx,
+                    context_len,
+                    model.clone(),
+                    model_signal.clone(),
+                    batch_signal.clone(),
+                    max_tokens,
+                    stop_words.clone(),
+                )
+            })
+            .collect())
+    }
+
+    /// Constructs a new [`CompletionStream`] for the single sampler in `samplers`, generating a
+    /// completion of the `prompt` tokens decoded by `decoder`.
+    ///
+    /// `max_tokens` must not exceed what [`SpeculativeDecoder::max_tokens`] allows.
+    #[allow(clippy::too_many_arguments)]
+    fn new_speculative(
+        decoder: &SpeculativeDecoder,
+        decoder_signal: ActiveSignal,
+        prompt: Vec<Token>,
+        model: LlamaModel,
+        model_signal: ActiveSignal,
+        samplers: Vec<EdgenSampler>,
+        max_tokens: usize,
+        stop_words: Vec<String>,
+    ) -> Result<Vec<Self>, LLMEndpointError> {
+        let context_len = prompt.len();
+        let mut sampler = samplers
+            .into_iter()
+            .next()
+            .ok_or_else(|| LLMEndpointError::Advance("no choices to generate".to_string()))?;
+        let logprobs_rx = sampler.logprobs();
+        let handle = decoder
+            .submit(prompt, sampler, max_tokens)
+            .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?;
+
+        Ok(vec![Self::decoded(
+            handle,
+            logprobs_rx,
+            context_len,
+            model,
+            model_signal,
+            decoder_signal,
+            max_tokens,
+            stop_words,
+        )])
+    }
+
+    /// Constructs a new [`CompletionStream`] for each of `samplers`, all of them generating
+    /// completions of the `prompt` tokens decoded by `decoder`, one after another.
+    ///
+    /// `max_tokens` must not exceed what [`AdapterDecoder::max_tokens`] allows.
+    #[allow(clippy::too_many_arguments)]
+    fn new_adapter(
+        decoder: &AdapterDecoder,
+        decoder_signal: ActiveSignal,
+        prompt: Vec<Token>,
+        model: LlamaModel,
+        model_signal: ActiveSignal,
+        samplers: Vec<EdgenSampler>,
+        max_tokens: usize,
+        stop_words: Vec<String>,
+    ) -> Result<Vec<Self>, LLMEndpointError> {
+        let context_len = prompt.len();
+        samplers
+            .into_iter()
+            .map(|mut sampler| {
+                let logprobs_rx = sampler.logprobs();
+                let handle = decoder
+                    .submit(prompt.clone(), sampler, max_tokens)
+                    .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?;
+
+                Ok(Self::decoded(
+                    handle,
+                    logprobs_rx,
+                    context_len,
+                    model.clone(),
+                    model_signal.clone(),
+                    decoder_signal.clone(),
+                    max_tokens,
+                    stop_words.clone(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Constructs a new [`CompletionStream`] for each of `samplers`, all of them generating
+    /// completions of `prompt`, which takes `prompt_len` positions, decoded by `decoder`, one
+    /// after another.
+    ///
+    /// `max_tokens` must not exceed what [`VisionDecoder::max_tokens`] allows.
+    #[allow(clippy::too_many_arguments)]
+    fn new_vision(
+        decoder: &VisionDecoder,
+        decoder_signal: ActiveSignal,
+        prompt: Vec<PromptChunk>,
+        prompt_len: usize,
+        model: LlamaModel,
+        model_signal: ActiveSignal,
+        samplers: Vec<EdgenSampler>,
+        max_tokens: usize,
+        stop_words: Vec<String>,
+    ) -> Result<Vec<Self>, LLMEndpointError> {
+        samplers
+            .into_iter()
+            .map(|mut sampler| {
+                let logprobs_rx = sampler.logprobs();
+                let handle = decoder
+                    .submit(prompt.clone(), sampler, max_tokens)
+                    .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?;
+
+                Ok(Self::decoded(
+                    handle,
+                    logprobs_rx,
+                    prompt_len,
+                    model.clone(),
+                    model_signal.clone(),
+                    decoder_signal.clone(),
+                    max_tokens,
+                    stop_words.clone(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Constructs a new [`CompletionStream`] of the tokens that `handle` receives from a
+    /// [`BatchScheduler`], a [`SpeculativeDecoder`], an [`AdapterDecoder`] or a
+    /// [`VisionDecoder`], whose [`ActiveSignal`] is `signal`.
+    #[allow(clippy::too_many_arguments)]
+    fn decoded(
+        handle: UnboundedReceiver<Token>,
+        logprobs_rx: Option<Receiver<SampledLogprobs>>,
+        context_len: usize,
+        model: LlamaModel,
+        model_signal: ActiveSignal,
+        signal: ActiveSignal,
+        max_tokens: usize,
+        stop_words: Vec<String>,
+    ) -> Self {
+        let (handle, tokens, logprobs) = count_and_stop(handle, model, stop_words, logprobs_rx);
+
+        Self {
+            handle,
+            tokens,
+            max_tokens,
+            ended: false,
+            session: SessionOption::None,
+            session_id: None,
+            session_name: None,
+            context_len,
+            completion: String::new(),
+            finished: false,
+            context_overflow: None,
+            logprobs,
+            deadline: None,
+            _memory: None,
+            finished_tx: None,
+            _model_signal: model_signal,
+            _session_signal: Some(signal),
+        }
+    }
+
+    /// Reports how the chat was fitted into the context in the last chunk of this stream.
+    fn with_context_overflow(mut self, overflow: ContextOverflow) -> Self {
+        self.context_overflow = Some(overflow);
+        self
+    }
+
+    /// Finishes generation with [`FinishReason::Timeout`] once `deadline` passes, if present,
+    /// keeping the completions generated so far.
+    fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline.map(|deadline| Box::pin(sleep_until(deadline)));
+        self
+    }
+
+    /// Ends this stream, returning the last chunk, which carries `finish_reason` and the usage.
+    fn end(&mut self, finish_reason: FinishReason) -> CompletionChunk {
+        self.ended = true;
+        CompletionChunk {
+            index: 0,
+            content: String::new(),
+            finish_reason: Some(finish_reason),
+            usage: Some(TokenUsage {
+                prompt_tokens: self.context_len as u32,
+                completion_tokens: self.tokens.load(Ordering::Relaxed) as u32,
+            }),
+            tool_calls: None,
+            context_overflow: self.context_overflow,
+            logprobs: None,
+        }
+    }
+}
+
+/// Helper function that makes `copies` one-shot copies of `session`, including its context.
+fn fork_session(
+    session: &LlamaSession,
+    copies: usize,
+) -> Result<Vec<LlamaSession>, LLMEndpointError> {
+    if copies > 0 {
+        info!("Copying LLM session for {copies} more choices");
+    }
+
+    (0..copies)
+        .map(|_| {
+            session
+                .deep_copy()
+                .map_err(move |e| LLMEndpointError::SessionCreationFailed(e.to_string()))
+        })
+        .collect()
+}
+
+/// Helper function that wraps a [`Stream`] of generated [`Token`]s in a [`StoppingStream`] of
+/// [`String`]s, returning it along with the number of tokens generated so far and, if
+/// `logprobs_rx` is present, the log probabilities of those tokens.
+fn count_and_stop(
+    handle: impl Stream<Item = Token> + Send + Unpin + 'static,
+    model: LlamaModel,
+    stop_words: Vec<String>,
+    logprobs_rx: Option<Receiver<SampledLogprobs>>,
+) -> (
+    StoppingStream<TokensToStrings<CountingTokens>>,
+    Arc<AtomicUsize>,
+    Option<LogprobsBuffer>,
+) {
+    let count = Arc::new(AtomicUsize::new(0));
+    let sampled = Arc::new(Mutex::new(vec![]));
+    let tokens = CountingTokens {
+        inner: Box::new(handle),
+        count: count.clone(),
+        logprobs: logprobs_rx.map(|rx| (rx, sampled.clone())),
+    };
+    let logprobs = tokens.logprobs.is_some().then(|| LogprobsBuffer {
+        model: model.clone(),
+        sampled,
+    });
+
+    let stream =
+        StoppingStream::wrap_with_stop_words(TokensToStrings::new(tokens, model), stop_words);
+    (stream, count, logprobs)
 }
 
 impl Stream for CompletionStream {
-    type Item = String;
+    type Item = CompletionChunk;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.ended {
+            return Poll::Ready(None);
+        }
+
+        // the session is trimmed to the completions emitted so far, as it is not `finished`
+        let timed_out = self
+            .deadline
+            .as_mut()
+            .is_some_and(|deadline| deadline.as_mut().poll(cx).is_ready());
+        if timed_out {
+            info!("Completions timed out");
+            return Poll::Ready(Some(self.end(FinishReason::Timeout)));
+        }
+
         match std::pin::pin!(&mut self.handle).poll_next(cx) {
             Poll::Ready(Some(val)) => {
                 if let Some(id) = &mut self.session_id {
                     id.advance(&val);
+                    self.completion.push_str(&val);
                 }
-                Poll::Ready(Some(val))
+                Poll::Ready(Some(CompletionChunk {
+                    index: 0,
+                    content: val,
+                    finish_reason: None,
+                    usage: None,
+                    tool_calls: None,
+                    context_overflow: None,
+                    logprobs: self.logprobs.as_ref().map(LogprobsBuffer::take),
+                }))
+            }
+            Poll::Ready(None) => {
+                let stopped = self.handle.stopped();
+                let tokens = self.tokens.load(Ordering::Relaxed);
+                let finish_reason = if !stopped && tokens >= self.max_tokens {
+                    FinishReason::Length
+                } else {
+                    FinishReason::Stop
+                };
+
+                self.finished = !stopped;
+                Poll::Ready(Some(self.end(finish_reason)))
             }
-            Poll::Ready(None) => Poll::Ready(None),
             Poll::Pending => Poll::Pending,
         }
     }
@@ -622,9 +2274,25 @@ impl Drop for CompletionStream {
         if let Some(id) = self.session_id.take() {
             if let SessionOption::Perishable(session) = self.session.take() {
                 if let Some(channel) = self.finished_tx.take() {
-                    channel.send((id, session)).unwrap_or_else(move |e| {
-                        error!("Failed to send session to maintenance thread: {e}")
-                    });
+                    let trim = if self.finished {
+                        None
+                    } else {
+                        Some(SessionTrim {
+                            context_len: self.context_len,
+                            completion: take(&mut self.completion),
+                        })
+                    };
+
+                    channel
+                        .send(FinishedSession {
+                            id,
+                            name: self.session_name,
+                            session,
+                            trim,
+                        })
+                        .unwrap_or_else(move |e| {
+                            error!("Failed to send session to maintenance thread: {e}")
+                        });
                 }
             }
         }
@@ -0,0 +1,392 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Mapping of [`CompletionArgs`] sampling parameters onto [`llama_cpp`] samplers.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use llama_cpp::grammar::LlamaGrammar;
+use llama_cpp::standard_sampler::{SamplerStage, StandardSampler};
+use llama_cpp::{Sampler, Token};
+use llama_cpp_sys::{llama_context, llama_set_rng_seed, llama_token_data, llama_token_data_array};
+
+use edgen_core::llm::{CompletionArgs, LLMEndpointError};
+
+use crate::grammar::grammar_for;
+
+/// The repetition penalty used when none is provided, matching [`StandardSampler::default`].
+const DEFAULT_REPETITION_PENALTY: f32 = 1.1;
+
+/// How many of the last tokens are considered when applying repetition penalties.
+const PENALTY_LAST_N: i32 = 64;
+
+/// The nucleus sampling threshold used when `top_p` is not provided.
+const DEFAULT_TOP_P: f32 = 0.95;
+
+/// The sampling temperature used when `temperature` is not provided.
+const DEFAULT_TEMPERATURE: f32 = 0.8;
+
+/// The bias at (or below) which a token is prevented from being selected at all.
+const BAN_BIAS: f32 = -100.0;
+
+/// The log probability of a sampled token, along with the most likely tokens at its position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampledLogprobs {
+    /// The sampled token.
+    pub token: Token,
+
+    /// The log probability of the sampled token.
+    pub logprob: f32,
+
+    /// The most likely tokens and their log probabilities, most likely first.
+    pub top: Vec<(Token, f32)>,
+}
+
+/// A [`Sampler`] honoring every sampling parameter of a [`CompletionArgs`].
+///
+/// This wraps a [`StandardSampler`], adding the parameters that it does not handle by itself,
+/// namely the logit bias, the RNG seed and the reporting of log probabilities.
+pub struct EdgenSampler {
+    /// The inner sampler, configured with the sampling stages derived from the arguments.
+    inner: StandardSampler,
+
+    /// Biases added to the logits of the respective token IDs before sampling.
+    logit_bias: HashMap<u32, f32>,
+
+    /// The RNG seed of the context, applied before the first token is sampled.
+    seed: Option<u32>,
+
+    /// If present, the number of most likely tokens reported along with the log probability of
+    /// every sampled token.
+    top_logprobs: Option<usize>,
+
+    /// Where the log probabilities of sampled tokens are sent, once [`EdgenSampler::logprobs`]
+    /// was called.
+    logprobs_tx: Option<Sender<SampledLogprobs>>,
+}
+
+impl EdgenSampler {
+    /// Creates a new [`EdgenSampler`] from the sampling parameters in `args`.
+    ///
+    /// A `temperature` of `0.0` (or less) selects the most likely token at every step. If the
+    /// completions must follow a grammar (see [`grammar_for`]), only tokens allowed by it can be
+    /// selected.
+    pub fn new(args: &CompletionArgs) -> Result<Self, LLMEndpointError> {
+        let grammar = grammar_for(args)
+            .map_err(move |e| LLMEndpointError::Grammar(e.to_string()))?
+            .map(|grammar| grammar.parse::<LlamaGrammar>())
+            .transpose()
+            .map_err(move |e| LLMEndpointError::Grammar(e.to_string()))?;
+
+        let inner = match (sampler_stages(args), grammar) {
+            (Some(stages), grammar) => StandardSampler::new_softmax(stages, 1, grammar),
+            // The greedy sampler does not take a grammar, so keep only the most likely token
+            // allowed by it instead.
+            (None, Some(grammar)) => {
+                StandardSampler::new_softmax(vec![SamplerStage::TopK(1)], 1, Some(grammar))
+            }
+            (None, None) => StandardSampler::new_greedy(),
+        };
+
+        Ok(Self {
+            inner,
+            logit_bias: args.logit_bias.clone().unwrap_or_default(),
+            seed: args.seed,
+            top_logprobs: args.requested_logprobs(),
+            logprobs_tx: None,
+        })
+    }
+
+    /// Creates an [`EdgenSampler`] for each of the choices of `args`.
+    ///
+    /// Choices are generated in copies of the same session, which share its RNG state, so each
+    /// sampler gets its own seed: the seed in `args` plus the index of the choice, or a random
+    /// seed plus the index if `args` has none.
+    pub fn for_choices(args: &CompletionArgs) -> Result<Vec<Self>, LLMEndpointError> {
+        let choices = args.choices() as u32;
+        if choices == 1 {
+            return Ok(vec![Self::new(args)?]);
+        }
+
+        let seed = args.seed.unwrap_or_else(random_seed);
+        (0..choices)
+            .map(|index| {
+                let mut sampler = Self::new(args)?;
+                sampler.seed = Some(seed.wrapping_add(index));
+                Ok(sampler)
+            })
+            .collect()
+    }
+
+    /// Returns a receiver of the log probabilities of every token sampled from now on, if the
+    /// arguments of this sampler requested them.
+    ///
+    /// The log probabilities of a token are sent before the token is handed back to `llama_cpp`.
+    pub fn logprobs(&mut self) -> Option<Receiver<SampledLogprobs>> {
+        self.top_logprobs?;
+
+        let (tx, rx) = channel();
+        self.logprobs_tx = Some(tx);
+        Some(rx)
+    }
+
+    /// Samples the token following `tokens` from the `logits` of every token in the vocabulary,
+    /// as computed by a context driven through [`llama_cpp_sys`] directly.
+    pub fn sample_logits(
+        &mut self,
+        context: *mut llama_context,
+        tokens: &[Token],
+        logits: &[f32],
+    ) -> Token {
+        let mut candidates: Vec<llama_token_data> = logits
+            .iter()
+            .enumerate()
+            .map(|(id, logit)| llama_token_data {
+                id: id as i32,
+                logit: *logit,
+                p: 0.0,
+            })
+            .collect();
+        let candidates_p = llama_token_data_array {
+            data: candidates.as_mut_ptr(),
+            size: candidates.len(),
+            sorted: false,
+        };
+
+        self.sample(context, tokens, candidates_p)
+    }
+}
+
+impl Sampler for EdgenSampler {
+    fn sample(
+        &mut self,
+        context: *mut llama_context,
+        tokens: &[Token],
+        mut candidates_p: llama_token_data_array,
+    ) -> Token {
+        if let Some(seed) = self.seed.take() {
+            // SAFETY: the context pointer is provided by `llama_cpp` and valid for this call.
+            unsafe { llama_set_rng_seed(context, seed) };
+        }
+
+        // SAFETY: `llama_cpp` hands over a candidates array that is valid for this call and not
+        // aliased anywhere else.
+        let candidates =
+            unsafe { std::slice::from_raw_parts_mut(candidates_p.data, candidates_p.size) };
+
+        if !self.logit_bias.is_empty() {
+            apply_logit_bias(candidates, &self.logit_bias);
+            candidates_p.sorted = false;
+        }
+
+        // The inner sampler reorders and truncates the candidates, so the distribution is copied
+        // before sampling.
+        let logprobs = match (&self.logprobs_tx, self.top_logprobs) {
+            (Some(tx), Some(top)) => Some((tx, top, candidates.to_vec())),
+            _ => None,
+        };
+
+        let token = self.inner.sample(context, tokens, candidates_p);
+
+        if let Some((tx, top, candidates)) = logprobs {
+            // The receiver is gone if the completion was dropped, in which case nobody cares.
+            let _ = tx.send(token_logprobs(&candidates, token, top));
+        }
+
+        token
+    }
+}
+
+/// Returns a random RNG seed.
+fn random_seed() -> u32 {
+    // every `RandomState` is randomly keyed
+    RandomState::new().build_hasher().finish() as u32
+}
+
+/// Returns the [`SamplerStage`]s matching the sampling parameters in `args`, or [`None`] if
+/// sampling should be greedy.
+fn sampler_stages(args: &CompletionArgs) -> Option<Vec<SamplerStage>> {
+    let temperature = args.temperature.unwrap_or(DEFAULT_TEMPERATURE);
+    if temperature <= 0.0 {
+        return None;
+    }
+
+    Some(vec![
+        SamplerStage::RepetitionPenalty {
+            repetition_penalty: DEFAULT_REPETITION_PENALTY,
+            frequency_penalty: args.frequency_penalty.unwrap_or(0.0),
+            presence_penalty: args.presence_penalty.unwrap_or(0.0),
+            last_n: PENALTY_LAST_N,
+        },
+        SamplerStage::TopP(args.top_p.unwrap_or(DEFAULT_TOP_P)),
+        SamplerStage::Temperature(temperature),
+    ])
+}
+
+/// Adds the biases in `logit_bias` to the logits of their respective tokens in `candidates`.
+///
+/// Tokens with a bias of `-100.0` or less can never be selected.
+fn apply_logit_bias(candidates: &mut [llama_token_data], logit_bias: &HashMap<u32, f32>) {
+    for candidate in candidates {
+        if let Some(bias) = logit_bias.get(&(candidate.id as u32)) {
+            if *bias <= BAN_BIAS {
+                candidate.logit = f32::NEG_INFINITY;
+            } else {
+                candidate.logit += bias;
+            }
+        }
+    }
+}
+
+/// Returns the log probability of `token` and the `top` most likely tokens, given the logits of
+/// every candidate.
+fn token_logprobs(candidates: &[llama_token_data], token: Token, top: usize) -> SampledLogprobs {
+    // log(sum(exp(logit))), shifted by the largest logit to avoid overflowing
+    let max = candidates
+        .iter()
+        .map(|candidate| candidate.logit)
+        .fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = candidates
+        .iter()
+        .map(|candidate| (candidate.logit - max).exp())
+        .sum();
+    let log_sum = max + sum.ln();
+
+    let logprob = candidates
+        .iter()
+        .find(|candidate| candidate.id == token.0)
+        .map_or(f32::NEG_INFINITY, |candidate| candidate.logit - log_sum);
+
+    let mut most_likely: Vec<&llama_token_data> = candidates
+        .iter()
+        .filter(|candidate| candidate.logit > f32::NEG_INFINITY)
+        .collect();
+    let by_logit = |a: &&llama_token_data, b: &&llama_token_data| b.logit.total_cmp(&a.logit);
+    if top < most_likely.len() {
+        most_likely.select_nth_unstable_by(top, by_logit);
+        most_likely.truncate(top);
+    }
+    most_likely.sort_by(by_logit);
+
+    SampledLogprobs {
+        token,
+        logprob,
+        top: most_likely
+            .into_iter()
+            .map(|candidate| (Token(candidate.id), candidate.logit - log_sum))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(id: i32, logit: f32) -> llama_token_data {
+        llama_token_data { id, logit, p: 0.0 }
+    }
+
+    #[test]
+    fn zero_temperature_is_greedy() {
+        let mut args = CompletionArgs::default();
+        args.temperature = Some(0.0);
+
+        assert!(sampler_stages(&args).is_none());
+    }
+
+    #[test]
+    fn stages_follow_args() {
+        let mut args = CompletionArgs::default();
+        args.temperature = Some(1.3);
+        args.top_p = Some(0.5);
+        args.frequency_penalty = Some(0.25);
+        args.presence_penalty = Some(-0.75);
+
+        let stages = sampler_stages(&args).expect("sampling should not be greedy");
+        assert_eq!(stages.len(), 3);
+        assert!(matches!(
+            stages[0],
+            SamplerStage::RepetitionPenalty {
+                frequency_penalty,
+                presence_penalty,
+                ..
+            } if frequency_penalty == 0.25 && presence_penalty == -0.75
+        ));
+        assert!(matches!(stages[1], SamplerStage::TopP(p) if p == 0.5));
+        assert!(matches!(stages[2], SamplerStage::Temperature(t) if t == 1.3));
+    }
+
+    #[test]
+    fn default_stages() {
+        let stages =
+            sampler_stages(&CompletionArgs::default()).expect("sampling should not be greedy");
+        assert!(matches!(stages[1], SamplerStage::TopP(p) if p == DEFAULT_TOP_P));
+        assert!(matches!(stages[2], SamplerStage::Temperature(t) if t == DEFAULT_TEMPERATURE));
+    }
+
+    #[test]
+    fn choices_have_distinct_seeds() {
+        let mut args = CompletionArgs::default();
+        args.n = Some(3);
+        args.seed = Some(7);
+
+        let seeds: Vec<_> = EdgenSampler::for_choices(&args)
+            .unwrap()
+            .into_iter()
+            .map(|sampler| sampler.seed)
+            .collect();
+        assert_eq!(seeds, [Some(7), Some(8), Some(9)]);
+
+        args.n = None;
+        let samplers = EdgenSampler::for_choices(&args).unwrap();
+        assert_eq!(samplers.len(), 1);
+        assert_eq!(samplers[0].seed, Some(7));
+    }
+
+    #[test]
+    fn logit_bias() {
+        let mut candidates = vec![candidate(1, 1.0), candidate(2, 2.0), candidate(3, 3.0)];
+        let bias = HashMap::from([(1, 5.0), (3, -100.0)]);
+
+        apply_logit_bias(&mut candidates, &bias);
+
+        assert_eq!(candidates[0].logit, 6.0);
+        assert_eq!(candidates[1].logit, 2.0);
+        assert_eq!(candidates[2].logit, f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn logprobs() {
+        let candidates = vec![
+            candidate(1, 0.0),
+            candidate(2, 2.0_f32.ln()),
+            candidate(3, f32::NEG_INFINITY),
+            candidate(4, 0.0),
+        ];
+
+        let logprobs = token_logprobs(&candidates, Token(1), 2);
+
+        assert_eq!(logprobs.token, Token(1));
+        assert!((logprobs.logprob - 0.25_f32.ln()).abs() < 1e-6);
+        assert_eq!(logprobs.top.len(), 2);
+        assert_eq!(logprobs.top[0].0, Token(2));
+        assert!((logprobs.top[0].1 - 0.5_f32.ln()).abs() < 1e-6);
+
+        // impossible tokens are never among the most likely ones
+        let logprobs = token_logprobs(&candidates, Token(3), 20);
+        assert_eq!(logprobs.logprob, f32::NEG_INFINITY);
+        assert_eq!(logprobs.top.len(), 3);
+    }
+}
@@ -0,0 +1,450 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Decoding of sequences in a llama.cpp context driven through [`llama_cpp_sys`] directly, for the
+//! decoders that need more than [`llama_cpp`] exposes.
+
+use std::ffi::CString;
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use llama_cpp::Token;
+use llama_cpp_sys::{
+    llama_batch, llama_batch_free, llama_batch_init, llama_context, llama_context_default_params,
+    llama_decode, llama_free, llama_free_model, llama_get_embeddings_ith, llama_get_embeddings_seq,
+    llama_get_logits_ith, llama_get_state_size, llama_kv_cache_seq_cp, llama_kv_cache_seq_rm,
+    llama_load_model_from_file, llama_model, llama_model_apply_lora_from_file,
+    llama_model_default_params, llama_n_embd, llama_n_vocab, llama_new_context_with_model,
+    llama_token_bos, llama_token_eos,
+};
+use thiserror::Error;
+
+use crate::sampler::EdgenSampler;
+
+/// The maximum number of tokens decoded in a single step.
+pub const BATCH_TOKENS: usize = 512;
+
+/// An error that occurred while creating a [`SequenceContext`].
+#[derive(Debug, Error)]
+pub enum SequenceError {
+    #[error("failed to load the model")]
+    LoadModel,
+    #[error("failed to apply the adapter to the model")]
+    ApplyAdapter,
+    #[error("failed to create the context")]
+    CreateContext,
+}
+
+/// How a [`SequenceContext`] is set up.
+#[derive(Debug, Clone, Copy)]
+pub struct SequenceParams {
+    /// The number of tokens in the context, shared by every sequence.
+    pub context_size: u32,
+
+    /// The maximum number of sequences decoded together.
+    pub sequences: u32,
+
+    /// The number of threads used to decode.
+    pub threads: u32,
+
+    /// The number of model layers offloaded to the GPU.
+    pub gpu_layers: u32,
+
+    /// Whether the context computes the embeddings of sequences instead of logits, in which case
+    /// a step decodes up to `context_size` tokens, so that every sequence is decoded at once.
+    pub embeddings: bool,
+}
+
+/// A LoRA adapter applied to the model of a [`SequenceContext`].
+pub struct SequenceAdapter<'a> {
+    /// The adapter file.
+    pub path: &'a CString,
+
+    /// How strongly the adapter is applied.
+    pub scale: f32,
+}
+
+/// A token to decode in a [`SequenceContext`], at position `pos` of sequence `seq`.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchToken {
+    pub token: Token,
+    pub pos: i32,
+    pub seq: i32,
+
+    /// Whether the logits of the token are computed.
+    pub logits: bool,
+}
+
+/// A completion being generated in a [`SequenceContext`].
+pub struct Job {
+    pub sampler: EdgenSampler,
+
+    /// Where the generated tokens are sent.
+    pub output: UnboundedSender<Token>,
+
+    /// The prompt and the tokens generated so far.
+    pub history: Vec<Token>,
+
+    /// The number of tokens generated so far.
+    pub generated: usize,
+
+    /// The maximum number of tokens to generate.
+    pub max_tokens: usize,
+}
+
+impl Job {
+    /// Creates a completion of `prompt` with `sampler`, returning it along with the stream of its
+    /// tokens. Dropping the stream stops the completion.
+    pub fn new(
+        prompt: Vec<Token>,
+        sampler: EdgenSampler,
+        max_tokens: usize,
+    ) -> (Self, UnboundedReceiver<Token>) {
+        let (output, stream) = unbounded();
+        let job = Self {
+            sampler,
+            output,
+            history: prompt,
+            generated: 0,
+            max_tokens,
+        };
+
+        (job, stream)
+    }
+
+    /// Sends the sampled `token`, returning **`false`** if the completion is finished, because
+    /// the model ended it, it generated all of its tokens, or its stream was dropped.
+    pub fn push(&mut self, token: Token, eos: Token) -> bool {
+        if token.0 == eos.0 || self.generated >= self.max_tokens {
+            return false;
+        }
+        if self.output.unbounded_send(token).is_err() {
+            return false;
+        }
+
+        self.generated += 1;
+        self.history.push(token);
+        self.generated < self.max_tokens
+    }
+}
+
+/// A model and a context of it, owned by a decoding thread, which decode one or more sequences.
+pub struct SequenceContext {
+    model: *mut llama_model,
+    ctx: *mut llama_context,
+    batch: llama_batch,
+
+    /// The maximum number of tokens decoded in a single step.
+    batch_tokens: usize,
+
+    /// The batch of embeddings, allocated on first use.
+    embd_batch: Option<llama_batch>,
+}
+
+impl SequenceContext {
+    /// Loads the model at `path`, applying `adapter` to it if present, and creates a context for
+    /// it.
+    ///
+    /// A model with an adapter is read into memory instead of being memory-mapped, as the adapter
+    /// changes its weights.
+    pub fn new(
+        path: &CString,
+        params: SequenceParams,
+        adapter: Option<SequenceAdapter>,
+    ) -> Result<Self, SequenceError> {
+        // SAFETY: the paths are valid C strings, and every pointer is checked before being used.
+        unsafe {
+            let mut model_params = llama_model_default_params();
+            model_params.n_gpu_layers = params.gpu_layers.min(i32::MAX as u32) as i32;
+            model_params.use_mmap = adapter.is_none();
+            let model = llama_load_model_from_file(path.as_ptr(), model_params);
+            if model.is_null() {
+                return Err(SequenceError::LoadModel);
+            }
+
+            if let Some(adapter) = adapter {
+                let applied = llama_model_apply_lora_from_file(
+                    model,
+                    adapter.path.as_ptr(),
+                    adapter.scale,
+                    std::ptr::null(),
+                    params.threads.min(i32::MAX as u32) as i32,
+                );
+                if applied != 0 {
+                    llama_free_model(model);
+                    return Err(SequenceError::ApplyAdapter);
+                }
+            }
+
+            let batch_tokens = if params.embeddings {
+                BATCH_TOKENS.max(params.context_size as usize)
+            } else {
+                BATCH_TOKENS
+            };
+
+            let mut ctx_params = llama_context_default_params();
+            ctx_params.n_ctx = params.context_size;
+            ctx_params.n_batch = batch_tokens as u32;
+            ctx_params.n_ubatch = batch_tokens as u32;
+            ctx_params.embeddings = params.embeddings;
+            ctx_params.n_seq_max = params.sequences.max(1);
+            ctx_params.n_threads = params.threads;
+            ctx_params.n_threads_batch = params.threads;
+            let ctx = llama_new_context_with_model(model, ctx_params);
+            if ctx.is_null() {
+                llama_free_model(model);
+                return Err(SequenceError::CreateContext);
+            }
+
+            Ok(Self {
+                model,
+                ctx,
+                batch: llama_batch_init(batch_tokens as i32, 0, 1),
+                batch_tokens,
+                embd_batch: None,
+            })
+        }
+    }
+
+    /// Decodes `tokens` of sequence `0`, the first of which is at position `pos`, returning the
+    /// logits of every token if `all_logits` is **`true`**, or only those of the last token
+    /// otherwise.
+    pub fn decode(
+        &mut self,
+        tokens: &[Token],
+        pos: usize,
+        all_logits: bool,
+    ) -> Result<Vec<Vec<f32>>, i32> {
+        let mut logits = vec![];
+        for (index, chunk) in tokens.chunks(BATCH_TOKENS).enumerate() {
+            let start = index * BATCH_TOKENS;
+            let last_chunk = start + chunk.len() == tokens.len();
+            let items: Vec<_> = chunk
+                .iter()
+                .enumerate()
+                .map(|(i, token)| BatchToken {
+                    token: *token,
+                    pos: (pos + start + i) as i32,
+                    seq: 0,
+                    logits: all_logits || (last_chunk && i + 1 == chunk.len()),
+                })
+                .collect();
+
+            logits.extend(self.decode_batch(&items)?);
+        }
+
+        Ok(logits)
+    }
+
+    /// Decodes `items`, which may belong to different sequences, returning the logits of the
+    /// items that asked for them, in order.
+    ///
+    /// `items` must not hold more than [`BATCH_TOKENS`] tokens.
+    pub fn decode_batch(&mut self, items: &[BatchToken]) -> Result<Vec<Vec<f32>>, i32> {
+        self.decode_items(items)?;
+
+        // SAFETY: the logits are read right after decoding, at indices of tokens that requested
+        // them.
+        unsafe {
+            let n_vocab = self.n_vocab();
+            Ok(items
+                .iter()
+                .enumerate()
+                .filter(|(_, item)| item.logits)
+                .map(|(i, _)| {
+                    let logits = llama_get_logits_ith(self.ctx, i as i32);
+                    std::slice::from_raw_parts(logits, n_vocab).to_vec()
+                })
+                .collect())
+        }
+    }
+
+    /// Decodes `items`, the tokens of sequences `0..sequences`, returning the embedding of each
+    /// sequence. The context must compute embeddings (see [`SequenceParams::embeddings`]).
+    ///
+    /// A sequence is embedded as the model pools the embeddings of its tokens, or as their mean if
+    /// the model does not pool them. Every token of a sequence must be in `items`, which must not
+    /// hold more than `context_size` tokens, all of them requesting their logits.
+    pub fn embed(&mut self, items: &[BatchToken], sequences: usize) -> Result<Vec<Vec<f32>>, i32> {
+        self.decode_items(items)?;
+
+        let n_embd = self.n_embd();
+        let mut embeddings = vec![vec![0.0; n_embd]; sequences];
+        // SAFETY: the embeddings are read right after decoding, for sequences and at indices of
+        // tokens that were decoded, and hold `n_embd` values each.
+        unsafe {
+            for (seq, embedding) in embeddings.iter_mut().enumerate() {
+                let pooled = llama_get_embeddings_seq(self.ctx, seq as i32);
+                if !pooled.is_null() {
+                    embedding.copy_from_slice(std::slice::from_raw_parts(pooled, n_embd));
+                    continue;
+                }
+
+                let mut count = 0;
+                for (i, _) in items
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, item)| item.seq == seq as i32)
+                {
+                    let token = std::slice::from_raw_parts(
+                        llama_get_embeddings_ith(self.ctx, i as i32),
+                        n_embd,
+                    );
+                    for (value, token_value) in embedding.iter_mut().zip(token) {
+                        *value += token_value;
+                    }
+                    count += 1;
+                }
+                if count > 0 {
+                    embedding
+                        .iter_mut()
+                        .for_each(|value| *value /= count as f32);
+                }
+            }
+        }
+
+        Ok(embeddings)
+    }
+
+    /// Decodes `items`, which may belong to different sequences.
+    fn decode_items(&mut self, items: &[BatchToken]) -> Result<(), i32> {
+        assert!(
+            items.len() <= self.batch_tokens,
+            "too many tokens in a batch"
+        );
+
+        // SAFETY: the batch was allocated for `batch_tokens` tokens of one sequence each, which
+        // `items` never exceeds.
+        unsafe {
+            self.batch.n_tokens = items.len() as i32;
+            for (i, item) in items.iter().enumerate() {
+                *self.batch.token.add(i) = item.token.0;
+                *self.batch.pos.add(i) = item.pos;
+                *self.batch.n_seq_id.add(i) = 1;
+                *(*self.batch.seq_id.add(i)) = item.seq;
+                *self.batch.logits.add(i) = item.logits as i8;
+            }
+
+            match llama_decode(self.ctx, self.batch) {
+                0 => Ok(()),
+                code => Err(code),
+            }
+        }
+    }
+
+    /// Decodes `embd`, the embeddings of consecutive positions of the sequence, the first of which
+    /// is at position `pos`, without computing any logits.
+    ///
+    /// `embd` must hold [`SequenceContext::n_embd`] values for each position.
+    pub fn decode_embeddings(&mut self, embd: &[f32], pos: usize) -> Result<(), i32> {
+        let n_embd = self.n_embd();
+        // SAFETY: the batch is allocated for `BATCH_TOKENS` embeddings of `n_embd` values.
+        let mut batch = *self.embd_batch.get_or_insert_with(|| unsafe {
+            llama_batch_init(BATCH_TOKENS as i32, n_embd as i32, 1)
+        });
+
+        for (index, chunk) in embd.chunks(BATCH_TOKENS * n_embd).enumerate() {
+            let start = index * BATCH_TOKENS;
+            let n_tokens = chunk.len() / n_embd;
+
+            // SAFETY: no chunk exceeds the `BATCH_TOKENS` embeddings the batch was allocated for.
+            unsafe {
+                batch.n_tokens = n_tokens as i32;
+                std::ptr::copy_nonoverlapping(chunk.as_ptr(), batch.embd, n_tokens * n_embd);
+                for i in 0..n_tokens {
+                    *batch.pos.add(i) = (pos + start + i) as i32;
+                    *batch.n_seq_id.add(i) = 1;
+                    *(*batch.seq_id.add(i)) = 0;
+                    *batch.logits.add(i) = 0;
+                }
+
+                match llama_decode(self.ctx, batch) {
+                    0 => {}
+                    code => return Err(code),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Samples the token following the history of `job` from `logits`.
+    pub fn sample(&self, job: &mut Job, logits: &[f32]) -> Token {
+        job.sampler.sample_logits(self.ctx, &job.history, logits)
+    }
+
+    /// Removes every token of sequence `0` at or past position `pos` from the context.
+    pub fn truncate(&mut self, pos: usize) {
+        // SAFETY: the context is valid for the lifetime of `self`.
+        unsafe {
+            llama_kv_cache_seq_rm(self.ctx, 0, pos as i32, -1);
+        }
+    }
+
+    /// Removes every token of sequence `seq` from the context.
+    pub fn clear(&mut self, seq: i32) {
+        // SAFETY: the context is valid for the lifetime of `self`.
+        unsafe {
+            llama_kv_cache_seq_rm(self.ctx, seq, -1, -1);
+        }
+    }
+
+    /// Copies every token of sequence `src` to sequence `dst`, sharing their context cells.
+    pub fn copy(&mut self, src: i32, dst: i32) {
+        // SAFETY: the context is valid for the lifetime of `self`.
+        unsafe {
+            llama_kv_cache_seq_cp(self.ctx, src, dst, -1, -1);
+        }
+    }
+
+    pub fn n_vocab(&self) -> usize {
+        // SAFETY: the model is valid for the lifetime of `self`.
+        unsafe { llama_n_vocab(self.model) as usize }
+    }
+
+    /// Returns the number of values in the embedding of a position of the sequence.
+    pub fn n_embd(&self) -> usize {
+        // SAFETY: the model is valid for the lifetime of `self`.
+        unsafe { llama_n_embd(self.model) as usize }
+    }
+
+    /// Returns the token that starts a sequence, if the model has one.
+    pub fn bos(&self) -> Option<Token> {
+        // SAFETY: the model is valid for the lifetime of `self`.
+        let bos = unsafe { llama_token_bos(self.model) };
+        (bos >= 0).then_some(Token(bos))
+    }
+
+    pub fn eos(&self) -> Token {
+        // SAFETY: the model is valid for the lifetime of `self`.
+        Token(unsafe { llama_token_eos(self.model) })
+    }
+
+    /// Returns the number of bytes taken by the context.
+    pub fn state_size(&self) -> usize {
+        // SAFETY: the context is valid for the lifetime of `self`.
+        unsafe { llama_get_state_size(self.ctx) }
+    }
+}
+
+impl Drop for SequenceContext {
+    fn drop(&mut self) {
+        // SAFETY: these were allocated in `SequenceContext::new`, and are not used after this.
+        unsafe {
+            llama_batch_free(self.batch);
+            if let Some(batch) = self.embd_batch {
+                llama_batch_free(batch);
+            }
+            llama_free(self.ctx);
+            llama_free_model(self.model);
+        }
+    }
+}
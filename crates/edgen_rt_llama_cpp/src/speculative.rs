@@ -0,0 +1,381 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Speculative decoding of one-shot completions, where a small draft model proposes the next
+//! tokens of a completion and the model being completed verifies all of them in a single step.
+//!
+//! [`llama_cpp`] only returns the logits of the last decoded token, so the decoder drives its
+//! contexts through [`llama_cpp_sys`] directly.
+
+use std::ffi::CString;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use futures::channel::mpsc::UnboundedReceiver;
+use llama_cpp::Token;
+use thiserror::Error;
+use tracing::{error, info};
+
+use edgen_core::speculative::{acceptance_rate, SPECULATIVE_STATS};
+
+use crate::sampler::EdgenSampler;
+use crate::sequence::{Job, SequenceContext, SequenceError, SequenceParams, BATCH_TOKENS};
+
+/// An error that occurred while starting a [`SpeculativeDecoder`].
+#[derive(Debug, Error)]
+pub enum SpeculativeError {
+    #[error("failed to load the model for speculative decoding")]
+    LoadModel,
+    #[error("failed to load the draft model")]
+    LoadDraftModel,
+    #[error("the draft model does not share the vocabulary of the model")]
+    VocabularyMismatch,
+    #[error("failed to create the speculative decoding contexts: {0}")]
+    Context(#[from] SequenceError),
+    #[error("the speculative decoding thread stopped")]
+    Stopped,
+}
+
+/// How a [`SpeculativeDecoder`] is set up.
+#[derive(Debug, Clone, Copy)]
+pub struct SpeculativeParams {
+    /// The number of tokens in the contexts of both models.
+    pub context_size: u32,
+
+    /// The number of tokens drafted at every step.
+    pub draft_tokens: u32,
+
+    /// The number of threads used to decode.
+    pub threads: u32,
+
+    /// The number of model layers offloaded to the GPU, for both models.
+    pub gpu_layers: u32,
+}
+
+/// Decodes one-shot completions of a model speculatively, on a dedicated thread.
+///
+/// At every step, the draft model greedily proposes the next few tokens, and the model decodes
+/// them all in a single batch, sampling a token at each of their positions with the completion's
+/// own sampler. Drafted tokens are accepted for as long as they match the sampled ones, and the
+/// first token that doesn't is replaced by the sampled one. Every emitted token is thus sampled
+/// from the logits of the model, just like without a draft, so greedy completions are the same
+/// as those decoded one token at a time, up to rounding differences between batch sizes.
+///
+/// Completions are decoded one at a time, in order of arrival. The decoder loads its own copies
+/// of the model weights, which are memory-mapped and so shared with the other copies when
+/// running on the CPU.
+pub struct SpeculativeDecoder {
+    /// Where new completions are sent to the decoding thread.
+    jobs_tx: Sender<Job>,
+
+    /// How the decoder was set up.
+    params: SpeculativeParams,
+
+    /// The number of bytes taken by the contexts and the draft model.
+    memory_size: usize,
+}
+
+impl SpeculativeParams {
+    fn sequence(&self) -> SequenceParams {
+        SequenceParams {
+            context_size: self.context_size,
+            sequences: 1,
+            threads: self.threads,
+            gpu_layers: self.gpu_layers,
+            embeddings: false,
+        }
+    }
+}
+
+impl SpeculativeDecoder {
+    /// Loads the model at `path` and the draft model at `draft_path`, and starts a decoder of the
+    /// completions of the model, blocking until the contexts are ready.
+    pub fn new(
+        path: impl AsRef<Path>,
+        draft_path: impl AsRef<Path>,
+        mut params: SpeculativeParams,
+    ) -> Result<Self, SpeculativeError> {
+        // every step verifies the drafted tokens along with the last sampled token
+        params.draft_tokens = params.draft_tokens.clamp(1, BATCH_TOKENS as u32 - 1);
+        let draft_size = std::fs::metadata(&draft_path).map_or(0, |metadata| metadata.len());
+        let path = CString::new(path.as_ref().to_string_lossy().as_bytes())
+            .map_err(|_| SpeculativeError::LoadModel)?;
+        let draft_path = CString::new(draft_path.as_ref().to_string_lossy().as_bytes())
+            .map_err(|_| SpeculativeError::LoadDraftModel)?;
+        let (jobs_tx, jobs_rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+
+        thread::spawn(move || {
+            let decoder = match load(&path, &draft_path, params) {
+                Ok(decoder) => decoder,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+            let memory_size =
+                decoder.target.state_size() + decoder.draft.state_size() + draft_size as usize;
+            let _ = ready_tx.send(Ok(memory_size));
+
+            decoder.run(jobs_rx);
+        });
+
+        let memory_size = ready_rx.recv().map_err(|_| SpeculativeError::Stopped)??;
+        info!(
+            "Started decoding speculatively, drafting {} tokens at a time",
+            params.draft_tokens
+        );
+
+        Ok(Self {
+            jobs_tx,
+            params,
+            memory_size,
+        })
+    }
+
+    /// Returns the number of bytes taken by the contexts and the draft model of this decoder.
+    pub fn memory_size(&self) -> usize {
+        self.memory_size
+    }
+
+    /// Returns the maximum number of tokens that a completion of a prompt of `prompt_len` tokens
+    /// can generate, given the `max_tokens` requested, or [`None`] if the completion cannot be
+    /// decoded by this decoder.
+    pub fn max_tokens(&self, prompt_len: usize, max_tokens: usize) -> Option<usize> {
+        let context_size = self.params.context_size as usize;
+        // the drafted tokens past the last one generated must fit as well
+        let reserved = prompt_len + self.params.draft_tokens as usize;
+        if prompt_len == 0 || reserved >= context_size {
+            return None;
+        }
+
+        Some(max_tokens.min(context_size - reserved))
+    }
+
+    /// Queues a completion of `prompt` with `sampler`, returning a stream of its tokens. Dropping
+    /// the stream stops the completion.
+    ///
+    /// `max_tokens` must not exceed what [`SpeculativeDecoder::max_tokens`] allows.
+    pub fn submit(
+        &self,
+        prompt: Vec<Token>,
+        sampler: EdgenSampler,
+        max_tokens: usize,
+    ) -> Result<UnboundedReceiver<Token>, SpeculativeError> {
+        let (job, stream) = Job::new(prompt, sampler, max_tokens);
+        self.jobs_tx
+            .send(job)
+            .map_err(|_| SpeculativeError::Stopped)?;
+
+        Ok(stream)
+    }
+}
+
+/// Loads the model at `path` and the draft model at `draft_path` into the contexts of a
+/// [`Decoder`].
+fn load(
+    path: &CString,
+    draft_path: &CString,
+    params: SpeculativeParams,
+) -> Result<Decoder, SpeculativeError> {
+    let load = |path, load_error| match SequenceContext::new(path, params.sequence(), None) {
+        Err(SequenceError::LoadModel) => Err(load_error),
+        context => context.map_err(SpeculativeError::from),
+    };
+    let target = load(path, SpeculativeError::LoadModel)?;
+    let draft = load(draft_path, SpeculativeError::LoadDraftModel)?;
+    if target.n_vocab() != draft.n_vocab() {
+        return Err(SpeculativeError::VocabularyMismatch);
+    }
+
+    Ok(Decoder {
+        target,
+        draft,
+        draft_tokens: params.draft_tokens as usize,
+    })
+}
+
+/// The state of the decoding thread.
+struct Decoder {
+    /// The model being completed, which verifies the drafted tokens.
+    target: SequenceContext,
+
+    /// The draft model.
+    draft: SequenceContext,
+
+    /// The number of tokens drafted at every step.
+    draft_tokens: usize,
+}
+
+impl Decoder {
+    /// Decodes completions until every [`SpeculativeDecoder`] handle is dropped and the last
+    /// completion is finished.
+    fn run(mut self, jobs_rx: Receiver<Job>) {
+        while let Ok(job) = jobs_rx.recv() {
+            if job.output.is_closed() {
+                continue;
+            }
+
+            let mut counts = DraftCounts::default();
+            if let Err(code) = self.complete(job, &mut counts) {
+                error!("Failed to decode speculatively (error {code}), stopping the completion");
+            }
+
+            let DraftCounts { drafted, accepted } = counts;
+            if drafted > 0 {
+                SPECULATIVE_STATS.record(drafted, accepted);
+                info!(
+                    "Accepted {accepted} of {drafted} drafted tokens ({:.0}%)",
+                    acceptance_rate(drafted as u64, accepted as u64) * 100.0
+                );
+            }
+        }
+    }
+
+    /// Generates the tokens of `job`, counting the drafted and accepted tokens in `counts`.
+    ///
+    /// Fails with the error code of llama.cpp if decoding fails.
+    fn complete(&mut self, mut job: Job, counts: &mut DraftCounts) -> Result<(), i32> {
+        let eos = self.target.eos();
+        self.target.truncate(0);
+        self.draft.truncate(0);
+
+        let logits = self.target.decode(&job.history, 0, false)?;
+        let token = self.target.sample(&mut job, &logits[0]);
+        if !job.push(token, eos) {
+            return Ok(());
+        }
+
+        // the number of tokens of the history in the context of the draft model
+        let mut draft_len = 0;
+        loop {
+            let remaining = job.max_tokens - job.generated;
+            let draft = self.draft(&job.history, &mut draft_len, remaining - 1, eos)?;
+
+            // the last sampled token is not in the context yet, and is decoded with the drafts
+            let pos = job.history.len() - 1;
+            let mut tokens = vec![job.history[pos]];
+            tokens.extend_from_slice(&draft);
+            let logits = self.target.decode(&tokens, pos, true)?;
+
+            let mut matched = 0;
+            let mut running = true;
+            for logits in &logits {
+                let token = self.target.sample(&mut job, logits);
+                let is_draft = draft
+                    .get(matched)
+                    .is_some_and(|drafted| drafted.0 == token.0);
+                running = job.push(token, eos);
+                if !running || !is_draft {
+                    break;
+                }
+                matched += 1;
+            }
+            counts.drafted += draft.len();
+            counts.accepted += matched;
+            if !running {
+                return Ok(());
+            }
+
+            // only the accepted drafts stay in the contexts
+            self.target.truncate(job.history.len() - 1);
+            draft_len = draft_len.min(job.history.len() - 1);
+            self.draft.truncate(draft_len);
+        }
+    }
+
+    /// Drafts up to `max_tokens` tokens following `history`, of which the first `draft_len` are
+    /// already in the context of the draft model, updating `draft_len` to the number of tokens in
+    /// that context once drafting is done.
+    ///
+    /// Drafting stops early at the end of stream token.
+    fn draft(
+        &mut self,
+        history: &[Token],
+        draft_len: &mut usize,
+        max_tokens: usize,
+        eos: Token,
+    ) -> Result<Vec<Token>, i32> {
+        let max_tokens = max_tokens.min(self.draft_tokens);
+        if max_tokens == 0 {
+            return Ok(vec![]);
+        }
+
+        let logits = self
+            .draft
+            .decode(&history[*draft_len..], *draft_len, false)?;
+        *draft_len = history.len();
+        let mut draft = vec![most_likely(&logits[0])];
+
+        while draft.len() < max_tokens {
+            // PANIC SAFETY: there is at least one drafted token.
+            let last = *draft.last().unwrap();
+            if last.0 == eos.0 {
+                break;
+            }
+
+            let logits = self.draft.decode(&[last], *draft_len, false)?;
+            *draft_len += 1;
+            draft.push(most_likely(&logits[0]));
+        }
+
+        Ok(draft)
+    }
+}
+
+/// The number of tokens drafted for a completion, and how many of them were accepted.
+#[derive(Default)]
+struct DraftCounts {
+    drafted: usize,
+    accepted: usize,
+}
+
+/// Returns the most likely token, given the `logits` of every token in the vocabulary.
+fn most_likely(logits: &[f32]) -> Token {
+    let id = logits
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map_or(0, |(id, _)| id);
+    Token(id as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_tokens_leave_room_for_drafts() {
+        let decoder = SpeculativeDecoder {
+            jobs_tx: channel().0,
+            params: SpeculativeParams {
+                context_size: 1024,
+                draft_tokens: 8,
+                threads: 1,
+                gpu_layers: 0,
+            },
+            memory_size: 0,
+        };
+
+        assert_eq!(decoder.max_tokens(24, 100), Some(100));
+        assert_eq!(decoder.max_tokens(24, 4096), Some(992));
+        assert_eq!(decoder.max_tokens(1016, 100), None);
+        assert_eq!(decoder.max_tokens(0, 100), None);
+    }
+
+    #[test]
+    fn most_likely_token() {
+        assert_eq!(most_likely(&[0.5, 2.0, -1.0, 1.5]).0, 1);
+        assert_eq!(most_likely(&[f32::NEG_INFINITY, -3.0]).0, 1);
+    }
+}
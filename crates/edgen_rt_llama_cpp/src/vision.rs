@@ -0,0 +1,375 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Decoding of completions of multimodal models, whose prompts have images embedded by a
+//! [`ClipEncoder`].
+//!
+//! [`llama_cpp`] cannot decode embeddings, so the decoder drives its own context of the model
+//! through [`llama_cpp_sys`] directly.
+
+use std::ffi::CString;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use edgen_core::vision::ImageInput;
+use futures::channel::mpsc::UnboundedReceiver;
+use llama_cpp::Token;
+use thiserror::Error;
+use tracing::error;
+
+use crate::clip::{ClipEncoder, ClipError};
+use crate::sampler::EdgenSampler;
+use crate::sequence::{Job, SequenceContext, SequenceError, SequenceParams};
+
+/// An error that occurred while starting a [`VisionDecoder`].
+#[derive(Debug, Error)]
+pub enum VisionError {
+    #[error("invalid path: {0}")]
+    InvalidPath(String),
+    #[error("failed to load the model: {0}")]
+    Context(#[from] SequenceError),
+    #[error("failed to load the projector: {0}")]
+    Projector(#[from] ClipError),
+    #[error("the projector embeds images in {projector} values, but the model in {model}")]
+    EmbeddingSize { projector: usize, model: usize },
+    #[error("the vision decoding thread stopped")]
+    Stopped,
+}
+
+/// How a [`VisionDecoder`] is set up.
+#[derive(Debug, Clone, Copy)]
+pub struct VisionParams {
+    /// The number of tokens in the context.
+    pub context_size: u32,
+
+    /// The number of threads used to decode.
+    pub threads: u32,
+
+    /// The number of model layers offloaded to the GPU.
+    pub gpu_layers: u32,
+}
+
+/// A part of the prompt of a multimodal completion.
+#[derive(Debug, Clone)]
+pub enum PromptChunk {
+    /// Text, tokenized.
+    Tokens(Vec<Token>),
+
+    /// An image, which is encoded into as many embeddings as the projector makes of an image.
+    Image(ImageInput),
+}
+
+/// The content of a position of the context, which tells whether a prompt continues it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Token(i32),
+
+    /// A position taken by the embeddings of the image with this id.
+    Image(u64),
+}
+
+/// A completion of a prompt with images.
+struct VisionJob {
+    job: Job,
+    prompt: Vec<PromptChunk>,
+}
+
+/// Decodes completions of a multimodal model, whose prompts have images, on a dedicated thread.
+///
+/// Completions are decoded one at a time, in order of arrival. The context keeps the prompt and
+/// the tokens of the last completion, so that a completion whose prompt starts with them, such as
+/// the next turn of a chat, only decodes the rest of its prompt, and only encodes its new images.
+pub struct VisionDecoder {
+    /// Where new completions are sent to the decoding thread.
+    jobs_tx: Sender<VisionJob>,
+
+    /// How the decoder was set up.
+    params: VisionParams,
+
+    /// The number of positions taken by the embeddings of an image.
+    image_tokens: usize,
+
+    /// The number of bytes taken by the projector and the context.
+    memory_size: usize,
+}
+
+impl VisionDecoder {
+    /// Loads the model at `path` and the multimodal projector at `mmproj_path`, and starts a
+    /// decoder of completions of the model, blocking until the context is ready.
+    pub fn new(
+        path: impl AsRef<Path>,
+        mmproj_path: impl AsRef<Path>,
+        params: VisionParams,
+    ) -> Result<Self, VisionError> {
+        let path = path.as_ref();
+        let path = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|_| VisionError::InvalidPath(path.to_string_lossy().to_string()))?;
+        let encoder = ClipEncoder::load(mmproj_path)?;
+        let (jobs_tx, jobs_rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+
+        thread::spawn(move || {
+            let sequence = SequenceParams {
+                context_size: params.context_size,
+                sequences: 1,
+                threads: params.threads,
+                gpu_layers: params.gpu_layers,
+                embeddings: false,
+            };
+            let context = match SequenceContext::new(&path, sequence, None) {
+                Ok(context) => context,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(VisionError::from(e)));
+                    return;
+                }
+            };
+            if encoder.n_embd() != context.n_embd() {
+                let _ = ready_tx.send(Err(VisionError::EmbeddingSize {
+                    projector: encoder.n_embd(),
+                    model: context.n_embd(),
+                }));
+                return;
+            }
+            let memory_size = encoder.memory_size() + context.state_size();
+            let _ = ready_tx.send(Ok((encoder.image_tokens(), memory_size)));
+
+            Decoder {
+                context,
+                encoder,
+                cached: vec![],
+            }
+            .run(jobs_rx);
+        });
+
+        let (image_tokens, memory_size) = ready_rx.recv().map_err(|_| VisionError::Stopped)??;
+
+        Ok(Self {
+            jobs_tx,
+            params,
+            image_tokens,
+            memory_size,
+        })
+    }
+
+    /// Returns the number of bytes taken by the projector and the context of this decoder. The
+    /// model itself is memory-mapped, and shared with the other contexts of the model.
+    pub fn memory_size(&self) -> usize {
+        self.memory_size
+    }
+
+    /// Returns the number of positions of the context that `prompt` takes.
+    pub fn prompt_len(&self, prompt: &[PromptChunk]) -> usize {
+        prompt
+            .iter()
+            .map(|chunk| match chunk {
+                PromptChunk::Tokens(tokens) => tokens.len(),
+                PromptChunk::Image(_) => self.image_tokens,
+            })
+            .sum()
+    }
+
+    /// Returns the maximum number of tokens that a completion of a prompt taking `prompt_len`
+    /// positions can generate, given the `max_tokens` requested, or [`None`] if the completion
+    /// cannot be decoded by this decoder.
+    pub fn max_tokens(&self, prompt_len: usize, max_tokens: usize) -> Option<usize> {
+        let context_size = self.params.context_size as usize;
+        if prompt_len == 0 || prompt_len >= context_size {
+            return None;
+        }
+
+        Some(max_tokens.min(context_size - prompt_len))
+    }
+
+    /// Queues a completion of `prompt` with `sampler`, returning a stream of its tokens. Dropping
+    /// the stream stops the completion.
+    ///
+    /// `prompt` must end with text, and `max_tokens` must not exceed what
+    /// [`VisionDecoder::max_tokens`] allows.
+    pub fn submit(
+        &self,
+        prompt: Vec<PromptChunk>,
+        sampler: EdgenSampler,
+        max_tokens: usize,
+    ) -> Result<UnboundedReceiver<Token>, VisionError> {
+        let tokens = prompt
+            .iter()
+            .filter_map(|chunk| match chunk {
+                PromptChunk::Tokens(tokens) => Some(tokens.iter().copied()),
+                PromptChunk::Image(_) => None,
+            })
+            .flatten()
+            .collect();
+        // penalties only apply to the tokens of the prompt, not to images
+        let (job, stream) = Job::new(tokens, sampler, max_tokens);
+        self.jobs_tx
+            .send(VisionJob { job, prompt })
+            .map_err(|_| VisionError::Stopped)?;
+
+        Ok(stream)
+    }
+}
+
+/// The state of the decoding thread.
+struct Decoder {
+    context: SequenceContext,
+    encoder: ClipEncoder,
+
+    /// The contents of the context.
+    cached: Vec<Slot>,
+}
+
+impl Decoder {
+    /// Decodes completions until every [`VisionDecoder`] handle is dropped and the last
+    /// completion is finished.
+    fn run(mut self, jobs_rx: Receiver<VisionJob>) {
+        while let Ok(job) = jobs_rx.recv() {
+            if job.job.output.is_closed() {
+                continue;
+            }
+
+            if let Err(e) = self.complete(job) {
+                error!("Failed to decode a prompt with images ({e}), stopping the completion");
+                self.cached.clear();
+            }
+        }
+    }
+
+    /// Generates the tokens of `job`, reusing the contents of the context that its prompt starts
+    /// with.
+    fn complete(&mut self, job: VisionJob) -> Result<(), String> {
+        let VisionJob { mut job, prompt } = job;
+        let eos = self.context.eos();
+
+        let mut slots = vec![];
+        let mut chunk_starts = vec![];
+        for chunk in &prompt {
+            chunk_starts.push(slots.len());
+            match chunk {
+                PromptChunk::Tokens(tokens) => {
+                    slots.extend(tokens.iter().map(|t| Slot::Token(t.0)))
+                }
+                PromptChunk::Image(image) => {
+                    let len = self.encoder.image_tokens();
+                    slots.resize(slots.len() + len, Slot::Image(image.id()))
+                }
+            }
+        }
+        if !matches!(slots.last(), Some(Slot::Token(_))) {
+            return Err("the prompt does not end with text".to_string());
+        }
+
+        // the last prompt token is always decoded, for the logits of the first generated token
+        let reused = reusable_prefix(&self.cached, &slots);
+        self.context.truncate(reused);
+        self.cached.truncate(reused);
+
+        let mut logits = vec![];
+        for (chunk, start) in prompt.iter().zip(chunk_starts) {
+            match chunk {
+                PromptChunk::Tokens(tokens) if start + tokens.len() > reused => {
+                    let skipped = reused.saturating_sub(start);
+                    logits = self
+                        .context
+                        .decode(&tokens[skipped..], start + skipped, false)
+                        .map_err(|code| format!("error {code}"))?
+                        .remove(0);
+                }
+                PromptChunk::Image(image) if start >= reused => {
+                    let embd = self
+                        .encoder
+                        .encode(image.bytes())
+                        .map_err(|e| e.to_string())?;
+                    self.context
+                        .decode_embeddings(&embd, start)
+                        .map_err(|code| format!("error {code}"))?;
+                }
+                _ => {}
+            }
+        }
+        self.cached = slots;
+
+        loop {
+            let token = self.context.sample(&mut job, &logits);
+            if !job.push(token, eos) {
+                return Ok(());
+            }
+
+            logits = self
+                .context
+                .decode(&[token], self.cached.len(), false)
+                .map_err(|code| format!("error {code}"))?
+                .remove(0);
+            self.cached.push(Slot::Token(token.0));
+        }
+    }
+}
+
+/// Returns the number of positions at the start of `cached` that a prompt made of `slots` can
+/// reuse, which leaves at least its last token to decode and never ends within an image.
+fn reusable_prefix(cached: &[Slot], slots: &[Slot]) -> usize {
+    let mut reused = cached
+        .iter()
+        .zip(slots)
+        .take_while(|(a, b)| a == b)
+        .count()
+        .min(slots.len().saturating_sub(1));
+
+    // the embeddings of an image are decoded whole
+    while reused > 0
+        && matches!(slots[reused], Slot::Image(_))
+        && slots[reused - 1] == slots[reused]
+    {
+        reused -= 1;
+    }
+
+    reused
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_are_reused_up_to_the_last_token() {
+        let image = Slot::Image(7);
+        let cached = [Slot::Token(1), image, image, Slot::Token(2), Slot::Token(3)];
+
+        let next_turn = [
+            Slot::Token(1),
+            image,
+            image,
+            Slot::Token(2),
+            Slot::Token(3),
+            Slot::Token(4),
+        ];
+        assert_eq!(reusable_prefix(&cached, &next_turn), 5);
+        assert_eq!(reusable_prefix(&cached, &cached), 4);
+
+        let other_image = [
+            Slot::Token(1),
+            Slot::Image(8),
+            Slot::Image(8),
+            Slot::Token(2),
+        ];
+        assert_eq!(reusable_prefix(&cached, &other_image), 1);
+    }
+
+    #[test]
+    fn images_are_never_split() {
+        let image = Slot::Image(7);
+        let cached = [Slot::Token(1), image, image];
+        let prompt = [Slot::Token(1), image, image, image, image, Slot::Token(2)];
+
+        assert_eq!(reusable_prefix(&cached, &prompt), 1);
+    }
+}
@@ -16,13 +16,15 @@ use std::sync::Arc;
 use dashmap::DashMap;
 use futures::executor::block_on;
 use tokio::spawn;
-use tokio::task::JoinHandle;
+use tokio::task::{spawn_blocking, JoinHandle};
 use tokio::time::{interval, MissedTickBehavior};
 use tracing::info;
 use uuid::Uuid;
 use whisper_cpp::{WhisperModel, WhisperParams, WhisperSampling, WhisperSession};
 
+use edgen_core::cancellation::CancellationToken;
 use edgen_core::cleanup_interval;
+use edgen_core::memory::MEMORY_BUDGET;
 use edgen_core::perishable::{ActiveSignal, Perishable, PerishableReadGuard, PerishableWriteGuard};
 use edgen_core::settings::{DevicePolicy, SETTINGS};
 use edgen_core::whisper::{
@@ -30,6 +32,12 @@ use edgen_core::whisper::{
     WhisperEndpointError,
 };
 
+/// The size of a [`WhisperSession`], as a fraction of the size of its model.
+///
+/// whisper.cpp does not report how much memory a session takes, so it is estimated to be about a
+/// quarter of the model, which covers its key/value caches and compute buffers.
+const SESSION_SIZE_DIVISOR: u64 = 4;
+
 /// A large language model endpoint, implementing [`WhisperEndpoint`] using a [`whisper_cpp`] backend.
 pub struct WhisperCppEndpoint {
     /// A map of the models currently loaded into memory, with their path as the key.
@@ -66,10 +74,20 @@ impl WhisperEndpoint for WhisperCppEndpoint {
         model_path: impl AsRef<Path> + Send,
         args: TranscriptionArgs,
     ) -> Result<(String, Option<Uuid>), WhisperEndpointError> {
-        let pcm = parse::pcm(&args.file)?;
+        // the audio is decoded on a blocking thread, so that decoding a long file does not stall
+        // the runtime
+        let file = args.file;
+        let pcm = spawn_blocking(move || parse::pcm(&file))
+            .await
+            .map_err(move |e| WhisperEndpointError::Decode(e.to_string()))??;
+
+        // whisper.cpp runs the transcription on a blocking thread, which is aborted if this future
+        // is dropped (e.g. because the peer disconnected or the request timed out)
+        let cancel = CancellationToken::new();
+        let _cancel_on_drop = cancel.clone().drop_guard();
         let model = self.get(model_path).await;
         model
-            .transcription(args.create_session, args.session, pcm)
+            .transcription(args.create_session, args.session, pcm, cancel)
             .await
     }
 
@@ -110,6 +128,8 @@ impl Drop for WhisperCppEndpoint {
 struct UnloadingModel {
     model: Perishable<WhisperModel>,
     path: PathBuf,
+    /// The size of the model file, which is about the memory the loaded model takes.
+    model_size: u64,
     sessions: Arc<DashMap<Uuid, Perishable<WhisperSession>>>,
     maintenance_thread: JoinHandle<()>,
 }
@@ -133,9 +153,13 @@ impl UnloadingModel {
             }
         });
 
+        let model_size = std::fs::metadata(&model_path).map_or(0, |metadata| metadata.len());
+
         Self {
-            model: Perishable::with_ttl(inactive_whisper_ttl()),
+            model: Perishable::with_ttl(inactive_whisper_ttl())
+                .with_memory_budget(MEMORY_BUDGET.clone(), move |_| model_size),
             path: model_path.as_ref().to_path_buf(),
+            model_size,
             sessions,
             maintenance_thread,
         }
@@ -146,12 +170,19 @@ impl UnloadingModel {
         self.model.is_alive().await
     }
 
-    /// Computes the full transcription for the provided *PCM*;
+    /// Returns the estimated number of bytes of memory a session of this model takes.
+    fn session_size(&self) -> u64 {
+        self.model_size / SESSION_SIZE_DIVISOR
+    }
+
+    /// Computes the full transcription for the provided *PCM*, which is aborted once `cancel` is
+    /// cancelled.
     async fn transcription(
         &self,
         create_session: bool,
         uuid: Option<Uuid>,
         pcm: Vec<f32>,
+        cancel: CancellationToken,
     ) -> Result<(String, Option<Uuid>), WhisperEndpointError> {
         let (_model_signal, model_guard) = get_or_init_model(&self.model, &self.path).await?;
 
@@ -160,13 +191,19 @@ impl UnloadingModel {
 
         params.thread_count = threads;
 
+        // whisper.cpp polls the abort callback between the computations of the transcription
+        let abort = cancel.clone();
+        params.set_abort_callback(move || abort.is_cancelled());
+
         let uuid = if let Some(uuid) = uuid {
             Some(uuid)
         } else {
             if create_session {
                 let uuid = Uuid::new_v4();
-                self.sessions
-                    .insert(uuid, Perishable::with_ttl(inactive_whisper_session_ttl()));
+                let session_size = self.session_size();
+                let session = Perishable::with_ttl(inactive_whisper_session_ttl())
+                    .with_memory_budget(MEMORY_BUDGET.clone(), move |_| session_size);
+                self.sessions.insert(uuid, session);
                 Some(uuid)
             } else {
                 None
@@ -188,7 +225,7 @@ impl UnloadingModel {
             session_guard
                 .advance(params, &pcm)
                 .await
-                .map_err(move |e| WhisperEndpointError::Advance(e.to_string()))?;
+                .map_err(|e| advance_error(e, &cancel))?;
             let res = session_guard
                 .new_context()
                 .map_err(move |e| WhisperEndpointError::Advance(e.to_string()))?;
@@ -200,6 +237,7 @@ impl UnloadingModel {
             }
         } else {
             info!("Allocating oneshot whisper session");
+            let _memory = MEMORY_BUDGET.reserve(self.session_size());
             let mut session = model_guard
                 .new_session()
                 .await
@@ -209,7 +247,7 @@ impl UnloadingModel {
             session
                 .advance(params, &pcm)
                 .await
-                .map_err(move |e| WhisperEndpointError::Advance(e.to_string()))?;
+                .map_err(|e| advance_error(e, &cancel))?;
             let res = session
                 .new_context()
                 .map_err(move |e| WhisperEndpointError::Advance(e.to_string()))?;
@@ -225,6 +263,18 @@ impl Drop for UnloadingModel {
     }
 }
 
+/// Helper function to convert an error advancing a [`WhisperSession`] into a
+/// [`WhisperEndpointError`], which is [`WhisperEndpointError::Cancelled`] if the transcription was
+/// aborted through `cancel`.
+fn advance_error(error: impl ToString, cancel: &CancellationToken) -> WhisperEndpointError {
+    if cancel.is_cancelled() {
+        info!("Transcription cancelled");
+        WhisperEndpointError::Cancelled
+    } else {
+        WhisperEndpointError::Advance(error.to_string())
+    }
+}
+
 /// Helper function to acquire a read guard to a [`WhisperModel`] (and its associated
 /// [`ActiveSignal`]).
 async fn get_or_init_model(
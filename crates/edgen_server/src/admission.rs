@@ -0,0 +1,220 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Admission control of the AI endpoints, which queues requests past the concurrency limit of an
+//! endpoint and rejects them with `429 Too Many Requests` once the queue is full.
+
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Json, Response};
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+use edgen_core::admission::{AdmissionError, AdmissionQueue, QueueStatus};
+use edgen_core::settings::SETTINGS;
+
+/// The header carrying the priority of a request, an integer that defaults to `0`. Requests with
+/// a higher priority are admitted first.
+pub const PRIORITY_HEADER: &str = "x-edgen-priority";
+
+/// The queue of chat and text completions requests.
+static CHAT_COMPLETIONS: Lazy<AdmissionQueue> = Lazy::new(Default::default);
+
+/// The queue of audio transcriptions requests.
+static AUDIO_TRANSCRIPTIONS: Lazy<AdmissionQueue> = Lazy::new(Default::default);
+
+/// The queue of embeddings requests.
+static EMBEDDINGS: Lazy<AdmissionQueue> = Lazy::new(Default::default);
+
+/// The queue of image generations requests.
+static IMAGE_GENERATIONS: Lazy<AdmissionQueue> = Lazy::new(Default::default);
+
+/// Applies the admission limits in the settings to every endpoint.
+pub async fn set_limits() {
+    let settings = SETTINGS.read().await;
+    let settings = settings.read().await;
+
+    CHAT_COMPLETIONS.set_limits(settings.chat_completions_admission);
+    AUDIO_TRANSCRIPTIONS.set_limits(settings.audio_transcriptions_admission);
+    EMBEDDINGS.set_limits(settings.embeddings_admission);
+    IMAGE_GENERATIONS.set_limits(settings.image_generations_admission);
+}
+
+/// Returns the state of the queue of chat and text completions requests.
+pub fn chat_completions_queue() -> QueueStatus {
+    CHAT_COMPLETIONS.status()
+}
+
+/// Returns the state of the queue of audio transcriptions requests.
+pub fn audio_transcriptions_queue() -> QueueStatus {
+    AUDIO_TRANSCRIPTIONS.status()
+}
+
+/// Returns the state of the queue of embeddings requests.
+pub fn embeddings_queue() -> QueueStatus {
+    EMBEDDINGS.status()
+}
+
+/// Returns the state of the queue of image generations requests.
+pub fn image_generations_queue() -> QueueStatus {
+    IMAGE_GENERATIONS.status()
+}
+
+/// Middleware admitting chat and text completions requests.
+pub async fn admit_chat_completions(request: Request, next: Next) -> Response {
+    admit(&CHAT_COMPLETIONS, request, next).await
+}
+
+/// Middleware admitting audio transcriptions requests.
+pub async fn admit_audio_transcriptions(request: Request, next: Next) -> Response {
+    admit(&AUDIO_TRANSCRIPTIONS, request, next).await
+}
+
+/// Middleware admitting embeddings requests.
+pub async fn admit_embeddings(request: Request, next: Next) -> Response {
+    admit(&EMBEDDINGS, request, next).await
+}
+
+/// Middleware admitting image generations requests.
+pub async fn admit_image_generations(request: Request, next: Next) -> Response {
+    admit(&IMAGE_GENERATIONS, request, next).await
+}
+
+/// Waits for the turn of `request` in `queue` before handling it, and holds the turn until the
+/// whole response body was sent, so that streamed responses count as in flight until they end.
+async fn admit(queue: &AdmissionQueue, request: Request, next: Next) -> Response {
+    let permit = match queue.admit(priority(request.headers())).await {
+        Ok(permit) => permit,
+        Err(e) => return AdmissionRejection::from(e).into_response(),
+    };
+
+    next.run(request).await.map(move |body| {
+        Body::from_stream(body.into_data_stream().map(move |chunk| {
+            let _turn = &permit;
+            chunk
+        }))
+    })
+}
+
+/// Returns the priority of a request with `headers`, which is `0` unless the request has a valid
+/// [`PRIORITY_HEADER`].
+fn priority(headers: &HeaderMap) -> i32 {
+    headers
+        .get(PRIORITY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// A request that was rejected because too many requests are waiting for their turn.
+///
+/// This is raised as a `429 Too Many Requests`, with a `Retry-After` header.
+#[derive(Serialize, Deserialize, Error, ToSchema, Debug, PartialEq, Eq)]
+#[error("too many requests are queued, retry after {retry_after} seconds")]
+pub struct AdmissionRejection {
+    /// The number of seconds after which the request may be admitted.
+    pub retry_after: u64,
+}
+
+impl From<AdmissionError> for AdmissionRejection {
+    fn from(value: AdmissionError) -> Self {
+        match value {
+            AdmissionError::QueueFull { retry_after } => Self {
+                // round up, so that the request is not retried too early
+                retry_after: retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0),
+            },
+        }
+    }
+}
+
+impl IntoResponse for AdmissionRejection {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, self.retry_after.to_string())],
+            Json(self),
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::middleware::from_fn;
+    use axum::routing::get;
+    use axum::Router;
+    use axum_test::TestServer;
+
+    use edgen_core::admission::AdmissionLimits;
+
+    use super::*;
+
+    #[test]
+    fn test_priority() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(priority(&headers), 0);
+
+        headers.insert(PRIORITY_HEADER, "-3".parse().unwrap());
+        assert_eq!(priority(&headers), -3);
+
+        headers.insert(PRIORITY_HEADER, "urgent".parse().unwrap());
+        assert_eq!(priority(&headers), 0);
+    }
+
+    #[test]
+    fn test_retry_after_rounds_up() {
+        let rejection = AdmissionRejection::from(AdmissionError::QueueFull {
+            retry_after: Duration::from_millis(2500),
+        });
+        assert_eq!(rejection.retry_after, 3);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_when_full() {
+        static QUEUE: Lazy<AdmissionQueue> = Lazy::new(|| {
+            AdmissionQueue::new(AdmissionLimits {
+                max_concurrent: 1,
+                max_queued: 0,
+            })
+        });
+
+        let router = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(from_fn(|request: Request, next: Next| {
+                admit(&QUEUE, request, next)
+            }));
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let response = server.get("/").await;
+        response.assert_status_ok();
+        response.assert_text("ok");
+        assert_eq!(QUEUE.status().in_flight, 0);
+
+        // the request holding the only turn is never finished
+        let _turn = QUEUE.admit(0).await.unwrap();
+        let response = server.get("/").await;
+        response.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.header(RETRY_AFTER), "1");
+        assert_eq!(
+            response.json::<AdmissionRejection>(),
+            AdmissionRejection { retry_after: 1 }
+        );
+    }
+}
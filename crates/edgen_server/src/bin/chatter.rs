@@ -74,6 +74,10 @@ pub struct Chat {
     #[argh(option, short = 'e', default = "0.0")]
     pub large_chance: f32,
 
+    /// send one-shot requests, which are decoded together if batching is enabled in the server.
+    #[argh(switch, short = 'o')]
+    pub one_shot: bool,
+
     /// the base URL of the endpoint the requests will be sent to.
     #[argh(
         option,
@@ -123,6 +127,7 @@ async fn main() {
         request_chains.push(chain);
     }
 
+    let start = Instant::now();
     let mut join_set = JoinSet::new();
     let (tx, mut rx) = mpsc::unbounded_channel();
     for (id, count) in request_chains.drain(..).enumerate() {
@@ -140,7 +145,7 @@ async fn main() {
         .replace(' ', "_")
         .replace(':', "-");
     let file_name = format!(
-        "n{}_b{:.3}_d{:.3}_i{:.3}_a{:.3}_l{}_e{:.3}_{}",
+        "n{}_b{:.3}_d{:.3}_i{:.3}_a{:.3}_l{}_e{:.3}{}_{}",
         chat_args.requests,
         chat_args.continue_chance,
         chat_args.chance_decay,
@@ -148,6 +153,7 @@ async fn main() {
         chat_args.max_idle,
         chat_args.message_limit,
         chat_args.large_chance,
+        if chat_args.one_shot { "_o" } else { "" },
         fmt_time
     );
     let file_name = file_name.replace('.', "-");
@@ -167,6 +173,7 @@ async fn main() {
         all_tokens_nf.extend(&stats.all_tokens[1..]);
         token_counts.push(stats.all_tokens.len());
     }
+    let elapsed = start.elapsed().as_secs_f32();
     f.flush().await.expect("Failed to flush file");
     println!("Wrote output to file: \"{file_path}\"");
 
@@ -177,7 +184,12 @@ async fn main() {
     println!("All token times (without first token):");
     print_stats(all_tokens_nf);
     println!("Token counts:");
+    let total_tokens: usize = token_counts.iter().sum();
     print_token_stats(token_counts);
+    println!(
+        "Throughput: {} tokens/s ({total_tokens} tokens in {elapsed}s, idle times included)",
+        total_tokens as f32 / elapsed
+    );
 
     while let Some(_) = join_set.join_next().await {}
 }
@@ -195,20 +207,28 @@ async fn chain_requests(
         model: Cow::from("default"),
         frequency_penalty: None,
         logit_bias: None,
+        logprobs: None,
+        top_logprobs: None,
         max_tokens: Some(chat_args.message_limit as u32),
         n: None,
         presence_penalty: None,
         seed: None,
         stop: None,
         stream: Some(true),
+        stream_options: None,
         response_format: None,
+        grammar: None,
+        context_overflow: None,
         temperature: None,
         top_p: None,
         tools: None,
         tool_choice: None,
         user: None,
-        one_shot: None,
+        one_shot: chat_args.one_shot.then_some(true),
         context_hint: None,
+        create_session: None,
+        session: None,
+        timeout: None,
     };
 
     body.messages.push(ChatMessage::System {
@@ -265,6 +285,11 @@ async fn chain_requests(
             match event {
                 Ok(Event::Open) => {}
                 Ok(Event::Message(message)) => {
+                    if message.data == "[DONE]" {
+                        event_source.close();
+                        break;
+                    }
+
                     if token_count >= chat_args.message_limit {
                         event_source.close();
                         break;
@@ -283,7 +308,13 @@ async fn chain_requests(
                     debug!("Chain {index} has received token {token_count}");
                     let response: ChatCompletionChunk =
                         serde_json::from_str(message.data.as_str()).unwrap();
-                    text += response.choices[0].delta.content.as_ref().unwrap();
+                    if let Some(content) = response
+                        .choices
+                        .first()
+                        .and_then(|choice| choice.delta.content.as_ref())
+                    {
+                        text += content;
+                    }
                 }
                 Err(reqwest_eventsource::Error::StreamEnded) => {}
                 Err(err) => {
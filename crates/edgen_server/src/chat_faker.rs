@@ -14,19 +14,22 @@
 
 use futures::Stream;
 use once_cell::sync::Lazy;
+use uuid::Uuid;
 
-use edgen_core::llm::{CompletionArgs, LLMEndpoint, LLMEndpointError};
+use edgen_core::llm::{
+    ChatSessionInfo, Completion, CompletionArgs, CompletionChunk, Embeddings, EmbeddingsArgs,
+    LLMEndpoint, LLMEndpointError, TextCompletionArgs,
+};
 use edgen_rt_chat_faker::ChatFakerEndpoint;
 
 use crate::model::Model;
-use crate::util::StoppingStream;
 
 static ENDPOINT: Lazy<ChatFakerEndpoint> = Lazy::new(Default::default);
 
 pub async fn chat_completion(
     model: Model,
     args: CompletionArgs,
-) -> Result<String, LLMEndpointError> {
+) -> Result<Vec<Completion>, LLMEndpointError> {
     ENDPOINT
         .chat_completions(
             model
@@ -40,41 +43,78 @@ pub async fn chat_completion(
 pub async fn chat_completion_stream(
     model: Model,
     args: CompletionArgs,
-) -> Result<StoppingStream<Box<dyn Stream<Item = String> + Unpin + Send>>, LLMEndpointError> {
-    let stream = ENDPOINT
+) -> Result<Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>, LLMEndpointError> {
+    ENDPOINT
         .stream_chat_completions(
             model
                 .file_path()
                 .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
             args,
         )
-        .await?;
+        .await
+}
 
-    Ok(StoppingStream::wrap_with_stop_words(
-        stream,
-        vec![
-            "<|ASSISTANT|>".to_string(),
-            "<|USER|>".to_string(),
-            "<|TOOL|>".to_string(),
-            "<|SYSTEM|>".to_string(),
-        ],
-    ))
+pub async fn text_completion(
+    model: Model,
+    args: TextCompletionArgs,
+) -> Result<Vec<Completion>, LLMEndpointError> {
+    ENDPOINT
+        .completions(
+            model
+                .file_path()
+                .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
+            args,
+        )
+        .await
+}
+
+pub async fn text_completion_stream(
+    model: Model,
+    args: TextCompletionArgs,
+) -> Result<Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>, LLMEndpointError> {
+    ENDPOINT
+        .stream_completions(
+            model
+                .file_path()
+                .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
+            args,
+        )
+        .await
+}
+
+pub async fn render_prompt(model: Model, args: CompletionArgs) -> Result<String, LLMEndpointError> {
+    ENDPOINT
+        .render_prompt(
+            model
+                .file_path()
+                .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
+            args,
+        )
+        .await
 }
 
 pub async fn embeddings(
     model: Model,
-    input: Vec<String>,
-) -> Result<Vec<Vec<f32>>, LLMEndpointError> {
+    args: EmbeddingsArgs,
+) -> Result<Embeddings, LLMEndpointError> {
     ENDPOINT
         .embeddings(
             model
                 .file_path()
                 .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
-            input,
+            args,
         )
         .await
 }
 
+pub async fn chat_sessions() -> Vec<ChatSessionInfo> {
+    ENDPOINT.chat_sessions().await
+}
+
+pub async fn delete_chat_session(id: Uuid) -> Result<(), LLMEndpointError> {
+    ENDPOINT.delete_chat_session(id).await
+}
+
 // Not needed. Just for completeness.
 #[allow(dead_code)]
 pub async fn reset_environment() {
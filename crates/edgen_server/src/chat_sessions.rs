@@ -0,0 +1,132 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Endpoints to manage explicit chat sessions, created with `create_session` in chat completions
+//! requests.
+
+use axum::extract;
+use axum::http::StatusCode;
+use axum::response::Json;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use edgen_core::llm::{ChatSessionInfo, LLMEndpointError};
+
+use crate::openai_shim::ChatCompletionError;
+use crate::{chat_faker, llm};
+
+/// An explicit chat session, kept until it expires or is deleted.
+#[derive(ToSchema, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct ChatSessionDesc {
+    /// The id of the session, to be passed as `session` in chat completions requests.
+    pub id: Uuid,
+
+    /// The file name of the model that the session belongs to.
+    pub model: String,
+
+    /// Whether the session is generating a completion at the moment.
+    pub in_use: bool,
+
+    /// The number of tokens in the context of the session. Absent while the session is in use.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<u32>,
+
+    /// The number of seconds the session has left to live if it is not used. Absent while the
+    /// session is in use.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<u64>,
+}
+
+impl From<ChatSessionInfo> for ChatSessionDesc {
+    fn from(value: ChatSessionInfo) -> Self {
+        Self {
+            id: value.id,
+            model: value
+                .model_path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            in_use: value.tokens.is_none(),
+            tokens: value.tokens,
+            expires_in: value.expires_in.map(|ttl| ttl.as_secs()),
+        }
+    }
+}
+
+/// Returns the explicit chat sessions of every model.
+async fn all_sessions() -> Vec<ChatSessionDesc> {
+    let mut sessions = llm::chat_sessions().await;
+    sessions.extend(chat_faker::chat_sessions().await);
+    sessions.into_iter().map(ChatSessionDesc::from).collect()
+}
+
+/// GET `/v1/chat/sessions`: returns the explicit chat sessions of every model.
+#[utoipa::path(
+        get,
+        path = "/chat/sessions",
+        responses(
+            (status = 200, description = "OK", body = [ChatSessionDesc]),
+        ),
+)]
+pub async fn list_sessions() -> Json<Vec<ChatSessionDesc>> {
+    Json(all_sessions().await)
+}
+
+/// GET `/v1/chat/sessions/{id}`: returns the explicit chat session with the id.
+///
+/// Raises a `404 Not Found` if there is no such session.
+#[utoipa::path(
+        get,
+        path = "/chat/sessions/{id}",
+        params(("id" = Uuid, Path, description = "the id of the session")),
+        responses(
+            (status = 200, description = "OK", body = ChatSessionDesc),
+            (status = 404, description = "the session does not exist", body = ChatCompletionError),
+        ),
+)]
+pub async fn retrieve_session(
+    extract::Path(id): extract::Path<Uuid>,
+) -> Result<Json<ChatSessionDesc>, ChatCompletionError> {
+    all_sessions()
+        .await
+        .into_iter()
+        .find(|session| session.id == id)
+        .map(Json)
+        .ok_or(ChatCompletionError::Endpoint(
+            LLMEndpointError::SessionNotFound,
+        ))
+}
+
+/// DELETE `/v1/chat/sessions/{id}`: deletes the explicit chat session with the id. A session that
+/// is generating a completion is discarded once it finishes.
+///
+/// Raises a `404 Not Found` if there is no such session.
+#[utoipa::path(
+        delete,
+        path = "/chat/sessions/{id}",
+        params(("id" = Uuid, Path, description = "the id of the session")),
+        responses(
+            (status = 204, description = "the session was deleted"),
+            (status = 404, description = "the session does not exist", body = ChatCompletionError),
+        ),
+)]
+pub async fn delete_session(
+    extract::Path(id): extract::Path<Uuid>,
+) -> Result<StatusCode, ChatCompletionError> {
+    match llm::delete_chat_session(id).await {
+        Err(LLMEndpointError::SessionNotFound) => chat_faker::delete_chat_session(id).await?,
+        result => result?,
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,6 +1,7 @@
 use crate::model_descriptor::{
     ModelDescriptor, ModelDescriptorError, ModelPaths, Quantization, StableDiffusionFiles,
 };
+use crate::requests::{request_timeout, with_timeout, RequestTimeout};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
@@ -74,6 +75,13 @@ pub struct CreateImageGenerationRequest<'a> {
     ///
     /// This value should probably not be set, if `model` is a pre-made descriptor name.
     pub vae_scale: Option<f64>,
+
+    /// The number of seconds the generation may take, past which the request fails with a
+    /// `504 Gateway Timeout`.
+    ///
+    /// If absent, the `request_timeout` configured for the server is used. `0` means there is no
+    /// limit.
+    pub timeout: Option<f32>,
 }
 
 /// This request is not at all conformant with OpenAI's API, as that one returns a URL to the
@@ -101,11 +109,18 @@ pub enum ImageGenerationError {
     /// Some parameter was missing from the request.
     #[error("A parameter was missing from the request: {0}")]
     MissingParam(String),
+    /// The request took longer than its timeout.
+    #[error(transparent)]
+    Timeout(#[from] RequestTimeout),
 }
 
 impl IntoResponse for ImageGenerationError {
     fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, Json(self)).into_response()
+        let status = match self {
+            ImageGenerationError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, Json(self)).into_response()
     }
 }
 
@@ -116,14 +131,16 @@ impl IntoResponse for ImageGenerationError {
 /// cannot do.
 ///
 /// On failure, may raise a `500 Internal Server Error` with a JSON-encoded [`ImageGenerationError`]
-/// to the peer.
+/// to the peer. If the generation takes longer than the timeout of the request, raises a
+/// `504 Gateway Timeout` instead.
 #[utoipa::path(
 post,
 path = "/image/generations",
 request_body = CreateImageGenerationRequest,
 responses(
 (status = 200, description = "OK", body = ImageGenerationResponse),
-(status = 500, description = "unexpected internal server error", body = ImageGenerationError)
+(status = 500, description = "unexpected internal server error", body = ImageGenerationError),
+(status = 504, description = "the request timed out", body = ImageGenerationError)
 ),
 )]
 pub async fn generate_image(
@@ -194,8 +211,10 @@ pub async fn generate_image(
     };
 
     let endpoint = CandleImageGenerationEndpoint {};
-    let images = endpoint
-        .generate_image(
+    let timeout = request_timeout(req.timeout).await;
+    let images = with_timeout(
+        timeout,
+        endpoint.generate_image(
             model_files,
             ImageGenerationArgs {
                 prompt: req.prompt.to_string(),
@@ -208,8 +227,9 @@ pub async fn generate_image(
                 guidance_scale: req.guidance_scale.unwrap_or(7.5),
                 vae_scale: req.vae_scale.unwrap_or(default_vae_scale),
             },
-        )
-        .await?;
+        ),
+    )
+    .await??;
 
     Ok(Json(ImageGenerationResponse { images }))
 }
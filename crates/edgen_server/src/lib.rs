@@ -31,6 +31,7 @@ use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use utoipa::OpenApi;
 
+use edgen_core::memory::MEMORY_BUDGET;
 use edgen_core::settings;
 use edgen_core::settings::SETTINGS;
 use openai_shim as chat;
@@ -39,7 +40,9 @@ use openai_shim as audio;
 #[macro_use]
 pub mod misc;
 
+pub mod admission;
 mod chat_faker;
+pub mod chat_sessions;
 pub mod cli;
 pub mod graceful_shutdown;
 mod image_generation;
@@ -48,6 +51,7 @@ mod model;
 mod model_descriptor;
 pub mod model_man;
 pub mod openai_shim;
+pub mod requests;
 mod routes;
 pub mod status;
 pub mod types;
@@ -62,22 +66,42 @@ mod whisper;
     ),
     paths(
         misc::edgen_version,
+        misc::render_prompt,
         chat::chat_completions,
+        chat::completions,
+        chat_sessions::list_sessions,
+        chat_sessions::retrieve_session,
+        chat_sessions::delete_session,
+        requests::abort_request,
         audio::create_transcription
     ),
     components(schemas(
         misc::Version,
+        misc::RenderedPrompt,
         openai_shim::CreateChatCompletionRequest,
         openai_shim::ChatCompletion,
         openai_shim::ChatCompletionChoice,
         openai_shim::ChatCompletionUsage,
         openai_shim::ChatCompletionChunk,
         openai_shim::ChatCompletionChunkDelta,
+        openai_shim::ChatCompletionChunkToolCall,
         openai_shim::ChatCompletionChunkChoice,
+        openai_shim::ChatCompletionStreamOptions,
+        openai_shim::ChatCompletionContextOverflow,
+        openai_shim::ChatCompletionLogprobs,
+        openai_shim::ChatCompletionTokenLogprob,
+        openai_shim::ChatCompletionTopLogprob,
         openai_shim::ChatCompletionError,
+        chat_sessions::ChatSessionDesc,
+        openai_shim::CreateCompletionRequest,
+        openai_shim::TextCompletion,
+        openai_shim::TextCompletionChoice,
+        openai_shim::TextCompletionLogprobs,
         openai_shim::ChatMessage,
         openai_shim::ChatMessages,
         openai_shim::ContentPart,
+        openai_shim::ResponseFormat,
+        openai_shim::JsonSchemaFormat,
         openai_shim::ToolStub,
         openai_shim::FunctionStub,
         openai_shim::AssistantFunctionStub,
@@ -86,11 +110,20 @@ mod whisper;
         openai_shim::EmbeddingsResponse,
         openai_shim::Embedding,
         openai_shim::EmbeddingsUsage,
+        openai_shim::EmbeddingsContextOverflow,
         openai_shim::CreateTranscriptionRequest,
         openai_shim::TranscriptionResponse,
         openai_shim::TranscriptionError,
+        admission::AdmissionRejection,
+        requests::RequestTimeout,
+        edgen_core::admission::QueueStatus,
+        edgen_core::speculative::SpeculativeStatus,
         model::ModelError,
         model::ModelKind,
+        edgen_core::context_overflow::ContextOverflowPolicy,
+        edgen_core::embeddings::EmbeddingEncoding,
+        edgen_core::embeddings::EmbeddingQuantization,
+        edgen_core::embeddings::EmbeddingsOverflowPolicy,
     ))
 )]
 struct ApiDoc;
@@ -193,6 +226,9 @@ async fn start_server(args: &cli::Serve) -> EdgenResult {
 }
 
 async fn run_server(args: &cli::Serve) -> Result<bool, types::EdgenError> {
+    MEMORY_BUDGET.set_limit(SETTINGS.read().await.read().await.memory_budget);
+    admission::set_limits().await;
+
     status::set_chat_completions_active_model(
         &SETTINGS
             .read()
@@ -328,6 +364,9 @@ async fn run_server(args: &cli::Serve) -> Result<bool, types::EdgenError> {
         }
     }
 
+    // unloading the models saves their chat sessions to the session cache
+    crate::llm::reset_environment().await;
+
     Ok(reset_flag.load(Ordering::SeqCst))
 }
 
@@ -337,7 +376,8 @@ mod tests {
     use std::io::Write;
     use std::path::Path;
 
-    use axum::routing::post;
+    use axum::http::StatusCode;
+    use axum::routing::{delete, get, post};
     use axum::Router;
     use axum_test::multipart;
     use axum_test::TestServer;
@@ -401,6 +441,171 @@ mod tests {
         .to_string()
     }
 
+    fn completion_stop_request() -> String {
+        r#"
+            {
+                "model": "fake-model.fake",
+                "stop": ["Portugal"],
+                "messages": [
+                    {
+                        "role": "user",
+                        "content": "What is the capital of Portugal?"
+                    }
+                ]
+            }
+        "#
+        .to_string()
+    }
+
+    /// A chat of 14 words, every word being a token of the fake model, with a context of 12 tokens.
+    fn completion_context_overflow_request(policy: &str, stream: bool) -> String {
+        format!(
+            r#"
+            {{
+                "model": "fake-model.fake",
+                "stream": {stream},
+                "context_hint": 12,
+                "context_overflow": "{policy}",
+                "messages": [
+                    {{
+                        "role": "system",
+                        "content": "Be brief."
+                    }},
+                    {{
+                        "role": "user",
+                        "content": "Tell me a long story."
+                    }},
+                    {{
+                        "role": "assistant",
+                        "content": "Once upon a time."
+                    }},
+                    {{
+                        "role": "user",
+                        "content": "What is the capital of Portugal?"
+                    }}
+                ]
+            }}
+        "#
+        )
+    }
+
+    fn completion_streaming_stop_request() -> String {
+        r#"
+            {
+                "model": "fake-model.fake",
+                "stream": true,
+                "stop": "42",
+                "messages": [
+                    {
+                        "role": "user",
+                        "content": "what is the result of 1 + 2?"
+                    }
+                ]
+            }
+        "#
+        .to_string()
+    }
+
+    fn completion_tools_request(tool_choice: &str, stream: bool) -> String {
+        format!(
+            r#"
+            {{
+                "model": "fake-model.fake",
+                "stream": {stream},
+                "tool_choice": {tool_choice},
+                "tools": [
+                    {{
+                        "type": "function",
+                        "function": {{
+                            "name": "get_weather",
+                            "description": "Gets the current weather in a city.",
+                            "parameters": {{
+                                "type": "object",
+                                "properties": {{"city": {{"type": "string"}}}}
+                            }}
+                        }}
+                    }}
+                ],
+                "messages": [
+                    {{
+                        "role": "user",
+                        "content": "What is the weather like in Lisbon?"
+                    }}
+                ]
+            }}
+        "#
+        )
+    }
+
+    fn completion_json_request(response_format: &str) -> String {
+        format!(
+            r#"
+            {{
+                "model": "fake-model.fake",
+                "response_format": {response_format},
+                "messages": [
+                    {{
+                        "role": "user",
+                        "content": "What is the capital of Portugal?"
+                    }}
+                ]
+            }}
+        "#
+        )
+    }
+
+    fn completion_max_tokens_request() -> String {
+        r#"
+            {
+                "model": "fake-model.fake",
+                "max_tokens": 3,
+                "messages": [
+                    {
+                        "role": "user",
+                        "content": "What is the capital of Portugal?"
+                    }
+                ]
+            }
+        "#
+        .to_string()
+    }
+
+    fn completion_streaming_max_tokens_request() -> String {
+        r#"
+            {
+                "model": "fake-model.fake",
+                "stream": true,
+                "max_tokens": 2,
+                "messages": [
+                    {
+                        "role": "user",
+                        "content": "what is the result of 1 + 2?"
+                    }
+                ]
+            }
+        "#
+        .to_string()
+    }
+
+    fn completion_streaming_usage_request() -> String {
+        r#"
+            {
+                "model": "fake-model.fake",
+                "stream": true,
+                "stream_options": {
+                    "include_usage": true
+                },
+                "messages": [
+                    {
+                        "role": "user",
+                        "content": "what is the result of 1 + 2?"
+                    }
+                ]
+            }
+        "#
+        .to_string()
+    }
+
     fn frost() -> String {
         " The woods are lovely, dark and deep, \
          but I have promises to keep \
@@ -446,6 +651,31 @@ mod tests {
         answer
     }
 
+    /// Returns the content of the chunks of `stream`, joined like [`poor_mans_stream_processor`]
+    /// does, and the last finish reason of the stream.
+    fn stream_content_and_finish_reason(stream: &str) -> (String, Option<String>) {
+        let mut words = vec![];
+        let mut finish_reason = None;
+        for line in stream.lines() {
+            if let Some(data) = line.strip_prefix("data:") {
+                let chunk: openai_shim::ChatCompletionChunk = match from_str(data.trim()) {
+                    Ok(chunk) => chunk,
+                    Err(_) => continue,
+                };
+                for choice in chunk.choices {
+                    match choice.delta.content {
+                        Some(content) if !content.is_empty() => words.push(content.to_string()),
+                        _ => {}
+                    }
+                    if let Some(reason) = choice.finish_reason {
+                        finish_reason = Some(reason.to_string());
+                    }
+                }
+            }
+        }
+        (words.join(" "), finish_reason)
+    }
+
     #[tokio::test]
     async fn test_axum_completions_stream() {
         init_settings_for_test().await;
@@ -521,6 +751,7 @@ mod tests {
         response.assert_status_ok();
         let mut answer = String::new();
         let completion: ChatCompletion = serde_json::from_str(&response.text()).unwrap();
+        assert_eq!(completion.object, "chat.completion");
         for choice in completion.choices {
             if let ChatMessage::Assistant { content, .. } = choice.message {
                 if let Some(content) = content {
@@ -531,6 +762,1045 @@ mod tests {
         assert_eq!(answer, chat_faker::CAPITAL_OF_PORTUGAL, "wrong answer");
     }
 
+    #[tokio::test]
+    async fn test_axum_completions_usage() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_request()).unwrap();
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let completion: ChatCompletion = serde_json::from_str(&response.text()).unwrap();
+        let usage = completion.usage;
+        assert_eq!(
+            usage.completion_tokens as usize,
+            chat_faker::CAPITAL_OF_PORTUGAL.split_whitespace().count()
+        );
+        assert!(usage.prompt_tokens > 0);
+        assert_eq!(
+            usage.total_tokens,
+            usage.prompt_tokens + usage.completion_tokens
+        );
+    }
+
+    #[tokio::test]
+    async fn test_axum_completions_stream_usage() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_streaming_usage_request()).unwrap();
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let text = response.text();
+        let answer = poor_mans_stream_processor(&text);
+        assert_eq!(answer, chat_faker::DEFAULT_ANSWER, "wrong answer");
+
+        let chunks: Vec<openai_shim::ChatCompletionChunk> = text
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .filter_map(|data| from_str(data.trim()).ok())
+            .collect();
+        let (last, rest) = chunks.split_last().expect("no chunks were streamed");
+        assert!(rest.iter().all(|chunk| chunk.usage.is_none()));
+        assert!(chunks.iter().all(|c| c.object == "chat.completion.chunk"));
+        assert!(last.choices.is_empty());
+
+        let usage = last.usage.as_ref().expect("no usage was streamed");
+        assert_eq!(
+            usage.completion_tokens as usize,
+            chat_faker::DEFAULT_ANSWER.split_whitespace().count()
+        );
+        assert!(usage.prompt_tokens > 0);
+    }
+
+    #[tokio::test]
+    async fn test_axum_completions_stream_protocol() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_streaming_request()).unwrap();
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let text = response.text();
+        let data: Vec<&str> = text
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(|data| data.trim())
+            .collect();
+        assert_eq!(data.last(), Some(&"[DONE]"), "missing [DONE] sentinel");
+
+        let chunks: Vec<openai_shim::ChatCompletionChunk> = data[..data.len() - 1]
+            .iter()
+            .map(|data| from_str(data).expect("cannot parse chunk"))
+            .collect();
+        assert!(chunks.len() > 2);
+
+        let id = &chunks[0].id;
+        assert!(id.starts_with("chatcmpl-"));
+        for chunk in &chunks {
+            assert_eq!(&chunk.id, id, "chunks have different ids");
+            assert_eq!(chunk.model, "fake-model.fake");
+            assert_eq!(chunk.created, chunks[0].created);
+        }
+
+        let first = &chunks[0].choices[0];
+        assert_eq!(first.delta.role.as_deref(), Some("assistant"));
+        assert!(first.finish_reason.is_none());
+
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(rest
+            .iter()
+            .all(|chunk| chunk.choices[0].finish_reason.is_none()));
+        assert!(rest[1..]
+            .iter()
+            .all(|chunk| chunk.choices[0].delta.role.is_none()));
+        assert_eq!(last.choices[0].finish_reason.as_deref(), Some("stop"));
+        assert!(last.choices[0].delta.content.is_none());
+        assert!(last.choices[0].delta.role.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_axum_completions_protocol() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_request()).unwrap();
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let completion: ChatCompletion = serde_json::from_str(&response.text()).unwrap();
+        assert!(completion.id.starts_with("chatcmpl-"));
+        assert_eq!(completion.model, "fake-model.fake");
+        assert_eq!(completion.choices[0].finish_reason.as_deref(), Some("stop"));
+    }
+
+    #[tokio::test]
+    async fn test_axum_completions_tools() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_tools_request(r#""auto""#, false)).unwrap();
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let completion: ChatCompletion = serde_json::from_str(&response.text()).unwrap();
+        let choice = &completion.choices[0];
+        assert_eq!(choice.finish_reason.as_deref(), Some("tool_calls"));
+        if let ChatMessage::Assistant {
+            content,
+            tool_calls,
+            ..
+        } = &choice.message
+        {
+            assert!(content.is_none());
+            let calls = tool_calls.as_ref().expect("missing tool calls");
+            assert_eq!(calls.len(), 1);
+            assert!(calls[0].id.starts_with("call_"));
+            assert_eq!(calls[0].type_, "function");
+            assert_eq!(calls[0].function.name, "get_weather");
+            assert_eq!(calls[0].function.arguments, "{}");
+        } else {
+            panic!("not an assistant message");
+        }
+
+        // the same request without tools answers with a message
+        let req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_tools_request(r#""none""#, false)).unwrap();
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let completion: ChatCompletion = serde_json::from_str(&response.text()).unwrap();
+        assert_eq!(completion.choices[0].finish_reason.as_deref(), Some("stop"));
+        assert!(matches!(
+            &completion.choices[0].message,
+            ChatMessage::Assistant {
+                content: Some(_),
+                tool_calls: None,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_axum_completions_stream_tools() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let tool_choice = r#"{"type": "function", "function": {"name": "get_weather"}}"#;
+        let req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_tools_request(tool_choice, true)).unwrap();
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let text = response.text();
+        let chunks: Vec<openai_shim::ChatCompletionChunk> = text
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(|data| data.trim())
+            .filter(|data| *data != "[DONE]")
+            .map(|data| from_str(data).expect("cannot parse chunk"))
+            .collect();
+
+        let (last, rest) = chunks.split_last().unwrap();
+        assert_eq!(last.choices[0].finish_reason.as_deref(), Some("tool_calls"));
+
+        let calls: Vec<_> = rest
+            .iter()
+            .filter_map(|chunk| chunk.choices[0].delta.tool_calls.as_ref())
+            .flatten()
+            .collect();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].index, 0);
+        assert_eq!(calls[0].function.name, "get_weather");
+        assert!(rest.iter().all(|chunk| chunk.choices[0]
+            .delta
+            .content
+            .as_deref()
+            .unwrap_or("")
+            .is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_axum_completions_json() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let formats = [
+            r#"{"type": "json_object"}"#,
+            r#"{"type": "json_schema", "json_schema": {"name": "answer", "schema": {"type": "object", "properties": {"answer": {"type": "string"}}}}}"#,
+        ];
+        for format in formats {
+            let req: openai_shim::CreateChatCompletionRequest =
+                from_str(&completion_json_request(format)).unwrap();
+            let response = server
+                .post("/v1/chat/completions")
+                .content_type(&"application/json")
+                .json(&req)
+                .await;
+
+            response.assert_status_ok();
+            let completion: ChatCompletion = serde_json::from_str(&response.text()).unwrap();
+            if let ChatMessage::Assistant {
+                content: Some(content),
+                ..
+            } = &completion.choices[0].message
+            {
+                let value: serde_json::Value =
+                    serde_json::from_str(content).expect("the completion is not valid JSON");
+                assert_eq!(value["answer"], chat_faker::CAPITAL_OF_PORTUGAL);
+            } else {
+                panic!("not an assistant message with content");
+            }
+        }
+
+        // text is not constrained
+        let req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_json_request(r#"{"type": "text"}"#)).unwrap();
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        assert!(response.text().contains(chat_faker::CAPITAL_OF_PORTUGAL));
+    }
+
+    #[tokio::test]
+    async fn test_axum_render_prompt() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router = Router::new().route("/v1/misc/render_prompt", post(misc::render_prompt));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_request()).unwrap();
+        let response = server
+            .post("/v1/misc/render_prompt")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let rendered: misc::RenderedPrompt = serde_json::from_str(&response.text()).unwrap();
+        assert_eq!(
+            rendered.prompt,
+            "<|SYSTEM|>You are a helpful assistant.\
+             <|USER|>What is the capital of Portugal?\
+             <|ASSISTANT|>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_axum_text_completions() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router = Router::new().route("/v1/completions", post(openai_shim::completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateCompletionRequest = from_str(
+            r#"
+            {
+                "model": "fake-model.fake",
+                "echo": true,
+                "prompt": ["What is the capital of Portugal?", "what is the result of 1 + 2?"]
+            }
+        "#,
+        )
+        .unwrap();
+        let response = server
+            .post("/v1/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let completion: openai_shim::TextCompletion =
+            serde_json::from_str(&response.text()).unwrap();
+        assert_eq!(completion.object, "text_completion");
+        assert_eq!(completion.choices.len(), 2);
+        assert_eq!(completion.choices[0].index, 0);
+        assert_eq!(
+            completion.choices[0].text,
+            format!(
+                "What is the capital of Portugal?{}",
+                chat_faker::CAPITAL_OF_PORTUGAL
+            )
+        );
+        assert_eq!(completion.choices[1].index, 1);
+        assert_eq!(
+            completion.choices[1].text,
+            format!("what is the result of 1 + 2?{}", chat_faker::DEFAULT_ANSWER)
+        );
+        assert_eq!(completion.usage.unwrap().prompt_tokens, 14);
+    }
+
+    #[tokio::test]
+    async fn test_axum_text_completions_fim() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router = Router::new().route("/v1/completions", post(openai_shim::completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateCompletionRequest = from_str(
+            r#"
+            {
+                "model": "fake-model.fake",
+                "prompt": "fn capital() -> &'static str {",
+                "suffix": "}",
+                "stop": "Ottawa"
+            }
+        "#,
+        )
+        .unwrap();
+        let response = server
+            .post("/v1/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let completion: openai_shim::TextCompletion =
+            serde_json::from_str(&response.text()).unwrap();
+        assert_eq!(completion.choices[0].text, "The capital of Canada is ");
+        assert_eq!(completion.choices[0].finish_reason.as_deref(), Some("stop"));
+
+        // the prompt and the suffix are joined with the fill-in-the-middle tokens of the model
+        let prompt_tokens = "<PRE> fn capital() -> &'static str { <SUF>} <MID>"
+            .split_whitespace()
+            .count() as u32;
+        assert_eq!(completion.usage.unwrap().prompt_tokens, prompt_tokens);
+    }
+
+    #[tokio::test]
+    async fn test_axum_text_completions_stream() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router = Router::new().route("/v1/completions", post(openai_shim::completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateCompletionRequest = from_str(
+            r#"
+            {
+                "model": "fake-model.fake",
+                "stream": true,
+                "stream_options": { "include_usage": true },
+                "prompt": ["What is the capital of Portugal?", "what is the result of 1 + 2?"]
+            }
+        "#,
+        )
+        .unwrap();
+        let response = server
+            .post("/v1/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let mut texts = vec![vec![], vec![]];
+        let mut finish_reasons = vec![];
+        let mut usage = None;
+        for line in response.text().lines() {
+            if let Some(data) = line.strip_prefix("data:") {
+                let chunk: openai_shim::TextCompletion = match from_str(data.trim()) {
+                    Ok(chunk) => chunk,
+                    Err(_) => continue,
+                };
+                for choice in chunk.choices {
+                    if !choice.text.is_empty() {
+                        texts[choice.index as usize].push(choice.text.to_string());
+                    }
+                    if let Some(reason) = choice.finish_reason {
+                        finish_reasons.push((choice.index, reason.to_string()));
+                    }
+                }
+                if chunk.usage.is_some() {
+                    usage = chunk.usage;
+                }
+            }
+        }
+
+        assert_eq!(texts[0].join(" "), chat_faker::CAPITAL_OF_PORTUGAL);
+        assert_eq!(texts[1].join(" "), chat_faker::DEFAULT_ANSWER);
+        assert_eq!(
+            finish_reasons,
+            vec![(0, "stop".to_string()), (1, "stop".to_string())]
+        );
+        assert_eq!(usage.expect("no usage").prompt_tokens, 14);
+        assert!(response.text().trim_end().ends_with("data: [DONE]"));
+    }
+
+    #[tokio::test]
+    async fn test_axum_completions_context_overflow() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_context_overflow_request("reject", false)).unwrap();
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        assert_eq!(response.status_code(), StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = serde_json::from_str(&response.text()).unwrap();
+        assert_eq!(error["ContextOverflow"]["prompt_tokens"], 14);
+        assert_eq!(error["ContextOverflow"]["context_size"], 12);
+
+        for (policy, dropped_messages, prompt_tokens) in
+            [("drop_oldest", 1, 10), ("sliding_window", 2, 7)]
+        {
+            let req: openai_shim::CreateChatCompletionRequest =
+                from_str(&completion_context_overflow_request(policy, false)).unwrap();
+            let response = server
+                .post("/v1/chat/completions")
+                .content_type(&"application/json")
+                .json(&req)
+                .await;
+
+            response.assert_status_ok();
+            let completion: ChatCompletion = serde_json::from_str(&response.text()).unwrap();
+            let overflow = completion.context_overflow.expect("no context overflow");
+            assert_eq!(
+                serde_json::to_value(overflow.policy).unwrap(),
+                policy,
+                "wrong policy"
+            );
+            assert_eq!(overflow.dropped_messages, dropped_messages);
+            assert_eq!(completion.usage.prompt_tokens, prompt_tokens);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_axum_completions_stream_context_overflow() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_context_overflow_request("sliding_window", true)).unwrap();
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let answer = poor_mans_stream_processor(&response.text());
+        assert_eq!(answer, chat_faker::CAPITAL_OF_PORTUGAL, "wrong answer");
+        assert!(response
+            .text()
+            .contains(r#""context_overflow":{"policy":"sliding_window","dropped_messages":2}"#));
+    }
+
+    #[tokio::test]
+    async fn test_axum_completions_stop() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_stop_request()).unwrap();
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let mut answer = String::new();
+        let completion: ChatCompletion = serde_json::from_str(&response.text()).unwrap();
+        for choice in completion.choices {
+            if let ChatMessage::Assistant { content, .. } = choice.message {
+                if let Some(content) = content {
+                    answer += &content;
+                }
+            }
+        }
+        assert_eq!(answer, "The capital of ", "wrong answer");
+    }
+
+    #[tokio::test]
+    async fn test_axum_completions_stream_stop() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_streaming_stop_request()).unwrap();
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        assert!(response.text().starts_with("data:"));
+        let answer = poor_mans_stream_processor(&response.text());
+        assert_eq!(answer, "The answer is", "wrong answer");
+    }
+
+    #[tokio::test]
+    async fn test_axum_completions_max_tokens() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_max_tokens_request()).unwrap();
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let completion: ChatCompletion = serde_json::from_str(&response.text()).unwrap();
+        assert_eq!(completion.choices.len(), 1);
+
+        let choice = &completion.choices[0];
+        assert_eq!(choice.finish_reason.as_deref(), Some("length"));
+        if let ChatMessage::Assistant { content, .. } = &choice.message {
+            assert_eq!(content.as_deref(), Some("The capital of"), "wrong answer");
+        } else {
+            panic!("not an assistant message");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_axum_completions_stream_max_tokens() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_streaming_max_tokens_request()).unwrap();
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let (answer, finish_reason) = stream_content_and_finish_reason(&response.text());
+        assert_eq!(finish_reason.as_deref(), Some("length"));
+        assert_eq!(answer, "The answer", "wrong answer");
+    }
+
+    #[tokio::test]
+    async fn test_axum_completions_logprobs() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let mut req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_request()).unwrap();
+        req.logprobs = Some(true);
+        req.top_logprobs = Some(2);
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let completion: ChatCompletion = serde_json::from_str(&response.text()).unwrap();
+        let logprobs = completion.choices[0]
+            .logprobs
+            .as_ref()
+            .and_then(|logprobs| logprobs.content.as_ref())
+            .expect("no logprobs were returned");
+
+        let tokens: String = logprobs
+            .iter()
+            .map(|logprob| logprob.token.as_ref())
+            .collect();
+        assert_eq!(tokens, chat_faker::CAPITAL_OF_PORTUGAL);
+        assert_eq!(
+            logprobs.len(),
+            chat_faker::CAPITAL_OF_PORTUGAL.split_whitespace().count()
+        );
+        for logprob in logprobs {
+            assert_eq!(logprob.logprob, 0.0);
+            assert_eq!(logprob.bytes.as_deref(), Some(logprob.token.as_bytes()));
+            assert_eq!(logprob.top_logprobs.len(), 1);
+            assert_eq!(logprob.top_logprobs[0].token, logprob.token);
+        }
+
+        // log probabilities are only returned if requested
+        req.logprobs = None;
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+        let completion: ChatCompletion = serde_json::from_str(&response.text()).unwrap();
+        assert!(completion.choices[0].logprobs.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_axum_completions_stream_logprobs() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let mut req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_streaming_request()).unwrap();
+        req.logprobs = Some(true);
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let chunks: Vec<openai_shim::ChatCompletionChunk> = response
+            .text()
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .filter_map(|data| from_str(data.trim()).ok())
+            .collect();
+
+        let mut answer = vec![];
+        for choice in chunks.iter().flat_map(|chunk| chunk.choices.iter()) {
+            let logprobs = choice
+                .logprobs
+                .as_ref()
+                .and_then(|logprobs| logprobs.content.as_ref());
+            match (&choice.delta.content, logprobs) {
+                (Some(content), Some(logprobs)) if !content.is_empty() => {
+                    let tokens: String = logprobs.iter().map(|l| l.token.as_ref()).collect();
+                    assert_eq!(&tokens, content);
+                    assert!(logprobs.iter().all(|l| l.top_logprobs.is_empty()));
+                    answer.push(tokens);
+                }
+                (_, None) => {}
+                (content, _) => panic!("unexpected logprobs for {content:?}"),
+            }
+        }
+        assert_eq!(answer.join(" "), chat_faker::DEFAULT_ANSWER);
+    }
+
+    #[tokio::test]
+    async fn test_axum_text_completions_logprobs() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router = Router::new().route("/v1/completions", post(openai_shim::completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateCompletionRequest = from_str(
+            r#"
+            {
+                "model": "fake-model.fake",
+                "echo": true,
+                "logprobs": 1,
+                "prompt": "what is the result of 1 + 2?"
+            }
+        "#,
+        )
+        .unwrap();
+        let response = server
+            .post("/v1/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let completion: openai_shim::TextCompletion =
+            serde_json::from_str(&response.text()).unwrap();
+        let logprobs = completion.choices[0]
+            .logprobs
+            .as_ref()
+            .expect("no logprobs were returned");
+
+        assert_eq!(logprobs.tokens, ["The ", "answer ", "is ", "42."]);
+        assert_eq!(logprobs.token_logprobs, [0.0; 4]);
+        assert_eq!(logprobs.top_logprobs[0].get("The "), Some(&0.0));
+
+        // the generated tokens follow the echoed prompt
+        let prompt_len = "what is the result of 1 + 2?".len() as u32;
+        assert_eq!(logprobs.text_offset[0], prompt_len);
+        assert_eq!(logprobs.text_offset[1], prompt_len + "The ".len() as u32);
+    }
+
+    #[tokio::test]
+    async fn test_axum_completions_choices() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let mut req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_request()).unwrap();
+        req.n = Some(3);
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let completion: ChatCompletion = serde_json::from_str(&response.text()).unwrap();
+        assert_eq!(completion.choices.len(), 3);
+        for (index, choice) in completion.choices.iter().enumerate() {
+            assert_eq!(choice.index, index as i32);
+            if let ChatMessage::Assistant { content, .. } = &choice.message {
+                assert_eq!(content.as_deref(), Some(chat_faker::CAPITAL_OF_PORTUGAL));
+            } else {
+                panic!("not an assistant message");
+            }
+        }
+
+        // the prompt is only counted once
+        let words = chat_faker::CAPITAL_OF_PORTUGAL.split_whitespace().count() as u32;
+        assert_eq!(completion.usage.completion_tokens, 3 * words);
+        assert_eq!(
+            completion.usage.total_tokens,
+            completion.usage.prompt_tokens + 3 * words
+        );
+    }
+
+    #[tokio::test]
+    async fn test_axum_completions_stream_choices() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router =
+            Router::new().route("/v1/chat/completions", post(openai_shim::chat_completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let mut req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_streaming_usage_request()).unwrap();
+        req.n = Some(2);
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let chunks: Vec<openai_shim::ChatCompletionChunk> = response
+            .text()
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .filter_map(|data| from_str(data.trim()).ok())
+            .collect();
+
+        let mut roles = vec![0; 2];
+        let mut answers = vec![vec![]; 2];
+        let mut finish_reasons = vec![None; 2];
+        for choice in chunks.iter().flat_map(|chunk| chunk.choices.iter()) {
+            let index = choice.index as usize;
+            if choice.delta.role.is_some() {
+                roles[index] += 1;
+            }
+            match &choice.delta.content {
+                Some(content) if !content.is_empty() => answers[index].push(content.to_string()),
+                _ => {}
+            }
+            if choice.finish_reason.is_some() {
+                finish_reasons[index] = choice.finish_reason.as_deref().map(str::to_string);
+            }
+        }
+        assert_eq!(roles, [1, 1]);
+        for (answer, finish_reason) in answers.iter().zip(&finish_reasons) {
+            assert_eq!(answer.join(" "), chat_faker::DEFAULT_ANSWER);
+            assert_eq!(finish_reason.as_deref(), Some("stop"));
+        }
+
+        // a single usage chunk follows every choice
+        let (last, rest) = chunks.split_last().expect("no chunks were streamed");
+        assert!(rest.iter().all(|chunk| chunk.usage.is_none()));
+        let words = chat_faker::DEFAULT_ANSWER.split_whitespace().count() as u32;
+        assert_eq!(
+            last.usage
+                .as_ref()
+                .expect("no usage was streamed")
+                .completion_tokens,
+            2 * words
+        );
+    }
+
+    #[tokio::test]
+    async fn test_axum_text_completions_choices() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router = Router::new().route("/v1/completions", post(openai_shim::completions));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let req: openai_shim::CreateCompletionRequest = from_str(
+            r#"
+            {
+                "model": "fake-model.fake",
+                "n": 2,
+                "prompt": ["What is the capital of Portugal?", "what is the result of 1 + 2?"]
+            }
+        "#,
+        )
+        .unwrap();
+        let response = server
+            .post("/v1/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let completion: openai_shim::TextCompletion =
+            serde_json::from_str(&response.text()).unwrap();
+        let choices: Vec<_> = completion
+            .choices
+            .iter()
+            .map(|choice| (choice.index, choice.text.as_ref()))
+            .collect();
+        assert_eq!(
+            choices,
+            [
+                (0, chat_faker::CAPITAL_OF_PORTUGAL),
+                (1, chat_faker::CAPITAL_OF_PORTUGAL),
+                (2, chat_faker::DEFAULT_ANSWER),
+                (3, chat_faker::DEFAULT_ANSWER),
+            ]
+        );
+        assert_eq!(completion.usage.unwrap().prompt_tokens, 14);
+    }
+
+    #[tokio::test]
+    async fn test_axum_chat_sessions() {
+        init_settings_for_test().await;
+        create_chat_fake_model_file().await;
+
+        let router = Router::new()
+            .route("/v1/chat/completions", post(openai_shim::chat_completions))
+            .route("/v1/chat/sessions", get(chat_sessions::list_sessions))
+            .route(
+                "/v1/chat/sessions/:id",
+                get(chat_sessions::retrieve_session),
+            )
+            .route(
+                "/v1/chat/sessions/:id",
+                delete(chat_sessions::delete_session),
+            );
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let mut req: openai_shim::CreateChatCompletionRequest =
+            from_str(&completion_request()).unwrap();
+        req.create_session = Some(true);
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let completion: ChatCompletion = serde_json::from_str(&response.text()).unwrap();
+        let id = completion.session.expect("no session was created");
+        let tokens = completion.usage.total_tokens;
+
+        let session: chat_sessions::ChatSessionDesc =
+            server.get(&format!("/v1/chat/sessions/{id}")).await.json();
+        assert_eq!(session.id, id);
+        assert_eq!(session.model, "fake-model.fake");
+        assert!(!session.in_use);
+        assert_eq!(session.tokens, Some(tokens));
+        assert!(session.expires_in.is_some());
+
+        // the chat continues in the session
+        req.create_session = None;
+        req.session = Some(id);
+        let answer = completion.choices.into_iter().next().unwrap().message;
+        assert!(matches!(answer, ChatMessage::Assistant { .. }));
+        req.messages.push(answer);
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+
+        response.assert_status_ok();
+        let completion: ChatCompletion = serde_json::from_str(&response.text()).unwrap();
+        assert_eq!(completion.session, Some(id));
+
+        let sessions: Vec<chat_sessions::ChatSessionDesc> =
+            server.get("/v1/chat/sessions").await.json();
+        assert!(sessions.iter().any(|session| session.id == id));
+
+        let response = server.delete(&format!("/v1/chat/sessions/{id}")).await;
+        assert_eq!(response.status_code(), StatusCode::NO_CONTENT);
+
+        let response = server.get(&format!("/v1/chat/sessions/{id}")).await;
+        assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+        let response = server.delete(&format!("/v1/chat/sessions/{id}")).await;
+        assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+        let response = server
+            .post("/v1/chat/completions")
+            .content_type(&"application/json")
+            .json(&req)
+            .await;
+        assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+    }
+
     #[tokio::test]
     #[ignore]
     //TODO This test expects speech-to-text (a.k.a. /audio/speech) to be implemented
@@ -12,19 +12,22 @@
 
 use futures::Stream;
 use once_cell::sync::Lazy;
+use uuid::Uuid;
 
-use edgen_core::llm::{CompletionArgs, LLMEndpoint, LLMEndpointError};
+use edgen_core::llm::{
+    ChatSessionInfo, Completion, CompletionArgs, CompletionChunk, Embeddings, EmbeddingsArgs,
+    LLMEndpoint, LLMEndpointError, TextCompletionArgs,
+};
 use edgen_rt_llama_cpp::LlamaCppEndpoint;
 
 use crate::model::Model;
-use crate::util::StoppingStream;
 
 static ENDPOINT: Lazy<LlamaCppEndpoint> = Lazy::new(Default::default);
 
 pub async fn chat_completion(
     model: Model,
     args: CompletionArgs,
-) -> Result<String, LLMEndpointError> {
+) -> Result<Vec<Completion>, LLMEndpointError> {
     ENDPOINT
         .chat_completions(
             model
@@ -38,41 +41,232 @@ pub async fn chat_completion(
 pub async fn chat_completion_stream(
     model: Model,
     args: CompletionArgs,
-) -> Result<StoppingStream<Box<dyn Stream<Item = String> + Unpin + Send>>, LLMEndpointError> {
-    let stream = ENDPOINT
+) -> Result<Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>, LLMEndpointError> {
+    ENDPOINT
         .stream_chat_completions(
             model
                 .file_path()
                 .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
             args,
         )
-        .await?;
-
-    Ok(StoppingStream::wrap_with_stop_words(
-        stream,
-        vec![
-            "<|ASSISTANT|>".to_string(),
-            "<|USER|>".to_string(),
-            "<|TOOL|>".to_string(),
-            "<|SYSTEM|>".to_string(),
-        ],
-    ))
+        .await
+}
+
+pub async fn text_completion(
+    model: Model,
+    args: TextCompletionArgs,
+) -> Result<Vec<Completion>, LLMEndpointError> {
+    ENDPOINT
+        .completions(
+            model
+                .file_path()
+                .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
+            args,
+        )
+        .await
+}
+
+pub async fn text_completion_stream(
+    model: Model,
+    args: TextCompletionArgs,
+) -> Result<Box<dyn Stream<Item = CompletionChunk> + Unpin + Send>, LLMEndpointError> {
+    ENDPOINT
+        .stream_completions(
+            model
+                .file_path()
+                .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
+            args,
+        )
+        .await
+}
+
+pub async fn render_prompt(model: Model, args: CompletionArgs) -> Result<String, LLMEndpointError> {
+    ENDPOINT
+        .render_prompt(
+            model
+                .file_path()
+                .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
+            args,
+        )
+        .await
 }
 
 pub async fn embeddings(
     model: Model,
-    input: Vec<String>,
-) -> Result<Vec<Vec<f32>>, LLMEndpointError> {
+    args: EmbeddingsArgs,
+) -> Result<Embeddings, LLMEndpointError> {
     ENDPOINT
         .embeddings(
             model
                 .file_path()
                 .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
-            input,
+            args,
         )
         .await
 }
 
+pub async fn chat_sessions() -> Vec<ChatSessionInfo> {
+    ENDPOINT.chat_sessions().await
+}
+
+pub async fn delete_chat_session(id: Uuid) -> Result<(), LLMEndpointError> {
+    ENDPOINT.delete_chat_session(id).await
+}
+
 pub async fn reset_environment() {
     ENDPOINT.reset()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Model, ModelKind};
+    use crate::types::Endpoint;
+    use edgen_core::llm::{ChatMessage, ChatMessages, FinishReason};
+    use edgen_core::settings::SETTINGS;
+    use either::Either;
+    use std::path::PathBuf;
+
+    async fn init_settings_for_test() {
+        SETTINGS
+            .write()
+            .await
+            .init()
+            .await
+            .expect("Failed to initialise settings");
+    }
+
+    async fn small_model() -> Model {
+        let model_name = "tinyllama-1.1b-chat-v1.0.Q2_K.gguf".to_string();
+        let repo = "TheBloke/TinyLlama-1.1B-Chat-v1.0-GGUF".to_string();
+        let dir = SETTINGS
+            .read()
+            .await
+            .read()
+            .await
+            .chat_completions_models_dir
+            .to_string();
+        let mut model = Model::new(ModelKind::LLM, &model_name, &repo, &PathBuf::from(&dir));
+        assert!(model.preload(Endpoint::ChatCompletions).await.is_ok());
+        model
+    }
+
+    fn seeded_args(one_shot: bool) -> CompletionArgs {
+        CompletionArgs {
+            messages: ChatMessages(vec![ChatMessage::User {
+                content: Either::Left("Please tell me a short story.".to_string()),
+                name: None,
+            }]),
+            seed: Some(42),
+            temperature: Some(0.0),
+            one_shot: Some(one_shot),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    #[ignore] // this test downloads a model
+    async fn test_seeded_completions_are_repeatable() {
+        init_settings_for_test().await;
+
+        for one_shot in [true, false] {
+            let first = chat_completion(small_model().await, seeded_args(one_shot))
+                .await
+                .expect("cannot create chat completion");
+            let second = chat_completion(small_model().await, seeded_args(one_shot))
+                .await
+                .expect("cannot create chat completion");
+
+            assert!(!first[0].content.is_empty());
+            assert_eq!(
+                first[0].content, second[0].content,
+                "seeded completions differ"
+            );
+        }
+    }
+
+    #[tokio::test]
+    #[ignore] // this test downloads a model
+    async fn test_max_tokens_finishes_with_length() {
+        init_settings_for_test().await;
+
+        for one_shot in [true, false] {
+            let mut args = seeded_args(one_shot);
+            args.max_tokens = Some(4);
+
+            let completions = chat_completion(small_model().await, args)
+                .await
+                .expect("cannot create chat completion");
+
+            assert_eq!(completions[0].finish_reason, FinishReason::Length);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore] // this test downloads a model
+    async fn test_choices_share_the_prompt() {
+        init_settings_for_test().await;
+
+        for one_shot in [true, false] {
+            let mut args = seeded_args(one_shot);
+            args.n = Some(3);
+            args.max_tokens = Some(8);
+
+            let completions = chat_completion(small_model().await, args)
+                .await
+                .expect("cannot create chat completion");
+
+            assert_eq!(completions.len(), 3);
+            for completion in &completions {
+                assert!(!completion.content.is_empty());
+                assert_eq!(
+                    completion.usage.prompt_tokens,
+                    completions[0].usage.prompt_tokens
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[ignore] // this test downloads a model
+    async fn test_sessions_are_saved_on_reset() {
+        init_settings_for_test().await;
+        let cache_dir = PathBuf::from(&SETTINGS.read().await.read().await.session_cache_dir);
+        let saved_sessions = || std::fs::read_dir(&cache_dir).map_or(0, |dir| dir.count());
+
+        let args = |messages| {
+            let mut args = seeded_args(false);
+            args.messages = ChatMessages(messages);
+            args.max_tokens = Some(8);
+            args
+        };
+        let user = |content: &str| ChatMessage::User {
+            content: Either::Left(content.to_string()),
+            name: None,
+        };
+
+        // a unique chat, whose session is not already saved
+        let greeting = format!("Say hello to {}.", uuid::Uuid::new_v4());
+        let completions = chat_completion(small_model().await, args(vec![user(&greeting)]))
+            .await
+            .expect("cannot create chat completion");
+        let before = saved_sessions();
+        reset_environment().await;
+        assert_eq!(saved_sessions(), before + 1);
+
+        // the chat continues from the saved session
+        let messages = vec![
+            user(&greeting),
+            ChatMessage::Assistant {
+                content: Some(completions[0].content.clone()),
+                name: None,
+                tool_calls: None,
+            },
+            user("And goodbye."),
+        ];
+        let completions = chat_completion(small_model().await, args(messages))
+            .await
+            .expect("cannot continue chat completion");
+        assert!(!completions[0].content.is_empty());
+    }
+}
@@ -18,6 +18,12 @@ use serde::{Deserialize, Serialize};
 use tracing::error;
 use utoipa::ToSchema;
 
+use crate::model::ModelKind;
+use crate::openai_shim::{
+    chat_completions_model, ChatCompletionError, CreateChatCompletionRequest,
+};
+use crate::{chat_faker, llm};
+
 /// Reads the version defined in Cargo.toml at compile time in the format
 /// `MAJOR.MINOR.PATCH_BUILD`
 #[macro_export]
@@ -56,6 +62,48 @@ pub async fn edgen_version() -> Response {
     }
 }
 
+/// The prompt that a model completes for a chat completions request.
+#[derive(ToSchema, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct RenderedPrompt {
+    /// The model that would complete the prompt.
+    pub model: String,
+
+    /// The prompt, rendered with the chat template of the model.
+    pub prompt: String,
+}
+
+/// POST `/v1/misc/render_prompt`: returns the exact prompt that a model would complete for a chat
+/// completions request, to debug chat templates.
+///
+/// The request body is the same as for `/v1/chat/completions`, and nothing is generated.
+/// The prompt is rendered with the `chat_template` configured for the model, or else with the one
+/// in the model file.
+#[utoipa::path(
+        post,
+        path = "/misc/render_prompt",
+        request_body = CreateChatCompletionRequest,
+        responses(
+            (status = 200, description = "OK", body = RenderedPrompt),
+            (status = 500, description = "unexpected internal server error", body = ChatCompletionError)
+        ),
+)]
+pub async fn render_prompt(
+    Json(req): Json<CreateChatCompletionRequest<'_>>,
+) -> Result<Json<RenderedPrompt>, ChatCompletionError> {
+    let (model, model_name, _) = chat_completions_model(req.model.as_ref()).await?;
+
+    let prompt = match model.kind {
+        ModelKind::LLM => llm::render_prompt(model, req.into()).await?,
+        ModelKind::ChatFaker => chat_faker::render_prompt(model, req.into()).await?,
+        _ => panic!("we should never get here"),
+    };
+
+    Ok(Json(RenderedPrompt {
+        model: model_name,
+        prompt,
+    }))
+}
+
 fn internal_server_error(msg: &str) -> Response {
     error!("[ERROR] {}", msg);
     StatusCode::INTERNAL_SERVER_ERROR.into_response()
@@ -52,7 +52,7 @@ pub enum ModelKind {
     StableDiffusion,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 enum ModelQuantization {
     Default,
 }
@@ -206,7 +206,7 @@ fn create_model_patterns_file(path: &Path) {
 }
 
 #[allow(dead_code)]
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Model {
     pub kind: ModelKind,
     quantization: ModelQuantization,
@@ -18,30 +18,45 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use axum::http::StatusCode;
 use axum::response::sse::Event;
 use axum::response::{IntoResponse, Response, Sse};
 use axum::Json;
 use axum_typed_multipart::{FieldData, TryFromMultipart, TypedMultipart};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use derive_more::{Deref, DerefMut, From};
 use either::Either;
 use futures::{Stream, StreamExt, TryStream};
+use once_cell::sync::Lazy;
 use serde_derive::{Deserialize, Serialize};
 use thiserror::Error;
 use time::OffsetDateTime;
-use tinyvec::{tiny_vec, TinyVec};
+use tinyvec::TinyVec;
 use tracing::error;
 use utoipa::ToSchema;
 use uuid::Uuid;
 
-use edgen_core::llm::{CompletionArgs, LLMEndpointError};
-use edgen_core::settings;
+use edgen_core::context_overflow::{ContextOverflow, ContextOverflowPolicy};
+use edgen_core::embeddings::{
+    shorten, EmbeddingEncoding, EmbeddingFormatError, EmbeddingQuantization, EmbeddingValues,
+    EmbeddingsOverflow, EmbeddingsOverflowPolicy,
+};
+use edgen_core::llm::{
+    ChatMessage, ChatSession, CompletionArgs, CompletionChunk, EmbeddingsArgs, LLMEndpointError,
+    TextCompletionArgs, TokenLogprob, TokenUsage, ToolChoice, TopLogprob,
+};
+use edgen_core::settings::{self, SETTINGS};
+use edgen_core::vision::{image_source, ImageInput, ImageSource};
 use edgen_core::whisper::WhisperEndpointError;
 
+use crate::admission::AdmissionRejection;
 use crate::chat_faker;
 use crate::llm;
 use crate::model::{Model, ModelError, ModelKind, MODEL_PATTERNS};
+use crate::requests::{request_timeout, with_timeout, RequestTimeout};
 use crate::types::Endpoint;
 
 /// The plaintext or image content of a [`ChatMessage`] within a [`CreateChatCompletionRequest`].
@@ -180,7 +195,10 @@ pub struct FunctionStub<'a> {
 
     /// A [JSON schema][json-schema] describing the parameters that the tool accepts.
     ///
+    /// If absent, the tool accepts any parameters.
+    ///
     /// [json-schema]: https://json-schema.org/
+    #[serde(default)]
     pub parameters: serde_json::Value,
 }
 
@@ -206,6 +224,46 @@ pub enum ToolStub<'a> {
     },
 }
 
+/// The format that the completions of a [`CreateChatCompletionRequest`] must follow.
+///
+/// See [the documentation for creating chat completions][openai] for more details.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/chat/create
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat<'a> {
+    /// Plain text.
+    Text,
+
+    /// Any valid JSON object.
+    JsonObject,
+
+    /// A JSON value that is valid against a JSON schema.
+    JsonSchema {
+        /// The JSON schema.
+        json_schema: JsonSchemaFormat<'a>,
+    },
+}
+
+/// A JSON schema that completions must follow, in a [`ResponseFormat`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct JsonSchemaFormat<'a> {
+    /// The name of the response format.
+    pub name: Cow<'a, str>,
+
+    /// A description of what the response format is for.
+    pub description: Option<Cow<'a, str>>,
+
+    /// The [JSON schema][json-schema] that completions must follow. If absent, completions can be
+    /// any JSON object.
+    ///
+    /// [json-schema]: https://json-schema.org/
+    pub schema: Option<serde_json::Value>,
+
+    /// Whether to strictly follow the schema. This is always the case within Edgen.
+    pub strict: Option<bool>,
+}
+
 /// A sequence of chat messages in a [`CreateChatCompletionRequest`].
 ///
 /// This implements [`Display`] to generate a transcript of the chat messages compatible with most
@@ -231,7 +289,8 @@ pub struct CreateChatCompletionRequest<'a> {
     #[serde(default)]
     pub messages: ChatMessages<'a>,
 
-    /// The model to use for generating completions.
+    /// The model to use for generating completions. A model of the form `base+adapter` applies
+    /// the LoRA adapter named `adapter`, declared in the settings of the model `base`.
     pub model: Cow<'a, str>,
 
     /// A number in `[-2.0, 2.0]`. A higher number decreases the likelihood that the model
@@ -244,12 +303,25 @@ pub struct CreateChatCompletionRequest<'a> {
     /// You could use this to, for example, prevent the model from emitting profanity.
     pub logit_bias: Option<HashMap<u32, f32>>,
 
-    /// The maximum number of tokens to generate. If `None`, terminates at the first stop token
-    /// or the end of sentence.
+    /// If `true`, the log probability of every generated token is returned in the `logprobs` of
+    /// each choice. `false` by default.
+    pub logprobs: Option<bool>,
+
+    /// The number of most likely tokens, in `[0, 20]`, whose log probabilities are returned for
+    /// every position of the completion. Requires `logprobs` to be `true`.
+    pub top_logprobs: Option<u32>,
+
+    /// The maximum number of tokens to generate. If `None`, the server-wide default is used.
+    ///
+    /// Either way, this is capped by the maximum configured for the model, if any. If generation
+    /// reaches the limit, the `finish_reason` of the completion is `length`.
     pub max_tokens: Option<u32>,
 
-    /// How many choices to generate for each token in the output. `1` by default. You can use
-    /// this to generate several sets of completions for the same prompt.
+    /// How many choices to generate, in `[1, 128]`. `1` by default. You can use this to generate
+    /// several sets of completions for the same prompt, which is only evaluated once.
+    ///
+    /// When streaming, the chunks of every choice are interleaved, and tell which choice they
+    /// belong to by their `index`.
     pub n: Option<u32>,
 
     /// A number in `[-2.0, 2.0]`. Positive values "increase the model's likelihood to talk about
@@ -274,10 +346,22 @@ pub struct CreateChatCompletionRequest<'a> {
     /// You can use this to live-stream completions to a client.
     pub stream: Option<bool>,
 
-    /// The format of the response stream.
+    /// Options for the response stream. Only used if `stream` is `true`.
+    pub stream_options: Option<ChatCompletionStreamOptions>,
+
+    /// The format that completions must follow. `text` by default.
     ///
-    /// This is always assumed to be JSON, which is non-conformant with the OpenAI spec.
-    pub response_format: Option<serde_json::Value>,
+    /// JSON formats are enforced by constraining generation, so completions are always valid
+    /// JSON, following the provided schema, if any.
+    pub response_format: Option<ResponseFormat<'a>>,
+
+    /// A [GBNF] grammar that completions must follow, taking precedence over `response_format`
+    /// and `tool_choice`.
+    ///
+    /// This is an extension to the OpenAI API.
+    ///
+    /// [GBNF]: https://github.com/ggerganov/llama.cpp/blob/master/grammars/README.md
+    pub grammar: Option<Cow<'a, str>>,
 
     /// The sampling temperature, in `[0.0, 2.0]`. Higher values make the output more random.
     pub temperature: Option<f32>,
@@ -294,8 +378,12 @@ pub struct CreateChatCompletionRequest<'a> {
     /// OpenAI states:
     ///
     /// - `none` prevents any tool from being used,
-    /// - `auto` allows any tool to be used, or
-    /// - you can provide a description of the tool entirely instead of a name.
+    /// - `auto` allows any tool to be used,
+    /// - `required` forces one or more tools to be used, or
+    /// - you can provide a description of the tool entirely instead of a name, forcing that
+    ///   tool to be used.
+    ///
+    /// If tools must be used, the model is constrained to only generate valid tool calls.
     #[serde(default, with = "either::serde_untagged_optional")]
     #[schema(value_type = String)]
     pub tool_choice: Option<Either<Cow<'a, str>, ToolStub<'a>>>,
@@ -304,16 +392,51 @@ pub struct CreateChatCompletionRequest<'a> {
     /// and user tracking, and is unused within Edgen.
     pub user: Option<Cow<'a, str>>,
 
+    /// What to do if the messages do not fit in the context of the model:
+    ///
+    /// - `reject` fails the request with a `400 Bad Request`, reporting the number of tokens in
+    ///   the prompt and in the context,
+    /// - `drop_oldest` drops the oldest messages that are not system messages until the prompt
+    ///   fits, or
+    /// - `sliding_window` keeps the first system message and the most recent messages that fit.
+    ///
+    /// If absent, the policy configured for the model is used, which is `reject` by default.
+    pub context_overflow: Option<ContextOverflowPolicy>,
+
     /// Indicate if this is an isolated request, with no associated past or future context. This may allow for
     /// optimisations in some implementations. Default: `false`
     pub one_shot: Option<bool>,
 
-    /// A hint for how big a context will be.
+    /// A hint for how big a context will be. For [`one_shot`](Self::one_shot) requests, this
+    /// overrides the context size of the model.
     ///
     /// # Warning
     /// An unsound hint may severely drop performance and/or inference quality, and in some cases even cause Edgen
     /// to crash. Do not set this value unless you know what you are doing.
     pub context_hint: Option<u32>,
+
+    /// Should a new chat session be created from this request. The session keeps the context of
+    /// the chat under its own id, instead of a session being matched by the messages, and is
+    /// never shared with other chats. This takes precedence over `one_shot`.
+    ///
+    /// If `true`, the response will contain a session [`Uuid`].
+    ///
+    /// The value of this member is ignored if `session` has some value.
+    pub create_session: Option<bool>,
+
+    /// The [`Uuid`] of an existing chat session, whose chat `messages` continue. If the messages
+    /// do not extend those of the previous request of the session, followed by its completion,
+    /// the session starts over with these messages.
+    pub session: Option<Uuid>,
+
+    /// The number of seconds completions may be generated for. Past it, the completions generated
+    /// so far are returned, with a `timeout` finish reason. If the completions did not start by
+    /// then, because the model was still loading or the prompt was still being evaluated, the
+    /// request fails with a `504 Gateway Timeout`.
+    ///
+    /// If absent, the `request_timeout` configured for the server is used. `0` means there is no
+    /// limit.
+    pub timeout: Option<f32>,
 }
 
 /// A message in a chat completion.
@@ -332,12 +455,100 @@ pub struct ChatCompletionChoice<'a> {
     ///
     /// This can be:
     ///
-    /// - `length`, indicating that the length cutoff was reached, or
-    /// - `stop`, indicating that a stop word was reached.
+    /// - `length`, indicating that the length cutoff was reached,
+    /// - `stop`, indicating that a stop word was reached, or
+    /// - `tool_calls`, indicating that the model called one or more tools.
     pub finish_reason: Option<Cow<'a, str>>,
 
     /// The index of this choice.
     pub index: i32,
+
+    /// If present, the log probabilities of the generated tokens, as requested by `logprobs` in
+    /// the [`CreateChatCompletionRequest`].
+    pub logprobs: Option<ChatCompletionLogprobs<'a>>,
+}
+
+/// The log probabilities of the tokens of a [`ChatCompletionChoice`] or
+/// [`ChatCompletionChunkChoice`].
+///
+/// See [the documentation for creating chat completions][openai] for more details.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/chat/object
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChatCompletionLogprobs<'a> {
+    /// The log probabilities of the tokens of the message content, in order.
+    pub content: Option<Vec<ChatCompletionTokenLogprob<'a>>>,
+}
+
+impl From<Vec<TokenLogprob>> for ChatCompletionLogprobs<'static> {
+    fn from(value: Vec<TokenLogprob>) -> Self {
+        Self {
+            content: Some(value.into_iter().map(Into::into).collect()),
+        }
+    }
+}
+
+/// The log probability of a generated token.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChatCompletionTokenLogprob<'a> {
+    /// The text of the token.
+    pub token: Cow<'a, str>,
+
+    /// The natural logarithm of the probability of the token.
+    pub logprob: f32,
+
+    /// The UTF-8 bytes of the token.
+    ///
+    /// A character may span several tokens, in which case the bytes of those tokens must be
+    /// combined to get its text.
+    pub bytes: Option<Vec<u8>>,
+
+    /// The most likely tokens at the position of this one, most likely first. There are as many
+    /// as requested by `top_logprobs`, unless fewer tokens were possible at all.
+    pub top_logprobs: Vec<ChatCompletionTopLogprob<'a>>,
+}
+
+impl From<TokenLogprob> for ChatCompletionTokenLogprob<'static> {
+    fn from(value: TokenLogprob) -> Self {
+        Self {
+            bytes: Some(value.token.as_bytes().to_vec()),
+            token: Cow::Owned(value.token),
+            logprob: value.logprob,
+            top_logprobs: value.top_logprobs.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+/// One of the most likely tokens at a position of a chat completion.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChatCompletionTopLogprob<'a> {
+    /// The text of the token.
+    pub token: Cow<'a, str>,
+
+    /// The natural logarithm of the probability of the token.
+    pub logprob: f32,
+
+    /// The UTF-8 bytes of the token.
+    pub bytes: Option<Vec<u8>>,
+}
+
+impl From<TopLogprob> for ChatCompletionTopLogprob<'static> {
+    fn from(value: TopLogprob) -> Self {
+        Self {
+            bytes: Some(value.token.as_bytes().to_vec()),
+            token: Cow::Owned(value.token),
+            logprob: value.logprob,
+        }
+    }
+}
+
+/// Options for the response stream of a [`CreateChatCompletionRequest`].
+#[derive(Debug, Serialize, Deserialize, Default, ToSchema)]
+pub struct ChatCompletionStreamOptions {
+    /// If `true`, an additional [`ChatCompletionChunk`] is streamed before the stream ends, with
+    /// the usage statistics of the whole request and no choices.
+    #[serde(default)]
+    pub include_usage: bool,
 }
 
 /// Statistics about a completed chat completion.
@@ -358,6 +569,37 @@ pub struct ChatCompletionUsage {
     pub total_tokens: u32,
 }
 
+impl From<TokenUsage> for ChatCompletionUsage {
+    fn from(value: TokenUsage) -> Self {
+        Self {
+            completion_tokens: value.completion_tokens,
+            prompt_tokens: value.prompt_tokens,
+            total_tokens: value.prompt_tokens + value.completion_tokens,
+        }
+    }
+}
+
+/// How the messages of a [`CreateChatCompletionRequest`] were fitted into the context of the
+/// model.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChatCompletionContextOverflow {
+    /// The context overflow policy that was applied.
+    pub policy: ContextOverflowPolicy,
+
+    /// The number of messages that were dropped from the request to fit its prompt into the
+    /// context.
+    pub dropped_messages: u32,
+}
+
+impl From<ContextOverflow> for ChatCompletionContextOverflow {
+    fn from(value: ContextOverflow) -> Self {
+        Self {
+            policy: value.policy,
+            dropped_messages: value.dropped_messages,
+        }
+    }
+}
+
 /// A fully generated chat completion.
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct ChatCompletion<'a> {
@@ -376,21 +618,58 @@ pub struct ChatCompletion<'a> {
     /// A unique identifier for the backend configuration that generated the completion.
     pub system_fingerprint: Cow<'a, str>,
 
-    /// The object type. This is always `text_completion`.
+    /// The object type. This is always `chat.completion`.
     pub object: Cow<'a, str>,
 
     /// Usage information about this completion.
     pub usage: ChatCompletionUsage,
+
+    /// If present, how the messages were fitted into the context of the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_overflow: Option<ChatCompletionContextOverflow>,
+
+    /// The [`Uuid`] of the chat session that generated the completion, present only if
+    /// `create_session` or `session` were set in the [`CreateChatCompletionRequest`]. This
+    /// additional member is **not normative** with OpenAI's specification.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<Uuid>,
 }
 
 /// A delta-encoded difference for an ongoing, stream-mode chat completion.
 #[derive(Debug, Serialize, Deserialize, Default, ToSchema)]
 pub struct ChatCompletionChunkDelta<'a> {
     /// If present, new content added to the end of the completion stream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub content: Option<Cow<'a, str>>,
 
     /// If present, `content` is being generated under a new role.
+    ///
+    /// This is only present in the first chunk of a stream, which is always `assistant`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub role: Option<Cow<'a, str>>,
+
+    /// If present, the tools that the model called.
+    ///
+    /// Tool calls are streamed whole, in a single chunk before the closing one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ChatCompletionChunkToolCall<'a>>>,
+}
+
+/// A tool call in a [`ChatCompletionChunkDelta`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChatCompletionChunkToolCall<'a> {
+    /// The index of this tool call among those of the completion.
+    pub index: u32,
+
+    /// A unique identifier for the invocation of this function.
+    pub id: Cow<'a, str>,
+
+    /// The type of the invoked tool, which is always `function`.
+    #[serde(rename = "type")]
+    pub type_: Cow<'a, str>,
+
+    /// The invoked function.
+    pub function: AssistantFunctionStub<'a>,
 }
 
 /// A chunk of a stream-mode chat completion.
@@ -402,19 +681,24 @@ pub struct ChatCompletionChunkChoice<'a> {
     /// If present, this choice terminated the completion stream. The following variants
     /// are available:
     ///
-    /// - `length`, indicating that the length cutoff was reached, or
-    /// - `stop`, indicating that a stop word was reached.
+    /// - `length`, indicating that the length cutoff was reached,
+    /// - `stop`, indicating that a stop word was reached, or
+    /// - `tool_calls`, indicating that the model called one or more tools.
     pub finish_reason: Option<Cow<'a, str>>,
 
     /// The index of this choice. If `n` was set in [`CreateChatCompletionRequest`], this is
     /// which stream this choice belongs to.
     pub index: u32,
+
+    /// If present, the log probabilities of the tokens in `delta`, as requested by `logprobs` in
+    /// the [`CreateChatCompletionRequest`].
+    pub logprobs: Option<ChatCompletionLogprobs<'a>>,
 }
 
 /// A chunk generated in streaming mode from a [`CreateChatCompletionRequest`].
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct ChatCompletionChunk<'a> {
-    /// A unique identifier for this chunk.
+    /// A unique identifier for the completion, shared by every chunk of a stream.
     pub id: Cow<'a, str>,
 
     /// The tokens generated by the model.
@@ -424,14 +708,34 @@ pub struct ChatCompletionChunk<'a> {
     /// The UNIX timestamp at which the chunk was generated.
     pub created: i64,
 
-    /// The model that generated the chunk.
+    /// The name of the model that generated the chunk, after resolving aliases such as
+    /// `default`.
     pub model: Cow<'a, str>,
 
     /// A unique identifier for the backend configuration that generated the chunk.
     pub system_fingerprint: Cow<'a, str>,
 
-    /// The object type. This is always `text_completion`.
+    /// The object type. This is always `chat.completion.chunk`.
     pub object: Cow<'a, str>,
+
+    /// If present, the usage statistics of the whole request.
+    ///
+    /// This is only present in the last chunk of a stream, and only if `include_usage` was set in
+    /// the `stream_options` of the [`CreateChatCompletionRequest`]. That chunk has no choices.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<ChatCompletionUsage>,
+
+    /// If present, how the messages were fitted into the context of the model.
+    ///
+    /// This is only present in the chunk with the `finish_reason`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_overflow: Option<ChatCompletionContextOverflow>,
+
+    /// The [`Uuid`] of the chat session that generated the chunk, present only if
+    /// `create_session` or `session` were set in the [`CreateChatCompletionRequest`]. This
+    /// additional member is **not normative** with OpenAI's specification.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<Uuid>,
 }
 
 /// An error condition raised by the chat completion API.
@@ -473,14 +777,50 @@ pub enum ChatCompletionError {
     #[error("an error occurred on the other side of a C FFI boundary; check `tracing`")]
     Ffi,
 
+    /// The image at a URL in the messages could not be read.
+    #[error("failed to read the image at {url}: {reason}")]
+    InvalidImage {
+        /// The URL of the image.
+        url: String,
+
+        /// A human-readable error message.
+        reason: Cow<'static, str>,
+    },
+
+    /// The embeddings cannot be returned in the requested format.
+    #[error(transparent)]
+    EmbeddingFormat(#[from] EmbeddingFormatError),
+
     /// An error occurred while processing the request to this endpoint.
     #[error("an error occurred while processing the request: {0}")]
     Endpoint(#[from] LLMEndpointError),
+
+    /// The request took longer than its timeout.
+    #[error(transparent)]
+    Timeout(#[from] RequestTimeout),
 }
 
 impl IntoResponse for ChatCompletionError {
     fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, Json(self)).into_response()
+        let status = match self {
+            ChatCompletionError::Endpoint(LLMEndpointError::ContextOverflow { .. }) => {
+                StatusCode::BAD_REQUEST
+            }
+            ChatCompletionError::Endpoint(LLMEndpointError::AdapterSession)
+            | ChatCompletionError::Endpoint(LLMEndpointError::ImagesUnsupported(_))
+            | ChatCompletionError::Endpoint(LLMEndpointError::UnknownToken(_))
+            | ChatCompletionError::InvalidImage { .. }
+            | ChatCompletionError::EmbeddingFormat(_) => StatusCode::BAD_REQUEST,
+            ChatCompletionError::Endpoint(LLMEndpointError::SessionNotFound)
+            | ChatCompletionError::Endpoint(LLMEndpointError::UnknownAdapter(_)) => {
+                StatusCode::NOT_FOUND
+            }
+            ChatCompletionError::Endpoint(LLMEndpointError::SessionBusy) => StatusCode::CONFLICT,
+            ChatCompletionError::Endpoint(LLMEndpointError::Timeout)
+            | ChatCompletionError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, Json(self)).into_response()
     }
 }
 
@@ -639,6 +979,60 @@ impl From<AssistantToolCall<'_>> for edgen_core::llm::AssistantToolCall {
     }
 }
 
+impl From<edgen_core::llm::AssistantToolCall> for AssistantToolCall<'static> {
+    fn from(value: edgen_core::llm::AssistantToolCall) -> Self {
+        Self {
+            id: Cow::Owned(value.id),
+            type_: Cow::Owned(value.type_),
+            function: AssistantFunctionStub {
+                name: Cow::Owned(value.function.name),
+                arguments: Cow::Owned(value.function.arguments),
+            },
+        }
+    }
+}
+
+impl From<ToolStub<'_>> for edgen_core::llm::ToolStub {
+    fn from(value: ToolStub) -> Self {
+        match value {
+            ToolStub::Function { function } => Self::Function {
+                function: edgen_core::llm::FunctionStub {
+                    description: function.description.map(|x| x.to_string()),
+                    name: function.name.to_string(),
+                    parameters: function.parameters,
+                },
+            },
+        }
+    }
+}
+
+impl From<Either<Cow<'_, str>, ToolStub<'_>>> for ToolChoice {
+    fn from(value: Either<Cow<'_, str>, ToolStub<'_>>) -> Self {
+        match value {
+            Either::Left(choice) => match choice.as_ref() {
+                "none" => Self::None,
+                "required" => Self::Required,
+                _ => Self::Auto,
+            },
+            Either::Right(ToolStub::Function { function }) => {
+                Self::Function(function.name.to_string())
+            }
+        }
+    }
+}
+
+impl From<ResponseFormat<'_>> for edgen_core::llm::ResponseFormat {
+    fn from(value: ResponseFormat) -> Self {
+        match value {
+            ResponseFormat::Text => Self::Text,
+            ResponseFormat::JsonObject => Self::JsonObject,
+            ResponseFormat::JsonSchema { json_schema } => Self::JsonSchema {
+                schema: json_schema.schema,
+            },
+        }
+    }
+}
+
 impl From<ChatMessage<'_>> for edgen_core::llm::ChatMessage {
     fn from(value: ChatMessage) -> Self {
         match value {
@@ -698,6 +1092,8 @@ impl From<CreateChatCompletionRequest<'_>> for CompletionArgs {
             messages: value.messages.into(),
             frequency_penalty: value.frequency_penalty,
             logit_bias: value.logit_bias,
+            logprobs: value.logprobs,
+            top_logprobs: value.top_logprobs,
             max_tokens: value.max_tokens,
             n: value.n,
             presence_penalty: value.presence_penalty,
@@ -708,8 +1104,22 @@ impl From<CreateChatCompletionRequest<'_>> for CompletionArgs {
             }),
             temperature: value.temperature,
             top_p: value.top_p,
+            tools: value
+                .tools
+                .map(|mut v| v.drain(..).map(|x| x.into()).collect()),
+            tool_choice: value.tool_choice.map(|x| x.into()),
+            response_format: value.response_format.map(|x| x.into()),
+            grammar: value.grammar.map(|x| x.to_string()),
+            context_overflow: value.context_overflow,
             one_shot: value.one_shot,
             context_hint: value.context_hint,
+            session: match (value.session, value.create_session) {
+                (Some(id), _) => Some(ChatSession::Continue(id)),
+                (None, Some(true)) => Some(ChatSession::Create(Uuid::new_v4())),
+                _ => None,
+            },
+            deadline: None,
+            adapter: None,
         }
     }
 }
@@ -734,38 +1144,28 @@ pub enum ParseError {
     NoRepo,
 }
 
-/// POST `/v1/chat/completions`: generate chat completions for the provided context, optionally
-/// streaming those completions in real-time.
-///
-/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
-///
-/// [openai]: https://platform.openai.com/docs/api-reference/chat/create
-///
-/// Generates completions for the given [`CreateChatCompletionRequest`] body.
-/// If `stream` is enabled, streams a number of newline-separated, JSON-encoded
-/// [`ChatCompletionChunk`]s to the client using [server-sent events][sse]. Otherwise, returns a
-/// single JSON-encoded [`ChatCompletion`].
-///
-/// [sse]: https://developer.mozilla.org/en-US/docs/Web/API/Server-sent_events
+/// Splits a model name of the form `base+adapter` into the name of the base model and the name of
+/// the LoRA adapter applied to it, if any.
+fn split_adapter(model_name: &str) -> (&str, Option<&str>) {
+    match model_name.rsplit_once('+') {
+        Some((base, adapter)) if !base.is_empty() && !adapter.is_empty() => (base, Some(adapter)),
+        _ => (model_name, None),
+    }
+}
+
+/// Resolves the model named `model_name` in a chat completions request into a preloaded
+/// [`Model`], along with the name of its file and the name of the LoRA adapter to apply to it.
 ///
-/// On failure, may raise a `500 Internal Server Error` with a JSON-encoded [`ChatCompletionError`]
-/// to the peer.
-#[utoipa::path(
-post,
-path = "/chat/completions",
-request_body = CreateChatCompletionRequest,
-responses(
-(status = 200, description = "OK", body = ChatCompletionResponse),
-(status = 500, description = "unexpected internal server error", body = ChatCompletionError)
-),
-)]
-pub async fn chat_completions(
-    Json(req): Json<CreateChatCompletionRequest<'_>>,
-) -> Result<impl IntoResponse, ChatCompletionError> {
-    let params = get_chat_completions_model_params(req.model.as_ref()).await;
+/// A `model_name` of the form `base+adapter` selects the adapter named `adapter` of the model
+/// `base`, in which case the returned name is that of its file followed by `+adapter`.
+pub(crate) async fn chat_completions_model(
+    model_name: &str,
+) -> Result<(Model, String, Option<String>), ChatCompletionError> {
+    let (base_name, adapter) = split_adapter(model_name);
+    let params = get_chat_completions_model_params(base_name).await;
     if let Err(error) = params {
         return Err(ChatCompletionError::ProhibitedName {
-            model_name: req.model.to_string(),
+            model_name: model_name.to_string(),
             reason: Cow::Borrowed(error),
         });
     }
@@ -774,13 +1174,13 @@ pub async fn chat_completions(
 
     if params.name.is_empty() {
         return Err(ChatCompletionError::ProhibitedName {
-            model_name: req.model.to_string(),
+            model_name: model_name.to_string(),
             reason: Cow::Borrowed("Empty model name in config"),
         });
     }
     if params.dir.is_empty() {
         return Err(ChatCompletionError::ProhibitedName {
-            model_name: req.model.to_string(),
+            model_name: model_name.to_string(),
             reason: Cow::Borrowed("Empty model directory in config"),
         });
     }
@@ -792,7 +1192,7 @@ pub async fn chat_completions(
         .get_top_model_kind(&params.kind_param, &[ModelKind::LLM, ModelKind::ChatFaker]);
     if let Err(error) = kind {
         return Err(ChatCompletionError::UnknownModelKind {
-            model_name: req.model.to_string(),
+            model_name: model_name.to_string(),
             reason: Cow::Owned(error.to_string()),
         });
     }
@@ -807,67 +1207,401 @@ pub async fn chat_completions(
     model
         .preload(Endpoint::ChatCompletions)
         .await
-        .map_err(move |_| ChatCompletionError::NoSuchModel {
+        .map_err(|_| ChatCompletionError::NoSuchModel {
             model_name: params.name.to_string(),
         })?;
 
-    let stream_response = req.stream.unwrap_or(false);
+    match adapter {
+        Some(adapter) => Ok((
+            model,
+            format!("{}+{adapter}", params.name),
+            Some(adapter.to_string()),
+        )),
+        None => Ok((model, params.name, None)),
+    }
+}
+
+/// How long fetching a remote image may take.
+const IMAGE_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The client fetching remote images, which does not follow redirects, as they could lead to a
+/// host that is not allowed by the `image_url_hosts` setting.
+static IMAGE_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(IMAGE_FETCH_TIMEOUT)
+        .build()
+        // PANIC SAFETY: the client only fails to build if TLS cannot be initialized
+        .expect("failed to build the image client")
+});
+
+/// Replaces the image URLs in the user messages of `args` with the images behind them, which
+/// multimodal models embed into their context.
+///
+/// Images are read from base64 `data:` URLs. Remote images are only fetched from the hosts
+/// allowed by the `image_url_hosts` setting, and up to `image_url_max_size` bytes.
+///
+/// The images are only read once they are known to be usable with `model` (see
+/// [`check_images`]).
+async fn resolve_images(
+    model: &Model,
+    args: &mut CompletionArgs,
+) -> Result<(), ChatCompletionError> {
+    let has_images = args.messages.iter().any(|message| {
+        matches!(
+            message,
+            ChatMessage::User {
+                content: Either::Right(parts),
+                ..
+            } if parts
+                .iter()
+                .any(|part| matches!(part, edgen_core::llm::ContentPart::ImageUrl { .. }))
+        )
+    });
+    if !has_images {
+        return Ok(());
+    }
+    check_images(model, args).await?;
+
+    for message in args.messages.iter_mut() {
+        let ChatMessage::User {
+            content: Either::Right(parts),
+            ..
+        } = message
+        else {
+            continue;
+        };
+
+        for part in parts.iter_mut() {
+            let edgen_core::llm::ContentPart::ImageUrl { url, .. } = part else {
+                continue;
+            };
+            let bytes =
+                image_bytes(url)
+                    .await
+                    .map_err(|reason| ChatCompletionError::InvalidImage {
+                        url: url.clone(),
+                        reason,
+                    })?;
+            *part = edgen_core::llm::ContentPart::Image(ImageInput::new(bytes));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the images in `args` can be used with `model`, which must have a multimodal
+/// projector, without an adapter or an explicit chat session.
+async fn check_images(model: &Model, args: &CompletionArgs) -> Result<(), LLMEndpointError> {
+    if args.adapter.is_some() {
+        return Err(LLMEndpointError::ImagesUnsupported(
+            "with an adapter".to_string(),
+        ));
+    }
+    if args.session.is_some() {
+        return Err(LLMEndpointError::ImagesUnsupported(
+            "in an explicit chat session".to_string(),
+        ));
+    }
+
+    let mmproj = match (&model.kind, model.file_path()) {
+        (ModelKind::LLM, Ok(path)) => SETTINGS.read().await.read().await.mmproj(path),
+        _ => None,
+    };
+    if mmproj.is_none() {
+        return Err(LLMEndpointError::ImagesUnsupported(
+            "with a model that has no multimodal projector".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Reads the encoded bytes of the image at `url`.
+async fn image_bytes(url: &str) -> Result<Vec<u8>, Cow<'static, str>> {
+    match image_source(url).map_err(|e| Cow::Owned(e.to_string()))? {
+        ImageSource::Data(payload) => BASE64
+            .decode(payload.trim())
+            .map_err(|e| Cow::Owned(e.to_string())),
+        ImageSource::Remote(url) => {
+            let url = reqwest::Url::parse(url).map_err(|e| Cow::Owned(e.to_string()))?;
+            let host = url.host_str().unwrap_or_default();
+            let (allowed, max_size) = {
+                let settings = SETTINGS.read().await;
+                let settings = settings.read().await;
+                (
+                    settings.image_host_allowed(host),
+                    settings.image_url_max_size,
+                )
+            };
+            if !allowed {
+                return Err(Cow::Owned(format!(
+                    "remote images from {host} are not allowed"
+                )));
+            }
+
+            // the reason of a failure is not returned, so that clients cannot probe the network
+            let unavailable = || Cow::Borrowed("the image could not be fetched");
+            let too_large = Cow::Owned(format!("the image is larger than {max_size} bytes"));
+            let mut response = IMAGE_CLIENT
+                .get(url)
+                .send()
+                .await
+                .map_err(|_| unavailable())?;
+            // redirects are not followed, so they are failures too
+            if !response.status().is_success() {
+                return Err(unavailable());
+            }
+            if response.content_length().is_some_and(|len| len > max_size) {
+                return Err(too_large);
+            }
+
+            // the length of the body may not be known beforehand, or may be wrong
+            let mut bytes = vec![];
+            while let Some(chunk) = response.chunk().await.map_err(|_| unavailable())? {
+                if (bytes.len() + chunk.len()) as u64 > max_size {
+                    return Err(too_large);
+                }
+                bytes.extend_from_slice(&chunk);
+            }
+
+            Ok(bytes)
+        }
+    }
+}
+
+/// POST `/v1/chat/completions`: generate chat completions for the provided context, optionally
+/// streaming those completions in real-time.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/chat/create
+///
+/// Generates completions for the given [`CreateChatCompletionRequest`] body.
+/// If `stream` is enabled, streams a number of newline-separated, JSON-encoded
+/// [`ChatCompletionChunk`]s to the client using [server-sent events][sse]. Otherwise, returns a
+/// single JSON-encoded [`ChatCompletion`].
+///
+/// [sse]: https://developer.mozilla.org/en-US/docs/Web/API/Server-sent_events
+///
+/// On failure, may raise a `500 Internal Server Error` with a JSON-encoded [`ChatCompletionError`]
+/// to the peer. If the messages do not fit in the context of the model and the
+/// `context_overflow` policy is `reject`, if an explicit `session` is used with an adapter, or if
+/// an image cannot be read or used with the model, raises a `400 Bad Request` instead. If the requested `session` or adapter does not exist,
+/// raises a `404 Not Found`, and if the session is generating another completion, a
+/// `409 Conflict`. If too many requests are queued, raises a
+/// `429 Too Many Requests` with a JSON-encoded [`AdmissionRejection`], and if the completions do
+/// not start before the timeout of the request, a `504 Gateway Timeout`.
+#[utoipa::path(
+post,
+path = "/chat/completions",
+request_body = CreateChatCompletionRequest,
+responses(
+(status = 200, description = "OK", body = ChatCompletionResponse),
+(status = 400, description = "the messages do not fit in the context of the model, a session is used with an adapter, or an image cannot be read or used", body = ChatCompletionError),
+(status = 404, description = "the session or adapter does not exist", body = ChatCompletionError),
+(status = 409, description = "the session is generating another completion", body = ChatCompletionError),
+(status = 429, description = "too many requests are queued", body = AdmissionRejection),
+(status = 500, description = "unexpected internal server error", body = ChatCompletionError),
+(status = 504, description = "the completions did not start before the request timed out", body = ChatCompletionError)
+),
+)]
+pub async fn chat_completions(
+    Json(req): Json<CreateChatCompletionRequest<'_>>,
+) -> Result<impl IntoResponse, ChatCompletionError> {
+    let (model, model_name, adapter) = chat_completions_model(req.model.as_ref()).await?;
 
+    let stream_response = req.stream.unwrap_or(false);
+    let include_usage = req
+        .stream_options
+        .as_ref()
+        .is_some_and(|options| options.include_usage);
+    let timeout = request_timeout(req.timeout).await;
+    let mut args = CompletionArgs::from(req);
+    args.deadline = timeout.map(|timeout| Instant::now() + timeout);
+    args.adapter = adapter;
+    resolve_images(&model, &mut args).await?;
+    let choices = args.choices();
+    let session = args.session.map(|session| session.id());
+
+    let id = format!("chatcmpl-{}", Uuid::new_v4());
+    let created = OffsetDateTime::now_utc().unix_timestamp();
     let fp = format!("edgen-{}", cargo_crate_version!());
     let response = if stream_response {
         let completions_stream = {
             let result = match model.kind {
-                ModelKind::LLM => llm::chat_completion_stream(model, req.into()).await?,
-                ModelKind::ChatFaker => {
-                    chat_faker::chat_completion_stream(model, req.into()).await?
-                }
+                ModelKind::LLM => llm::chat_completion_stream(model, args).await?,
+                ModelKind::ChatFaker => chat_faker::chat_completion_stream(model, args).await?,
                 _ => panic!("we should never get here"),
             };
-            result.map(move |chunk| {
-                Event::default().json_data(ChatCompletionChunk {
-                    id: Uuid::new_v4().to_string().into(),
-                    choices: tiny_vec![ChatCompletionChunkChoice {
-                        index: 0,
-                        finish_reason: None,
+
+            // every chunk shares the same id, creation time and model
+            let chunk_event =
+                move |choice: Option<ChatCompletionChunkChoice>,
+                      usage: Option<ChatCompletionUsage>,
+                      context_overflow: Option<ChatCompletionContextOverflow>| {
+                    Event::default().json_data(ChatCompletionChunk {
+                        id: Cow::Borrowed(&id),
+                        choices: choice.into_iter().collect(),
+                        created,
+                        model: Cow::Borrowed(&model_name),
+                        system_fingerprint: Cow::Borrowed(&fp),
+                        object: Cow::Borrowed("chat.completion.chunk"),
+                        usage,
+                        context_overflow,
+                        session,
+                    })
+                };
+
+            // every choice starts with a chunk introducing the role
+            let first: Vec<_> = (0..choices as u32)
+                .map(|index| {
+                    chunk_event(
+                        Some(ChatCompletionChunkChoice {
+                            index,
+                            finish_reason: None,
+                            delta: ChatCompletionChunkDelta {
+                                content: Some(Cow::Borrowed("")),
+                                role: Some(Cow::Borrowed("assistant")),
+                                tool_calls: None,
+                            },
+                            logprobs: None,
+                        }),
+                        None,
+                        None,
+                    )
+                })
+                .collect();
+
+            // the usage of the choices that finished so far; the prompt is shared by all of them
+            let mut total = TokenUsage::default();
+            let mut finished = 0;
+            let chunks = result.flat_map(move |chunk| {
+                let mut events = vec![];
+
+                // tool calls get a chunk of their own, before the closing one
+                if let Some(calls) = chunk.tool_calls {
+                    let calls = calls
+                        .into_iter()
+                        .enumerate()
+                        .map(|(index, call)| {
+                            let call = AssistantToolCall::from(call);
+                            ChatCompletionChunkToolCall {
+                                index: index as u32,
+                                id: call.id,
+                                type_: call.type_,
+                                function: call.function,
+                            }
+                        })
+                        .collect();
+
+                    events.push(chunk_event(
+                        Some(ChatCompletionChunkChoice {
+                            index: chunk.index,
+                            finish_reason: None,
+                            delta: ChatCompletionChunkDelta {
+                                tool_calls: Some(calls),
+                                ..Default::default()
+                            },
+                            logprobs: None,
+                        }),
+                        None,
+                        None,
+                    ));
+                }
+
+                // the closing chunk has an empty delta
+                let content = if chunk.content.is_empty() && chunk.finish_reason.is_some() {
+                    None
+                } else {
+                    Some(Cow::Owned(chunk.content))
+                };
+
+                events.push(chunk_event(
+                    Some(ChatCompletionChunkChoice {
+                        index: chunk.index,
+                        finish_reason: chunk
+                            .finish_reason
+                            .map(|reason| Cow::Owned(reason.to_string())),
                         delta: ChatCompletionChunkDelta {
-                            content: Some(Cow::Owned(chunk)),
-                            role: None,
+                            content,
+                            ..Default::default()
                         },
-                    }],
-                    created: OffsetDateTime::now_utc().unix_timestamp(),
-                    model: Cow::Borrowed("main"),
-                    system_fingerprint: Cow::Borrowed(&fp),
-                    object: Cow::Borrowed("text_completion"),
-                })
-            })
+                        logprobs: chunk.logprobs.map(Into::into),
+                    }),
+                    None,
+                    chunk.context_overflow.map(Into::into),
+                ));
+
+                // the usage of the whole request follows the last choice to finish
+                if let Some(usage) = chunk.usage {
+                    total.prompt_tokens = usage.prompt_tokens;
+                    total.completion_tokens += usage.completion_tokens;
+                    finished += 1;
+                    if include_usage && finished == choices {
+                        events.push(chunk_event(None, Some(total.into()), None));
+                    }
+                }
+
+                futures::stream::iter(events)
+            });
+
+            futures::stream::iter(first)
+                .chain(chunks)
+                .chain(futures::stream::iter([Ok(Event::default().data("[DONE]"))]))
         };
         ChatCompletionResponse::Stream(Sse::new(completions_stream))
     } else {
-        let content_str = match model.kind {
-            ModelKind::LLM => llm::chat_completion(model, req.into()).await?,
-            ModelKind::ChatFaker => crate::chat_faker::chat_completion(model, req.into()).await?,
+        let completions = match model.kind {
+            ModelKind::LLM => llm::chat_completion(model, args).await?,
+            ModelKind::ChatFaker => crate::chat_faker::chat_completion(model, args).await?,
             _ => panic!("we should never get here"),
         };
-        let response = ChatCompletion {
-            id: Uuid::new_v4().to_string().into(),
-            choices: vec![ChatCompletionChoice {
+
+        // the prompt is shared by every choice
+        let usage = TokenUsage {
+            prompt_tokens: completions
+                .first()
+                .map_or(0, |completion| completion.usage.prompt_tokens),
+            completion_tokens: completions
+                .iter()
+                .map(|completion| completion.usage.completion_tokens)
+                .sum(),
+        };
+        let context_overflow = completions
+            .first()
+            .and_then(|completion| completion.context_overflow);
+
+        let choices = completions
+            .into_iter()
+            .enumerate()
+            .map(|(index, completion)| ChatCompletionChoice {
                 message: ChatMessage::Assistant {
-                    content: Some(Cow::Owned(content_str)),
+                    content: match completion.tool_calls {
+                        Some(_) => None,
+                        None => Some(Cow::Owned(completion.content)),
+                    },
                     name: None,
-                    tool_calls: None,
+                    tool_calls: completion
+                        .tool_calls
+                        .map(|calls| calls.into_iter().map(AssistantToolCall::from).collect()),
                 },
-                finish_reason: None,
-                index: 0,
-            }],
-            created: OffsetDateTime::now_utc().unix_timestamp(),
-            model: Cow::Borrowed("main"),
-            object: Cow::Borrowed("text_completion"),
+                finish_reason: Some(Cow::Owned(completion.finish_reason.to_string())),
+                index: index as i32,
+                logprobs: completion.logprobs.map(Into::into),
+            })
+            .collect();
+
+        let response = ChatCompletion {
+            id: Cow::Owned(id),
+            choices,
+            created,
+            model: Cow::Owned(model_name),
+            object: Cow::Borrowed("chat.completion"),
             system_fingerprint: Cow::Owned(fp),
-            usage: ChatCompletionUsage {
-                completion_tokens: 0,
-                prompt_tokens: 0,
-                total_tokens: 0,
-            },
+            usage: usage.into(),
+            context_overflow: context_overflow.map(Into::into),
+            session,
         };
 
         ChatCompletionResponse::Full(Json(response))
@@ -876,31 +1610,533 @@ pub async fn chat_completions(
     Ok(response)
 }
 
+/// A request to complete one or more raw prompts, which are given to the model as they are,
+/// without applying a chat template.
+///
+/// An `axum` handler, [`completions`][completions], is provided to handle this request.
+///
+/// See [the documentation for creating completions][openai] for more details.
+///
+/// [completions]: fn.completions.html
+/// [openai]: https://platform.openai.com/docs/api-reference/completions/create
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreateCompletionRequest<'a> {
+    /// The model to use for generating completions. A model of the form `base+adapter` applies
+    /// the LoRA adapter named `adapter`, declared in the settings of the model `base`.
+    pub model: Cow<'a, str>,
+
+    /// The prompt to complete, or several prompts, which are completed separately.
+    #[serde(with = "either::serde_untagged")]
+    #[schema(value_type = String)]
+    pub prompt: Either<Cow<'a, str>, Vec<Cow<'a, str>>>,
+
+    /// If present, the text that follows the completion, which is then generated between the
+    /// prompt and the suffix. This is only supported by models trained for fill-in-the-middle,
+    /// such as most code models.
+    pub suffix: Option<Cow<'a, str>>,
+
+    /// If `true`, the prompt is included at the start of the text of each choice.
+    pub echo: Option<bool>,
+
+    /// If present, the log probabilities of the generated tokens are returned in the `logprobs`
+    /// of each choice, along with this many most likely tokens, in `[0, 20]`, for every position.
+    ///
+    /// Log probabilities are only returned for generated tokens, even if `echo` is set.
+    pub logprobs: Option<u32>,
+
+    /// A number in `[-2.0, 2.0]`. A higher number decreases the likelihood that the model
+    /// repeats itself.
+    pub frequency_penalty: Option<f32>,
+
+    /// A map of token IDs to `[-100.0, +100.0]`. Adds a percentage bias to those tokens before
+    /// sampling; a value of `-100.0` prevents the token from being selected at all.
+    pub logit_bias: Option<HashMap<u32, f32>>,
+
+    /// The maximum number of tokens to generate for each prompt. If `None`, the server-wide
+    /// default is used.
+    ///
+    /// Either way, this is capped by the maximum configured for the model, if any.
+    pub max_tokens: Option<u32>,
+
+    /// How many choices to generate for each prompt, in `[1, 128]`. `1` by default.
+    pub n: Option<u32>,
+
+    /// A number in `[-2.0, 2.0]`. Positive values "increase the model's likelihood to talk about
+    /// new topics."
+    pub presence_penalty: Option<f32>,
+
+    /// An RNG seed for the session. Random by default.
+    pub seed: Option<u32>,
+
+    /// A stop phrase or set of stop phrases, which are never emitted to the client.
+    #[serde(default, with = "either::serde_untagged_optional")]
+    #[schema(value_type = String)]
+    pub stop: Option<Either<Cow<'a, str>, Vec<Cow<'a, str>>>>,
+
+    /// If `true`, stream [`TextCompletion`] chunks instead of a single [`TextCompletion`].
+    pub stream: Option<bool>,
+
+    /// Options for the response stream. Only used if `stream` is `true`.
+    pub stream_options: Option<ChatCompletionStreamOptions>,
+
+    /// The sampling temperature, in `[0.0, 2.0]`. Higher values make the output more random.
+    pub temperature: Option<f32>,
+
+    /// Nucleus sampling. If you set this value to 10%, only the top 10% of tokens are used for
+    /// sampling, preventing sampling of very low-probability tokens.
+    pub top_p: Option<f32>,
+
+    /// A unique identifier for the _end user_ creating this request. This is used for telemetry
+    /// and user tracking, and is unused within Edgen.
+    pub user: Option<Cow<'a, str>>,
+
+    /// A hint for how big the context will be, overriding the context size of the model.
+    ///
+    /// # Warning
+    /// An unsound hint may severely drop performance and/or inference quality, and in some cases even cause Edgen
+    /// to crash. Do not set this value unless you know what you are doing.
+    pub context_hint: Option<u32>,
+
+    /// The number of seconds completions may be generated for, shared by every prompt. Past it,
+    /// the completions generated so far are returned, with a `timeout` finish reason. If the
+    /// completions did not start by then, because the model was still loading or a prompt was
+    /// still being evaluated, the request fails with a `504 Gateway Timeout`.
+    ///
+    /// If absent, the `request_timeout` configured for the server is used. `0` means there is no
+    /// limit.
+    pub timeout: Option<f32>,
+}
+
+impl CreateCompletionRequest<'_> {
+    /// Returns the prompts of this request.
+    fn prompts(&self) -> Vec<String> {
+        match &self.prompt {
+            Either::Left(prompt) => vec![prompt.to_string()],
+            Either::Right(prompts) => prompts.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    /// Returns the arguments to complete `prompt`, which is one of the prompts of this request,
+    /// with an optional LoRA `adapter` and until an optional `deadline`.
+    fn args(
+        &self,
+        prompt: &str,
+        adapter: Option<String>,
+        deadline: Option<Instant>,
+    ) -> TextCompletionArgs {
+        TextCompletionArgs {
+            prompt: prompt.to_string(),
+            suffix: self.suffix.as_ref().map(|x| x.to_string()),
+            sampling: CompletionArgs {
+                frequency_penalty: self.frequency_penalty,
+                logit_bias: self.logit_bias.clone(),
+                logprobs: self.logprobs.map(|_| true),
+                top_logprobs: self.logprobs,
+                max_tokens: self.max_tokens,
+                n: self.n,
+                presence_penalty: self.presence_penalty,
+                seed: self.seed,
+                stop: self.stop.as_ref().map(|x| match x {
+                    Either::Left(text) => Either::Left(text.to_string()),
+                    Either::Right(v) => Either::Right(v.iter().map(|x| x.to_string()).collect()),
+                }),
+                temperature: self.temperature,
+                top_p: self.top_p,
+                one_shot: Some(true),
+                context_hint: self.context_hint,
+                deadline,
+                adapter,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// A choice of a [`TextCompletion`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TextCompletionChoice<'a> {
+    /// The generated text, preceded by the prompt if `echo` was set.
+    ///
+    /// When streaming, this is the text added to the end of the choice.
+    pub text: Cow<'a, str>,
+
+    /// The index of this choice. The `n` choices of each prompt follow each other, in the order of
+    /// the prompts, so this is `prompt index * n + choice index`.
+    pub index: u32,
+
+    /// If present, the reason that generation terminated at this choice.
+    ///
+    /// This can be:
+    ///
+    /// - `length`, indicating that the length cutoff was reached, or
+    /// - `stop`, indicating that the model finished or that a stop phrase was reached.
+    pub finish_reason: Option<Cow<'a, str>>,
+
+    /// If present, the log probabilities of the generated tokens, as requested by `logprobs` in
+    /// the [`CreateCompletionRequest`].
+    pub logprobs: Option<TextCompletionLogprobs>,
+}
+
+/// The log probabilities of the tokens of a [`TextCompletionChoice`], as parallel lists with an
+/// item for each token.
+///
+/// See [the documentation for creating completions][openai] for more details.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/completions/object
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TextCompletionLogprobs {
+    /// The text of each token.
+    pub tokens: Vec<String>,
+
+    /// The natural logarithm of the probability of each token.
+    pub token_logprobs: Vec<f32>,
+
+    /// The most likely tokens at the position of each token, mapped to their log probabilities.
+    pub top_logprobs: Vec<HashMap<String, f32>>,
+
+    /// The character offset of each token in the text of the choice.
+    pub text_offset: Vec<u32>,
+}
+
+impl TextCompletionLogprobs {
+    /// Creates the log probabilities of `logprobs`, the first of which is at character `offset`
+    /// of the text of the choice.
+    fn new(logprobs: Vec<TokenLogprob>, mut offset: u32) -> Self {
+        let mut res = Self {
+            tokens: Vec::with_capacity(logprobs.len()),
+            token_logprobs: Vec::with_capacity(logprobs.len()),
+            top_logprobs: Vec::with_capacity(logprobs.len()),
+            text_offset: Vec::with_capacity(logprobs.len()),
+        };
+
+        for logprob in logprobs {
+            res.text_offset.push(offset);
+            offset += logprob.token.chars().count() as u32;
+            res.top_logprobs.push(
+                logprob
+                    .top_logprobs
+                    .into_iter()
+                    .map(|top| (top.token, top.logprob))
+                    .collect(),
+            );
+            res.token_logprobs.push(logprob.logprob);
+            res.tokens.push(logprob.token);
+        }
+
+        res
+    }
+}
+
+/// A fully generated text completion, or a chunk of one in streaming mode.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TextCompletion<'a> {
+    /// A unique identifier for the completion, shared by every chunk of a stream.
+    pub id: Cow<'a, str>,
+
+    /// The generated choices, `n` for each prompt.
+    ///
+    /// When streaming, each chunk has at most one choice.
+    pub choices: Vec<TextCompletionChoice<'a>>,
+
+    /// The UNIX timestamp at which the completion was generated.
+    pub created: i64,
+
+    /// The name of the model that generated the completion, after resolving aliases such as
+    /// `default`.
+    pub model: Cow<'a, str>,
+
+    /// A unique identifier for the backend configuration that generated the completion.
+    pub system_fingerprint: Cow<'a, str>,
+
+    /// The object type. This is always `text_completion`.
+    pub object: Cow<'a, str>,
+
+    /// Usage information about the whole request.
+    ///
+    /// When streaming, this is only present in the last chunk, and only if `include_usage` was
+    /// set in the `stream_options` of the [`CreateCompletionRequest`]. That chunk has no choices.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<ChatCompletionUsage>,
+}
+
+/// POST `/v1/completions`: complete one or more raw prompts, without applying a chat template.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/completions/create
+///
+/// Generates completions for the given [`CreateCompletionRequest`] body, one choice for each
+/// prompt, in the same order. If `stream` is enabled, streams JSON-encoded [`TextCompletion`]
+/// chunks to the client using [server-sent events][sse], completing the prompts one after the
+/// other. Otherwise, returns a single JSON-encoded [`TextCompletion`].
+///
+/// [sse]: https://developer.mozilla.org/en-US/docs/Web/API/Server-sent_events
+///
+/// On failure, may raise a `500 Internal Server Error` with a JSON-encoded [`ChatCompletionError`]
+/// to the peer. If a prompt does not fit in the context of the model, raises a
+/// `400 Bad Request` instead, and if the adapter does not exist, a `404 Not Found`. If too many
+/// requests are queued, raises a `429 Too Many Requests` with a JSON-encoded
+/// [`AdmissionRejection`], and if the completions do not start before the timeout of the request,
+/// a `504 Gateway Timeout`.
+#[utoipa::path(
+post,
+path = "/completions",
+request_body = CreateCompletionRequest,
+responses(
+(status = 200, description = "OK", body = TextCompletion),
+(status = 400, description = "a prompt does not fit in the context of the model", body = ChatCompletionError),
+(status = 404, description = "the adapter does not exist", body = ChatCompletionError),
+(status = 429, description = "too many requests are queued", body = AdmissionRejection),
+(status = 500, description = "unexpected internal server error", body = ChatCompletionError),
+(status = 504, description = "the completions did not start before the request timed out", body = ChatCompletionError)
+),
+)]
+pub async fn completions(
+    Json(req): Json<CreateCompletionRequest<'_>>,
+) -> Result<Response, ChatCompletionError> {
+    let (model, model_name, adapter) = chat_completions_model(req.model.as_ref()).await?;
+
+    let prompts = req.prompts();
+    let echo = req.echo.unwrap_or(false);
+    let id = format!("cmpl-{}", Uuid::new_v4());
+    let created = OffsetDateTime::now_utc().unix_timestamp();
+    let fp = format!("edgen-{}", cargo_crate_version!());
+    let deadline = request_timeout(req.timeout)
+        .await
+        .map(|timeout| Instant::now() + timeout);
+
+    if req.stream.unwrap_or(false) {
+        let include_usage = req
+            .stream_options
+            .as_ref()
+            .is_some_and(|options| options.include_usage);
+
+        // the streams are created up front, so that errors are returned before streaming starts
+        let mut streams = Vec::with_capacity(prompts.len());
+        let mut choices = 0;
+        for (prompt_index, prompt) in prompts.iter().enumerate() {
+            let args = req.args(prompt, adapter.clone(), deadline);
+            let n = args.sampling.choices();
+            let stream = match model.kind {
+                ModelKind::LLM => llm::text_completion_stream(model.clone(), args).await?,
+                ModelKind::ChatFaker => {
+                    chat_faker::text_completion_stream(model.clone(), args).await?
+                }
+                _ => panic!("we should never get here"),
+            };
+
+            let echoed: Vec<_> = (0..n as u32)
+                .filter(|_| echo)
+                .map(|index| CompletionChunk {
+                    index,
+                    content: prompt.clone(),
+                    finish_reason: None,
+                    usage: None,
+                    tool_calls: None,
+                    context_overflow: None,
+                    logprobs: None,
+                })
+                .collect();
+            let first = (prompt_index * n) as u32;
+            streams.push(
+                futures::stream::iter(echoed)
+                    .chain(stream)
+                    .map(move |chunk| (first + chunk.index, chunk)),
+            );
+            choices += n;
+        }
+
+        // every chunk shares the same id, creation time and model
+        let chunk_event = move |choice: Option<TextCompletionChoice>,
+                                usage: Option<ChatCompletionUsage>| {
+            Event::default().json_data(TextCompletion {
+                id: Cow::Borrowed(&id),
+                choices: choice.into_iter().collect(),
+                created,
+                model: Cow::Borrowed(&model_name),
+                system_fingerprint: Cow::Borrowed(&fp),
+                object: Cow::Borrowed("text_completion"),
+                usage,
+            })
+        };
+
+        // the usage of the choices that finished so far, and the length in characters of the
+        // text of each choice, which locates its tokens
+        let mut total = TokenUsage::default();
+        let mut finished = 0;
+        let mut offsets = vec![0; choices];
+        let events = futures::stream::iter(streams)
+            .flatten()
+            .flat_map(move |(index, chunk)| {
+                let mut events = vec![];
+                if !chunk.content.is_empty() || chunk.finish_reason.is_some() {
+                    let offset = &mut offsets[index as usize];
+                    let logprobs = chunk
+                        .logprobs
+                        .map(|logprobs| TextCompletionLogprobs::new(logprobs, *offset));
+                    *offset += chunk.content.chars().count() as u32;
+                    events.push(chunk_event(
+                        Some(TextCompletionChoice {
+                            text: Cow::Owned(chunk.content),
+                            index,
+                            finish_reason: chunk
+                                .finish_reason
+                                .map(|reason| Cow::Owned(reason.to_string())),
+                            logprobs,
+                        }),
+                        None,
+                    ));
+                }
+
+                // the usage of the whole request follows the last choice to finish, counting the
+                // prompt shared by the choices of each prompt once
+                if let Some(usage) = chunk.usage {
+                    if chunk.index == 0 {
+                        total.prompt_tokens += usage.prompt_tokens;
+                    }
+                    total.completion_tokens += usage.completion_tokens;
+                    finished += 1;
+                    if include_usage && finished == choices {
+                        events.push(chunk_event(None, Some(total.into())));
+                    }
+                }
+
+                futures::stream::iter(events)
+            })
+            .chain(futures::stream::iter([Ok(Event::default().data("[DONE]"))]));
+
+        Ok(Sse::new(events).into_response())
+    } else {
+        let mut choices = vec![];
+        let mut usage = TokenUsage::default();
+        for prompt in prompts {
+            let args = req.args(&prompt, adapter.clone(), deadline);
+            let completions = match model.kind {
+                ModelKind::LLM => llm::text_completion(model.clone(), args).await?,
+                ModelKind::ChatFaker => chat_faker::text_completion(model.clone(), args).await?,
+                _ => panic!("we should never get here"),
+            };
+
+            // the prompt is shared by every choice
+            if let Some(completion) = completions.first() {
+                usage.prompt_tokens += completion.usage.prompt_tokens;
+            }
+            for completion in completions {
+                usage.completion_tokens += completion.usage.completion_tokens;
+                let (offset, text) = if echo {
+                    (
+                        prompt.chars().count() as u32,
+                        prompt.clone() + &completion.content,
+                    )
+                } else {
+                    (0, completion.content)
+                };
+                choices.push(TextCompletionChoice {
+                    text: Cow::Owned(text),
+                    index: choices.len() as u32,
+                    finish_reason: Some(Cow::Owned(completion.finish_reason.to_string())),
+                    logprobs: completion
+                        .logprobs
+                        .map(|logprobs| TextCompletionLogprobs::new(logprobs, offset)),
+                });
+            }
+        }
+
+        let response = TextCompletion {
+            id: Cow::Owned(id),
+            choices,
+            created,
+            model: Cow::Owned(model_name),
+            system_fingerprint: Cow::Owned(fp),
+            object: Cow::Borrowed("text_completion"),
+            usage: Some(usage.into()),
+        };
+
+        Ok(Json(response).into_response())
+    }
+}
+
+/// The input of a [`CreateEmbeddingsRequest`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingsInput<'a> {
+    /// A piece of text.
+    Text(Cow<'a, str>),
+
+    /// Several pieces of text.
+    Texts(Vec<Cow<'a, str>>),
+
+    /// The ids of the tokens of a piece of text.
+    Tokens(Vec<u32>),
+
+    /// The ids of the tokens of several pieces of text.
+    TokenArrays(Vec<Vec<u32>>),
+}
+
+impl From<EmbeddingsInput<'_>> for Vec<edgen_core::llm::EmbeddingsInput> {
+    fn from(value: EmbeddingsInput) -> Self {
+        use edgen_core::llm::EmbeddingsInput as Input;
+
+        match value {
+            EmbeddingsInput::Text(text) => vec![Input::Text(text.to_string())],
+            EmbeddingsInput::Texts(texts) => texts
+                .into_iter()
+                .map(|text| Input::Text(text.to_string()))
+                .collect(),
+            EmbeddingsInput::Tokens(tokens) => vec![Input::Tokens(tokens)],
+            EmbeddingsInput::TokenArrays(arrays) => arrays.into_iter().map(Input::Tokens).collect(),
+        }
+    }
+}
+
 /// A request to generate embeddings for one or more pieces of text.
 ///
 /// An `axum` handler, [`create_embeddings`][create_embeddings], is provided to handle this request.
 ///
-/// See [the documentation for creating transcriptions][openai] for more details.
+/// See [the documentation for creating transcriptions][openai] for more details. This request has
+/// two additional optional parameters, which are **not normative** with OpenAI's specification,
+/// `quantization` and `context_overflow`, to deal with functionality specific to **Edgen**.
 ///
 /// [embeddings]: fn.create_embeddings.html
 /// [openai]: https://platform.openai.com/docs/api-reference/embeddings/create
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct CreateEmbeddingsRequest<'a> {
-    /// The text input to embed as either a string or an array of strings.
-    #[serde(with = "either::serde_untagged")]
+    /// The input to embed as either a string, an array of strings, an array of token ids or an
+    /// array of arrays of token ids.
     #[schema(value_type = String)]
-    pub input: Either<Cow<'a, str>, Vec<Cow<'a, str>>>,
+    pub input: EmbeddingsInput<'a>,
 
     /// ID of the model to use.
     #[schema(value_type = String)]
     pub model: Cow<'a, str>,
 
-    /// The format to return the embeddings in. Can be either `float` or `base64`.
-    #[schema(value_type = String)]
-    pub encoding_format: Option<Cow<'a, str>>,
+    /// The format to return the embeddings in. Can be either `float` (the default) or `base64`,
+    /// the little-endian bytes of the values.
+    pub encoding_format: Option<EmbeddingEncoding>,
 
-    /// The number of dimensions the resulting output embeddings should have. Only supported in some models.
+    /// The number of dimensions the resulting output embeddings should have. The embeddings are
+    /// shortened to their first `dimensions` values and normalized again, which only keeps them
+    /// meaningful for models trained with Matryoshka representation learning.
     pub dimensions: Option<usize>,
+
+    /// Quantizes the values of the embeddings for compact storage, into `int8` or packed `binary`
+    /// values. If absent, the values are floats.
+    pub quantization: Option<EmbeddingQuantization>,
+
+    /// What to do with inputs that do not fit in the context of the model:
+    /// - `reject` fails with a `400 Bad Request`,
+    /// - `truncate` embeds the first tokens of the input that fit, or
+    /// - `chunk` splits the input into chunks that fit, and averages their embeddings.
+    ///
+    /// If absent, inputs are rejected.
+    pub context_overflow: Option<EmbeddingsOverflowPolicy>,
+
+    /// The number of seconds the embeddings may take, past which the request fails with a
+    /// `504 Gateway Timeout`.
+    ///
+    /// If absent, the `request_timeout` configured for the server is used. `0` means there is no
+    /// limit.
+    pub timeout: Option<f32>,
 }
 
 /// The return type of [`create_embeddings`].
@@ -910,13 +2146,38 @@ pub struct EmbeddingsResponse {
     pub object: String,
 
     /// The generated embeddings.
-    pub embeddings: Vec<Embedding>,
+    pub data: Vec<Embedding>,
 
     /// The model used for generation.
     pub model: String,
 
     /// The usage statistics of the request.
     pub usage: EmbeddingsUsage,
+
+    /// If present, how the inputs that did not fit in the context of the model were embedded.
+    /// This additional member is **not normative** with OpenAI's specification.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_overflow: Option<EmbeddingsContextOverflow>,
+}
+
+/// How the inputs of a [`CreateEmbeddingsRequest`] that did not fit in the context of the model
+/// were embedded.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct EmbeddingsContextOverflow {
+    /// The context overflow policy that was applied.
+    pub policy: EmbeddingsOverflowPolicy,
+
+    /// The indices of the inputs that did not fit.
+    pub inputs: Vec<usize>,
+}
+
+impl From<EmbeddingsOverflow> for EmbeddingsContextOverflow {
+    fn from(value: EmbeddingsOverflow) -> Self {
+        Self {
+            policy: value.policy,
+            inputs: value.inputs,
+        }
+    }
 }
 
 /// Represents an embedding vector returned by embedding endpoint.
@@ -929,8 +2190,11 @@ pub struct Embedding {
     /// Always `"embedding"`.
     pub object: String,
 
-    /// The embedding vector, which is a list of floats. The length of vector depends on the model.
-    pub embedding: Vec<f32>,
+    /// The embedding vector, which is a list of floats, or of integers if quantized, or a base64
+    /// string of their bytes. The length of vector depends on the model.
+    #[serde(with = "either::serde_untagged")]
+    #[schema(value_type = Vec<f32>)]
+    pub embedding: Either<EmbeddingValues, String>,
 
     /// The index of the embedding in the list of embeddings.
     pub index: usize,
@@ -939,12 +2203,10 @@ pub struct Embedding {
 /// The usage statistics of the request.
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct EmbeddingsUsage {
-    // TODO doc
-    /// ???
+    /// The number of tokens in all the inputs.
     pub prompt_tokens: usize,
 
-    // TODO doc
-    /// ???
+    /// The total number of tokens processed, which is always the same as `prompt_tokens`.
     pub total_tokens: usize,
 }
 
@@ -956,14 +2218,22 @@ pub struct EmbeddingsUsage {
 /// [openai]: https://platform.openai.com/docs/api-reference/embeddings/create
 ///
 /// On failure, may raise a `500 Internal Server Error` with a JSON-encoded [`ChatCompletionError`]
-/// to the peer.
+/// to the peer. If the embeddings cannot be shortened to the requested `dimensions`, if an input
+/// has a token that is not in the vocabulary of the model, or if an input does not fit in the
+/// context of the model and the `context_overflow` policy is `reject`, raises a
+/// `400 Bad Request` instead. If too many requests are queued, raises a `429 Too Many Requests`
+/// with a JSON-encoded [`AdmissionRejection`], and if the embeddings take longer than the timeout
+/// of the request, a `504 Gateway Timeout`.
 #[utoipa::path(
 post,
 path = "/embeddings",
 request_body = CreateEmbeddingsRequest,
 responses(
 (status = 200, description = "OK", body = EmbeddingsResponse),
-(status = 500, description = "unexpected internal server error", body = ChatCompletionError)
+(status = 400, description = "the embeddings cannot be shortened to the requested dimensions, or an input has an unknown token or does not fit in the context of the model", body = ChatCompletionError),
+(status = 429, description = "too many requests are queued", body = AdmissionRejection),
+(status = 500, description = "unexpected internal server error", body = ChatCompletionError),
+(status = 504, description = "the request timed out", body = ChatCompletionError)
 ),
 )]
 pub async fn create_embeddings(
@@ -1013,32 +2283,47 @@ pub async fn create_embeddings(
             model_name: params.name.to_string(),
         })?;
 
-    let input = req.input.either(
-        move |s| vec![s.to_string()],
-        move |v| v.iter().map(move |s| s.to_string()).collect(),
-    );
-    let mut res = match model.kind {
-        ModelKind::LLM => llm::embeddings(model, input).await?,
-        ModelKind::ChatFaker => chat_faker::embeddings(model, input).await?,
-        _ => todo!(),
+    let args = EmbeddingsArgs {
+        inputs: req.input.into(),
+        context_overflow: req.context_overflow.unwrap_or_default(),
     };
+    let timeout = request_timeout(req.timeout).await;
+    let res = with_timeout(timeout, async move {
+        match model.kind {
+            ModelKind::LLM => llm::embeddings(model, args).await,
+            ModelKind::ChatFaker => chat_faker::embeddings(model, args).await,
+            _ => todo!(),
+        }
+    })
+    .await??;
+
+    let mut data = Vec::with_capacity(res.embeddings.len());
+    for (index, mut embedding) in res.embeddings.into_iter().enumerate() {
+        if let Some(dimensions) = req.dimensions {
+            shorten(&mut embedding, dimensions)?;
+        }
+
+        let values = EmbeddingValues::new(embedding, req.quantization);
+        let embedding = match req.encoding_format.unwrap_or_default() {
+            EmbeddingEncoding::Float => Either::Left(values),
+            EmbeddingEncoding::Base64 => Either::Right(BASE64.encode(values.to_le_bytes())),
+        };
+        data.push(Embedding {
+            object: "embedding".to_string(),
+            embedding,
+            index,
+        });
+    }
 
     Ok(Json(EmbeddingsResponse {
         object: "list".to_string(),
-        embeddings: res
-            .drain(..)
-            .enumerate()
-            .map(move |(index, embedding)| Embedding {
-                object: "embedding".to_string(),
-                embedding,
-                index,
-            })
-            .collect(),
+        data,
         model: req.model.to_string(),
         usage: EmbeddingsUsage {
-            prompt_tokens: 0,
-            total_tokens: 0,
+            prompt_tokens: res.prompt_tokens as usize,
+            total_tokens: res.prompt_tokens as usize,
         },
+        context_overflow: res.overflow.map(Into::into),
     }))
 }
 
@@ -1094,6 +2379,13 @@ pub struct CreateTranscriptionRequest {
 
     /// The [`Uuid`] of an existing audio session.
     pub session: Option<Uuid>,
+
+    /// The number of seconds the transcription may take, past which the request fails with a
+    /// `504 Gateway Timeout`.
+    ///
+    /// If absent, the `request_timeout` configured for the server is used. `0` means there is no
+    /// limit.
+    pub timeout: Option<f32>,
 }
 
 /// The return type of [`create_transcription`].
@@ -1116,14 +2408,18 @@ pub struct TranscriptionResponse {
 /// [openai]: https://platform.openai.com/docs/api-reference/audio/createTranscription
 ///
 /// On failure, may raise a `500 Internal Server Error` with a JSON-encoded [`TranscriptionError`]
-/// to the peer.
+/// to the peer. If too many requests are queued, raises a `429 Too Many Requests` with a
+/// JSON-encoded [`AdmissionRejection`], and if the transcription takes longer than the timeout of
+/// the request, a `504 Gateway Timeout`.
 #[utoipa::path(
 post,
 path = "/audio/transcriptions",
 request_body = CreateTranscriptionRequest,
 responses(
 (status = 200, description = "OK", body = TranscriptionResponse),
-(status = 500, description = "unexpected internal server error", body = TranscriptionError)
+(status = 429, description = "too many requests are queued", body = AdmissionRejection),
+(status = 500, description = "unexpected internal server error", body = TranscriptionError),
+(status = 504, description = "the request timed out", body = TranscriptionError)
 ),
 )]
 pub async fn create_transcription(
@@ -1169,16 +2465,20 @@ pub async fn create_transcription(
 
     model.preload(Endpoint::AudioTranscriptions).await?;
 
-    let (text, session) = crate::whisper::create_transcription(
-        &req.file.contents,
-        model,
-        req.language.as_deref(),
-        req.prompt.as_deref(),
-        req.temperature,
-        req.create_session.unwrap_or(false),
-        req.session,
+    let timeout = request_timeout(req.timeout).await;
+    let (text, session) = with_timeout(
+        timeout,
+        crate::whisper::create_transcription(
+            &req.file.contents,
+            model,
+            req.language.as_deref(),
+            req.prompt.as_deref(),
+            req.temperature,
+            req.create_session.unwrap_or(false),
+            req.session,
+        ),
     )
-    .await?;
+    .await??;
 
     Ok(Json(TranscriptionResponse { text, session }))
 }
@@ -1229,11 +2529,19 @@ pub enum TranscriptionError {
     /// An error occurred while processing the request to this endpoint.
     #[error("an error occurred while processing the request: {0}")]
     Endpoint(#[from] WhisperEndpointError),
+
+    /// The request took longer than its timeout.
+    #[error(transparent)]
+    Timeout(#[from] RequestTimeout),
 }
 
 impl IntoResponse for TranscriptionError {
     fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, Json(self)).into_response()
+        let status = match self {
+            TranscriptionError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, Json(self)).into_response()
     }
 }
 
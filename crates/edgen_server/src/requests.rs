@@ -0,0 +1,281 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Tracking of the requests in flight in the AI endpoints, which can be aborted by their id, and
+//! enforcement of their timeouts.
+//!
+//! A request is stopped by dropping its handler, which stops the generation in the runtimes. This
+//! also happens when the peer disconnects, as the server then drops the handler.
+//!
+//! The ids are random UUIDs, and knowing the id of a request is all it takes to abort it. There
+//! are no users to scope them to, so an id should only be shared with whoever may abort the
+//! request.
+
+use std::future::Future;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::{self, Request};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::info;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use edgen_core::cancellation::CancellationToken;
+use edgen_core::settings::SETTINGS;
+
+/// The header carrying the id of a request, which may be passed to abort it. If a request does
+/// not have one, the server picks a random id. The id is returned in the response headers.
+///
+/// An id picked by the client must be a UUID, so that other clients cannot guess it.
+pub const REQUEST_ID_HEADER: &str = "x-edgen-request-id";
+
+/// The status of the response to a request that was aborted before its handler returned.
+///
+/// This is the non-standard `499 Client Closed Request`, which is not sent to the peer if it
+/// disconnected.
+const ABORTED: u16 = 499;
+
+/// The requests in flight, by id.
+static IN_FLIGHT: Lazy<DashMap<String, CancellationToken>> = Lazy::new(Default::default);
+
+/// A request in flight, which is forgotten when dropped.
+struct InFlight {
+    id: String,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.remove(&self.id);
+    }
+}
+
+/// Middleware tracking a request while it is in flight, which is until the whole response body was
+/// sent, so that it may be aborted with [`abort_request`].
+///
+/// Raises a `400 Bad Request` if the request has an id that is not a UUID, and a `409 Conflict` if
+/// another request in flight has the same id.
+pub async fn track(request: Request, next: Next) -> Response {
+    let Some(id) = request_id(request.headers()) else {
+        let reason = format!("the {REQUEST_ID_HEADER} header must be a UUID");
+        return (StatusCode::BAD_REQUEST, reason).into_response();
+    };
+    let cancel = CancellationToken::new();
+    let in_flight = match IN_FLIGHT.entry(id.clone()) {
+        Entry::Occupied(_) => return StatusCode::CONFLICT.into_response(),
+        Entry::Vacant(entry) => {
+            entry.insert(cancel.clone());
+            InFlight { id: id.clone() }
+        }
+    };
+    // PANIC SAFETY: the id is a UUID
+    let header = HeaderValue::from_str(&id).unwrap();
+
+    let mut response = tokio::select! {
+        response = next.run(request) => response,
+        _ = cancel.cancelled() => {
+            info!("Request {id} aborted");
+            // PANIC SAFETY: the status code is within the valid range
+            StatusCode::from_u16(ABORTED).unwrap().into_response()
+        }
+    };
+    response.headers_mut().insert(REQUEST_ID_HEADER, header);
+
+    // a streamed response body ends early if the request is aborted
+    let aborted = async move { cancel.cancelled().await };
+    response.map(move |body| {
+        Body::from_stream(
+            body.into_data_stream()
+                .map(move |chunk| {
+                    let _in_flight = &in_flight;
+                    chunk
+                })
+                .take_until(aborted),
+        )
+    })
+}
+
+/// Returns the id in the [`REQUEST_ID_HEADER`] of a request with `headers`, or a random id if it
+/// does not have one.
+///
+/// Returns `None` if the id of the request is not a UUID.
+fn request_id(headers: &HeaderMap) -> Option<String> {
+    match headers.get(REQUEST_ID_HEADER) {
+        Some(value) => parse_id(value.to_str().ok()?),
+        None => Some(Uuid::new_v4().to_string()),
+    }
+}
+
+/// Parses a request id, which must be a UUID, into its canonical form.
+fn parse_id(id: &str) -> Option<String> {
+    Uuid::parse_str(id.trim()).ok().map(|id| id.to_string())
+}
+
+/// DELETE `/v1/requests/{id}`: aborts the request in flight with the id, which was returned in its
+/// `X-Edgen-Request-Id` response header, or passed in that same request header. A request that is
+/// aborted before its response started is answered with a `499`, and a streamed response ends
+/// early.
+///
+/// The response headers of a request that is not streamed only arrive with its end, so its id
+/// must be a random UUID picked by the client and sent in the `X-Edgen-Request-Id` request header
+/// for the request to be aborted.
+///
+/// Anyone who knows the id of a request may abort it.
+///
+/// Raises a `404 Not Found` if there is no such request in flight.
+#[utoipa::path(
+        delete,
+        path = "/requests/{id}",
+        params(("id" = String, Path, description = "the id of the request")),
+        responses(
+            (status = 204, description = "the request was aborted"),
+            (status = 404, description = "the request is not in flight"),
+        ),
+)]
+pub async fn abort_request(extract::Path(id): extract::Path<String>) -> StatusCode {
+    let Some(id) = parse_id(&id) else {
+        return StatusCode::NOT_FOUND;
+    };
+    match IN_FLIGHT.get(&id).map(|cancel| cancel.clone()) {
+        Some(cancel) => {
+            cancel.cancel();
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+/// A request that took longer than its timeout.
+#[derive(Serialize, Deserialize, Error, ToSchema, Debug, Clone, Copy, PartialEq)]
+#[error("the request timed out after {seconds} seconds")]
+pub struct RequestTimeout {
+    /// The number of seconds the request was allowed to take.
+    pub seconds: f32,
+}
+
+/// Returns how long a request may take, given its `timeout` in seconds, or `None` if there is no
+/// limit.
+///
+/// If the request doesn't specify a timeout, the `request_timeout` in the settings is used.
+pub async fn request_timeout(requested: Option<f32>) -> Option<Duration> {
+    SETTINGS.read().await.read().await.timeout(requested)
+}
+
+/// Awaits `future`, or fails with a [`RequestTimeout`] once it takes longer than `timeout`. The
+/// future is then dropped, which cancels the work of the runtimes on their blocking threads
+/// through the [`CancellationToken`]s it owns.
+///
+/// [`CancellationToken`]: edgen_core::cancellation::CancellationToken
+pub async fn with_timeout<F: Future>(
+    timeout: Option<Duration>,
+    future: F,
+) -> Result<F::Output, RequestTimeout> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| RequestTimeout {
+                seconds: timeout.as_secs_f32(),
+            }),
+        None => Ok(future.await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::middleware::from_fn;
+    use axum::routing::{delete, get};
+    use axum::Router;
+    use axum_test::TestServer;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    #[test]
+    fn test_request_id() {
+        let mut headers = HeaderMap::new();
+        assert!(Uuid::parse_str(&request_id(&headers).unwrap()).is_ok());
+
+        let id = "4C7B1E2A-0F3D-4B8E-9A6C-5D2E1F0A3B4C";
+        headers.insert(REQUEST_ID_HEADER, format!(" {id} ").parse().unwrap());
+        assert_eq!(request_id(&headers).unwrap(), id.to_lowercase());
+
+        // predictable ids would let other clients abort the request
+        headers.insert(REQUEST_ID_HEADER, "job-42".parse().unwrap());
+        assert_eq!(request_id(&headers), None);
+    }
+
+    #[tokio::test]
+    async fn test_with_timeout() {
+        assert_eq!(with_timeout(None, async { 1 }).await, Ok(1));
+        assert_eq!(
+            with_timeout(Some(Duration::from_secs(60)), async { 1 }).await,
+            Ok(1)
+        );
+        assert_eq!(
+            with_timeout(
+                Some(Duration::from_millis(10)),
+                sleep(Duration::from_secs(60))
+            )
+            .await,
+            Err(RequestTimeout { seconds: 0.01 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_abort_request() {
+        let router = Router::new()
+            .route(
+                "/slow",
+                get(|| async {
+                    sleep(Duration::from_secs(60)).await;
+                    "done"
+                })
+                .layer(from_fn(track)),
+            )
+            .route("/requests/:id", delete(abort_request));
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+        let id = Uuid::new_v4().to_string();
+
+        let request = async {
+            server
+                .get("/slow")
+                .add_header(REQUEST_ID_HEADER.parse().unwrap(), id.parse().unwrap())
+                .await
+        };
+        let abort = async {
+            // wait for the request to be in flight
+            while !IN_FLIGHT.contains_key(&id) {
+                sleep(Duration::from_millis(10)).await;
+            }
+            server.delete(&format!("/requests/{id}")).await
+        };
+        let (response, abort) = tokio::join!(request, abort);
+
+        abort.assert_status(StatusCode::NO_CONTENT);
+        assert_eq!(response.status_code().as_u16(), ABORTED);
+        assert_eq!(response.header(REQUEST_ID_HEADER), id);
+        assert!(!IN_FLIGHT.contains_key(&id));
+
+        server
+            .delete(&format!("/requests/{id}"))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+}
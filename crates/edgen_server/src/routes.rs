@@ -14,6 +14,7 @@
 
 use axum::{
     http::{uri::Uri, Method, StatusCode},
+    middleware::from_fn,
     response::IntoResponse,
     routing::{delete, get, post},
     Router,
@@ -21,8 +22,11 @@ use axum::{
 
 use tracing::warn;
 
+use crate::admission;
+use crate::chat_sessions;
 use crate::model_man;
 use crate::openai_shim;
+use crate::requests;
 use crate::status;
 use crate::{image_generation, misc};
 
@@ -30,19 +34,50 @@ pub fn routes() -> Router {
     Router::new()
         // -- AI endpoints -----------------------------------------------------
         // ---- Chat -----------------------------------------------------------
-        .route("/v1/chat/completions", post(openai_shim::chat_completions))
+        .route(
+            "/v1/chat/completions",
+            post(openai_shim::chat_completions)
+                .layer(from_fn(admission::admit_chat_completions))
+                .layer(from_fn(requests::track)),
+        )
+        .route(
+            "/v1/completions",
+            post(openai_shim::completions)
+                .layer(from_fn(admission::admit_chat_completions))
+                .layer(from_fn(requests::track)),
+        )
+        .route("/v1/chat/sessions", get(chat_sessions::list_sessions))
+        .route(
+            "/v1/chat/sessions/:id",
+            get(chat_sessions::retrieve_session),
+        )
+        .route(
+            "/v1/chat/sessions/:id",
+            delete(chat_sessions::delete_session),
+        )
         // ---- Embeddings -----------------------------------------------------
-        .route("/v1/embeddings", post(openai_shim::create_embeddings))
+        .route(
+            "/v1/embeddings",
+            post(openai_shim::create_embeddings)
+                .layer(from_fn(admission::admit_embeddings))
+                .layer(from_fn(requests::track)),
+        )
         // ---- Audio ----------------------------------------------------------
         .route(
             "/v1/audio/transcriptions",
-            post(openai_shim::create_transcription),
+            post(openai_shim::create_transcription)
+                .layer(from_fn(admission::admit_audio_transcriptions))
+                .layer(from_fn(requests::track)),
         )
         // ---- Image ----------------------------------------------------------
         .route(
             "/v1/image/generations",
-            post(image_generation::generate_image),
+            post(image_generation::generate_image)
+                .layer(from_fn(admission::admit_image_generations))
+                .layer(from_fn(requests::track)),
         )
+        // ---- Requests -------------------------------------------------------
+        .route("/v1/requests/:id", delete(requests::abort_request))
         // -- AI status endpoints ----------------------------------------------
         // ---- Chat -----------------------------------------------------------
         .route(
@@ -56,6 +91,11 @@ pub fn routes() -> Router {
         )
         // ---- Embeddings -----------------------------------------------------
         .route("/v1/embeddings/status", get(status::embeddings_status))
+        // ---- Image ----------------------------------------------------------
+        .route(
+            "/v1/image/generations/status",
+            get(status::image_generations_status),
+        )
         // -- Model Manager ----------------------------------------------------
         // -- Model Manager ----------------------------------------------------
         .route("/v1/models", get(model_man::list_models))
@@ -63,6 +103,7 @@ pub fn routes() -> Router {
         .route("/v1/models/:model", delete(model_man::delete_model))
         // -- Miscellaneous services -------------------------------------------
         .route("/v1/misc/version", get(misc::edgen_version))
+        .route("/v1/misc/render_prompt", post(misc::render_prompt))
         // -- Catch-all route to log all requests ------------------------------
         .fallback(catch_all)
 }
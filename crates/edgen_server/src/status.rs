@@ -24,13 +24,24 @@ use tokio::sync::RwLock;
 use tracing::{error, info, warn};
 use utoipa::ToSchema;
 
+use edgen_core::admission::QueueStatus;
+use edgen_core::memory::{MemoryUsage, MEMORY_BUDGET};
+use edgen_core::speculative::{SpeculativeStatus, SPECULATIVE_STATS};
+
+use crate::admission;
+
 /// GET `/v1/chat/completions/status`: returns the current status of the /chat/completions endpoint.
 ///
 /// The status is returned as json value AIStatus.
 /// For any error, the version endpoint returns "internal server error".
 pub async fn chat_completions_status() -> Response {
-    let state = get_chat_completions_status().read().await;
-    Json(state.clone()).into_response()
+    let mut state = current_status(
+        get_chat_completions_status(),
+        admission::chat_completions_queue(),
+    )
+    .await;
+    state.speculative_decoding = SPECULATIVE_STATS.status();
+    Json(state).into_response()
 }
 
 /// GET `/v1/audio/transcriptions/status`: returns the current status of the /audio/transcriptions endpoint.
@@ -38,8 +49,11 @@ pub async fn chat_completions_status() -> Response {
 /// The status is returned as json value AIStatus.
 /// For any error, the version endpoint returns "internal server error".
 pub async fn audio_transcriptions_status() -> Response {
-    let state = get_audio_transcriptions_status().read().await;
-    Json(state.clone()).into_response()
+    status_response(
+        get_audio_transcriptions_status(),
+        admission::audio_transcriptions_queue(),
+    )
+    .await
 }
 
 /// GET `/v1/embeddings`: returns the current status of the /embeddings endpoint.
@@ -47,12 +61,39 @@ pub async fn audio_transcriptions_status() -> Response {
 /// The status is returned as json value AIStatus.
 /// For any error, the version endpoint returns "internal server error".
 pub async fn embeddings_status() -> Response {
-    let state = get_embeddings_status().read().await;
-    Json(state.clone()).into_response()
+    status_response(get_embeddings_status(), admission::embeddings_queue()).await
+}
+
+/// GET `/v1/image/generations/status`: returns the current status of the /image/generations
+/// endpoint.
+///
+/// The status is returned as json value AIStatus.
+/// For any error, the version endpoint returns "internal server error".
+pub async fn image_generations_status() -> Response {
+    status_response(
+        get_image_generations_status(),
+        admission::image_generations_queue(),
+    )
+    .await
+}
+
+/// Returns `status` as a json value, along with the current memory usage and the state of the
+/// endpoint's request `queue`.
+async fn status_response(status: &RwLock<AIStatus>, queue: QueueStatus) -> Response {
+    Json(current_status(status, queue).await).into_response()
+}
+
+/// Returns a copy of `status`, along with the current memory usage and the state of the
+/// endpoint's request `queue`.
+async fn current_status(status: &RwLock<AIStatus>, queue: QueueStatus) -> AIStatus {
+    let mut state = status.read().await.clone();
+    state.memory = MEMORY_BUDGET.usage();
+    state.queue = queue;
+    state
 }
 
 /// Current Endpoint status.
-#[derive(ToSchema, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[derive(ToSchema, Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub struct AIStatus {
     /// currently active model for this endpoint
     pub active_model: String,
@@ -62,6 +103,15 @@ pub struct AIStatus {
     pub download_progress: u64,
     /// last errors that occurred for this endpoint
     pub last_errors: VecDeque<String>,
+    /// memory taken by the models and sessions of all endpoints
+    #[serde(default)]
+    pub memory: MemoryUsage,
+    /// requests being handled and waiting for their turn in this endpoint
+    #[serde(default)]
+    pub queue: QueueStatus,
+    /// tokens drafted and accepted by speculative decoding, if any were drafted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speculative_decoding: Option<SpeculativeStatus>,
 }
 
 impl Default for AIStatus {
@@ -71,6 +121,9 @@ impl Default for AIStatus {
             download_ongoing: false,
             download_progress: 0,
             last_errors: VecDeque::from([]),
+            memory: MemoryUsage::default(),
+            queue: QueueStatus::default(),
+            speculative_decoding: None,
         }
     }
 }
@@ -82,6 +135,7 @@ static AISTATES: Lazy<AIStates> = Lazy::new(Default::default);
 const EP_CHAT_COMPLETIONS: usize = 0;
 const EP_AUDIO_TRANSCRIPTIONS: usize = 1;
 const EP_EMBEDDINGS: usize = 2;
+const EP_IMAGE_GENERATIONS: usize = 3;
 
 const MAX_ERRORS: usize = 32;
 
@@ -103,6 +157,12 @@ pub fn get_embeddings_status() -> &'static RwLock<AIStatus> {
     get_status(EP_EMBEDDINGS)
 }
 
+/// Get a protected image generations status.
+/// Call read() or write() on the returned value to get either read or write access.
+pub fn get_image_generations_status() -> &'static RwLock<AIStatus> {
+    get_status(EP_IMAGE_GENERATIONS)
+}
+
 fn get_status(idx: usize) -> &'static RwLock<AIStatus> {
     &AISTATES.endpoints[idx]
 }
@@ -122,6 +182,11 @@ pub async fn reset_embeddings_status() {
     reset_status(EP_EMBEDDINGS).await;
 }
 
+/// Reset the image generations status to its defaults
+pub async fn reset_image_generations_status() {
+    reset_status(EP_IMAGE_GENERATIONS).await;
+}
+
 async fn reset_status(idx: usize) {
     let mut status = get_status(idx).write().await;
     *status = AIStatus::default();
@@ -268,6 +333,7 @@ impl Default for AIStates {
                 RwLock::new(Default::default()),
                 RwLock::new(Default::default()),
                 RwLock::new(Default::default()),
+                RwLock::new(Default::default()),
             ],
         }
     }
@@ -388,7 +454,9 @@ mod tests {
         "{\"active_model\":\"unknown\",\
           \"download_ongoing\":false,\
           \"download_progress\":0,\
-          \"last_errors\":[]\
+          \"last_errors\":[],\
+          \"memory\":{\"used\":0,\"budget\":0},\
+          \"queue\":{\"in_flight\":0,\"queued\":0,\"average_wait_ms\":0}\
          }"
         .to_string()
     }
@@ -520,6 +588,10 @@ mod tests {
         response.assert_status_ok();
         assert!(response.text().len() > 0);
         assert_eq!(response.json::<AIStatus>().active_model, "unknown");
+        assert_eq!(
+            response.json::<AIStatus>().memory.budget,
+            MEMORY_BUDGET.usage().budget
+        );
 
         let model = "shes-a-model-and-shes-looking-good".to_string();
         set_chat_completions_active_model(&model).await;
@@ -705,4 +777,23 @@ mod tests {
         assert!(response.text().len() > 0);
         assert_eq!(response.json::<AIStatus>().active_model, model);
     }
+
+    #[tokio::test]
+    async fn test_image_generations_status() {
+        reset_image_generations_status().await;
+
+        let router = Router::new().route(
+            "/v1/image/generations/status",
+            get(image_generations_status),
+        );
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let response = server.get("/v1/image/generations/status").await;
+
+        response.assert_status_ok();
+        let status = response.json::<AIStatus>();
+        assert_eq!(status.active_model, "unknown");
+        assert_eq!(status.queue, admission::image_generations_queue());
+    }
 }
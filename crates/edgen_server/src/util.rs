@@ -12,8 +12,7 @@
 
 //! Utility types.
 
+pub use edgen_core::stopping_stream::*;
 pub use perishable::*;
-pub use stopping_stream::*;
 
 mod perishable;
-mod stopping_stream;
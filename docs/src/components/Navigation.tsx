@@ -252,6 +252,7 @@ export const navigation: Array<NavGroup> = [
     links: [
       { title: 'Audio', href: '/api-reference/audio' },
       { title: 'Chat', href: '/api-reference/chat' },
+      { title: 'Completions', href: '/api-reference/completions' },
       { title: 'Embeddings', href: '/api-reference/embeddings' },
       { title: 'Models', href: '/api-reference/models' },
       { title: 'Image', href: '/api-reference/image' },
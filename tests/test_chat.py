@@ -22,14 +22,17 @@ def test_completions_streaming():
     assert(stream.response.status_code == 200)
 
     answer = ""
+    finish_reason = None
     for chunk in stream:
         if not chunk.choices:
             continue
 
-        answer += chunk.choices[0].delta.content
+        answer += chunk.choices[0].delta.content or ""
+        finish_reason = chunk.choices[0].finish_reason or finish_reason
 
     # print(answer)
     assert(type(answer) is str)
+    assert(finish_reason in ("stop", "length"))
 
 def test_completions():
     try:
@@ -50,6 +53,27 @@ def test_completions():
     assert(type(content) is str)
     assert("3" in content)
 
+def test_completions_seeded():
+    def complete():
+        try:
+            answer = client.chat.completions.create(
+                model="default",
+                messages=[
+                    {
+                        "role": "user",
+                        "content": "Please tell me a short story.",
+                    },
+                ],
+                seed=42,
+                temperature=0,
+            )
+        except APIConnectionError:
+            pytest.fail("No connection. Is edgen running?")
+
+        return answer.choices[0].message.content
+
+    assert(complete() == complete())
+
 def test_completions_status():
     try:
         status = client.chat.completions.status.create()